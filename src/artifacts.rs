@@ -0,0 +1,216 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::config::ArtifactRetention;
+use slog::{error, info, Logger};
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// The artifact directories that jobs are still using, which cleanup
+/// must leave alone.
+#[derive(Clone, Default)]
+pub struct InFlightDirs {
+	dirs: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+/// Marks a directory in flight until dropped.
+pub struct InFlight {
+	dirs: InFlightDirs,
+	dir: PathBuf,
+}
+
+impl Drop for InFlight {
+	fn drop(&mut self) {
+		self.dirs.dirs.lock().unwrap().remove(&self.dir);
+	}
+}
+
+impl InFlightDirs {
+	pub fn claim(&self, dir: &Path) -> InFlight {
+		self.dirs.lock().unwrap().insert(dir.to_path_buf());
+		InFlight {
+			dirs: self.clone(),
+			dir: dir.to_path_buf(),
+		}
+	}
+
+	fn contains(&self, dir: &Path) -> bool {
+		self.dirs.lock().unwrap().contains(dir)
+	}
+}
+
+/// How many artifact directories in a row couldn't be made. A few in a
+/// row mean the disk is full or read-only rather than one unlucky job.
+#[derive(Clone, Default)]
+pub struct DirFailures {
+	consecutive: Arc<AtomicUsize>,
+}
+
+impl DirFailures {
+	/// Count a failure, returning how many there have now been in a row.
+	pub fn failed(&self) -> usize {
+		self.consecutive.fetch_add(1, Ordering::SeqCst) + 1
+	}
+
+	pub fn succeeded(&self) {
+		self.consecutive.store(0, Ordering::SeqCst);
+	}
+
+	pub fn consecutive(&self) -> usize {
+		self.consecutive.load(Ordering::SeqCst)
+	}
+}
+
+/// Whether the last check found too little disk space to build in.
+#[derive(Clone, Default)]
+pub struct LowDisk {
+	low: Arc<AtomicBool>,
+}
+
+impl LowDisk {
+	pub fn set(&self, low: bool) {
+		self.low.store(low, Ordering::SeqCst);
+	}
+
+	pub fn is_low(&self) -> bool {
+		self.low.load(Ordering::SeqCst)
+	}
+}
+
+/// How many bytes are free, to an unprivileged user, on the filesystem
+/// holding `path`.
+pub fn free_space(path: &Path) -> Result<u64, String> {
+	let c_path = CString::new(path.as_os_str().as_bytes())
+		.map_err(|e| format!("Oops, {} isn't a valid path: {}", path.display(), e))?;
+	let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+	if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+		return Err(format!(
+			"Oops, couldn't check the free space on {}: {}",
+			path.display(),
+			std::io::Error::last_os_error()
+		));
+	}
+	#[allow(clippy::unnecessary_cast)]
+	Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+fn size_of(path: &Path) -> u64 {
+	match fs::symlink_metadata(path) {
+		Ok(metadata) if metadata.is_dir() => fs::read_dir(path)
+			.map(|entries| {
+				entries
+					.filter_map(|entry| entry.ok())
+					.map(|entry| size_of(&entry.path()))
+					.sum()
+			})
+			.unwrap_or(0),
+		Ok(metadata) => metadata.len(),
+		Err(_) => 0,
+	}
+}
+
+/*
+ * Add the files under `dir` to `files`, as paths relative to `root`,
+ * until there are `limit` of them.
+ */
+fn collect_files(root: &Path, dir: &Path, limit: usize, files: &mut Vec<String>) {
+	let mut entries: Vec<PathBuf> = match fs::read_dir(dir) {
+		Ok(entries) => entries
+			.filter_map(|entry| entry.ok())
+			.map(|e| e.path())
+			.collect(),
+		Err(_) => return,
+	};
+	entries.sort();
+	for path in entries {
+		if files.len() >= limit {
+			return;
+		}
+		if path.is_dir() {
+			collect_files(root, &path, limit, files);
+		} else if let Ok(relative) = path.strip_prefix(root) {
+			files.push(relative.display().to_string());
+		}
+	}
+}
+
+/// Up to `limit` of the files a build left in its artifact directory,
+/// relative to it, to show what it made instead of what was expected.
+pub fn list_files(artifact_area: &Path, limit: usize) -> Vec<String> {
+	let mut files = vec![];
+	collect_files(artifact_area, artifact_area, limit, &mut files);
+	files
+}
+
+/// Delete the artifact directories under `root` that are older than the
+/// retention age, then the oldest of the rest until they fit in the
+/// size budget. Directories of jobs still running are never deleted.
+pub fn clean_artifacts(
+	root: &Path,
+	retention: &ArtifactRetention,
+	in_flight: &InFlightDirs,
+	logger: &Logger,
+) {
+	let entries = match fs::read_dir(root) {
+		Ok(entries) => entries,
+		Err(e) => {
+			error!(
+				logger,
+				"Could not list the artifacts in {}: {}",
+				root.display(),
+				e
+			);
+			return;
+		}
+	};
+	let mut dirs: Vec<(PathBuf, SystemTime, u64)> = entries
+		.filter_map(|entry| entry.ok())
+		.map(|entry| entry.path())
+		.filter(|path| path.is_dir() && !in_flight.contains(path))
+		.map(|path| {
+			let modified = fs::metadata(&path)
+				.and_then(|m| m.modified())
+				.unwrap_or_else(|_| SystemTime::now());
+			let size = size_of(&path);
+			(path, modified, size)
+		})
+		.collect();
+	dirs.sort_by_key(|(_, modified, _)| *modified);
+
+	let max_age = retention.max_age_secs.map(Duration::from_secs);
+	let mut total: u64 = dirs.iter().map(|(_, _, size)| size).sum();
+	for (path, modified, size) in dirs {
+		let too_old = match max_age {
+			Some(max_age) => modified.elapsed().map(|age| age > max_age).unwrap_or(false),
+			None => false,
+		};
+		let over_budget = match retention.max_total_bytes {
+			Some(max_total_bytes) => total > max_total_bytes,
+			None => false,
+		};
+		if !too_old && !over_budget {
+			continue;
+		}
+		match fs::remove_dir_all(&path) {
+			Ok(()) => {
+				total -= size;
+				info!(logger, "Removed the artifacts in {}.", path.display());
+			}
+			Err(e) => error!(
+				logger,
+				"Could not remove the artifacts in {}: {}",
+				path.display(),
+				e
+			),
+		}
+	}
+}
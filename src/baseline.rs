@@ -0,0 +1,69 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::profile::ScenarioResult;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/*
+ * Enough baselines for a handful of repos with a long history of
+ * profiled bases; beyond this the oldest are dropped.
+ */
+const MAX_BASELINES: usize = 256;
+
+/// A profile of a commit that later profiles can be compared against.
+#[derive(Clone, Debug)]
+pub struct Baseline {
+	pub sha: String,
+	pub scenarios: Vec<ScenarioResult>,
+	pub recorded_at: SystemTime,
+}
+
+/// Baselines keyed by commit SHA, shared by every worker.
+#[derive(Clone, Default)]
+pub struct BaselineCache {
+	baselines: Arc<Mutex<HashMap<String, Baseline>>>,
+}
+
+impl BaselineCache {
+	pub fn insert(&self, sha: &str, scenarios: Vec<ScenarioResult>) {
+		let mut baselines = self.baselines.lock().unwrap();
+		if baselines.len() >= MAX_BASELINES && !baselines.contains_key(sha) {
+			let oldest = baselines
+				.values()
+				.min_by_key(|b| b.recorded_at)
+				.map(|b| b.sha.clone());
+			if let Some(oldest) = oldest {
+				baselines.remove(&oldest);
+			}
+		}
+		baselines.insert(
+			sha.to_string(),
+			Baseline {
+				sha: sha.to_string(),
+				scenarios,
+				recorded_at: SystemTime::now(),
+			},
+		);
+	}
+
+	pub fn for_sha(&self, sha: &str) -> Option<Baseline> {
+		self.baselines.lock().unwrap().get(sha).cloned()
+	}
+
+	pub fn len(&self) -> usize {
+		self.baselines.lock().unwrap().len()
+	}
+
+	/// Forget every cached baseline, returning how many there were.
+	pub fn clear(&self) -> usize {
+		let mut baselines = self.baselines.lock().unwrap();
+		let cleared = baselines.len();
+		baselines.clear();
+		cleared
+	}
+}
@@ -0,0 +1,128 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::format::format_batch_comment;
+use crate::github::GitHubClient;
+use crate::job::ProfileOutcome;
+use crate::profile::ScenarioResult;
+use slog::{error, info, Logger};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// How the profile of one pull request in a batch ended.
+pub struct BatchEntry {
+	pub number: u64,
+	pub sha: String,
+	/// A word or two, such as "passed" or "build failed".
+	pub result: &'static str,
+	pub profile_url: Option<String>,
+	pub scenarios: Vec<ScenarioResult>,
+}
+
+/// The profiles asked for by one `profile batch`. Every job in the
+/// batch holds a clone. Once `start_batch` has queued them all and the
+/// worker running the last of them says it has finished, however its
+/// job ended, the profiles are compared in one comment where the batch
+/// was asked for.
+#[derive(Clone)]
+pub struct Batch {
+	inner: Arc<BatchInner>,
+}
+
+struct BatchInner {
+	numbers: Vec<u64>,
+	entries: Mutex<Vec<BatchEntry>>,
+	/* The pull requests whose jobs were queued. */
+	queued: Mutex<Vec<u64>>,
+	/* The queued jobs still to finish, and one more until all_queued. */
+	unfinished: AtomicUsize,
+	comments_url: String,
+	github: GitHubClient,
+	logger: Logger,
+}
+
+impl Batch {
+	pub fn new(numbers: &[u64], comments_url: &str, github: GitHubClient, logger: Logger) -> Self {
+		Self {
+			inner: Arc::new(BatchInner {
+				numbers: numbers.to_vec(),
+				entries: Mutex::new(vec![]),
+				queued: Mutex::new(vec![]),
+				unfinished: AtomicUsize::new(1),
+				comments_url: comments_url.to_string(),
+				github,
+				logger,
+			}),
+		}
+	}
+
+	/// Count the job for pull request `number` as one to wait for. This
+	/// comes before the job is queued, since it may finish at once.
+	pub fn queued(&self, number: u64) {
+		self.inner.unfinished.fetch_add(1, Ordering::SeqCst);
+		self.inner.queued.lock().unwrap().push(number);
+	}
+
+	/// Stop waiting for the job for pull request `number` after all,
+	/// because the queue turned it away.
+	pub fn turned_away(&self, number: u64) {
+		self.inner.queued.lock().unwrap().retain(|&n| n != number);
+		self.release();
+	}
+
+	/// Say that every job the batch will have has been queued.
+	pub fn all_queued(&self) {
+		self.release();
+	}
+
+	/// Say, from the worker, that one of the batch's queued jobs has
+	/// finished.
+	pub fn finished(&self) {
+		self.release();
+	}
+
+	/// Keep how the profile of pull request `number` ended, for the
+	/// comparison.
+	pub fn record(
+		&self,
+		number: u64,
+		sha: &str,
+		outcome: &ProfileOutcome,
+		budgets_ms: &BTreeMap<String, u64>,
+	) {
+		self.inner.entries.lock().unwrap().push(BatchEntry {
+			number,
+			sha: sha.to_string(),
+			result: outcome.summary(budgets_ms),
+			profile_url: outcome.profile_url().map(|url| url.to_string()),
+			scenarios: outcome.scenarios().to_vec(),
+		});
+	}
+
+	/*
+	 * Whoever lets go of the last hold on the batch compares it, unless
+	 * nothing was ever queued.
+	 */
+	fn release(&self) {
+		let inner = &self.inner;
+		if inner.unfinished.fetch_sub(1, Ordering::SeqCst) != 1 {
+			return;
+		}
+		if inner.queued.lock().unwrap().is_empty() {
+			info!(inner.logger, "Nothing in the batch was queued to compare.");
+			return;
+		}
+		let comment = {
+			let entries = inner.entries.lock().unwrap();
+			format_batch_comment(&inner.numbers, &entries)
+		};
+		match inner.github.post_comment(&inner.comments_url, &comment) {
+			Ok(()) => info!(inner.logger, "Posted the batch's comparison."),
+			Err(e) => error!(inner.logger, "Failed to post the batch's comparison: {}", e),
+		}
+	}
+}
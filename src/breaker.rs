@@ -0,0 +1,66 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Counts NimbleDroid failures in a row, and once there have been enough
+/// of them stops jobs from building APKs ND won't take. While it is open
+/// it lets one job through now and then to see whether ND is back.
+#[derive(Clone, Default)]
+pub struct Breaker {
+	state: Arc<Mutex<BreakerState>>,
+}
+
+#[derive(Default)]
+struct BreakerState {
+	consecutive: usize,
+	/* When the breaker opened, or last let a job through to probe. */
+	opened_at: Option<Instant>,
+}
+
+impl Breaker {
+	/// Whether a job may go ahead. While the breaker is open, one job is
+	/// let through every `probe_every` to find out whether ND is back.
+	pub fn allow(&self, probe_every: Duration) -> bool {
+		let mut state = self.state.lock().unwrap();
+		match state.opened_at {
+			None => true,
+			Some(opened_at) if opened_at.elapsed() >= probe_every => {
+				state.opened_at = Some(Instant::now());
+				true
+			}
+			Some(_) => false,
+		}
+	}
+
+	/// Count a failure, opening the breaker once there have been
+	/// `threshold` in a row. Returns whether this opened it.
+	pub fn failed(&self, threshold: usize) -> bool {
+		let mut state = self.state.lock().unwrap();
+		state.consecutive += 1;
+		if state.opened_at.is_none() && state.consecutive >= threshold {
+			state.opened_at = Some(Instant::now());
+			return true;
+		}
+		false
+	}
+
+	/// Close the breaker. Returns whether it was open.
+	pub fn succeeded(&self) -> bool {
+		let mut state = self.state.lock().unwrap();
+		state.consecutive = 0;
+		state.opened_at.take().is_some()
+	}
+
+	pub fn is_open(&self) -> bool {
+		self.state.lock().unwrap().opened_at.is_some()
+	}
+
+	pub fn consecutive(&self) -> usize {
+		self.state.lock().unwrap().consecutive
+	}
+}
@@ -0,0 +1,463 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::config::Config;
+use crate::ToExitCode;
+use slog::{error, info, Logger};
+use std::fs::File;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// What to build, and where to leave the output.
+pub struct BuildRequest<'a> {
+	/// The name of the build's container, so that it can be killed.
+	pub job_name: &'a str,
+	/// The local directory the build's output ends up in.
+	pub artifact_area: &'a Path,
+	pub clone_url: &'a str,
+	pub sha: &'a str,
+	pub gradle_task: &'a str,
+	pub config: &'a Config,
+	/// Where to write everything the build prints, instead of passing it
+	/// through to ndhook's own output.
+	pub log_path: Option<&'a Path>,
+}
+
+/// How a build ran.
+pub struct BuildRun {
+	/// The build script's exit code. Meaningless when there is an
+	/// `error`.
+	pub exit_code: i32,
+	/// The ID the build image resolved to where the build ran.
+	pub image_id: Option<String>,
+	/// Why the build couldn't be run at all, when it couldn't.
+	pub error: Option<String>,
+}
+
+/// Somewhere the build script runs.
+pub trait Builder {
+	/// Run the build script in the configured image, leaving its output
+	/// in the request's artifact area.
+	fn build(&self, request: &BuildRequest, logger: &Logger) -> BuildRun;
+	/// Stop a build's container, if it is still running.
+	fn kill(&self, job_name: &str, logger: &Logger);
+	/// Run `command` wherever builds run, such as to pull the build
+	/// image before the first build needs it.
+	fn warm_up(&self, command: &[String]) -> Result<(), String>;
+}
+
+/// The command `warmup_on_start` runs: `warmup_command`, or by default
+/// a container of the build image that does nothing, which is enough to
+/// pull it.
+pub fn warmup_command(config: &Config) -> Vec<String> {
+	if !config.warmup_command.is_empty() {
+		return config.warmup_command.clone();
+	}
+	vec![
+		"docker".to_string(),
+		"run".to_string(),
+		"--rm".to_string(),
+		config.build_image.clone(),
+		"true".to_string(),
+	]
+}
+
+/*
+ * Run a warmup command to the end, saying why it failed if it did.
+ */
+fn run_warmup(mut command: Command) -> Result<(), String> {
+	match command.output() {
+		Ok(output) if output.status.success() => Ok(()),
+		Ok(output) => Err(format!(
+			"it exited with {}: {}",
+			output.status,
+			String::from_utf8_lossy(&output.stderr).trim()
+		)),
+		Err(e) => Err(format!("it couldn't be run: {}", e)),
+	}
+}
+
+/// Builds with Docker on this host.
+pub struct LocalBuilder;
+
+/// Builds with Docker on other hosts, over SSH, taking turns between
+/// them. Each host needs Docker and the build image; the output is copied
+/// back with tar. The build environment's values are visible in the
+/// remote process list.
+pub struct SshBuilder {
+	hosts: Vec<String>,
+}
+
+/*
+ * Which host the next remote build goes to, shared by every worker so
+ * that concurrent builds spread out.
+ */
+static NEXT_HOST: AtomicUsize = AtomicUsize::new(0);
+
+/*
+ * The `docker run` arguments for a build writing to `output_dir`.
+ *
+ * Locally, docker passes each build environment variable through from
+ * its own environment so that the values don't show up in the process
+ * list. A `remote` build can't pass anything but its command line along,
+ * so the values go there; and it has no terminal to attach. Neither does
+ * a build whose output is logged.
+ */
+fn docker_run_args(request: &BuildRequest, output_dir: &str, remote: bool) -> Vec<String> {
+	let config = request.config;
+	let mut args: Vec<String> = vec!["run".to_string(), "--rm".to_string()];
+	if !remote && request.log_path.is_none() {
+		args.push("-ti".to_string());
+	}
+	args.extend(vec![
+		"--name".to_string(),
+		request.job_name.to_string(),
+		"--volume".to_string(),
+		format!(
+			"{}:/build_output/{}",
+			output_dir,
+			config
+				.build_volume_relabel
+				.map_or("", |relabel| relabel.mount_suffix())
+		),
+	]);
+	for (name, value) in &config.build_env {
+		args.push("--env".to_string());
+		if remote {
+			args.push(format!("{}={}", name, value));
+		} else {
+			args.push(name.to_string());
+		}
+	}
+	args.push(config.build_image.clone());
+	args.push(config.build_script.clone());
+	args.push(request.clone_url.to_string());
+	args.push(request.sha.to_string());
+	args.push(request.gradle_task.to_string());
+	args.push("app/build/outputs/apk/*".to_string());
+	args.extend(config.extra_build_args.iter().cloned());
+	args
+}
+
+fn image_inspect_args(image: &str) -> Vec<String> {
+	vec![
+		"image".to_string(),
+		"inspect".to_string(),
+		"--format".to_string(),
+		"{{.Id}}".to_string(),
+		image.to_string(),
+	]
+}
+
+/*
+ * The ID the build image resolves to, from the output of inspecting it,
+ * so that a result can be tied to the exact toolchain even when the
+ * image is given by tag.
+ */
+fn image_id(output: std::io::Result<std::process::Output>, logger: &Logger) -> Option<String> {
+	match output {
+		Ok(ref output) if output.status.success() => {
+			let id = String::from_utf8_lossy(&output.stdout).trim().to_string();
+			if id.is_empty() {
+				None
+			} else {
+				Some(id)
+			}
+		}
+		Ok(output) => {
+			error!(
+				logger,
+				"Could not inspect the build image: {}",
+				String::from_utf8_lossy(&output.stderr).trim()
+			);
+			None
+		}
+		Err(e) => {
+			error!(logger, "Could not inspect the build image: {}", e);
+			None
+		}
+	}
+}
+
+/*
+ * Why `program` couldn't be started to run the build, if it couldn't.
+ * The errno that leaves as the exit code would be mistaken for the
+ * build's own.
+ */
+fn spawn_error(started: &std::io::Result<ExitStatus>, program: &str) -> Option<String> {
+	match started {
+		Ok(_) => None,
+		Err(e) if e.kind() == ErrorKind::NotFound => {
+			Some(format!("{} is not installed or not on PATH", program))
+		}
+		Err(e) => Some(format!("failed to spawn {}: {}", program, e)),
+	}
+}
+
+/*
+ * Send everything a command prints to the request's log, if it has one.
+ */
+fn log_output(command: &mut Command, request: &BuildRequest) -> Result<(), String> {
+	let path = match request.log_path {
+		Some(path) => path,
+		None => return Ok(()),
+	};
+	let log = File::create(path)
+		.and_then(|log| Ok((log.try_clone()?, log)))
+		.map_err(|e| format!("couldn't create the build log: {}", e))?;
+	command.stdout(log.0).stderr(log.1);
+	Ok(())
+}
+
+/*
+ * Quote a word for the remote shell that ssh hands its command to.
+ */
+fn shell_quote(word: &str) -> String {
+	format!("'{}'", word.replace('\'', "'\\''"))
+}
+
+impl Builder for LocalBuilder {
+	fn build(&self, request: &BuildRequest, logger: &Logger) -> BuildRun {
+		let output_dir = request.artifact_area.display().to_string();
+		let mut docker = Command::new("docker");
+		docker
+			.args(docker_run_args(request, &output_dir, false))
+			.envs(&request.config.build_env);
+		if let Err(error) = log_output(&mut docker, request) {
+			return BuildRun {
+				exit_code: -1,
+				image_id: None,
+				error: Some(error),
+			};
+		}
+		let started = docker.status();
+		if let Some(error) = spawn_error(&started, "Docker") {
+			return BuildRun {
+				exit_code: started.to_exit_code(),
+				image_id: None,
+				error: Some(error),
+			};
+		}
+		let exit_code = started.to_exit_code();
+		let image_id = image_id(
+			Command::new("docker")
+				.args(image_inspect_args(&request.config.build_image))
+				.output(),
+			logger,
+		);
+		BuildRun {
+			exit_code,
+			image_id,
+			error: None,
+		}
+	}
+
+	fn kill(&self, job_name: &str, logger: &Logger) {
+		match Command::new("docker").arg("kill").arg(job_name).output() {
+			Ok(ref output) if output.status.success() => {
+				info!(logger, "Killed the build container {}.", job_name)
+			}
+			Ok(_) => info!(logger, "There was no build container {} to kill.", job_name),
+			Err(e) => error!(logger, "Could not run docker to kill a build: {}", e),
+		}
+	}
+
+	fn warm_up(&self, command: &[String]) -> Result<(), String> {
+		let (program, args) = command
+			.split_first()
+			.ok_or_else(|| "Oops, the warmup command is empty.".to_string())?;
+		let mut warmup = Command::new(program);
+		warmup.args(args);
+		run_warmup(warmup).map_err(|e| format!("Oops, the warmup failed: {}", e))
+	}
+}
+
+impl SshBuilder {
+	fn ssh(&self, host: &str, words: &[String]) -> Command {
+		let remote: Vec<String> = words.iter().map(|w| shell_quote(w)).collect();
+		let mut ssh = Command::new("ssh");
+		ssh.arg("-o")
+			.arg("BatchMode=yes")
+			.arg(host)
+			.arg("--")
+			.arg(remote.join(" "));
+		ssh
+	}
+
+	/*
+	 * Copy the contents of a remote directory into a local one by piping
+	 * tar through ssh.
+	 */
+	fn copy_back(&self, host: &str, remote_dir: &str, local_dir: &Path) -> Result<(), String> {
+		let mut sender = self
+			.ssh(
+				host,
+				&[
+					"tar".to_string(),
+					"-C".to_string(),
+					remote_dir.to_string(),
+					"-cf".to_string(),
+					"-".to_string(),
+					".".to_string(),
+				],
+			)
+			.stdout(Stdio::piped())
+			.spawn()
+			.map_err(|e| format!("couldn't run ssh: {}", e))?;
+		let received = Command::new("tar")
+			.arg("-C")
+			.arg(local_dir)
+			.arg("-xf")
+			.arg("-")
+			.stdin(sender.stdout.take().unwrap())
+			.status();
+		let sent = sender.wait();
+		match (sent, received) {
+			(Ok(sent), Ok(received)) if sent.success() && received.success() => Ok(()),
+			(Ok(sent), Ok(received)) => Err(format!(
+				"sending ended with {} and receiving with {}",
+				sent, received
+			)),
+			(Err(e), _) | (_, Err(e)) => Err(e.to_string()),
+		}
+	}
+}
+
+impl Builder for SshBuilder {
+	fn build(&self, request: &BuildRequest, logger: &Logger) -> BuildRun {
+		let host = &self.hosts[NEXT_HOST.fetch_add(1, Ordering::Relaxed) % self.hosts.len()];
+		info!(logger, "Building on {}.", host);
+		let failed = |e: String| {
+			error!(logger, "(Err) Could not build on {}: {}", host, e);
+			BuildRun {
+				exit_code: -1,
+				image_id: None,
+				error: Some(format!("couldn't build on {}: {}", host, e)),
+			}
+		};
+
+		let made = self
+			.ssh(host, &["mktemp".to_string(), "-d".to_string()])
+			.output();
+		let remote_dir = match made {
+			Ok(ref output) if output.status.success() => {
+				String::from_utf8_lossy(&output.stdout).trim().to_string()
+			}
+			Ok(output) => {
+				return failed(format!(
+					"couldn't make a directory: {}",
+					String::from_utf8_lossy(&output.stderr).trim()
+				))
+			}
+			Err(ref e) if e.kind() == ErrorKind::NotFound => {
+				return failed("ssh is not installed or not on PATH".to_string())
+			}
+			Err(e) => return failed(format!("couldn't run ssh: {}", e)),
+		};
+		/* As locally, the build runs as a different user. */
+		let opened = self
+			.ssh(
+				host,
+				&["chmod".to_string(), "0733".to_string(), remote_dir.clone()],
+			)
+			.status();
+		if !opened.map(|s| s.success()).unwrap_or(false) {
+			return failed(format!("couldn't open up {} for the build", remote_dir));
+		}
+
+		let mut docker = vec!["docker".to_string()];
+		docker.extend(docker_run_args(request, &remote_dir, true));
+		let mut docker = self.ssh(host, &docker);
+		if let Err(e) = log_output(&mut docker, request) {
+			return failed(e);
+		}
+		let started = docker.status();
+		let exit_code = started.to_exit_code();
+		/* The remote shell exits with 127 when it can't find a command. */
+		let error = if let Some(error) = spawn_error(&started, "ssh") {
+			Some(error)
+		} else if exit_code == 127 {
+			Some(format!(
+				"Docker is not installed or not on PATH on {}",
+				host
+			))
+		} else {
+			None
+		};
+		let mut inspect = vec!["docker".to_string()];
+		inspect.extend(image_inspect_args(&request.config.build_image));
+		let image_id = image_id(self.ssh(host, &inspect).output(), logger);
+
+		if let Err(e) = self.copy_back(host, &remote_dir, request.artifact_area) {
+			error!(
+				logger,
+				"(Err) Could not copy the build output back from {}: {}", host, e
+			);
+		}
+		let removed = self
+			.ssh(
+				host,
+				&["rm".to_string(), "-rf".to_string(), remote_dir.clone()],
+			)
+			.status();
+		if !removed.map(|s| s.success()).unwrap_or(false) {
+			error!(logger, "Could not remove {} on {}.", remote_dir, host);
+		}
+		BuildRun {
+			exit_code,
+			image_id,
+			error,
+		}
+	}
+
+	/* The build could be on any of the hosts, so try them all. */
+	fn kill(&self, job_name: &str, logger: &Logger) {
+		for host in &self.hosts {
+			let killed = self
+				.ssh(
+					host,
+					&[
+						"docker".to_string(),
+						"kill".to_string(),
+						job_name.to_string(),
+					],
+				)
+				.output();
+			if let Ok(ref output) = killed {
+				if output.status.success() {
+					info!(
+						logger,
+						"Killed the build container {} on {}.", job_name, host
+					);
+					return;
+				}
+			}
+		}
+		info!(logger, "There was no build container {} to kill.", job_name);
+	}
+
+	fn warm_up(&self, command: &[String]) -> Result<(), String> {
+		for host in &self.hosts {
+			run_warmup(self.ssh(host, command))
+				.map_err(|e| format!("Oops, the warmup on {} failed: {}", host, e))?;
+		}
+		Ok(())
+	}
+}
+
+/// Build where the configuration says to: over SSH on `build_hosts`
+/// when there are any, otherwise on this host.
+pub fn builder_from_config(config: &Config) -> Box<dyn Builder> {
+	if config.build_hosts.is_empty() {
+		Box::new(LocalBuilder)
+	} else {
+		Box::new(SshBuilder {
+			hosts: config.build_hosts.clone(),
+		})
+	}
+}
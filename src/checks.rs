@@ -0,0 +1,175 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::config::ProfilersSource;
+use std::io::ErrorKind;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The outcome of one environment check: a short name and either a
+/// description of what was found or why it failed.
+pub struct Check {
+	pub name: &'static str,
+	pub result: Result<String, String>,
+}
+
+/// Everything the environment checks need to know about the deployment.
+pub struct CheckTargets<'a> {
+	/// The image to build in, or `None` when building is disabled.
+	pub build_image: Option<&'a str>,
+	/// The hosts to build on over SSH; empty means this one.
+	pub build_hosts: &'a [String],
+	/// The GitHub API the token is for.
+	pub api_base: &'a str,
+	pub git_key: &'a str,
+	pub nd_key: &'a str,
+	pub profilers: &'a ProfilersSource,
+}
+
+/// Validate the environment the bot depends on. Run at startup (where
+/// failures are logged) and by `ndhook selftest` (where they are printed).
+pub fn run_checks(targets: &CheckTargets) -> Vec<Check> {
+	vec![
+		Check {
+			name: "docker image",
+			result: check_docker_image(targets.build_image, targets.build_hosts),
+		},
+		Check {
+			name: "github token",
+			result: check_github_token(targets.api_base, targets.git_key),
+		},
+		Check {
+			name: "nimbledroid key",
+			result: check_nd_key(targets.nd_key),
+		},
+		Check {
+			name: "profilers",
+			result: check_profilers(targets.profilers),
+		},
+	]
+}
+
+/// The checks of what the bot needs to keep on working, rather than to
+/// start: that the build image is there and GitHub takes the token.
+/// They are run now and then for `/readyz`.
+pub fn readiness_checks(
+	build_image: Option<&str>,
+	build_hosts: &[String],
+	api_base: &str,
+	git_key: &str,
+) -> Vec<Check> {
+	vec![
+		Check {
+			name: "docker image",
+			result: check_docker_image(build_image, build_hosts),
+		},
+		Check {
+			name: "github token",
+			result: check_github_token(api_base, git_key),
+		},
+	]
+}
+
+/// What the latest readiness checks found wrong, if they have run, and
+/// whether the warmup is still going.
+#[derive(Clone, Default)]
+pub struct Readiness {
+	failures: Arc<Mutex<Option<Vec<String>>>>,
+	warming_up: Arc<AtomicBool>,
+}
+
+impl Readiness {
+	pub fn set_warming_up(&self, warming_up: bool) {
+		self.warming_up.store(warming_up, Ordering::SeqCst);
+	}
+
+	pub fn is_warming_up(&self) -> bool {
+		self.warming_up.load(Ordering::SeqCst)
+	}
+
+	pub fn update(&self, checks: &[Check]) {
+		let failures = checks
+			.iter()
+			.filter_map(|check| match &check.result {
+				Ok(_) => None,
+				Err(detail) => Some(format!("{}: {}", check.name, detail)),
+			})
+			.collect();
+		*self.failures.lock().unwrap() = Some(failures);
+	}
+
+	/// `None` until the checks have run once.
+	pub fn failures(&self) -> Option<Vec<String>> {
+		self.failures.lock().unwrap().clone()
+	}
+}
+
+fn check_docker_image(image: Option<&str>, hosts: &[String]) -> Result<String, String> {
+	let image = match image {
+		Some(image) => image,
+		None => return Ok("not needed: building is disabled".to_string()),
+	};
+	if hosts.is_empty() {
+		return inspect_image(Command::new("docker"), image);
+	}
+	for host in hosts {
+		let mut ssh = Command::new("ssh");
+		ssh.arg("-o")
+			.arg("BatchMode=yes")
+			.arg(host)
+			.arg("--")
+			.arg("docker");
+		inspect_image(ssh, image).map_err(|e| format!("on {}, {}", host, e))?;
+	}
+	Ok(format!("{} is available on {}", image, hosts.join(", ")))
+}
+
+/* `docker` is either docker itself or ssh set up to run it remotely. */
+fn inspect_image(mut docker: Command, image: &str) -> Result<String, String> {
+	match docker.arg("image").arg("inspect").arg(image).output() {
+		Ok(output) if output.status.success() => Ok(format!("{} is available", image)),
+		Ok(output) => Err(format!(
+			"could not inspect {}: {}",
+			image,
+			String::from_utf8_lossy(&output.stderr).trim()
+		)),
+		Err(ref e) if e.kind() == ErrorKind::NotFound => {
+			Err("docker is not installed or not on PATH".to_string())
+		}
+		Err(e) => Err(format!("could not run docker: {}", e)),
+	}
+}
+
+fn check_github_token(api_base: &str, git_key: &str) -> Result<String, String> {
+	let client = reqwest::Client::new();
+	match client
+		.get(&format!("{}/user", api_base.trim_end_matches('/')))
+		.header(reqwest::header::AUTHORIZATION, format!("token {}", git_key))
+		.send()
+	{
+		Ok(response) if response.status().is_success() => Ok("token accepted".to_string()),
+		Ok(response) => Err(format!("GitHub responded {}", response.status())),
+		Err(e) => Err(format!("could not reach GitHub: {}", e)),
+	}
+}
+
+fn check_nd_key(nd_key: &str) -> Result<String, String> {
+	let client = reqwest::Client::new();
+	match client
+		.get("https://nimbledroid.com/api/v2/apps")
+		.basic_auth(nd_key, Some(""))
+		.send()
+	{
+		Ok(response) if response.status().is_success() => Ok("key accepted".to_string()),
+		Ok(response) => Err(format!("NimbleDroid responded {}", response.status())),
+		Err(e) => Err(format!("could not reach NimbleDroid: {}", e)),
+	}
+}
+
+fn check_profilers(source: &ProfilersSource) -> Result<String, String> {
+	crate::load_profilers(source).map(|p| format!("{} profilers from {}", p.len(), source))
+}
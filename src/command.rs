@@ -0,0 +1,654 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::job::normalize_sha;
+use serde_json::{json, Value};
+
+/// An argument one of the commands takes: a bare flag when it has no
+/// value, otherwise `name=<value>`. Either may also be written like a
+/// command-line option, as `--flag` or `--name <value>`.
+pub struct ArgumentSpec {
+	pub name: &'static str,
+	pub value: Option<&'static str>,
+	pub description: &'static str,
+}
+
+/// One of the commands the bot answers, written after `profile`.
+pub struct CommandSpec {
+	/// The word after `profile`; empty for a plain profile request.
+	pub name: &'static str,
+	/// What the command has to be given after its name, if anything.
+	pub operand: Option<&'static str>,
+	pub arguments: &'static [ArgumentSpec],
+	pub description: &'static str,
+}
+
+/// Every command the parser accepts. The usage line, the `profile help`
+/// reply and `GET /commands` are all generated from these, so keep them
+/// in step with `parse_command`.
+pub static COMMANDS: [CommandSpec; 7] = [
+	CommandSpec {
+		name: "",
+		operand: None,
+		arguments: &[
+			ArgumentSpec {
+				name: "retry-failed",
+				value: None,
+				description:
+					"Re-profile only the scenarios that failed in the last profile of this commit.",
+			},
+			ArgumentSpec {
+				name: "force",
+				value: None,
+				description: "For admins only: profile even if you aren't on the profilers list.",
+			},
+			ArgumentSpec {
+				name: "variant",
+				value: Some("<name>"),
+				description: "The build variant to profile, instead of the default one.",
+			},
+			ArgumentSpec {
+				name: "sha",
+				value: Some("<commit>"),
+				description:
+					"The full SHA of the commit to profile, instead of the pull request's head.",
+			},
+			ArgumentSpec {
+				name: "scenarios",
+				value: Some("<name>,<name>,..."),
+				description: "The scenarios to report on, instead of all of them.",
+			},
+			ArgumentSpec {
+				name: "notify",
+				value: Some("@<user>,@<org>/<team>,..."),
+				description: "Who else to mention when the results are posted.",
+			},
+		],
+		description: "Build the pull request and profile it on NimbleDroid.",
+	},
+	CommandSpec {
+		name: "cancel",
+		operand: None,
+		arguments: &[],
+		description: "Cancel this pull request's queued and running profiles.",
+	},
+	CommandSpec {
+		name: "resume",
+		operand: Some("<nd_url>"),
+		arguments: &[],
+		description: "Skip the build and upload: wait for the NimbleDroid profile at this URL and post its results.",
+	},
+	CommandSpec {
+		name: "batch",
+		operand: Some("#<number> #<number> ..."),
+		arguments: &[],
+		description: "For admins only: profile the heads of these pull requests and compare them in one comment here.",
+	},
+	CommandSpec {
+		name: "history",
+		operand: None,
+		arguments: &[],
+		description: "List this pull request's latest profiles.",
+	},
+	CommandSpec {
+		name: "help",
+		operand: None,
+		arguments: &[],
+		description: "List the commands the bot understands.",
+	},
+	CommandSpec {
+		name: "debug",
+		operand: None,
+		arguments: &[],
+		description:
+			"Show the configuration that applies to this repository, with secrets redacted.",
+	},
+];
+
+impl ArgumentSpec {
+	fn syntax(&self) -> String {
+		match self.value {
+			Some(value) => format!("{}={}", self.name, value),
+			None => self.name.to_string(),
+		}
+	}
+}
+
+impl CommandSpec {
+	fn syntax(&self) -> String {
+		let mut syntax = "profile".to_string();
+		if !self.name.is_empty() {
+			syntax.push(' ');
+			syntax.push_str(self.name);
+		}
+		if let Some(operand) = self.operand {
+			syntax.push(' ');
+			syntax.push_str(operand);
+		}
+		for argument in self.arguments {
+			syntax.push_str(&format!(" [{}]", argument.syntax()));
+		}
+		syntax
+	}
+}
+
+/// A one-line summary of every command, for usage errors.
+pub fn usage_line() -> String {
+	let forms: Vec<String> = COMMANDS
+		.iter()
+		.map(|command| format!("`{}`", command.syntax()))
+		.collect();
+	format!("Usage: {}", forms.join(" or "))
+}
+
+/// Markdown describing every command and its arguments, ready to post
+/// as a comment.
+pub fn help_text() -> String {
+	let mut help = String::new();
+	for command in COMMANDS.iter() {
+		help.push_str(&format!(
+			"* `{}`: {}\n",
+			command.syntax(),
+			command.description
+		));
+		for argument in command.arguments {
+			help.push_str(&format!(
+				"  * `{}`: {}\n",
+				argument.syntax(),
+				argument.description
+			));
+		}
+	}
+	help
+}
+
+/// Every command and its arguments, for API clients.
+pub fn commands_json() -> Value {
+	let commands: Vec<Value> = COMMANDS
+		.iter()
+		.map(|command| {
+			let arguments: Vec<Value> = command
+				.arguments
+				.iter()
+				.map(|argument| {
+					json!({
+						"name": argument.name,
+						"value": argument.value,
+						"description": argument.description,
+					})
+				})
+				.collect();
+			json!({
+				"command": command.syntax(),
+				"description": command.description,
+				"arguments": arguments,
+			})
+		})
+		.collect();
+	json!(commands)
+}
+
+/// A request, parsed out of a pull request comment, to profile it.
+#[derive(Debug, Default, PartialEq)]
+pub struct Command {
+	/// Which build variant to profile, if not the default.
+	pub variant: Option<String>,
+	/// Which commit to profile, if not the pull request's head.
+	pub sha: Option<String>,
+	/// Which scenarios to report on. Empty means all of them.
+	pub scenarios: Vec<String>,
+	/// Who else to mention in the results, without the @.
+	pub notify: Vec<String>,
+	/// Re-profile only the scenarios that failed last time.
+	pub retry_failed: bool,
+	/// Profile even though the commenter isn't on the profilers list,
+	/// which only admins may do.
+	pub force: bool,
+	/// Cancel this pull request's queued and running profiles instead.
+	pub cancel: bool,
+	/// Reply with the list of commands instead.
+	pub help: bool,
+	/// Reply with the repository's configuration instead.
+	pub debug: bool,
+	/// Reply with the pull request's latest profiles instead.
+	pub history: bool,
+	/// Wait for the results of this NimbleDroid profile instead of
+	/// building and uploading another.
+	pub resume: Option<String>,
+	/// Profile the heads of these pull requests instead, and compare
+	/// them in one comment.
+	pub batch: Vec<u64>,
+}
+
+impl Command {
+	/// What `command_associations` can restrict about the command: its
+	/// name (`profile` for a plain request) and the names of the
+	/// arguments it was given.
+	pub fn names(&self) -> Vec<&'static str> {
+		let name = if self.cancel {
+			"cancel"
+		} else if self.help {
+			"help"
+		} else if self.debug {
+			"debug"
+		} else if self.history {
+			"history"
+		} else if self.resume.is_some() {
+			"resume"
+		} else if !self.batch.is_empty() {
+			"batch"
+		} else {
+			"profile"
+		};
+		let mut names = vec![name];
+		if self.retry_failed {
+			names.push("retry-failed");
+		}
+		if self.force {
+			names.push("force");
+		}
+		if self.variant.is_some() {
+			names.push("variant");
+		}
+		if self.sha.is_some() {
+			names.push("sha");
+		}
+		if !self.scenarios.is_empty() {
+			names.push("scenarios");
+		}
+		if !self.notify.is_empty() {
+			names.push("notify");
+		}
+		names
+	}
+}
+
+/// Whether `name` is something `Command::names` can return.
+pub fn is_known_name(name: &str) -> bool {
+	name == "profile"
+		|| COMMANDS.iter().any(|command| {
+			(!command.name.is_empty() && command.name == name)
+				|| command
+					.arguments
+					.iter()
+					.any(|argument| argument.name == name)
+		})
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CommandError {
+	/// The comment isn't addressed to the bot; ignore it quietly.
+	NotACommand,
+	/// The comment is a malformed command. The message is meant for the
+	/// person who wrote it.
+	Usage(String),
+	/// The comment looks like a mistyped command. The message suggests
+	/// the command that was probably meant.
+	Unknown(String),
+}
+
+fn usage(problem: &str) -> CommandError {
+	CommandError::Usage(format!("{} {}", problem, usage_line()))
+}
+
+/*
+ * How many single-character insertions, deletions and substitutions
+ * turn `a` into `b`.
+ */
+fn edit_distance(a: &str, b: &str) -> usize {
+	let b: Vec<char> = b.chars().collect();
+	let mut previous: Vec<usize> = (0..=b.len()).collect();
+	for (i, ca) in a.chars().enumerate() {
+		let mut current = vec![i + 1];
+		for (j, cb) in b.iter().enumerate() {
+			let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+			current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+		}
+		previous = current;
+	}
+	previous[b.len()]
+}
+
+/*
+ * The `/profile` a first word such as `/prof` or `/profile-now` was
+ * probably meant to be. Without the slash, a word is just a word.
+ */
+fn suggest_prefix(word: &str) -> Option<CommandError> {
+	if word.starts_with("/prof") || (word.starts_with('/') && edit_distance(word, "/profile") <= 2)
+	{
+		Some(CommandError::Unknown(format!(
+			"Unknown command `{}`. Did you mean `/profile`?",
+			word
+		)))
+	} else {
+		None
+	}
+}
+
+/*
+ * The command or flag a bare word after `profile` was probably meant to
+ * be, such as `history` for `histroy`.
+ */
+fn suggest_name(word: &str) -> Option<CommandError> {
+	let names = COMMANDS.iter().map(|command| command.name).chain(
+		COMMANDS
+			.iter()
+			.flat_map(|command| command.arguments.iter())
+			.filter(|argument| argument.value.is_none())
+			.map(|argument| argument.name),
+	);
+	let (distance, name) = names
+		.filter(|name| !name.is_empty())
+		.map(|name| (edit_distance(word, name), name))
+		.min()?;
+	if distance == 0 || distance > 2 {
+		return None;
+	}
+	Some(CommandError::Unknown(format!(
+		"Unknown command `{}`. Did you mean `profile {}`?",
+		word, name
+	)))
+}
+
+/*
+ * Rewrite `--flag`, `--name=<value>` and `--name <value>` as the bot's
+ * own `flag` and `name=<value>`. A name that isn't one of the plain
+ * command's arguments is left to be reported as unknown.
+ */
+fn option_words<'a>(words: impl Iterator<Item = &'a str>) -> Vec<String> {
+	let takes_value = |name: &str| {
+		COMMANDS[0]
+			.arguments
+			.iter()
+			.any(|argument| argument.name == name && argument.value.is_some())
+	};
+	let mut rewritten = vec![];
+	let mut words = words.peekable();
+	while let Some(word) = words.next() {
+		let name = match word.strip_prefix("--") {
+			Some(name) if !name.is_empty() => name,
+			_ => {
+				rewritten.push(word.to_string());
+				continue;
+			}
+		};
+		let value = match words.peek() {
+			Some(value) if takes_value(name) && !value.starts_with("--") => words.next(),
+			_ => None,
+		};
+		match value {
+			Some(value) => rewritten.push(format!("{}={}", name, value)),
+			None => rewritten.push(name.to_string()),
+		}
+	}
+	rewritten
+}
+
+/// Parse a comment such as `profile variant=fenixNightly scenarios=a,b`,
+/// or `profile --variant fenixNightly --scenarios a,b`.
+pub fn parse_command(body: &str) -> Result<Command, CommandError> {
+	let mut words = body.split_whitespace();
+	match words.next() {
+		Some("profile") | Some("/profile") => (),
+		Some(word) => return Err(suggest_prefix(word).unwrap_or(CommandError::NotACommand)),
+		None => return Err(CommandError::NotACommand),
+	}
+
+	let mut command = Command::default();
+	let mut words = words.peekable();
+	if let Some(&name) = words.peek() {
+		if name == "cancel" || name == "help" || name == "debug" || name == "history" {
+			words.next();
+			if words.next().is_some() {
+				return Err(usage(&format!("`{}` doesn't take any arguments.", name)));
+			}
+			command.cancel = name == "cancel";
+			command.help = name == "help";
+			command.debug = name == "debug";
+			command.history = name == "history";
+			return Ok(command);
+		}
+		if name == "resume" {
+			words.next();
+			let url = match words.next() {
+				Some(url) => url,
+				None => return Err(usage("`resume` needs the URL of the NimbleDroid profile.")),
+			};
+			if words.next().is_some() {
+				return Err(usage("`resume` takes only the profile's URL."));
+			}
+			command.resume = Some(url.to_string());
+			return Ok(command);
+		}
+		if name == "batch" {
+			words.next();
+			for word in words {
+				match word.trim_start_matches('#').parse::<u64>() {
+					Ok(number) if !command.batch.contains(&number) => command.batch.push(number),
+					Ok(_) => return Err(usage(&format!("`{}` was given more than once.", word))),
+					Err(_) => {
+						return Err(usage(&format!("`{}` is not a pull request number.", word)))
+					}
+				}
+			}
+			if command.batch.is_empty() {
+				return Err(usage("`batch` needs the pull requests to profile."));
+			}
+			return Ok(command);
+		}
+	}
+	for word in &option_words(words) {
+		let word = word.as_str();
+		if word == "retry-failed" {
+			if command.retry_failed {
+				return Err(usage("`retry-failed` was given more than once."));
+			}
+			command.retry_failed = true;
+			continue;
+		}
+		if word == "force" {
+			if command.force {
+				return Err(usage("`force` was given more than once."));
+			}
+			command.force = true;
+			continue;
+		}
+		let (key, value) = match word.find('=') {
+			Some(i) => (&word[..i], &word[i + 1..]),
+			None => {
+				return Err(suggest_name(word).unwrap_or_else(|| {
+					usage(&format!(
+						"Arguments look like `name=value`, but got `{}`.",
+						word
+					))
+				}))
+			}
+		};
+		if value.is_empty() {
+			return Err(usage(&format!("`{}=` needs a value.", key)));
+		}
+
+		match key {
+			"variant" => {
+				if command.variant.is_some() {
+					return Err(usage("`variant=` was given more than once."));
+				}
+				command.variant = Some(value.to_string());
+			}
+			"sha" => {
+				if command.sha.is_some() {
+					return Err(usage("`sha=` was given more than once."));
+				}
+				match normalize_sha(value) {
+					Some(sha) => command.sha = Some(sha),
+					None => return Err(usage(&format!("`{}` is not a full commit SHA.", value))),
+				}
+			}
+			"scenarios" => {
+				if !command.scenarios.is_empty() {
+					return Err(usage("`scenarios=` was given more than once."));
+				}
+				let scenarios: Vec<String> = value.split(',').map(|s| s.to_string()).collect();
+				if scenarios.iter().any(|s| s.is_empty()) {
+					return Err(usage("`scenarios=` has an empty scenario name."));
+				}
+				command.scenarios = scenarios;
+			}
+			"notify" => {
+				if !command.notify.is_empty() {
+					return Err(usage("`notify=` was given more than once."));
+				}
+				for mention in value.split(',') {
+					let name = mention.trim_start_matches('@');
+					let valid = !name.is_empty()
+						&& name
+							.chars()
+							.all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '/');
+					if !valid {
+						return Err(usage(&format!(
+							"`{}` is not a user or team to notify.",
+							mention
+						)));
+					}
+					command.notify.push(name.to_string());
+				}
+			}
+			_ => return Err(usage(&format!("`{}` is not a known argument.", key))),
+		}
+	}
+	if command.retry_failed && !command.scenarios.is_empty() {
+		return Err(usage(
+			"`retry-failed` picks the scenarios itself, so it can't be combined with `scenarios=`.",
+		));
+	}
+	Ok(command)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	static SHA: &str = "0123456789abcdef0123456789abcdef01234567";
+
+	#[test]
+	fn parses_good_commands() {
+		let cases = vec![
+			("profile", Command::default()),
+			("/profile", Command::default()),
+			(
+				"profile variant=nightly",
+				Command {
+					variant: Some("nightly".to_string()),
+					..Command::default()
+				},
+			),
+			(
+				"/profile --variant nightly",
+				Command {
+					variant: Some("nightly".to_string()),
+					..Command::default()
+				},
+			),
+			(
+				"profile sha=0123456789ABCDEF0123456789ABCDEF01234567",
+				Command {
+					sha: Some(SHA.to_string()),
+					..Command::default()
+				},
+			),
+			(
+				"profile scenarios=startup,scroll notify=@alice,mozilla/perf",
+				Command {
+					scenarios: vec!["startup".to_string(), "scroll".to_string()],
+					notify: vec!["alice".to_string(), "mozilla/perf".to_string()],
+					..Command::default()
+				},
+			),
+			(
+				"profile retry-failed force",
+				Command {
+					retry_failed: true,
+					force: true,
+					..Command::default()
+				},
+			),
+			(
+				"profile cancel",
+				Command {
+					cancel: true,
+					..Command::default()
+				},
+			),
+			(
+				"profile history",
+				Command {
+					history: true,
+					..Command::default()
+				},
+			),
+			(
+				"profile resume https://nimbledroid.com/profiles/1",
+				Command {
+					resume: Some("https://nimbledroid.com/profiles/1".to_string()),
+					..Command::default()
+				},
+			),
+			(
+				"profile batch #12 13",
+				Command {
+					batch: vec![12, 13],
+					..Command::default()
+				},
+			),
+		];
+		for (body, expected) in cases {
+			assert_eq!(parse_command(body), Ok(expected), "parsing {:?}", body);
+		}
+	}
+
+	#[test]
+	fn refuses_bad_commands() {
+		let not_commands = vec!["", "looks good to me", "profiles are nice"];
+		for body in not_commands {
+			assert_eq!(
+				parse_command(body),
+				Err(CommandError::NotACommand),
+				"parsing {:?}",
+				body
+			);
+		}
+
+		let mistyped = vec!["/proflie", "profile histroy"];
+		for body in mistyped {
+			match parse_command(body) {
+				Err(CommandError::Unknown(_)) => (),
+				other => panic!("parsing {:?} gave {:?}", body, other),
+			}
+		}
+
+		let malformed = vec![
+			"profile cancel now",
+			"profile resume",
+			"profile batch",
+			"profile batch 12 12",
+			"profile batch twelve",
+			"profile variant=",
+			"profile variant=a variant=b",
+			"profile sha=abc123",
+			"profile scenarios=startup,,scroll",
+			"profile notify=@",
+			"profile colour=blue",
+			"profile just-some-words",
+			"profile retry-failed scenarios=startup",
+		];
+		for body in malformed {
+			match parse_command(body) {
+				Err(CommandError::Usage(_)) => (),
+				other => panic!("parsing {:?} gave {:?}", body, other),
+			}
+		}
+	}
+}
@@ -0,0 +1,1503 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::command::is_known_name;
+use crate::format::check_template;
+use crate::github::DEFAULT_API_BASE;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+
+/// A destination for the outcome of each profile run.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SinkKind {
+	/// A comment on the pull request with the full results.
+	Comment,
+	/// A commit status on the profiled head.
+	Status,
+	/// A summary posted to `slack_webhook_url`.
+	Slack,
+	/// A review of the pull request with the full results, requesting
+	/// changes when a scenario fails or goes over its budget.
+	Review,
+	/// The outcome as JSON, POSTed to `webhook_sink_url`.
+	Webhook,
+}
+
+/// Where a run's artifacts are kept after it.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreKind {
+	/// On the host, under `artifact_root` when that is set.
+	Local,
+	/// Uploaded to `s3_bucket` at `s3_endpoint` after a successful
+	/// build, and linked from the comment.
+	S3,
+}
+
+/// When the `comment` sink comments on the pull request.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CommentOn {
+	Always,
+	/// Only when a scenario failed or went over budget, or the run
+	/// didn't finish.
+	Failure,
+	Never,
+}
+
+/// What to do with a profile of a pull request that already has one
+/// queued or running.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateJobs {
+	/// Run it as well.
+	Allow,
+	/// Skip it when the other is profiling the same commit and variant.
+	Skip,
+	/// Skip it like `skip`, and otherwise cancel the others, which are
+	/// of commits it replaces.
+	Supersede,
+}
+
+/// Where the list of who may ask for profiles comes from.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProfilersSource {
+	/// The logins themselves, written in the configuration.
+	Inline(Vec<String>),
+	/// A JSON array of logins in a local file.
+	File(String),
+	/// A JSON array of logins fetched over HTTP(S), and fetched again
+	/// every `profilers_refresh_secs`.
+	Url(String),
+}
+
+impl std::fmt::Display for ProfilersSource {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			ProfilersSource::Inline(_) => write!(f, "the configuration"),
+			ProfilersSource::File(filename) => write!(f, "{}", filename),
+			ProfilersSource::Url(url) => write!(f, "{}", url),
+		}
+	}
+}
+
+/// How the bot authenticates to GitHub.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum GitHubAuth {
+	/// With a personal access token, from `github_token_file`.
+	Pat,
+	/// As an installation of a GitHub App, with short-lived tokens
+	/// minted from the app's private key.
+	App,
+}
+
+/// How to lay out the results comment for each way a run can end.
+///
+/// `{header}` (the commit and image that were profiled), `{message}`
+/// (what the comment would say without a template), `{scenarios}` (the
+/// results table), `{nd_url}`, `{sha}` and `{commenter}` are replaced;
+/// write `{{` and `}}` for literal braces.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct CommentTemplates {
+	pub success: String,
+	pub timeout: String,
+	/// Used when the results couldn't be had at all.
+	pub error: String,
+}
+
+impl Default for CommentTemplates {
+	fn default() -> Self {
+		Self {
+			success: "{header}{message}".to_string(),
+			timeout: "{header}{message}".to_string(),
+			error: "{header}{message}".to_string(),
+		}
+	}
+}
+
+/// How Docker should relabel the build output directory for SELinux.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum VolumeRelabel {
+	/// `:z`, a label any container may use.
+	Shared,
+	/// `:Z`, a label only the build container may use.
+	Private,
+}
+
+impl VolumeRelabel {
+	pub fn mount_suffix(self) -> &'static str {
+		match self {
+			VolumeRelabel::Shared => ":z",
+			VolumeRelabel::Private => ":Z",
+		}
+	}
+}
+
+/// When to re-profile the default branch of each allowed repository.
+#[derive(Clone, Debug, Deserialize)]
+pub struct BaselineSchedule {
+	/// Seconds between rounds of baseline profiles.
+	pub interval_secs: u64,
+}
+
+/// How long to keep the artifact directories under `artifact_root`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ArtifactRetention {
+	/// Seconds between cleanups.
+	pub interval_secs: u64,
+	/// Delete artifact directories older than this.
+	pub max_age_secs: Option<u64>,
+	/// Delete the oldest artifact directories while they take up more
+	/// than this.
+	pub max_total_bytes: Option<u64>,
+}
+
+/// How to build one flavor of the app, and where the build leaves it.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Variant {
+	/// The Gradle task the build script runs.
+	pub gradle_task: String,
+	/// The APK to profile, relative to the build output directory.
+	pub apk_path: String,
+	/// The key of the NimbleDroid project whose scenarios profile this
+	/// variant, instead of the repository's.
+	#[serde(default)]
+	pub nd_key: Option<String>,
+	/// How long to wait for NimbleDroid to profile this variant, instead
+	/// of profile_timeout_secs.
+	#[serde(default)]
+	pub profile_timeout_secs: Option<u64>,
+}
+
+/// Settings a single repository can change, under `[repos."owner/name"]`.
+/// Anything left out falls back to the global setting.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RepoOverrides {
+	pub build_image: Option<String>,
+	pub build_script: Option<String>,
+	pub profile_timeout_secs: Option<u64>,
+	/// Variants to add, or to replace the global ones of the same name.
+	pub variants: BTreeMap<String, Variant>,
+	pub default_variant: Option<String>,
+	/// Who may ask for profiles of this repository, instead of the
+	/// profilers list.
+	pub profilers: Option<Vec<String>>,
+	/// Who else may ask for profiles of this repository. They are added
+	/// to the profilers list, or to `profilers` when the repository has
+	/// its own, so an organization's core list needn't be repeated for
+	/// every repository.
+	pub extra_profilers: Vec<String>,
+	/// The key of this repository's own NimbleDroid project, instead of
+	/// the global one.
+	pub nd_key: Option<String>,
+	/// Branches to profile whenever they are pushed to, recording each
+	/// result as a baseline.
+	pub push_branches: Vec<String>,
+	/// The most jobs this repository may have running at once, instead
+	/// of the global max_concurrent_jobs.
+	pub max_concurrent_jobs: Option<usize>,
+	/// Which changed files make a pull request worth profiling, instead
+	/// of the global profile_paths.
+	pub profile_paths: Option<Vec<String>>,
+	/// Which base branches a pull request may target to be profiled,
+	/// instead of the global base_branches.
+	pub base_branches: Option<Vec<String>>,
+	/// The author associations this repository trusts, instead of the
+	/// global trusted_associations.
+	pub trusted_associations: Option<Vec<String>>,
+	/// The secrets this repository's webhook signs deliveries with,
+	/// instead of the global webhook_secrets. The repository is read
+	/// from a delivery before its signature can be checked; see
+	/// `Config::has_repo_webhook_secrets`.
+	pub webhook_secrets: Option<Vec<String>>,
+	/// Commands and arguments to restrict further, added to (or
+	/// replacing) the global command_associations.
+	pub command_associations: BTreeMap<String, Vec<String>>,
+}
+
+/// Deployment configuration, read from a TOML file.
+///
+/// Every field has a default so that an absent file (or an absent key)
+/// preserves the bot's original behavior.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+	/// Profile when an allowed user comments `profile` on a pull request.
+	pub comment_trigger: bool,
+	/// Answer a comment that looks like a mistyped command, such as
+	/// `/prof` or `profile histroy`, with the command it was probably
+	/// meant to be.
+	pub suggest_commands: bool,
+	/// Wait this long after a `profile` comment, then go ahead only if
+	/// the comment hasn't been deleted in the meantime.
+	pub trigger_delay_secs: u64,
+	/// After a pull request is profiled, refuse `profile` comments asking
+	/// for the same commit again for this long. A push starts afresh.
+	pub profile_cooldown_secs: Option<u64>,
+	/// How many profiles each commenter may ask for in an hour, the
+	/// allowance refilling steadily. Every profile request that names a
+	/// known variant counts, even one that goes on to be refused for its
+	/// pull request; help, cancel and mistyped commands don't. Unlimited
+	/// when unset. `NDHOOK_RATE_PER_USER` overrides it.
+	pub rate_per_user: Option<u32>,
+	/// How many profiles everyone together may ask for in an hour,
+	/// counted as `rate_per_user` is. Unlimited when unset.
+	/// `NDHOOK_RATE_GLOBAL` overrides it.
+	pub rate_global: Option<u32>,
+	/// Profile automatically when a pull request is opened, pushed to or
+	/// marked ready for review.
+	pub auto_profile_on_push: bool,
+	/// Profile draft pull requests. Turned off, whatever would profile
+	/// a draft is skipped until it is marked ready for review.
+	pub profile_drafts: bool,
+	/// The author associations (`MEMBER`, `COLLABORATOR`, ...) GitHub
+	/// gives people the bot trusts. When set, a pull request isn't
+	/// profiled automatically unless its author has one of them, and a
+	/// comment only asks for a profile if its commenter does. A trusted
+	/// commenter's comment or the trigger label, which only maintainers
+	/// can add, still profiles anyone's pull request. Empty trusts
+	/// everyone.
+	pub trusted_associations: Vec<String>,
+	/// The author associations a commenter needs for particular commands
+	/// (`debug`, or `profile` for a plain request) or arguments (`sha`,
+	/// `scenarios`, ...), on top of trusted_associations. A comment
+	/// using several of them needs to meet each one.
+	pub command_associations: BTreeMap<String, Vec<String>>,
+	/// Glob patterns (`**/*.kt`) for the files that affect performance.
+	/// A pull request changing none of them isn't profiled. Empty
+	/// profiles every pull request.
+	pub profile_paths: Vec<String>,
+	/// Glob patterns (`main`, `release/*`) for the base branches a pull
+	/// request must target to be profiled. Empty profiles pull requests
+	/// into any branch.
+	pub base_branches: Vec<String>,
+	/// Profile when this label is added to a pull request.
+	pub trigger_label: Option<String>,
+	/// Take the trigger label back off once the profile has been reported.
+	pub remove_trigger_label: bool,
+	/// Repositories (`owner/name`) the bot will act on. Empty means all.
+	pub allowed_repos: Vec<String>,
+	/// Longest comment body to post; GitHub rejects anything over 65536.
+	pub max_comment_length: usize,
+	/// The most of a pull request's details to read from GitHub. A
+	/// longer response, such as for an enormous description, fails the
+	/// request instead.
+	pub max_pull_request_bytes: u64,
+	/// Show times in seconds, rather than milliseconds, when any scenario
+	/// in a comment took at least this many milliseconds.
+	pub seconds_threshold_ms: Option<u64>,
+	/// How often to re-check NimbleDroid for scenarios that are still
+	/// running after it reports the profile ready.
+	pub profile_poll_interval_secs: u64,
+	/// How many times to run a job's whole pipeline again when it fails
+	/// for reasons of infrastructure (Docker, SSH, NimbleDroid) rather
+	/// than of the code, before reporting the failure. A poll interval
+	/// passes between attempts.
+	pub infra_retries: u32,
+	/// The longest each named scenario may take, in milliseconds.
+	/// Scenarios without a budget are informational only.
+	pub scenario_budgets_ms: BTreeMap<String, u64>,
+	/// Scenarios too flaky to gate on: they are still shown, marked
+	/// ignored, but never fail the run or go over a budget.
+	pub ignored_scenarios: Vec<String>,
+	/// Scenarios left out of the results altogether.
+	pub hidden_scenarios: Vec<String>,
+	/// Fail the commit status when any scenario goes over its budget.
+	pub fail_over_budget: bool,
+	/// Describe a profile on its commit status in one compact line that
+	/// leads with regressions against the base, rather than as counts
+	/// of passes and failures. Regressions need `compare_base`. A
+	/// `NDHOOK_REPORT_MODE` that sets a status turns this on.
+	pub compact_status: bool,
+	/// How much slower than the previous run, or than the base with
+	/// `compare_base`, in percent, a scenario must be to count as a
+	/// regression. `NDHOOK_REGRESSION_PCT` overrides it.
+	pub regression_percent: f64,
+	/// Build APKs to profile. A profile-only deployment turns this off
+	/// and then needs no Docker at all; whatever would build is refused.
+	pub build_enabled: bool,
+	/// The Docker image to build in. `NDHOOK_BUILD_IMAGE` overrides it,
+	/// so a rebuilt image can be given as a tag.
+	pub build_image: String,
+	/// The build script inside the image. It is run with the clone URL,
+	/// the commit, the Gradle task and the APK glob to copy out.
+	/// `NDHOOK_BUILD_SCRIPT` overrides it.
+	pub build_script: String,
+	/// The Gradle tasks a variant may run. Every variant's task has to be
+	/// one of these, so a bad mapping can't make the build script run
+	/// anything else.
+	pub gradle_tasks: Vec<String>,
+	/// SSH destinations (`user@host`) to build on, in turn, instead of
+	/// this host. Each needs Docker and the build image.
+	pub build_hosts: Vec<String>,
+	/// Run `warmup_command` wherever builds run once the bot has started,
+	/// so the first profile after a deploy doesn't pay for a cold cache.
+	/// With `warmup_gates_ready`, `/readyz` waits for it to finish. A
+	/// warmup that fails is logged and doesn't hold readiness back.
+	pub warmup_on_start: bool,
+	/// The warmup's command and arguments. Empty runs a container of the
+	/// build image that does nothing, pulling the image if it must.
+	pub warmup_command: Vec<String>,
+	pub warmup_gates_ready: bool,
+	/// Environment variables to set for the build script.
+	pub build_env: BTreeMap<String, String>,
+	/// Arguments to pass to the build script after the usual ones.
+	pub extra_build_args: Vec<String>,
+	/// A command to run, with the APK's path added as its last argument,
+	/// in the build output directory once the build succeeds and before
+	/// the APK is uploaded. Empty runs nothing; failing stops the run.
+	pub post_build_command: Vec<String>,
+	/// Keep the build's output rather than passing it through, post it
+	/// to a secret gist with the GitHub token, and link that from the
+	/// results. Whatever the build prints ends up in the gist.
+	pub build_log_gist: bool,
+	/// Keep the build's output, and when a build fails without an APK,
+	/// quote this many of the last lines of it in the comment. `None`
+	/// quotes nothing.
+	pub build_log_tail_lines: Option<usize>,
+	/// Build the pull request merged into its base branch, as GitHub's
+	/// merge ref has it, rather than its head. Pull requests that don't
+	/// merge cleanly are built from their head.
+	pub profile_merge_ref: bool,
+	/// Relabel the build output directory when mounting it, which
+	/// SELinux-enforcing hosts need before the build can write to it.
+	/// The directory is opened up to mode 0733 either way, for a build
+	/// user other than ours; SELinux checks its label on top of that.
+	pub build_volume_relabel: Option<VolumeRelabel>,
+	/// Build in directories under here and keep them afterwards, for
+	/// debugging. They are deleted after each run when this is unset.
+	pub artifact_root: Option<String>,
+	/// Clean up old directories under `artifact_root`.
+	pub artifact_retention: Option<ArtifactRetention>,
+	/// Where the APK, build log and results of a successful build go.
+	pub artifact_store: StoreKind,
+	/// The S3-compatible service the `s3` store uploads to, such as
+	/// `https://s3.us-east-1.amazonaws.com`, or
+	/// `https://storage.googleapis.com` for GCS with HMAC keys. Objects
+	/// are addressed by path, under the bucket.
+	pub s3_endpoint: Option<String>,
+	pub s3_bucket: Option<String>,
+	pub s3_region: String,
+	pub s3_access_key_id: Option<String>,
+	pub s3_secret_access_key: Option<String>,
+	/// How long to wait for NimbleDroid to finish a profile.
+	pub profile_timeout_secs: u64,
+	/// After a profile times out, keep polling NimbleDroid in the
+	/// background for this long, and deliver the results again if they
+	/// turn up after all. Unset gives up at the timeout.
+	pub late_results_grace_secs: Option<u64>,
+	/// Periodically profile the default branch of every repository in
+	/// `allowed_repos` and cache the results as baselines.
+	pub auto_baseline_schedule: Option<BaselineSchedule>,
+	/// The build variants a comment may ask for, by name.
+	pub variants: BTreeMap<String, Variant>,
+	/// The variant to profile when none is asked for.
+	pub default_variant: String,
+	/// Where to deliver the outcome of each run. `NDHOOK_REPORT_MODE`
+	/// (`comment`, `status` or `both`) overrides which of `comment` and
+	/// `status` are among them.
+	pub sinks: Vec<SinkKind>,
+	/// When the `comment` sink posts.
+	pub comment_on: CommentOn,
+	/// @-mention whoever asked for a profile in its results, so they
+	/// hear when it finishes.
+	pub mention_commenter: bool,
+	/// Users or teams (`org/team`) to @-mention in every result.
+	pub mention: Vec<String>,
+	/// Have the `review` sink approve pull requests whose profiles pass,
+	/// rather than only comment on them.
+	pub review_approve: bool,
+	/// What the `comment` sink posts.
+	pub comment_templates: CommentTemplates,
+	/// Fold the results table away under a summary line, left open
+	/// when a scenario failed or went over its budget.
+	pub collapse_results: bool,
+	/// Append the outcome as JSON, as `/profile-apk` returns it, in a
+	/// collapsed block at the end of each results comment, for anyone
+	/// who wants the raw numbers. It is left out when it would make the
+	/// comment too long.
+	pub raw_results: bool,
+	/// Keep one comment per pull request up to date with the latest
+	/// results and its last `history_count` runs, rather than comment
+	/// on every run.
+	pub sticky_comment: bool,
+	/// Post the `comment` sink's results to a review thread on this file
+	/// of the pull request, at `review_thread_line`, rather than to the
+	/// conversation. The first run starts the thread and later ones
+	/// reply to it. GitHub only allows this on a line the pull request
+	/// changes; when it refuses, the results are commented as usual.
+	pub review_thread_path: Option<String>,
+	pub review_thread_line: u64,
+	/// Compare each scenario with the previous profile of the same pull
+	/// request, in an extra column of the results table.
+	pub compare_previous_run: bool,
+	/// Compare each scenario with the baseline profile of the pull
+	/// request's base, when there is one, in an extra column of the
+	/// results table, and flag the regressions.
+	pub compare_base: bool,
+	/// How many of a pull request's runs `profile history` lists.
+	pub history_count: usize,
+	/// The Slack incoming-webhook URL used by the `slack` sink.
+	pub slack_webhook_url: Option<String>,
+	/// Where the `webhook` sink POSTs each outcome, with this bearer
+	/// token, signed with this secret (in `X-Ndhook-Signature` and
+	/// `X-Ndhook-Signature-256`, as GitHub signs its deliveries) when
+	/// they are set. A failed POST is
+	/// tried again up to `webhook_sink_retries` times.
+	pub webhook_sink_url: Option<String>,
+	pub webhook_sink_token: Option<String>,
+	pub webhook_sink_secret: Option<String>,
+	pub webhook_sink_retries: u32,
+	/// Sign each results comment with this secret, in a hidden
+	/// `<!-- ndhook:signature=sha256=<hex> -->` last line, so automation
+	/// reading the comments can tell the bot's from anyone else's. The
+	/// signature is the HMAC-SHA256 of the comment's text before the
+	/// blank line that precedes it.
+	pub comment_signing_secret: Option<String>,
+	/// How many profiles to run at once. Each one is a full Gradle build,
+	/// so more than one per CPU only makes them all slower. The HTTP
+	/// server's threads are `server_threads`. `NDHOOK_MAX_JOBS` overrides
+	/// it.
+	pub workers: usize,
+	/// Replace a worker whose job has made no progress in this long,
+	/// killing its build. Builds report no progress while they run, so
+	/// this must be longer than the longest build.
+	pub worker_stuck_secs: Option<u64>,
+	/// Abandon a job that is still running this long after it started,
+	/// whatever it is doing, killing its build and saying so on the
+	/// pull request.
+	pub job_timeout_secs: Option<u64>,
+	/// Drop a queued request, with a comment saying so, once it has
+	/// waited this long for a worker. `None` waits forever.
+	pub max_queue_wait_secs: Option<u64>,
+	/// Turn requests away, with a comment saying so, once this many are
+	/// waiting for a worker. `None` queues everything.
+	pub max_queued_jobs: Option<usize>,
+	/// How many pull requests one `profile batch` can profile.
+	pub max_batch_size: usize,
+	/// Tell requesters where their job is in the queue when it has to
+	/// wait for a worker.
+	pub queue_position_comment: bool,
+	/// What to do when a pull request that already has a profile queued
+	/// or running asks for another.
+	pub duplicate_jobs: DuplicateJobs,
+	/// Say that a requested profile is starting as soon as it is
+	/// accepted, since the results can take many minutes. A queue
+	/// position comment takes its place when there is one.
+	pub start_comment: bool,
+	/// The most jobs any one repository may have running at once, so a
+	/// busy repository can't take every worker. Its other jobs wait
+	/// while jobs for other repositories go ahead. `None` lets a
+	/// repository use all of them.
+	pub max_concurrent_jobs: Option<usize>,
+	/// Don't start another job while the host's one-minute load average
+	/// is above this, even with a worker free; the job waits in the
+	/// queue until the load comes down. `None` starts jobs regardless.
+	pub max_load_average: Option<f64>,
+	/// Keep the notifications behind unfinished jobs in
+	/// `pending_jobs_file`, and run them again after a restart. Both are
+	/// read at startup only.
+	pub persistent_queue: bool,
+	pub pending_jobs_file: String,
+	/// The secrets GitHub may sign webhook deliveries with. Deliveries
+	/// signed with none of them are refused; list both the old and the
+	/// new secret while rotating. Nothing is checked when this is empty.
+	pub webhook_secrets: Vec<String>,
+	/// The bearer token that unlocks the `/admin` endpoints. They are
+	/// disabled when this is unset.
+	pub admin_token: Option<String>,
+	/// Report unhealthy on `/health` once this many artifact directories
+	/// in a row couldn't be made, so the host can be drained.
+	pub dir_failures_unhealthy: usize,
+	/// How often `/readyz` checks again that Docker has the build image
+	/// and GitHub takes the token.
+	pub readiness_interval_secs: u64,
+	/// Don't start a build with less than this many megabytes free where
+	/// it would be built, and report unhealthy until there are. Nothing
+	/// is checked when this is unset.
+	pub min_free_disk_mb: Option<u64>,
+	/// How many of the latest builds and profiles of each variant the
+	/// durations on `/metrics` are worked out from.
+	pub timing_window: usize,
+	/// The comment posted when NimbleDroid turns an upload away for
+	/// having no runs left.
+	pub nd_quota_message: String,
+	/// Where NimbleDroid is. Profile URLs are only believed when they
+	/// are on this host or one of its subdomains. nimbledroidrs can't be
+	/// pointed at another deployment yet, so nothing else is accepted.
+	pub nd_base_url: String,
+	/// Stop building for new jobs once NimbleDroid has failed this many
+	/// uploads or results in a row, and turn them away instead. One job
+	/// is let through every `nd_breaker_probe_secs` to see whether ND is
+	/// back. Unset never stops.
+	pub nd_breaker_failures: Option<usize>,
+	pub nd_breaker_probe_secs: u64,
+	/// How long a notification's body may take to arrive before the
+	/// request is answered with a 408 instead.
+	pub request_body_timeout_secs: u64,
+	/// Drop a delivery whose `X-GitHub-Delivery` ID already came within
+	/// this many seconds, as when GitHub retries one it thinks failed.
+	/// The IDs are only kept in memory. `None` keeps no window.
+	pub dedup_window_secs: Option<u64>,
+	/// Largest APK `POST /profile-apk` will accept, in bytes.
+	pub max_apk_upload_bytes: u64,
+	/// Where to send OTLP traces of each job, such as
+	/// `http://localhost:4318`. Needs the `otel` feature; tracing is off
+	/// when this is unset.
+	pub otel_endpoint: Option<String>,
+	/// The GitHub API to talk to: github.com's, or a GitHub Enterprise
+	/// server's such as `https://github.example.com/api/v3`. Deliveries
+	/// whose API URLs point anywhere else are refused.
+	/// `NDHOOK_GITHUB_API_BASE` overrides it.
+	pub github_api_base: String,
+	/// The address and port to listen on, read only at startup.
+	/// `NDHOOK_BIND` overrides it.
+	pub bind: String,
+	/// How many threads serve HTTP requests, read only at startup. Unset
+	/// is one per CPU, which is plenty even on big hosts: handlers hand
+	/// anything slow to threads of their own.
+	pub server_threads: Option<usize>,
+	/// Serve HTTPS with the PEM certificate chain in this file and the
+	/// key in `tls_key_path`, for when there is no proxy in front to do
+	/// it; GitHub only delivers to HTTPS URLs. Plain HTTP when unset.
+	/// Both are read at startup and again on every SIGHUP, so a renewed
+	/// certificate can be put in place without a restart. Needs the
+	/// `tls` feature.
+	pub tls_cert_path: Option<String>,
+	/// The PEM private key for `tls_cert_path`.
+	pub tls_key_path: Option<String>,
+	/// Append each successful profile's results to this file, one JSON
+	/// object a line, to chart them over time. Nothing is kept when this
+	/// is unset. `NDHOOK_HISTORY_FILE` overrides it.
+	pub history_file: Option<String>,
+	/// Read the GitHub token from this file, and re-read it every
+	/// `github_token_refresh_secs` so it can be rotated in place.
+	pub github_token_file: Option<String>,
+	pub github_token_refresh_secs: u64,
+	pub github_auth: GitHubAuth,
+	/// The GitHub App to authenticate as when `github_auth` is `app`:
+	/// its ID, the PEM file holding its private key, and the
+	/// installation the bot acts for. A delivery from another
+	/// installation of the app is answered with a token for that one.
+	pub github_app_id: Option<u64>,
+	pub github_app_key_file: Option<String>,
+	pub github_app_installation_id: Option<u64>,
+	/// Who may ask for profiles.
+	pub profilers: ProfilersSource,
+	/// Who may use `profile force` to ask for profiles in an emergency,
+	/// whether or not they are profilers or trusted. Nobody can when
+	/// this is empty.
+	pub admins: Vec<String>,
+	/// Other logins the profilers and admins comment as, each mapped to
+	/// the login the lists know them by, for renamed accounts. Case
+	/// never matters.
+	pub commenter_aliases: BTreeMap<String, String>,
+	/// Bots and apps whose comments are skipped without a word, such as
+	/// `dependabot[bot]`. Case never matters.
+	pub ignored_commenters: Vec<String>,
+	/// How often to fetch the profilers list again when it comes from a
+	/// URL. The last list fetched stays in use while the URL fails.
+	pub profilers_refresh_secs: u64,
+	/// Per-repository overrides, keyed by `owner/name`.
+	pub repos: BTreeMap<String, RepoOverrides>,
+	/// More apps served by the same bot, keyed by the path their
+	/// webhook is mounted at (such as `/focus`), each with settings in
+	/// place of the global ones. `/` serves the global settings. The
+	/// paths are mounted at startup, so adding one takes a restart.
+	pub apps: BTreeMap<String, RepoOverrides>,
+	/// The NimbleDroid key of the app this configuration was made for
+	/// by `for_app`, if it has its own.
+	#[serde(skip)]
+	pub app_nd_key: Option<String>,
+	/// The GitHub token from `NDHOOK_GITHUB_TOKEN`; see `config_from_env`.
+	#[serde(skip)]
+	pub github_token: Option<String>,
+	/// The NimbleDroid key from `NDHOOK_ND_KEY`.
+	#[serde(skip)]
+	pub nd_key: Option<String>,
+}
+
+/// The NimbleDroid that nimbledroidrs talks to, the only one it can.
+pub static DEFAULT_ND_BASE_URL: &str = "https://nimbledroid.com";
+
+/*
+ * The paths the bot serves itself, which no app can be mounted at.
+ */
+static RESERVED_PATHS: [&str; 9] = [
+	"/",
+	"/health",
+	"/livez",
+	"/readyz",
+	"/metrics",
+	"/history.csv",
+	"/commands",
+	"/version",
+	"/profile-apk",
+];
+
+fn default_variants() -> BTreeMap<String, Variant> {
+	let mut variants = BTreeMap::new();
+	variants.insert(
+		"fenixNightly".to_string(),
+		Variant {
+			gradle_task: "assembleGeckoNightlyFenixNightly".to_string(),
+			apk_path: "fenixNightly/app-geckoNightly-armeabi-v7a-fenixNightly-unsigned.apk"
+				.to_string(),
+			nd_key: None,
+			profile_timeout_secs: None,
+		},
+	);
+	variants
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Self {
+			comment_trigger: true,
+			suggest_commands: true,
+			trusted_associations: vec![],
+			command_associations: BTreeMap::new(),
+			trigger_delay_secs: 0,
+			profile_cooldown_secs: None,
+			rate_per_user: None,
+			rate_global: None,
+			auto_profile_on_push: false,
+			profile_drafts: true,
+			profile_paths: vec![],
+			base_branches: vec![],
+			trigger_label: None,
+			remove_trigger_label: false,
+			allowed_repos: vec![],
+			max_comment_length: 65536,
+			max_pull_request_bytes: 4 * 1024 * 1024,
+			seconds_threshold_ms: None,
+			profile_poll_interval_secs: 30,
+			infra_retries: 0,
+			late_results_grace_secs: None,
+			scenario_budgets_ms: BTreeMap::new(),
+			ignored_scenarios: vec![],
+			hidden_scenarios: vec![],
+			fail_over_budget: false,
+			compact_status: false,
+			regression_percent: 5.0,
+			build_enabled: true,
+			build_image: "3683fdbe380c".to_string(),
+			build_script: "/buildtools/build_fenix.sh".to_string(),
+			gradle_tasks: vec!["assembleGeckoNightlyFenixNightly".to_string()],
+			build_hosts: vec![],
+			warmup_on_start: false,
+			warmup_command: vec![],
+			warmup_gates_ready: true,
+			build_env: BTreeMap::new(),
+			extra_build_args: vec![],
+			post_build_command: vec![],
+			build_log_gist: false,
+			build_log_tail_lines: Some(50),
+			profile_merge_ref: false,
+			build_volume_relabel: None,
+			artifact_root: None,
+			artifact_retention: None,
+			artifact_store: StoreKind::Local,
+			s3_endpoint: None,
+			s3_bucket: None,
+			s3_region: "us-east-1".to_string(),
+			s3_access_key_id: None,
+			s3_secret_access_key: None,
+			profile_timeout_secs: 2 * 60 * 60,
+			auto_baseline_schedule: None,
+			variants: default_variants(),
+			default_variant: "fenixNightly".to_string(),
+			sinks: vec![SinkKind::Comment],
+			comment_on: CommentOn::Always,
+			mention_commenter: false,
+			mention: vec![],
+			review_approve: false,
+			comment_templates: CommentTemplates::default(),
+			collapse_results: true,
+			raw_results: false,
+			sticky_comment: false,
+			review_thread_path: None,
+			review_thread_line: 1,
+			compare_previous_run: false,
+			compare_base: true,
+			history_count: 5,
+			slack_webhook_url: None,
+			webhook_sink_url: None,
+			webhook_sink_token: None,
+			comment_signing_secret: None,
+			webhook_sink_secret: None,
+			webhook_sink_retries: 2,
+			workers: 1,
+			worker_stuck_secs: None,
+			job_timeout_secs: None,
+			max_queue_wait_secs: None,
+			max_queued_jobs: None,
+			max_batch_size: 5,
+			queue_position_comment: false,
+			duplicate_jobs: DuplicateJobs::Allow,
+			start_comment: false,
+			max_concurrent_jobs: None,
+			max_load_average: None,
+			persistent_queue: false,
+			pending_jobs_file: "./pending_jobs.json".to_string(),
+			webhook_secrets: vec![],
+			admin_token: None,
+			dir_failures_unhealthy: 3,
+			readiness_interval_secs: 60,
+			min_free_disk_mb: None,
+			timing_window: 50,
+			nd_quota_message: "NimbleDroid run quota exhausted; contact your admin.".to_string(),
+			nd_base_url: DEFAULT_ND_BASE_URL.to_string(),
+			nd_breaker_failures: None,
+			nd_breaker_probe_secs: 600,
+			request_body_timeout_secs: 30,
+			dedup_window_secs: Some(60),
+			max_apk_upload_bytes: 256 * 1024 * 1024,
+			otel_endpoint: None,
+			github_api_base: DEFAULT_API_BASE.to_string(),
+			bind: "0.0.0.0:8000".to_string(),
+			server_threads: None,
+			tls_cert_path: None,
+			tls_key_path: None,
+			history_file: None,
+			github_token_file: None,
+			github_token_refresh_secs: 300,
+			github_auth: GitHubAuth::Pat,
+			github_app_id: None,
+			github_app_key_file: None,
+			github_app_installation_id: None,
+			profilers: ProfilersSource::File("./profilers.json".to_string()),
+			admins: vec![],
+			commenter_aliases: BTreeMap::new(),
+			ignored_commenters: vec![],
+			profilers_refresh_secs: 300,
+			repos: BTreeMap::new(),
+			apps: BTreeMap::new(),
+			app_nd_key: None,
+			github_token: None,
+			nd_key: None,
+		}
+	}
+}
+
+impl Config {
+	fn validate(&self) -> Result<(), String> {
+		if self.build_enabled && self.build_image.trim().is_empty() {
+			return Err("Oops, build_image is empty.".to_string());
+		}
+		if self.build_enabled && self.build_script.trim().is_empty() {
+			return Err("Oops, build_script is empty.".to_string());
+		}
+		if self.variant(&self.default_variant).is_none() {
+			return Err(format!(
+				"Oops, the default_variant {} isn't one of the variants.",
+				self.default_variant
+			));
+		}
+		if self.regression_percent.is_nan() || self.regression_percent < 0.0 {
+			return Err("Oops, regression_percent must be at least 0.".to_string());
+		}
+		if let Some(max_load_average) = self.max_load_average {
+			if max_load_average.is_nan() || max_load_average <= 0.0 {
+				return Err("Oops, max_load_average must be above 0.".to_string());
+			}
+		}
+		if self.max_concurrent_jobs == Some(0) {
+			return Err("Oops, max_concurrent_jobs must be at least 1.".to_string());
+		}
+		for name in self.command_associations.keys() {
+			if !is_known_name(name) {
+				return Err(format!(
+					"Oops, {} in command_associations isn't a command or an argument.",
+					name
+				));
+			}
+		}
+		for (repo_full_name, overrides) in &self.repos {
+			for name in overrides.command_associations.keys() {
+				if !is_known_name(name) {
+					return Err(format!(
+						"Oops, {} in command_associations for {} isn't a command or an argument.",
+						name, repo_full_name
+					));
+				}
+			}
+		}
+		if self.nd_breaker_failures == Some(0) {
+			return Err("Oops, nd_breaker_failures must be at least 1.".to_string());
+		}
+		if self.readiness_interval_secs == 0 {
+			return Err("Oops, readiness_interval_secs must be at least 1.".to_string());
+		}
+		if self.max_batch_size == 0 {
+			return Err("Oops, max_batch_size must be at least 1.".to_string());
+		}
+		if self.dir_failures_unhealthy == 0 {
+			return Err("Oops, dir_failures_unhealthy must be at least 1.".to_string());
+		}
+		if reqwest::Url::parse(&self.nd_base_url).is_err() {
+			return Err(format!(
+				"Oops, nd_base_url {:?} isn't a URL.",
+				self.nd_base_url
+			));
+		}
+		if self.nd_base_url.trim_end_matches('/') != DEFAULT_ND_BASE_URL {
+			return Err(format!(
+				"Oops, nd_base_url can't be {:?}: nimbledroidrs always uploads to {}, and can't be pointed anywhere else yet.",
+				self.nd_base_url, DEFAULT_ND_BASE_URL
+			));
+		}
+		if self.job_timeout_secs == Some(0) {
+			return Err("Oops, job_timeout_secs must be at least 1.".to_string());
+		}
+		if self.timing_window == 0 {
+			return Err("Oops, timing_window must be at least 1.".to_string());
+		}
+		for (name, variant) in &self.variants {
+			if !self.is_allowed_task(&variant.gradle_task) {
+				return Err(format!(
+					"Oops, the gradle task {} of variant {} isn't one of the gradle_tasks.",
+					variant.gradle_task, name
+				));
+			}
+		}
+		for path in self.apps.keys() {
+			let reserved = RESERVED_PATHS.contains(&path.as_str()) || path.starts_with("/admin/");
+			if !path.starts_with('/') || reserved {
+				return Err(format!(
+					"Oops, an app can't be mounted at {:?}; use a path like \"/focus\".",
+					path
+				));
+			}
+			let config = self.for_app(path).unwrap();
+			for (name, variant) in &config.variants {
+				if !config.is_allowed_task(&variant.gradle_task) {
+					return Err(format!(
+						"Oops, the gradle task {} of variant {} for the app at {} isn't one of the gradle_tasks.",
+						variant.gradle_task, name, path
+					));
+				}
+			}
+			if config.variant(&config.default_variant).is_none() {
+				return Err(format!(
+					"Oops, the default_variant {} for the app at {} isn't one of its variants.",
+					config.default_variant, path
+				));
+			}
+		}
+		for repo_full_name in self.repos.keys() {
+			let config = self.for_repo(repo_full_name);
+			for (name, variant) in &config.variants {
+				if !config.is_allowed_task(&variant.gradle_task) {
+					return Err(format!(
+						"Oops, the gradle task {} of variant {} for {} isn't one of the gradle_tasks.",
+						variant.gradle_task, name, repo_full_name
+					));
+				}
+			}
+			if config.variant(&config.default_variant).is_none() {
+				return Err(format!(
+					"Oops, the default_variant {} for {} isn't one of its variants.",
+					config.default_variant, repo_full_name
+				));
+			}
+			if config.max_concurrent_jobs == Some(0) {
+				return Err(format!(
+					"Oops, max_concurrent_jobs for {} must be at least 1.",
+					repo_full_name
+				));
+			}
+		}
+		if self.sinks.contains(&SinkKind::Slack) && self.slack_webhook_url.is_none() {
+			return Err("Oops, the slack sink needs a slack_webhook_url.".to_string());
+		}
+		if self.artifact_store == StoreKind::S3 {
+			match &self.s3_endpoint {
+				Some(endpoint) if reqwest::Url::parse(endpoint).is_ok() => (),
+				Some(endpoint) => {
+					return Err(format!("Oops, s3_endpoint {:?} isn't a URL.", endpoint))
+				}
+				None => return Err("Oops, the s3 store needs an s3_endpoint.".to_string()),
+			}
+			if self.s3_bucket.is_none()
+				|| self.s3_access_key_id.is_none()
+				|| self.s3_secret_access_key.is_none()
+			{
+				return Err(
+					"Oops, the s3 store needs s3_bucket, s3_access_key_id and s3_secret_access_key."
+						.to_string(),
+				);
+			}
+		}
+		if self.sinks.contains(&SinkKind::Webhook) && self.webhook_sink_url.is_none() {
+			return Err("Oops, the webhook sink needs a webhook_sink_url.".to_string());
+		}
+		let templates = &self.comment_templates;
+		for (name, template) in &[
+			("success", &templates.success),
+			("timeout", &templates.timeout),
+			("error", &templates.error),
+		] {
+			check_template(template)
+				.map_err(|e| format!("Oops, the {} comment template is invalid: {}.", name, e))?;
+		}
+		if !self.build_enabled && self.auto_baseline_schedule.is_some() {
+			return Err(
+				"Oops, auto_baseline_schedule builds, so it needs build_enabled.".to_string(),
+			);
+		}
+		if !self.build_enabled && self.warmup_on_start {
+			return Err("Oops, warmup_on_start builds, so it needs build_enabled.".to_string());
+		}
+		if self.github_auth == GitHubAuth::App
+			&& (self.github_app_id.is_none()
+				|| self.github_app_key_file.is_none()
+				|| self.github_app_installation_id.is_none())
+		{
+			return Err("Oops, github_auth = \"app\" needs github_app_id, github_app_key_file and github_app_installation_id.".to_string());
+		}
+		if self.build_log_tail_lines == Some(0) {
+			return Err("Oops, build_log_tail_lines must be at least 1.".to_string());
+		}
+		if self.rate_per_user == Some(0) {
+			return Err("Oops, rate_per_user must be at least 1.".to_string());
+		}
+		if self.rate_global == Some(0) {
+			return Err("Oops, rate_global must be at least 1.".to_string());
+		}
+		if self.dedup_window_secs == Some(0) {
+			return Err("Oops, dedup_window_secs must be at least 1.".to_string());
+		}
+		match reqwest::Url::parse(&self.github_api_base) {
+			Ok(url) if url.scheme() == "https" || url.scheme() == "http" => (),
+			_ => {
+				return Err(format!(
+					"Oops, github_api_base {:?} isn't a URL.",
+					self.github_api_base
+				))
+			}
+		}
+		match self.bind.rsplit_once(':') {
+			Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => (),
+			_ => {
+				return Err(format!(
+					"Oops, bind {:?} isn't an address and port.",
+					self.bind
+				))
+			}
+		}
+		if self.workers == 0 {
+			return Err("Oops, workers must be at least 1.".to_string());
+		}
+		if self.server_threads == Some(0) {
+			return Err("Oops, server_threads must be at least 1.".to_string());
+		}
+		if self.tls_cert_path.is_some() != self.tls_key_path.is_some() {
+			return Err(
+				"Oops, tls_cert_path and tls_key_path go together: set both or neither."
+					.to_string(),
+			);
+		}
+		if self.tls_cert_path.is_some() && !cfg!(feature = "tls") {
+			return Err(
+				"Oops, tls_cert_path is set but ndhook was built without the tls feature."
+					.to_string(),
+			);
+		}
+		Ok(())
+	}
+
+	pub fn variant(&self, name: &str) -> Option<&Variant> {
+		self.variants.get(name)
+	}
+
+	fn repo_overrides(&self, repo_full_name: &str) -> Option<&RepoOverrides> {
+		self.repos
+			.iter()
+			.find(|(name, _)| name.eq_ignore_ascii_case(repo_full_name))
+			.map(|(_, overrides)| overrides)
+	}
+
+	/// The configuration in effect for one repository: the global
+	/// settings with that repository's overrides applied.
+	pub fn for_repo(&self, repo_full_name: &str) -> Config {
+		let mut config = self.clone();
+		if let Some(overrides) = self.repo_overrides(repo_full_name) {
+			config.apply(overrides);
+		}
+		config
+	}
+
+	/// The configuration of the app whose webhook is mounted at `path`:
+	/// the global settings with the app's applied. `None` when there is
+	/// no such app.
+	pub fn for_app(&self, path: &str) -> Option<Config> {
+		let overrides = self.apps.get(path)?;
+		let mut config = self.clone();
+		config.apply(overrides);
+		config.app_nd_key = overrides.nd_key.clone();
+		Some(config)
+	}
+
+	fn apply(&mut self, overrides: &RepoOverrides) {
+		let config = self;
+		if let Some(build_image) = &overrides.build_image {
+			config.build_image = build_image.clone();
+		}
+		if let Some(build_script) = &overrides.build_script {
+			config.build_script = build_script.clone();
+		}
+		if let Some(profile_timeout_secs) = overrides.profile_timeout_secs {
+			config.profile_timeout_secs = profile_timeout_secs;
+		}
+		for (name, variant) in &overrides.variants {
+			config.variants.insert(name.clone(), variant.clone());
+		}
+		if let Some(default_variant) = &overrides.default_variant {
+			config.default_variant = default_variant.clone();
+		}
+		if let Some(max_concurrent_jobs) = overrides.max_concurrent_jobs {
+			config.max_concurrent_jobs = Some(max_concurrent_jobs);
+		}
+		if let Some(profile_paths) = &overrides.profile_paths {
+			config.profile_paths = profile_paths.clone();
+		}
+		if let Some(base_branches) = &overrides.base_branches {
+			config.base_branches = base_branches.clone();
+		}
+		if let Some(webhook_secrets) = &overrides.webhook_secrets {
+			config.webhook_secrets = webhook_secrets.clone();
+		}
+		if let Some(trusted_associations) = &overrides.trusted_associations {
+			config.trusted_associations = trusted_associations.clone();
+		}
+		for (name, associations) in &overrides.command_associations {
+			config
+				.command_associations
+				.insert(name.clone(), associations.clone());
+		}
+	}
+
+	/// Whether any repository has webhook secrets of its own. Then a
+	/// delivery has to be decompressed and parsed, unauthenticated, to
+	/// find which secrets should have signed it. That exposes the parser
+	/// to anyone who can reach the bot, and a delivery naming a
+	/// repository whose webhook_secrets are empty isn't checked at all,
+	/// so give every such repository secrets of its own.
+	pub fn has_repo_webhook_secrets(&self) -> bool {
+		self.repos
+			.values()
+			.any(|overrides| overrides.webhook_secrets.is_some())
+	}
+
+	/// Whether comments by `login` are skipped quietly.
+	pub fn is_ignored_commenter(&self, login: &str) -> bool {
+		self.ignored_commenters
+			.iter()
+			.any(|ignored| ignored.eq_ignore_ascii_case(login))
+	}
+
+	/// The lowercased login the profilers and admins lists know a
+	/// commenter by, after `commenter_aliases`.
+	pub fn canonical_login(&self, login: &str) -> String {
+		self.commenter_aliases
+			.iter()
+			.find(|(alias, _)| alias.eq_ignore_ascii_case(login))
+			.map_or(login, |(_, canonical)| canonical.as_str())
+			.to_lowercase()
+	}
+
+	/// Whether GitHub's author_association for someone is one the bot
+	/// trusts.
+	pub fn is_trusted(&self, author_association: &str) -> bool {
+		self.trusted_associations.is_empty()
+			|| self
+				.trusted_associations
+				.iter()
+				.any(|trusted| trusted.eq_ignore_ascii_case(author_association))
+	}
+
+	/// The first of a command's `names` that someone with this
+	/// author_association isn't allowed to use, if any.
+	pub fn restricted<'a>(&self, names: &[&'a str], author_association: &str) -> Option<&'a str> {
+		names
+			.iter()
+			.copied()
+			.find(|name| match self.command_associations.get(*name) {
+				Some(allowed) => !allowed
+					.iter()
+					.any(|allowed| allowed.eq_ignore_ascii_case(author_association)),
+				None => false,
+			})
+	}
+
+	/// Whether a Gradle task is one of the gradle_tasks. Nothing that
+	/// Gradle would take for an option is, whatever the list says.
+	pub fn is_allowed_task(&self, task: &str) -> bool {
+		!task.starts_with('-') && self.gradle_tasks.iter().any(|allowed| allowed == task)
+	}
+
+	/// Whether a changed file is one of the profile_paths.
+	pub fn is_profiled_path(&self, path: &str) -> bool {
+		self.profile_paths
+			.iter()
+			.any(|pattern| glob_match(pattern, path))
+	}
+
+	/// Whether a pull request into `base_ref` may be profiled.
+	pub fn is_profiled_base(&self, base_ref: &str) -> bool {
+		self.base_branches.is_empty()
+			|| self
+				.base_branches
+				.iter()
+				.any(|pattern| glob_match(pattern, base_ref))
+	}
+
+	/// The NimbleDroid key for a repository, if it has its own project.
+	pub fn repo_nd_key(&self, repo_full_name: &str) -> Option<&str> {
+		self.repo_overrides(repo_full_name)
+			.and_then(|overrides| overrides.nd_key.as_deref())
+	}
+
+	/// The branches of a repository to profile when they are pushed to.
+	pub fn repo_push_branches(&self, repo_full_name: &str) -> &[String] {
+		self.repo_overrides(repo_full_name)
+			.map_or(&[][..], |overrides| &overrides.push_branches[..])
+	}
+
+	/// Who may ask for profiles of a repository, if it has its own list.
+	pub fn repo_profilers(&self, repo_full_name: &str) -> Option<&[String]> {
+		self.repo_overrides(repo_full_name)
+			.and_then(|overrides| overrides.profilers.as_deref())
+	}
+
+	/// Who may ask for profiles of a repository besides its profilers.
+	pub fn repo_extra_profilers(&self, repo_full_name: &str) -> &[String] {
+		self.repo_overrides(repo_full_name)
+			.map_or(&[][..], |overrides| &overrides.extra_profilers[..])
+	}
+
+	pub fn repo_allowed(&self, repo_full_name: &str) -> bool {
+		self.allowed_repos.is_empty()
+			|| self
+				.allowed_repos
+				.iter()
+				.any(|r| r.eq_ignore_ascii_case(repo_full_name))
+	}
+}
+
+/*
+ * Match a path against a glob: `**` is any number of whole directories,
+ * `*` any run of characters within one, and `?` any one character.
+ */
+fn glob_match(pattern: &str, path: &str) -> bool {
+	let pattern: Vec<&str> = pattern.split('/').collect();
+	let path: Vec<&str> = path.split('/').collect();
+	segments_match(&pattern, &path)
+}
+
+fn segments_match(pattern: &[&str], path: &[&str]) -> bool {
+	match pattern.split_first() {
+		None => path.is_empty(),
+		Some((&"**", rest)) => (0..=path.len()).any(|skip| segments_match(rest, &path[skip..])),
+		Some((first, rest)) => match path.split_first() {
+			Some((name, path_rest)) => {
+				let first: Vec<char> = first.chars().collect();
+				let name: Vec<char> = name.chars().collect();
+				segment_match(&first, &name) && segments_match(rest, path_rest)
+			}
+			None => false,
+		},
+	}
+}
+
+fn segment_match(pattern: &[char], name: &[char]) -> bool {
+	match pattern.split_first() {
+		None => name.is_empty(),
+		Some(('*', rest)) => (0..=name.len()).any(|skip| segment_match(rest, &name[skip..])),
+		Some(('?', rest)) => !name.is_empty() && segment_match(rest, &name[1..]),
+		Some((c, rest)) => name.first() == Some(c) && segment_match(rest, &name[1..]),
+	}
+}
+
+/*
+ * Expand `${NAME}` from the environment in every string of the
+ * configuration, so secrets can stay out of the file. `$${` is a literal
+ * `${`.
+ */
+fn interpolate_value(value: &mut toml::Value) -> Result<(), String> {
+	match value {
+		toml::Value::String(s) => {
+			*s = interpolate(s)?;
+			Ok(())
+		}
+		toml::Value::Array(values) => values.iter_mut().try_for_each(interpolate_value),
+		toml::Value::Table(table) => table
+			.iter_mut()
+			.try_for_each(|(_, value)| interpolate_value(value)),
+		_ => Ok(()),
+	}
+}
+
+fn interpolate(s: &str) -> Result<String, String> {
+	let mut expanded = String::new();
+	let mut rest = s;
+	while let Some(start) = rest.find("${") {
+		if rest[..start].ends_with('$') {
+			expanded.push_str(&rest[..start]);
+			expanded.push('{');
+			rest = &rest[start + 2..];
+			continue;
+		}
+		expanded.push_str(&rest[..start]);
+		let end = rest[start..]
+			.find('}')
+			.ok_or_else(|| format!("Oops, an unclosed ${{ in {:?}", s))?;
+		let name = &rest[start + 2..start + end];
+		let found = std::env::var(name).map_err(|_| {
+			format!(
+				"Oops, the configuration uses ${{{}}} but it isn't set",
+				name
+			)
+		})?;
+		expanded.push_str(&found);
+		rest = &rest[start + end + 1..];
+	}
+	expanded.push_str(rest);
+	Ok(expanded)
+}
+
+/// The keys the bot authenticates with, which never go in the file.
+pub const ENV_KEYS: [&str; 3] = [
+	"NDHOOK_GITHUB_TOKEN",
+	"NDHOOK_ND_KEY",
+	"NDHOOK_WEBHOOK_SECRET",
+];
+
+/// An environment variable with the whitespace pasted around it
+/// trimmed, or `None` when it is unset or empty.
+pub fn env_key(name: &str) -> Option<String> {
+	std::env::var(name)
+		.ok()
+		.map(|value| value.trim().to_string())
+		.filter(|value| !value.is_empty())
+}
+
+/// `config` with the keys in the environment: the GitHub token from
+/// `NDHOOK_GITHUB_TOKEN`, the NimbleDroid key from `NDHOOK_ND_KEY` and
+/// another webhook secret from `NDHOOK_WEBHOOK_SECRET`. The GitHub token
+/// isn't needed when it comes from `github_token_file` or the app.
+/// `NDHOOK_MAX_JOBS`, if set, is how many workers to run, and
+/// `NDHOOK_BUILD_IMAGE` and `NDHOOK_BUILD_SCRIPT` what to build with.
+/// `NDHOOK_GITHUB_API_BASE` points the bot at a GitHub Enterprise server,
+/// `NDHOOK_BIND` says where to listen, `NDHOOK_HISTORY_FILE` where to
+/// keep the history of results, and `NDHOOK_REPORT_MODE` whether to
+/// report with a comment, a commit status or both.
+/// `NDHOOK_REGRESSION_PCT` is how much slower makes a regression,
+/// `NDHOOK_RATE_PER_USER` how many profiles a commenter may ask for in an
+/// hour, and `NDHOOK_RATE_GLOBAL` how many everyone together may.
+pub fn config_from_env(config: Config) -> Result<Config, String> {
+	let mut config = Config {
+		github_token: env_key("NDHOOK_GITHUB_TOKEN"),
+		nd_key: env_key("NDHOOK_ND_KEY"),
+		..config
+	};
+	if config.github_token.is_none()
+		&& config.github_auth == GitHubAuth::Pat
+		&& config.github_token_file.is_none()
+	{
+		return Err(
+			"Oops, NDHOOK_GITHUB_TOKEN is missing or empty, and there is no github_token_file."
+				.to_string(),
+		);
+	}
+	if config.nd_key.is_none() {
+		return Err("Oops, NDHOOK_ND_KEY is missing or empty.".to_string());
+	}
+	if let Some(secret) = env_key("NDHOOK_WEBHOOK_SECRET") {
+		if !config.webhook_secrets.contains(&secret) {
+			config.webhook_secrets.push(secret);
+		}
+	}
+	if let Some(api_base) = env_key("NDHOOK_GITHUB_API_BASE") {
+		if reqwest::Url::parse(&api_base).is_err() {
+			return Err(format!(
+				"Oops, NDHOOK_GITHUB_API_BASE {:?} isn't a URL.",
+				api_base
+			));
+		}
+		config.github_api_base = api_base;
+	}
+	if let Some(bind) = env_key("NDHOOK_BIND") {
+		config.bind = bind;
+	}
+	if let Some(history_file) = env_key("NDHOOK_HISTORY_FILE") {
+		config.history_file = Some(history_file);
+	}
+	if let Some(regression_pct) = env_key("NDHOOK_REGRESSION_PCT") {
+		config.regression_percent = match regression_pct.parse::<f64>() {
+			Ok(percent) if percent >= 0.0 => percent,
+			_ => {
+				return Err(format!(
+					"Oops, NDHOOK_REGRESSION_PCT must be a percentage of at least 0, not {:?}.",
+					regression_pct
+				))
+			}
+		};
+	}
+	if let Some(rate_per_user) = env_key("NDHOOK_RATE_PER_USER") {
+		config.rate_per_user = match rate_per_user.parse::<u32>() {
+			Ok(rate) if rate > 0 => Some(rate),
+			_ => {
+				return Err(format!(
+					"Oops, NDHOOK_RATE_PER_USER must be a number of at least 1, not {:?}.",
+					rate_per_user
+				))
+			}
+		};
+	}
+	if let Some(rate_global) = env_key("NDHOOK_RATE_GLOBAL") {
+		config.rate_global = match rate_global.parse::<u32>() {
+			Ok(rate) if rate > 0 => Some(rate),
+			_ => {
+				return Err(format!(
+					"Oops, NDHOOK_RATE_GLOBAL must be a number of at least 1, not {:?}.",
+					rate_global
+				))
+			}
+		};
+	}
+	if let Some(report_mode) = env_key("NDHOOK_REPORT_MODE") {
+		let reports: &[SinkKind] = match report_mode.as_str() {
+			"comment" => &[SinkKind::Comment],
+			"status" => &[SinkKind::Status],
+			"both" => &[SinkKind::Comment, SinkKind::Status],
+			_ => {
+				return Err(format!(
+					"Oops, NDHOOK_REPORT_MODE must be comment, status or both, not {:?}.",
+					report_mode
+				))
+			}
+		};
+		config
+			.sinks
+			.retain(|sink| *sink != SinkKind::Comment && *sink != SinkKind::Status);
+		config.sinks.extend_from_slice(reports);
+		/* A status asked for this way should say what got slower. */
+		if reports.contains(&SinkKind::Status) {
+			config.compact_status = true;
+		}
+	}
+	if let Some(build_image) = env_key("NDHOOK_BUILD_IMAGE") {
+		config.build_image = build_image;
+	}
+	if let Some(build_script) = env_key("NDHOOK_BUILD_SCRIPT") {
+		config.build_script = build_script;
+	}
+	if let Some(max_jobs) = env_key("NDHOOK_MAX_JOBS") {
+		config.workers = match max_jobs.parse::<usize>() {
+			Ok(workers) if workers > 0 => workers,
+			_ => {
+				return Err(format!(
+					"Oops, NDHOOK_MAX_JOBS must be a number of at least 1, not {:?}.",
+					max_jobs
+				))
+			}
+		};
+	}
+	Ok(config)
+}
+
+pub fn config_from_file(filename: &str) -> Result<Config, String> {
+	match fs::read_to_string(filename) {
+		Ok(contents) => {
+			let parse_error = |e: toml::de::Error| {
+				format!(
+					"Oops, couldn't parse the configuration in {}: {}",
+					filename, e
+				)
+			};
+			let mut value: toml::Value = toml::from_str(&contents).map_err(parse_error)?;
+			interpolate_value(&mut value).map_err(|e| format!("{} in {}", e, filename))?;
+			let config: Config = value.try_into().map_err(parse_error)?;
+			config.validate()?;
+			Ok(config)
+		}
+		Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(Config::default()),
+		Err(e) => Err(format!(
+			"Oops, couldn't read the configuration in {}: {}",
+			filename, e
+		)),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn with_task(task: &str) -> Config {
+		let mut config = Config::default();
+		config.variants.insert(
+			"beta".to_string(),
+			Variant {
+				gradle_task: task.to_string(),
+				apk_path: "beta.apk".to_string(),
+				nd_key: None,
+				profile_timeout_secs: None,
+			},
+		);
+		config
+	}
+
+	#[test]
+	fn refuses_another_nimbledroid() {
+		let config = Config {
+			nd_base_url: "https://nd.example.com".to_string(),
+			..Config::default()
+		};
+		let e = config.validate().unwrap_err();
+		assert!(e.contains("nd_base_url"), "{}", e);
+
+		let config = Config {
+			nd_base_url: "https://nimbledroid.com/".to_string(),
+			..Config::default()
+		};
+		assert!(config.validate().is_ok());
+	}
+
+	#[test]
+	fn allows_a_listed_gradle_task() {
+		let config = with_task("assembleGeckoNightlyFenixNightly");
+		assert!(config.validate().is_ok());
+		assert!(config.is_allowed_task("assembleGeckoNightlyFenixNightly"));
+	}
+
+	#[test]
+	fn refuses_an_unlisted_gradle_task() {
+		let config = with_task("assembleGeckoBetaFenixBeta");
+		let e = config.validate().unwrap_err();
+		assert!(e.contains("isn't one of the gradle_tasks"), "{}", e);
+		assert!(!config.is_allowed_task("assembleGeckoBetaFenixBeta"));
+	}
+
+	#[test]
+	fn refuses_a_gradle_option_even_when_listed() {
+		let mut config = with_task("--init-script=evil.gradle");
+		config
+			.gradle_tasks
+			.push("--init-script=evil.gradle".to_string());
+		let e = config.validate().unwrap_err();
+		assert!(e.contains("isn't one of the gradle_tasks"), "{}", e);
+	}
+}
@@ -0,0 +1,30 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The `X-GitHub-Delivery` IDs of the latest deliveries, for dropping
+/// the ones GitHub sends again soon after.
+#[derive(Clone, Default)]
+pub struct RecentDeliveries {
+	seen: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl RecentDeliveries {
+	/// Whether the delivery `id` already came within `window`. A delivery
+	/// that didn't is remembered for the next `window`.
+	pub fn is_duplicate(&self, id: &str, window: Duration) -> bool {
+		let mut seen = self.seen.lock().unwrap();
+		seen.retain(|_, at| at.elapsed() < window);
+		if seen.contains_key(id) {
+			return true;
+		}
+		seen.insert(id.to_string(), Instant::now());
+		false
+	}
+}
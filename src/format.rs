@@ -0,0 +1,905 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::baseline::Baseline;
+use crate::batch::BatchEntry;
+use crate::config::{CommentTemplates, Config, SinkKind, StoreKind};
+use crate::job::{BuildInfo, ProfileOutcome, ProfiledRef};
+use crate::profile::ScenarioResult;
+use crate::results::{PastRun, RecentResult};
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+/*
+ * Secrets are only ever said to be set or not; their values stay out of
+ * comments.
+ */
+fn secret(value: Option<&str>) -> &'static str {
+	match value {
+		Some(_) => "set (redacted)",
+		None => "not set",
+	}
+}
+
+/// Describe the configuration that applies to a repository, for the
+/// `profile debug` command. Every key, token and build environment value
+/// is redacted.
+pub fn format_config_comment(config: &Config, repo_full_name: &str) -> String {
+	let mut lines = vec![format!("Configuration for `{}`:\n", repo_full_name)];
+	if config.build_enabled {
+		lines.push(format!("* build image: `{}`", config.build_image));
+		lines.push(format!("* build script: `{}`", config.build_script));
+	} else {
+		lines.push("* building: disabled".to_string());
+	}
+	if !config.build_env.is_empty() {
+		let names: Vec<String> = config.build_env.keys().cloned().collect();
+		lines.push(format!(
+			"* build environment: `{}` (values redacted)",
+			names.join("`, `")
+		));
+	}
+	if !config.extra_build_args.is_empty() {
+		lines.push(format!(
+			"* extra build arguments: {} (redacted)",
+			config.extra_build_args.len()
+		));
+	}
+	if config.profile_merge_ref {
+		lines.push("* builds: the pull request merged into its base".to_string());
+	}
+	lines.push("* variants:".to_string());
+	for (name, variant) in &config.variants {
+		let default = if *name == config.default_variant {
+			" (default)"
+		} else {
+			""
+		};
+		lines.push(format!(
+			"  * `{}`{}: gradle task `{}`, APK `{}`, NimbleDroid key {}, timeout {}s",
+			name,
+			default,
+			variant.gradle_task,
+			variant.apk_path,
+			secret(variant.nd_key.as_deref()),
+			variant
+				.profile_timeout_secs
+				.unwrap_or(config.profile_timeout_secs)
+		));
+	}
+	lines.push(format!(
+		"* NimbleDroid key for the repository: {}",
+		match config.repo_nd_key(repo_full_name) {
+			Some(_) => "its own (redacted)",
+			None => "the global one (redacted)",
+		}
+	));
+	lines.push(format!(
+		"* profile timeout: {}s, polled every {}s",
+		config.profile_timeout_secs, config.profile_poll_interval_secs
+	));
+	if config.scenario_budgets_ms.is_empty() {
+		lines.push("* scenario budgets: none".to_string());
+	} else {
+		let budgets: Vec<String> = config
+			.scenario_budgets_ms
+			.iter()
+			.map(|(name, budget)| format!("`{}` {}ms", name, budget))
+			.collect();
+		lines.push(format!(
+			"* scenario budgets: {}{}",
+			budgets.join(", "),
+			if config.fail_over_budget {
+				", failing the status when over"
+			} else {
+				""
+			}
+		));
+	}
+	for (label, names) in &[
+		("ignored scenarios", &config.ignored_scenarios),
+		("hidden scenarios", &config.hidden_scenarios),
+	] {
+		if !names.is_empty() {
+			let names: Vec<String> = names.iter().map(|n| format!("`{}`", n)).collect();
+			lines.push(format!("* {}: {}", label, names.join(", ")));
+		}
+	}
+	lines.push(format!("* trigger delay: {}s", config.trigger_delay_secs));
+	let sinks: Vec<String> = config
+		.sinks
+		.iter()
+		.map(|sink| format!("{:?}", sink).to_lowercase())
+		.collect();
+	lines.push(format!(
+		"* results go to: {}, commenting {}",
+		sinks.join(", "),
+		format!("{:?}", config.comment_on).to_lowercase()
+	));
+	lines.push(format!(
+		"* Slack webhook: {}",
+		secret(config.slack_webhook_url.as_deref())
+	));
+	lines.push(format!(
+		"* comment signing secret: {}",
+		secret(config.comment_signing_secret.as_deref())
+	));
+	if config.artifact_store == StoreKind::S3 {
+		lines.push(format!(
+			"* artifacts go to: bucket `{}` at {}, key {}",
+			config.s3_bucket.as_deref().unwrap_or_default(),
+			secret(config.s3_endpoint.as_deref()),
+			secret(config.s3_secret_access_key.as_deref())
+		));
+	}
+	if config.sinks.contains(&SinkKind::Webhook) {
+		lines.push(format!(
+			"* result webhook: {}, token {}, signing secret {}",
+			secret(config.webhook_sink_url.as_deref()),
+			secret(config.webhook_sink_token.as_deref()),
+			secret(config.webhook_sink_secret.as_deref())
+		));
+	}
+	lines.join("\n")
+}
+
+/// How to lay out a results comment.
+#[derive(Clone, Debug)]
+pub struct CommentOptions {
+	/// Longest comment body to produce.
+	pub max_length: usize,
+	/// Show the time column in seconds when any scenario took at least
+	/// this many milliseconds. `None` always shows milliseconds.
+	pub seconds_threshold_ms: Option<u64>,
+	/// Flag scenarios that took longer than these, by name.
+	pub budgets_ms: BTreeMap<String, u64>,
+	pub templates: CommentTemplates,
+	/// A previous profile to compare each scenario's time with.
+	pub previous_run: Option<RecentResult>,
+	/// A profile of the pull request's base to compare each scenario's
+	/// time with.
+	pub baseline: Option<Baseline>,
+	/// How much slower than the base, in percent, a scenario must be to
+	/// be flagged as a regression.
+	pub regression_percent: f64,
+	/// Who to @-mention at the end, without the @.
+	pub mentions: Vec<String>,
+	/// The results came in after the profile had been reported as
+	/// timed out.
+	pub late: bool,
+	/// What to say when NimbleDroid has no runs left.
+	pub quota_message: String,
+	/// Fold the results table into a `<details>` block under a summary.
+	pub collapse_table: bool,
+	/// End with the outcome as JSON in a collapsed block.
+	pub raw_json: bool,
+}
+
+impl CommentOptions {
+	pub fn from_config(config: &Config) -> Self {
+		Self {
+			max_length: config.max_comment_length,
+			seconds_threshold_ms: config.seconds_threshold_ms,
+			budgets_ms: config.scenario_budgets_ms.clone(),
+			templates: config.comment_templates.clone(),
+			previous_run: None,
+			baseline: None,
+			regression_percent: config.regression_percent,
+			mentions: vec![],
+			late: false,
+			quota_message: config.nd_quota_message.clone(),
+			collapse_table: config.collapse_results,
+			raw_json: config.raw_results,
+		}
+	}
+}
+
+const TEMPLATE_VARIABLES: [&str; 6] = [
+	"header",
+	"message",
+	"scenarios",
+	"nd_url",
+	"sha",
+	"commenter",
+];
+
+enum Piece<'a> {
+	Text(String),
+	Variable(&'a str),
+}
+
+fn parse_template(template: &str) -> Result<Vec<Piece<'_>>, String> {
+	let mut pieces = vec![];
+	let mut text = String::new();
+	let mut chars = template.char_indices().peekable();
+	while let Some((i, c)) = chars.next() {
+		let doubled = chars.peek().map(|&(_, next)| next) == Some(c);
+		match c {
+			'{' | '}' if doubled => {
+				chars.next();
+				text.push(c);
+			}
+			'{' => {
+				let end = match template[i..].find('}') {
+					Some(end) => i + end,
+					None => return Err("a `{` is never closed".to_string()),
+				};
+				let name = &template[i + 1..end];
+				if !TEMPLATE_VARIABLES.contains(&name) {
+					return Err(format!("`{{{}}}` isn't a variable", name));
+				}
+				pieces.push(Piece::Text(std::mem::take(&mut text)));
+				pieces.push(Piece::Variable(name));
+				while let Some(&(j, _)) = chars.peek() {
+					if j > end {
+						break;
+					}
+					chars.next();
+				}
+			}
+			'}' => return Err("a `}` was never opened".to_string()),
+			c => text.push(c),
+		}
+	}
+	pieces.push(Piece::Text(text));
+	Ok(pieces)
+}
+
+/// Check that a comment template will render, so that a bad one is
+/// caught when the configuration loads rather than when commenting.
+pub fn check_template(template: &str) -> Result<(), String> {
+	parse_template(template).map(|_| ())
+}
+
+/*
+ * Fill in the template's variables with their values.
+ */
+fn render(pieces: &[Piece], values: &[(&str, &str)]) -> String {
+	pieces
+		.iter()
+		.map(|piece| match piece {
+			Piece::Text(text) => text.to_string(),
+			Piece::Variable(name) => values
+				.iter()
+				.find(|(n, _)| n == name)
+				.map_or(String::new(), |(_, value)| value.to_string()),
+		})
+		.collect()
+}
+
+/*
+ * How long ago something happened, roughly.
+ */
+fn format_age(at: SystemTime) -> String {
+	let minutes = at.elapsed().map(|age| age.as_secs() / 60).unwrap_or(0);
+	match minutes {
+		0 => "just now".to_string(),
+		1 => "1 minute ago".to_string(),
+		2..=119 => format!("{} minutes ago", minutes),
+		120..=2879 => format!("{} hours ago", minutes / 60),
+		_ => format!("{} days ago", minutes / (60 * 24)),
+	}
+}
+
+/// List a pull request's latest runs, newest first, for the `profile
+/// history` command. Only runs since the bot last started are known.
+pub fn format_history_comment(runs: &[PastRun]) -> String {
+	if runs.is_empty() {
+		return "There are no profiles of this pull request since the bot last started."
+			.to_string();
+	}
+	let mut comment = "The latest profiles of this pull request:\n\n".to_string();
+	comment.push_str("Commit | Variant | Profiled | Result | NimbleDroid");
+	comment.push('\n');
+	comment.push_str("-------|---------|----------|--------|------------");
+	comment.push('\n');
+	for run in runs {
+		let profile = match &run.profile_url {
+			Some(profile_url) => format!("[results]({})", profile_url),
+			None => "-".to_string(),
+		};
+		comment.push_str(&format!(
+			"`{}` | {} | {} | {} | {}\n",
+			&run.sha[..run.sha.len().min(7)],
+			run.variant,
+			format_age(run.recorded_at),
+			run.result,
+			profile
+		));
+	}
+	comment
+}
+
+/// Compare the profiles of a `profile batch`, a pull request to a
+/// column, in the order they were asked for.
+pub fn format_batch_comment(numbers: &[u64], entries: &[BatchEntry]) -> String {
+	let entry = |number: &u64| entries.iter().find(|entry| entry.number == *number);
+	let mut comment = "The batch's profiles:\n\n".to_string();
+	comment.push_str("Pull request | Commit | Result | NimbleDroid");
+	comment.push('\n');
+	comment.push_str("-------------|--------|--------|------------");
+	comment.push('\n');
+	for number in numbers {
+		let row = match entry(number) {
+			Some(entry) => format!(
+				"#{} | `{}` | {} | {}",
+				number,
+				&entry.sha[..entry.sha.len().min(7)],
+				entry.result,
+				match &entry.profile_url {
+					Some(profile_url) => format!("[results]({})", profile_url),
+					None => "-".to_string(),
+				}
+			),
+			None => format!("#{} | - | didn't run | -", number),
+		};
+		comment.push_str(&row);
+		comment.push('\n');
+	}
+
+	let mut scenarios: Vec<&str> = vec![];
+	for entry in entries {
+		for scenario in &entry.scenarios {
+			if !scenarios.contains(&scenario.name.as_str()) {
+				scenarios.push(&scenario.name);
+			}
+		}
+	}
+	if scenarios.is_empty() {
+		return comment;
+	}
+	comment.push('\n');
+	comment.push_str("Scenario");
+	for number in numbers {
+		comment.push_str(&format!(" | #{}", number));
+	}
+	comment.push('\n');
+	comment.push_str("--------");
+	comment.push_str(&"|---".repeat(numbers.len()));
+	comment.push('\n');
+	for name in scenarios {
+		comment.push_str(name);
+		for number in numbers {
+			let time = entry(number)
+				.and_then(|entry| entry.scenarios.iter().find(|s| s.name == name))
+				.map(|s| format!("{} ms", s.time_in_ms))
+				.unwrap_or_else(|| "-".to_string());
+			comment.push_str(&format!(" | {}", time));
+		}
+		comment.push('\n');
+	}
+	comment
+}
+
+/// The longest description GitHub keeps on a commit status.
+pub const STATUS_DESCRIPTION_LENGTH: usize = 140;
+
+/// Longest scenario name a status description names before cutting it.
+const STATUS_NAME_LENGTH: usize = 40;
+
+/// Describe a profile in one line for a commit status, such as
+/// "5 scenarios · 1 regression · startup +6% · details →". Regressions
+/// come first, the worst of them named, then failures and budgets, and
+/// whatever doesn't fit in `STATUS_DESCRIPTION_LENGTH` is left out.
+///
+/// A regression is a scenario at least `regression_percent` slower than
+/// it was on the base, in `baseline`.
+pub fn format_status_description(
+	scenarios: &[ScenarioResult],
+	budgets_ms: &BTreeMap<String, u64>,
+	baseline: Option<&Baseline>,
+	regression_percent: f64,
+	has_details: bool,
+) -> String {
+	let mut regressions: Vec<(&str, f64)> = scenarios
+		.iter()
+		.filter_map(|s| {
+			let change = slowdown(s, baseline?)?;
+			if change > 0.0 && change >= regression_percent {
+				Some((s.name.as_str(), change))
+			} else {
+				None
+			}
+		})
+		.collect();
+	regressions.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+	let failed = scenarios
+		.iter()
+		.filter(|s| !s.passed() && !s.is_pending())
+		.count();
+	let over_budget = scenarios
+		.iter()
+		.filter(|s| s.over_budget(budgets_ms).is_some())
+		.count();
+
+	let plural = |count: usize, one: &str, many: &str| {
+		format!("{} {}", count, if count == 1 { one } else { many })
+	};
+	let mut parts = vec![plural(scenarios.len(), "scenario", "scenarios")];
+	if let Some((name, change)) = regressions.first() {
+		parts.push(plural(regressions.len(), "regression", "regressions"));
+		let name: String = if name.chars().count() > STATUS_NAME_LENGTH {
+			let cut: String = name.chars().take(STATUS_NAME_LENGTH - 1).collect();
+			format!("{}…", cut)
+		} else {
+			name.to_string()
+		};
+		parts.push(format!("{} +{:.0}%", name, change));
+	}
+	if failed > 0 {
+		parts.push(format!("{} failed", failed));
+	}
+	if over_budget > 0 {
+		parts.push(format!("{} over budget", over_budget));
+	}
+	if parts.len() == 1 {
+		parts.push("all passed".to_string());
+	}
+
+	/* Keep room for the link so it is never what gets left out. */
+	let suffix = if has_details { " · details →" } else { "" };
+	let limit = STATUS_DESCRIPTION_LENGTH - suffix.chars().count();
+	let mut description = String::new();
+	for part in parts {
+		let separator = if description.is_empty() { "" } else { " · " };
+		if description.chars().count() + separator.chars().count() + part.chars().count() <= limit {
+			description.push_str(separator);
+			description.push_str(&part);
+		}
+	}
+	description.push_str(suffix);
+	description
+}
+
+/*
+ * How much slower, in percent, a scenario was than in `baseline`, if it
+ * was timed in both.
+ */
+fn slowdown(s: &ScenarioResult, baseline: &Baseline) -> Option<f64> {
+	if s.is_pending() {
+		return None;
+	}
+	let base = baseline
+		.scenarios
+		.iter()
+		.find(|b| b.name == s.name && !b.is_pending() && b.time_in_ms > 0)?;
+	Some((s.time_in_ms as f64 - base.time_in_ms as f64) * 100.0 / base.time_in_ms as f64)
+}
+
+/*
+ * Whether a scenario was at least `regression_percent` slower than in
+ * `baseline`.
+ */
+fn regressed(s: &ScenarioResult, baseline: &Baseline, regression_percent: f64) -> bool {
+	match slowdown(s, baseline) {
+		Some(change) => change > 0.0 && change >= regression_percent,
+		None => false,
+	}
+}
+
+fn format_size(bytes: u64) -> String {
+	if bytes >= 1024 * 1024 {
+		format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+	} else if bytes >= 1024 {
+		format!("{:.1} KiB", bytes as f64 / 1024.0)
+	} else {
+		format!("{} bytes", bytes)
+	}
+}
+
+fn format_header(build: &BuildInfo, previous_run: Option<&RecentResult>) -> String {
+	let mut header = String::new();
+	let profiled_ref = match &build.profiled_ref {
+		Some(ProfiledRef::Merge { base_ref }) => {
+			Some(format!("the pull request merged into `{}`", base_ref))
+		}
+		Some(ProfiledRef::Head) => {
+			Some("the pull request's head, as GitHub had no clean merge of it".to_string())
+		}
+		None => None,
+	};
+	match (&build.built_sha, profiled_ref) {
+		(Some(sha), Some(profiled_ref)) => header.push_str(&format!(
+			"Profiled commit `{}`, {}.\n",
+			&sha[..sha.len().min(7)],
+			profiled_ref
+		)),
+		(Some(sha), None) => header.push_str(&format!(
+			"Profiled commit `{}`.\n",
+			&sha[..sha.len().min(7)]
+		)),
+		(None, Some(profiled_ref)) => header.push_str(&format!("Profiled {}.\n", profiled_ref)),
+		(None, None) => (),
+	}
+	if let Some(image) = &build.image {
+		header.push_str(&format!("Built with image `{}`", image));
+		if let Some(image_id) = &build.image_id {
+			let image_id = image_id.trim_start_matches("sha256:");
+			header.push_str(&format!(" (`{}`)", &image_id[..image_id.len().min(12)]));
+		}
+		if let Some(version) = &build.toolchain_version {
+			header.push_str(&format!(", toolchain `{}`", version));
+		}
+		header.push_str(".\n");
+	}
+	if let Some(apk_bytes) = build.apk_bytes {
+		header.push_str(&format!("APK size: {}", format_size(apk_bytes)));
+		let previous = previous_run.and_then(|previous| previous.build.apk_bytes);
+		if let Some(previous) = previous {
+			let (sign, change) = if apk_bytes >= previous {
+				("+", apk_bytes - previous)
+			} else {
+				("-", previous - apk_bytes)
+			};
+			header.push_str(&format!(
+				" ({}{} vs your previous run)",
+				sign,
+				format_size(change)
+			));
+		}
+		header.push_str(".\n");
+	}
+	if let Some(log_url) = &build.log_url {
+		header.push_str(&format!("Full logs: {}\n", log_url));
+	}
+	if !build.artifact_urls.is_empty() {
+		let links: Vec<String> = build
+			.artifact_urls
+			.iter()
+			.map(|(name, url)| format!("[{}]({})", name, url))
+			.collect();
+		header.push_str(&format!("Artifacts: {}\n", links.join(", ")));
+	}
+	if !header.is_empty() {
+		header.push('\n');
+	}
+	header
+}
+
+/// Render the pull request comment describing how a run ended from the
+/// configured template for that kind of ending. By default it is led by
+/// the commit that was actually profiled, the toolchain that built it
+/// and the size of its APK, as far as the build reported them.
+pub fn format_outcome_comment(
+	outcome: &ProfileOutcome,
+	build: &BuildInfo,
+	head_sha: &str,
+	commenter: Option<&str>,
+	options: &CommentOptions,
+) -> String {
+	let (template, scenarios) = match outcome {
+		ProfileOutcome::Success { scenarios, .. } => (&options.templates.success, &scenarios[..]),
+		ProfileOutcome::Timeout { scenarios, .. } => (&options.templates.timeout, &scenarios[..]),
+		_ => (&options.templates.error, &[][..]),
+	};
+	/* The templates were checked when the configuration loaded. */
+	let pieces = parse_template(template).unwrap();
+	let mentions = if options.mentions.is_empty() {
+		String::new()
+	} else {
+		let mentions: Vec<String> = options.mentions.iter().map(|m| format!("@{}", m)).collect();
+		format!("\n\ncc {}", mentions.join(" "))
+	};
+
+	let mut header = format_header(build, options.previous_run.as_ref());
+	if let (ProfileOutcome::Success { .. }, Some(baseline)) = (outcome, &options.baseline) {
+		let regressed: Vec<&str> = scenarios
+			.iter()
+			.filter(|s| regressed(s, baseline, options.regression_percent))
+			.map(|s| s.name.as_str())
+			.collect();
+		if !regressed.is_empty() {
+			header = format!(
+				"⚠️ {} at least {}% slower than the base (`{}`): {}.\n\n{}",
+				if regressed.len() == 1 {
+					"1 scenario is".to_string()
+				} else {
+					format!("{} scenarios are", regressed.len())
+				},
+				options.regression_percent,
+				&baseline.sha[..baseline.sha.len().min(7)],
+				regressed.join(", "),
+				header
+			);
+		}
+	}
+	if options.late {
+		header = format!(
+			"ND finished after the profile timed out; these are its results.\n{}",
+			header
+		);
+	}
+	let sha = build.built_sha.as_deref().unwrap_or(head_sha);
+	let sha = &sha[..sha.len().min(7)];
+	let commenter = commenter.unwrap_or_default();
+	let nd_url = outcome.profile_url().unwrap_or_default();
+	let values = |message: &str, table: &str| {
+		render(
+			&pieces,
+			&[
+				("header", &header),
+				("message", message),
+				("scenarios", table),
+				("nd_url", nd_url),
+				("sha", sha),
+				("commenter", commenter),
+			],
+		)
+	};
+
+	/*
+	 * Cut the message and the table down to share whatever room the
+	 * rest of the template leaves them.
+	 */
+	let tables = pieces
+		.iter()
+		.filter(|p| match p {
+			Piece::Variable(name) => *name == "message" || *name == "scenarios",
+			Piece::Text(_) => false,
+		})
+		.count();
+	let room = options
+		.max_length
+		.saturating_sub(values("", "").len() + mentions.len())
+		/ tables.max(1);
+	let max_length = options.max_length;
+	let raw_json = options.raw_json;
+	let options = CommentOptions {
+		max_length: room,
+		..options.clone()
+	};
+	let message = format_outcome_body(outcome, &options);
+	let table = match outcome.profile_url() {
+		Some(profile_url) if !scenarios.is_empty() => {
+			format_profile_comment(scenarios, profile_url, &options)
+		}
+		_ => String::new(),
+	};
+	let comment = values(&message, &table) + &mentions;
+	if !raw_json {
+		return comment;
+	}
+	/* The whole block goes or none of it, so it is never cut short. */
+	let raw = format_raw_json(outcome);
+	if comment.len() + raw.len() <= max_length {
+		comment + &raw
+	} else {
+		comment
+	}
+}
+
+fn format_raw_json(outcome: &ProfileOutcome) -> String {
+	let json = serde_json::to_string_pretty(&outcome.to_json()).unwrap();
+	format!(
+		"\n\n<details><summary>Raw results</summary>\n\n```json\n{}\n```\n</details>",
+		json
+	)
+}
+
+fn format_outcome_body(outcome: &ProfileOutcome, options: &CommentOptions) -> String {
+	match outcome {
+		ProfileOutcome::Success {
+			profile_url,
+			scenarios,
+		} => format_profile_comment(scenarios, profile_url, options),
+		ProfileOutcome::Timeout {
+			profile_url,
+			scenarios,
+		} => {
+			let mut comment =
+				"Timeout while waiting for ND to complete profiling the application.".to_string();
+			if !scenarios.is_empty() {
+				comment.push_str(
+					" These results are partial; scenarios marked ⏳ were still pending.",
+				);
+				comment.push('\n');
+				comment.push('\n');
+				let remaining = CommentOptions {
+					max_length: options.max_length.saturating_sub(comment.len()),
+					..options.clone()
+				};
+				comment.push_str(&format_profile_comment(scenarios, profile_url, &remaining));
+			}
+			comment
+		}
+		ProfileOutcome::ResultsUnavailable { detail, .. } => format!(
+			"Failed to get the results of the profile from ND: {}",
+			detail
+		),
+		ProfileOutcome::Cancelled { .. } => {
+			"The profile was cancelled before ND finished.".to_string()
+		}
+		ProfileOutcome::BuildFailed {
+			detail, log_tail, ..
+		} => {
+			let mut comment = format!(
+				"The build failed, so there was nothing to profile: {}",
+				detail
+			);
+			if let Some(log_tail) = log_tail {
+				comment.push_str(&format!(
+					"\n\nThe end of the build log:\n\n```\n{}\n```",
+					log_tail
+				));
+			}
+			comment
+		}
+		ProfileOutcome::UploadRejected { quota: true, .. } => options.quota_message.clone(),
+		ProfileOutcome::UploadRejected { detail, .. } => {
+			format!("NimbleDroid rejected the upload: {}", detail)
+		}
+	}
+}
+
+/// Render the scenario table for a pull request comment.
+///
+/// If the whole table would be longer than the maximum length, only as
+/// many rows as fit are kept, followed by a pointer to the full results
+/// on NimbleDroid. Rows are never split.
+///
+/// Every time in the table is shown in the same unit, so the column
+/// header always matches its values. With a previous run to compare
+/// with, each time is followed by how much it changed since then.
+pub fn format_profile_comment(
+	scenarios: &[ScenarioResult],
+	profile_url: &str,
+	options: &CommentOptions,
+) -> String {
+	/*
+	 * A one-line summary stays in view and the table folds away under
+	 * it, unless something failed or went over its budget.
+	 */
+	let (opening, closing) = if options.collapse_table {
+		let failed = scenarios
+			.iter()
+			.filter(|s| !s.passed() && !s.is_pending())
+			.count();
+		let over_budget = scenarios
+			.iter()
+			.filter(|s| s.over_budget(&options.budgets_ms).is_some())
+			.count();
+		let details = if failed + over_budget > 0 {
+			"<details open>"
+		} else {
+			"<details>"
+		};
+		(
+			format!(
+				"{}<summary>{} scenarios: {} failed, {} over budget</summary>\n\n",
+				details,
+				scenarios.len(),
+				failed,
+				over_budget
+			),
+			"\n</details>".to_string(),
+		)
+	} else {
+		(String::new(), String::new())
+	};
+	let max_length = options.max_length.saturating_sub(closing.len());
+	let in_seconds = match options.seconds_threshold_ms {
+		Some(threshold) => scenarios.iter().any(|s| s.time_in_ms >= threshold),
+		None => false,
+	};
+
+	let previous_run = options.previous_run.as_ref();
+	let baseline = options.baseline.as_ref();
+	let mut comment = opening;
+	if in_seconds {
+		comment.push_str("Scenario | Status | Time (s)");
+	} else {
+		comment.push_str("Scenario | Status | Time (ms)");
+	}
+	if let Some(previous_run) = previous_run {
+		comment.push_str(&format!(
+			" | vs your previous run (`{}`)",
+			&previous_run.sha[..previous_run.sha.len().min(7)]
+		));
+	}
+	if let Some(baseline) = baseline {
+		comment.push_str(&format!(
+			" | vs the base (`{}`)",
+			&baseline.sha[..baseline.sha.len().min(7)]
+		));
+	}
+	comment.push('\n');
+	comment.push_str("---------|--------|----------");
+	if previous_run.is_some() {
+		comment.push_str("|----------");
+	}
+	if baseline.is_some() {
+		comment.push_str("|----------");
+	}
+	comment.push('\n');
+
+	let format_time = |time_in_ms: u64| {
+		if in_seconds {
+			format!("{:.2}", time_in_ms as f64 / 1000.0)
+		} else {
+			time_in_ms.to_string()
+		}
+	};
+	/* Blank when either run hasn't finished the scenario. */
+	let format_change = |s: &ScenarioResult, compared: &[ScenarioResult]| {
+		let previous = compared
+			.iter()
+			.find(|p| p.name == s.name && !p.is_pending());
+		match previous {
+			Some(previous) if !s.is_pending() => {
+				let (sign, change) = if s.time_in_ms >= previous.time_in_ms {
+					("+", s.time_in_ms - previous.time_in_ms)
+				} else {
+					("-", previous.time_in_ms - s.time_in_ms)
+				};
+				let mut formatted = format!("{}{}", sign, format_time(change));
+				if previous.time_in_ms > 0 {
+					formatted.push_str(&format!(
+						" ({}{:.1}%)",
+						sign,
+						change as f64 * 100.0 / previous.time_in_ms as f64
+					));
+				}
+				formatted
+			}
+			_ => String::new(),
+		}
+	};
+	let rows: Vec<String> = scenarios
+		.iter()
+		.map(|s| {
+			let marker = if s.is_pending() { "⏳ " } else { "" };
+			let mut time = format_time(s.time_in_ms);
+			if let Some(budget) = s.over_budget(&options.budgets_ms) {
+				time.push_str(&format!(" 🚨 over its {} budget", format_time(budget)));
+			}
+			if let Some(previous_run) = previous_run {
+				time.push_str(&format!(" | {}", format_change(s, &previous_run.scenarios)));
+			}
+			if let Some(baseline) = baseline {
+				time.push_str(&format!(" | {}", format_change(s, &baseline.scenarios)));
+				if regressed(s, baseline, options.regression_percent) {
+					time.push_str(" ⚠️");
+				}
+			}
+			let ignored = if s.ignored { " (ignored)" } else { "" };
+			format!(
+				"{}{} | {}{} | {}\n",
+				s.name, ignored, marker, s.status, time
+			)
+		})
+		.collect();
+
+	let total: usize = comment.len() + rows.iter().map(|r| r.len()).sum::<usize>();
+	if total <= max_length {
+		rows.iter().for_each(|r| comment.push_str(r));
+		comment.push_str(&closing);
+		return comment;
+	}
+
+	/*
+	 * Budget for the longest footer we could need so that adding it
+	 * after the last kept row can never push us over the limit.
+	 */
+	let footer_length = truncation_footer(rows.len(), profile_url).len();
+	let mut kept = 0;
+	for row in &rows {
+		if comment.len() + row.len() + footer_length > max_length {
+			break;
+		}
+		comment.push_str(row);
+		kept += 1;
+	}
+	comment.push_str(&truncation_footer(rows.len() - kept, profile_url));
+	comment.push_str(&closing);
+	comment
+}
+
+fn truncation_footer(remaining: usize, profile_url: &str) -> String {
+	format!(
+		"\n…and {} more, see [full results]({})\n",
+		remaining, profile_url
+	)
+}
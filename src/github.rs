@@ -0,0 +1,703 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::retry::{is_transient, with_retries, Failure};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use slog::{error, info, Logger};
+use std::io::{self, Read};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/*
+ * What is sent to create or edit a comment with `body`, which is plain
+ * markdown; serde_json does all of the escaping.
+ */
+fn comment_request(body: &str) -> String {
+	json!({ "body": body }).to_string()
+}
+
+/// The API of github.com, rather than of a GitHub Enterprise server.
+pub static DEFAULT_API_BASE: &str = "https://api.github.com";
+
+/// Whether `url` is on the GitHub API at `api_base`. The URLs a delivery
+/// names are requested with the bot's token, so no others are followed.
+pub fn is_api_url(url: &str, api_base: &str) -> bool {
+	match (reqwest::Url::parse(url), reqwest::Url::parse(api_base)) {
+		(Ok(url), Ok(base)) => {
+			url.scheme() == base.scheme()
+				&& url.host_str() == base.host_str()
+				&& url.port_or_known_default() == base.port_or_known_default()
+				&& url.path().starts_with(base.path().trim_end_matches('/'))
+		}
+		_ => false,
+	}
+}
+
+/*
+ * How many times to try fetching a pull request or sending a comment
+ * when GitHub can't be reached or answers with a server error.
+ */
+const REQUEST_ATTEMPTS: u32 = 3;
+
+/*
+ * How many times to wait out a secondary rate limit before giving up,
+ * and the longest GitHub may ask us to wait each time.
+ */
+const RATE_LIMIT_RETRIES: u32 = 3;
+const MAX_RETRY_AFTER_SECS: u64 = 120;
+
+/*
+ * GitHub lists no more than 3000 of a pull request's files, 100 a page.
+ */
+const MAX_FILE_PAGES: u32 = 30;
+
+/*
+ * A reader that fails once more than `remaining` bytes have come
+ * through it.
+ */
+struct Capped<R> {
+	inner: R,
+	remaining: u64,
+}
+
+impl<R: Read> Read for Capped<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let read = self.inner.read(buf)?;
+		if read as u64 > self.remaining {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				"the response is over the size limit",
+			));
+		}
+		self.remaining -= read as u64;
+		Ok(read)
+	}
+}
+
+/*
+ * The parts of a pull request the bot reads. Everything else, the
+ * description above all, is skipped over as it is parsed rather than
+ * kept.
+ */
+#[derive(Deserialize)]
+struct PullRequestFields {
+	#[serde(default)]
+	state: Value,
+	#[serde(default)]
+	head: Value,
+	#[serde(default)]
+	base: Value,
+	#[serde(default)]
+	mergeable: Value,
+	#[serde(default)]
+	merge_commit_sha: Value,
+}
+
+/// The GitHub API calls the bot makes, authenticated with its token.
+///
+/// In a dry run nothing is sent; each call is logged instead.
+///
+/// Clones share one token, so replacing it with `set_token` takes
+/// effect for every request made from then on.
+#[derive(Clone)]
+pub struct GitHubClient {
+	token: Arc<RwLock<String>>,
+	/// Where the API is, such as `https://api.github.com`.
+	api_base: String,
+	dry_run: bool,
+	client: reqwest::Client,
+	logger: Logger,
+}
+
+impl GitHubClient {
+	pub fn new(token: &str, api_base: &str, dry_run: bool, logger: Logger) -> Self {
+		Self {
+			token: Arc::new(RwLock::new(token.to_string())),
+			api_base: api_base.trim_end_matches('/').to_string(),
+			dry_run,
+			client: reqwest::Client::new(),
+			logger,
+		}
+	}
+
+	pub fn dry_run(&self) -> bool {
+		self.dry_run
+	}
+
+	pub fn api_base(&self) -> &str {
+		&self.api_base
+	}
+
+	/// A client like this one but with a token of its own, such as for
+	/// another installation of the GitHub App.
+	pub fn with_token(&self, token: &str) -> Self {
+		Self {
+			token: Arc::new(RwLock::new(token.to_string())),
+			..self.clone()
+		}
+	}
+
+	/// Use a new token, such as after it has been rotated.
+	pub fn set_token(&self, token: &str) {
+		*self.token.write().unwrap() = token.to_string();
+	}
+
+	/// The token requests are made with now.
+	pub fn token(&self) -> String {
+		self.token.read().unwrap().clone()
+	}
+
+	fn authorization(&self) -> String {
+		format!("token {}", self.token.read().unwrap())
+	}
+
+	/*
+	 * GitHub can echo request details back in an error body; never let
+	 * the token reach the logs that way.
+	 */
+	fn redact(&self, text: &str) -> String {
+		let token = self.token.read().unwrap();
+		if token.is_empty() {
+			return text.to_string();
+		}
+		text.replace(token.as_str(), "[redacted]")
+	}
+
+	/*
+	 * Send a request, waiting and sending it again when GitHub answers
+	 * with a secondary rate limit: a 403 or 429 with a Retry-After.
+	 * `request` builds the request afresh for each attempt.
+	 */
+	fn send<F>(&self, request: F) -> reqwest::Result<reqwest::Response>
+	where
+		F: Fn(&reqwest::Client) -> reqwest::RequestBuilder,
+	{
+		let mut attempt = 0;
+		loop {
+			let response = request(&self.client)
+				.header(reqwest::header::AUTHORIZATION, self.authorization())
+				.send()?;
+			let status = response.status();
+			if status != reqwest::StatusCode::FORBIDDEN
+				&& status != reqwest::StatusCode::TOO_MANY_REQUESTS
+			{
+				return Ok(response);
+			}
+			let retry_after = response
+				.headers()
+				.get(reqwest::header::RETRY_AFTER)
+				.and_then(|v| v.to_str().ok())
+				.and_then(|v| v.trim().parse::<u64>().ok());
+			match retry_after {
+				Some(secs) if secs <= MAX_RETRY_AFTER_SECS && attempt < RATE_LIMIT_RETRIES => {
+					attempt += 1;
+					info!(
+						self.logger,
+						"GitHub rate limited {}; retrying in {}s.",
+						response.url(),
+						secs
+					);
+					std::thread::sleep(Duration::from_secs(secs));
+				}
+				_ => return Ok(response),
+			}
+		}
+	}
+
+	pub fn get_json(&self, url: &str) -> Result<Value, String> {
+		let mut response = self
+			.send(|client| client.get(url))
+			.map_err(|e| format!("Oops, couldn't get {}: {}", url, e))?;
+		if !response.status().is_success() {
+			return Err(format!(
+				"Oops, couldn't get {}: GitHub responded {}",
+				url,
+				response.status()
+			));
+		}
+		response
+			.json()
+			.map_err(|e| format!("Oops, couldn't parse {}: {}", url, e))
+	}
+
+	/// A pull request, with only its state, head, base and merge fields.
+	/// Fails rather than read more than `max_bytes` of it.
+	/// Fetching it is tried again if GitHub can't be reached or has a
+	/// server error.
+	pub fn pull_request(&self, url: &str, max_bytes: u64) -> Result<Value, String> {
+		let response = with_retries(&self.logger, REQUEST_ATTEMPTS, || {
+			let response = self.send(|client| client.get(url)).map_err(|e| {
+				Failure::Transient(format!("Oops, couldn't download PR information: {}", e))
+			})?;
+			let status = response.status();
+			if status.is_success() {
+				return Ok(response);
+			}
+			let e = format!(
+				"Oops, couldn't download PR information: GitHub responded {}",
+				status
+			);
+			Err(if is_transient(status) {
+				Failure::Transient(e)
+			} else {
+				Failure::Permanent(e)
+			})
+		})?;
+		if let Some(length) = response.content_length() {
+			if length > max_bytes {
+				return Err(format!(
+					"Oops, the PR information is {} bytes, over the {} byte limit.",
+					length, max_bytes
+				));
+			}
+		}
+		let fields: PullRequestFields = serde_json::from_reader(Capped {
+			inner: response,
+			remaining: max_bytes,
+		})
+		.map_err(|e| format!("Oops, couldn't parse PR information: {}", e))?;
+		Ok(json!({
+			"state": fields.state,
+			"head": fields.head,
+			"base": fields.base,
+			"mergeable": fields.mergeable,
+			"merge_commit_sha": fields.merge_commit_sha,
+		}))
+	}
+
+	/// The paths of the files a pull request changes. GitHub lists at
+	/// most 3000 of them.
+	pub fn changed_files(&self, pull_request_url: &str) -> Result<Vec<String>, String> {
+		let mut files = vec![];
+		for page in 1..=MAX_FILE_PAGES {
+			let listed = self.get_json(&format!(
+				"{}/files?per_page=100&page={}",
+				pull_request_url, page
+			))?;
+			let listed = listed
+				.as_array()
+				.ok_or_else(|| "Oops, the changed files aren't a list.".to_string())?;
+			files.extend(
+				listed
+					.iter()
+					.filter_map(|file| file["filename"].as_str())
+					.map(|filename| filename.to_string()),
+			);
+			if listed.len() < 100 {
+				break;
+			}
+		}
+		Ok(files)
+	}
+
+	/// Whether something, such as a comment, is still there.
+	pub fn exists(&self, url: &str) -> Result<bool, String> {
+		let response = self
+			.send(|client| client.get(url))
+			.map_err(|e| format!("Oops, couldn't get {}: {}", url, e))?;
+		match response.status() {
+			status if status.is_success() => Ok(true),
+			reqwest::StatusCode::NOT_FOUND => Ok(false),
+			status => Err(format!(
+				"Oops, couldn't get {}: GitHub responded {}",
+				url, status
+			)),
+		}
+	}
+
+	/// The clone URL and current head SHA of a repository's default
+	/// branch.
+	pub fn default_branch_head(&self, repo_full_name: &str) -> Result<(String, String), String> {
+		let repo = self.get_json(&format!("{}/repos/{}", self.api_base, repo_full_name))?;
+		let (clone_url, default_branch) = match (&repo["clone_url"], &repo["default_branch"]) {
+			(Value::String(c), Value::String(b)) => (c.to_string(), b.to_string()),
+			_ => {
+				return Err(format!(
+					"Oops, couldn't find the default branch of {}.",
+					repo_full_name
+				))
+			}
+		};
+		let commit = self.get_json(&format!(
+			"{}/repos/{}/commits/{}",
+			self.api_base, repo_full_name, default_branch
+		))?;
+		match &commit["sha"] {
+			Value::String(sha) => Ok((clone_url, sha.to_string())),
+			_ => Err(format!(
+				"Oops, couldn't find the head of {} in {}.",
+				default_branch, repo_full_name
+			)),
+		}
+	}
+
+	/// Post a comment, retrying if GitHub can't be reached or has a
+	/// server error. Any other unsuccessful response, such as for a bad
+	/// token or a locked pull request, is an error.
+	pub fn post_comment(&self, comments_url: &str, body: &str) -> Result<(), String> {
+		self.create_comment(comments_url, body).map(|_| ())
+	}
+
+	/// Post a comment like `post_comment` and return its API URL so that
+	/// it can be edited later. A dry run has no URL to return.
+	pub fn create_comment(&self, comments_url: &str, body: &str) -> Result<Option<String>, String> {
+		let comment = self.send_comment(reqwest::Method::POST, comments_url, body)?;
+		Ok(comment.and_then(|c| c["url"].as_str().map(|url| url.to_string())))
+	}
+
+	/// Post a comment carrying `marker`, a hidden tag unique to what it
+	/// reports, unless one of the pull request's recent comments already
+	/// carries it, in which case that comment is edited instead. Posting
+	/// again after an attempt that seemed to fail, but didn't, then can't
+	/// leave a duplicate.
+	pub fn upsert_comment(
+		&self,
+		comments_url: &str,
+		marker: &str,
+		body: &str,
+	) -> Result<(), String> {
+		match self.find_comment(comments_url, marker)? {
+			Some(comment_url) => {
+				info!(self.logger, "Editing the comment already posted.");
+				self.edit_comment(&comment_url, body)
+			}
+			None => self.post_comment(comments_url, body),
+		}
+	}
+
+	/*
+	 * The API URL of the newest comment containing `marker`, among the
+	 * last page of comments.
+	 */
+	fn find_comment(&self, comments_url: &str, marker: &str) -> Result<Option<String>, String> {
+		let first_page = format!("{}?per_page=100", comments_url);
+		let response = self
+			.send(|client| client.get(&first_page))
+			.map_err(|e| format!("Oops, couldn't list the comments: {}", e))?;
+		/* Comments are listed oldest first, so the newest are on the last page. */
+		let last_page = response
+			.headers()
+			.get(reqwest::header::LINK)
+			.and_then(|link| link.to_str().ok())
+			.and_then(|link| {
+				link.split(',')
+					.find(|l| l.contains("rel=\"last\""))
+					.and_then(|l| Some(l[l.find('<')? + 1..l.find('>')?].to_string()))
+			});
+		let mut response = match last_page {
+			Some(last_page) => self
+				.send(|client| client.get(&last_page))
+				.map_err(|e| format!("Oops, couldn't list the comments: {}", e))?,
+			None => response,
+		};
+		if !response.status().is_success() {
+			return Err(format!(
+				"Oops, couldn't list the comments: GitHub responded {}",
+				response.status()
+			));
+		}
+		let comments: Value = response
+			.json()
+			.map_err(|e| format!("Oops, couldn't parse the comments: {}", e))?;
+		Ok(comments
+			.as_array()
+			.and_then(|comments| {
+				comments
+					.iter()
+					.rev()
+					.find(|c| c["body"].as_str().unwrap_or("").contains(marker))
+			})
+			.and_then(|c| c["url"].as_str())
+			.map(|url| url.to_string()))
+	}
+
+	/// Replace the body of a comment made by `create_comment`.
+	pub fn edit_comment(&self, comment_url: &str, body: &str) -> Result<(), String> {
+		self.send_comment(reqwest::Method::PATCH, comment_url, body)
+			.map(|_| ())
+	}
+
+	fn send_comment(
+		&self,
+		method: reqwest::Method,
+		url: &str,
+		body: &str,
+	) -> Result<Option<Value>, String> {
+		if self.dry_run {
+			info!(self.logger, "Dry run: not sending to {}: {}", url, body);
+			return Ok(None);
+		}
+
+		with_retries(&self.logger, REQUEST_ATTEMPTS, || {
+			let mut response = self
+				.send(|client| {
+					client
+						.request(method.clone(), url)
+						.body(comment_request(body))
+				})
+				.map_err(|e| {
+					Failure::Transient(format!("Oops, couldn't send a comment to {}: {}", url, e))
+				})?;
+			let status = response.status();
+			if status.is_success() {
+				info!(self.logger, "Comment response: {}", status);
+				return Ok(response.json().ok());
+			}
+
+			let detail = response.text().unwrap_or_default();
+			error!(
+				self.logger,
+				"GitHub responded {} to a comment: {}",
+				status,
+				self.redact(&detail)
+			);
+			let e = format!("Oops, GitHub responded {} to a comment on {}", status, url);
+			Err(if is_transient(status) {
+				Failure::Transient(e)
+			} else {
+				Failure::Permanent(e)
+			})
+		})
+	}
+
+	/// Post to the pull request's review thread on `line` of `path`: as a
+	/// reply to the review comment carrying `thread_marker`, or, when
+	/// there isn't one yet, as a new review comment on `commit_id`
+	/// starting the thread, which should carry the marker. GitHub only
+	/// takes a review comment on a line that is part of the diff.
+	pub fn post_to_review_thread(
+		&self,
+		pull_request_url: &str,
+		thread_marker: &str,
+		commit_id: &str,
+		(path, line): (&str, u64),
+		body: &str,
+		root_body: &str,
+	) -> Result<(), String> {
+		let comments_url = format!("{}/comments", pull_request_url);
+		let root = self.find_comment(&comments_url, thread_marker)?;
+		let comment = match root
+			.as_deref()
+			.and_then(|url| url.rsplit('/').next())
+			.and_then(|id| id.parse::<u64>().ok())
+		{
+			Some(id) => json!({ "body": body, "in_reply_to": id }),
+			None => json!({
+				"body": root_body,
+				"commit_id": commit_id,
+				"path": path,
+				"line": line,
+				"side": "RIGHT",
+			}),
+		}
+		.to_string();
+		if self.dry_run {
+			info!(
+				self.logger,
+				"Dry run: not posting to {}: {}", comments_url, comment
+			);
+			return Ok(());
+		}
+
+		let mut response = self
+			.send(|client| client.post(&comments_url).body(comment.clone()))
+			.map_err(|e| {
+				format!(
+					"Oops, couldn't send a review comment to {}: {}",
+					comments_url, e
+				)
+			})?;
+		let status = response.status();
+		if status.is_success() {
+			info!(self.logger, "Review comment response: {}", status);
+			return Ok(());
+		}
+		let detail = response.text().unwrap_or_default();
+		error!(
+			self.logger,
+			"GitHub responded {} to a review comment: {}",
+			status,
+			self.redact(&detail)
+		);
+		Err(format!(
+			"Oops, GitHub responded {} to a review comment on {}",
+			status, pull_request_url
+		))
+	}
+
+	/// Review a pull request, with `event` one of `APPROVE`,
+	/// `REQUEST_CHANGES` or `COMMENT`. Without a `commit_id` the review is of whatever the head is now.
+	pub fn create_review(
+		&self,
+		pull_request_url: &str,
+		commit_id: Option<&str>,
+		event: &str,
+		body: &str,
+	) -> Result<(), String> {
+		let reviews_url = format!("{}/reviews", pull_request_url);
+		let mut review = json!({ "body": body, "event": event });
+		if let Some(commit_id) = commit_id {
+			review["commit_id"] = json!(commit_id);
+		}
+		let review = review.to_string();
+		if self.dry_run {
+			info!(
+				self.logger,
+				"Dry run: not posting to {}: {}", reviews_url, review
+			);
+			return Ok(());
+		}
+
+		let mut response = self
+			.send(|client| client.post(&reviews_url).body(review.clone()))
+			.map_err(|e| format!("Oops, couldn't send a review to {}: {}", reviews_url, e))?;
+		let status = response.status();
+		if status.is_success() {
+			info!(self.logger, "Review response: {}", status);
+			return Ok(());
+		}
+		let detail = response.text().unwrap_or_default();
+		error!(
+			self.logger,
+			"GitHub responded {} to a review: {}",
+			status,
+			self.redact(&detail)
+		);
+		Err(format!(
+			"Oops, GitHub responded {} to a review of {}",
+			status, pull_request_url
+		))
+	}
+
+	/// Post a secret gist of one file, returning its page. A dry run has
+	/// no page to return.
+	pub fn create_gist(
+		&self,
+		description: &str,
+		filename: &str,
+		content: &str,
+	) -> Result<Option<String>, String> {
+		let mut files = serde_json::Map::new();
+		files.insert(filename.to_string(), json!({ "content": content }));
+		let gist = json!({
+			"description": description,
+			"public": false,
+			"files": files,
+		});
+		if self.dry_run {
+			info!(self.logger, "Dry run: not posting a gist: {}", description);
+			return Ok(None);
+		}
+
+		let gists_url = format!("{}/gists", self.api_base);
+		let mut response = self
+			.send(|client| client.post(&gists_url).json(&gist))
+			.map_err(|e| format!("Oops, couldn't post a gist: {}", e))?;
+		if !response.status().is_success() {
+			return Err(format!(
+				"Oops, couldn't post a gist: GitHub responded {}",
+				response.status()
+			));
+		}
+		let posted: Value = response
+			.json()
+			.map_err(|e| format!("Oops, couldn't parse the posted gist: {}", e))?;
+		Ok(posted["html_url"].as_str().map(|url| url.to_string()))
+	}
+
+	pub fn create_status(
+		&self,
+		statuses_url: &str,
+		state: &str,
+		description: &str,
+		target_url: Option<&str>,
+	) -> Result<(), reqwest::Error> {
+		let mut status = json!({
+			"state": state,
+			"context": "ndhook/profile",
+			"description": description,
+		});
+		if let Some(target_url) = target_url {
+			status["target_url"] = json!(target_url);
+		}
+		if self.dry_run {
+			info!(
+				self.logger,
+				"Dry run: not posting to {}: {}", statuses_url, status
+			);
+			return Ok(());
+		}
+
+		let response = self.send(|client| client.post(statuses_url).json(&status))?;
+		info!(self.logger, "Status response: {:?}", response);
+		Ok(())
+	}
+
+	pub fn delete(&self, url: &str) -> Result<(), reqwest::Error> {
+		if self.dry_run {
+			info!(self.logger, "Dry run: not deleting {}", url);
+			return Ok(());
+		}
+
+		let response = self.send(|client| client.delete(url))?;
+		info!(self.logger, "Delete response: {:?}", response);
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::Config;
+	use crate::format::{format_profile_comment, CommentOptions};
+	use crate::profile::ScenarioResult;
+
+	#[test]
+	fn keeps_to_the_api_base() {
+		let base = DEFAULT_API_BASE;
+		assert!(is_api_url("https://api.github.com/repos/a/b/pulls/1", base));
+		assert!(!is_api_url("https://evil.example/repos/a/b/pulls/1", base));
+		assert!(!is_api_url(
+			"https://api.github.com.evil.example/repos",
+			base
+		));
+		assert!(!is_api_url("http://api.github.com/repos/a/b", base));
+		assert!(!is_api_url("https://api.github.com:8443/repos/a/b", base));
+		assert!(!is_api_url("not a url", base));
+
+		let enterprise = "https://github.example.com/api/v3";
+		assert!(is_api_url(
+			"https://github.example.com/api/v3/repos/a/b",
+			enterprise
+		));
+		assert!(!is_api_url(
+			"https://github.example.com/other/repos/a/b",
+			enterprise
+		));
+	}
+
+	#[test]
+	fn sends_a_quoted_scenario_name_as_valid_json() {
+		let scenarios = vec![ScenarioResult {
+			name: "tap \"Open\" then scroll".to_string(),
+			status: "profiled".to_string(),
+			time_in_ms: 1200,
+			ignored: false,
+		}];
+		let comment = format_profile_comment(
+			&scenarios,
+			"https://nimbledroid.com/profiles/1",
+			&CommentOptions::from_config(&Config::default()),
+		);
+		let sent: Value = serde_json::from_str(&comment_request(&comment)).unwrap();
+		let body = sent["body"].as_str().unwrap();
+		assert_eq!(body, comment);
+		assert!(body.contains("tap \"Open\" then scroll"));
+		assert!(body.contains('\n'));
+		assert!(!body.contains("\\n"));
+	}
+}
@@ -0,0 +1,204 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+/*
+ * Authenticating as a GitHub App: a JWT signed with the app's private key
+ * buys an installation token, which is then used like any other token
+ * until it is replaced by the next one.
+ */
+
+use crate::config::Config;
+use crate::github::GitHubClient;
+use openssl::base64;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long to use an installation token before minting the next one.
+/// GitHub's last an hour.
+pub const TOKEN_REFRESH: Duration = Duration::from_secs(50 * 60);
+
+/// A GitHub App installation the bot can mint tokens for.
+pub struct GitHubApp {
+	app_id: u64,
+	installation_id: u64,
+	key: PKey<Private>,
+	api_base: String,
+}
+
+#[derive(Deserialize)]
+struct InstallationToken {
+	token: String,
+}
+
+/*
+ * Base64 as JWTs have it: URL-safe and unpadded.
+ */
+fn base64url(bytes: &[u8]) -> String {
+	base64::encode_block(bytes)
+		.trim_end_matches('=')
+		.replace('+', "-")
+		.replace('/', "_")
+}
+
+impl GitHubApp {
+	/// The app configured by `github_app_id`, `github_app_key_file` and
+	/// `github_app_installation_id`.
+	pub fn from_config(config: &Config) -> Result<Self, String> {
+		let (app_id, key_file, installation_id) = match (
+			config.github_app_id,
+			&config.github_app_key_file,
+			config.github_app_installation_id,
+		) {
+			(Some(app_id), Some(key_file), Some(installation_id)) => {
+				(app_id, key_file, installation_id)
+			}
+			_ => return Err("Oops, the GitHub App isn't fully configured.".to_string()),
+		};
+		let pem = std::fs::read(key_file)
+			.map_err(|e| format!("Oops, couldn't read {}: {}", key_file, e))?;
+		let key = PKey::private_key_from_pem(&pem)
+			.map_err(|e| format!("Oops, couldn't parse the key in {}: {}", key_file, e))?;
+		Ok(Self {
+			app_id,
+			installation_id,
+			key,
+			api_base: config.github_api_base.trim_end_matches('/').to_string(),
+		})
+	}
+
+	/*
+	 * A JWT saying the bot is the app, good for the few minutes GitHub
+	 * allows. It claims to be a minute old in case this host's clock is
+	 * ahead of GitHub's.
+	 */
+	fn jwt(&self) -> Result<String, String> {
+		let now = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map_err(|e| format!("Oops, the clock is wrong: {}", e))?
+			.as_secs();
+		let header = base64url(
+			json!({ "alg": "RS256", "typ": "JWT" })
+				.to_string()
+				.as_bytes(),
+		);
+		let claims = base64url(
+			json!({ "iat": now - 60, "exp": now + 9 * 60, "iss": self.app_id })
+				.to_string()
+				.as_bytes(),
+		);
+		let signed = format!("{}.{}", header, claims);
+		let signature = Signer::new(MessageDigest::sha256(), &self.key)
+			.and_then(|mut signer| {
+				signer.update(signed.as_bytes())?;
+				signer.sign_to_vec()
+			})
+			.map_err(|e| format!("Oops, couldn't sign the GitHub App's JWT: {}", e))?;
+		Ok(format!("{}.{}", signed, base64url(&signature)))
+	}
+
+	/// A new token for the configured installation.
+	pub fn installation_token(&self) -> Result<String, String> {
+		self.token_for(self.installation_id)
+	}
+
+	/// A new token for any installation of the app.
+	pub fn token_for(&self, installation_id: u64) -> Result<String, String> {
+		let url = format!(
+			"{}/app/installations/{}/access_tokens",
+			self.api_base, installation_id
+		);
+		let mut response = reqwest::Client::new()
+			.post(&url)
+			.header(
+				reqwest::header::AUTHORIZATION,
+				format!("Bearer {}", self.jwt()?),
+			)
+			.header(
+				reqwest::header::ACCEPT,
+				"application/vnd.github.machine-man-preview+json",
+			)
+			.send()
+			.map_err(|e| format!("Oops, couldn't get an installation token: {}", e))?;
+		if !response.status().is_success() {
+			return Err(format!(
+				"Oops, couldn't get an installation token: GitHub responded {}",
+				response.status()
+			));
+		}
+		let token: InstallationToken = response
+			.json()
+			.map_err(|e| format!("Oops, couldn't parse the installation token: {}", e))?;
+		Ok(token.token)
+	}
+}
+
+/// The app's installations that deliveries have come from, each with a
+/// client using a token for it. The configured installation uses the
+/// bot's own client.
+#[derive(Clone)]
+pub struct Installations {
+	app: Arc<GitHubApp>,
+	clients: Arc<Mutex<HashMap<u64, GitHubClient>>>,
+}
+
+impl Installations {
+	pub fn new(app: GitHubApp) -> Self {
+		Self {
+			app: Arc::new(app),
+			clients: Arc::new(Mutex::new(HashMap::new())),
+		}
+	}
+
+	/// The client to act for `installation_id` with, minting its first
+	/// token if it has none yet. `default` is the client for the
+	/// configured installation.
+	pub fn client(
+		&self,
+		installation_id: u64,
+		default: &GitHubClient,
+	) -> Result<GitHubClient, String> {
+		if installation_id == self.app.installation_id {
+			return Ok(default.clone());
+		}
+		let mut clients = self.clients.lock().unwrap();
+		if let Some(client) = clients.get(&installation_id) {
+			return Ok(client.clone());
+		}
+		let client = default.with_token(&self.app.token_for(installation_id)?);
+		clients.insert(installation_id, client.clone());
+		Ok(client)
+	}
+
+	/// Mint new tokens for the configured installation, into `default`,
+	/// and for every other one seen so far. Those in flight pick them up,
+	/// as clones share their token.
+	pub fn refresh(&self, default: &GitHubClient) -> Result<(), String> {
+		default.set_token(&self.app.installation_token()?);
+		let clients: Vec<(u64, GitHubClient)> = self
+			.clients
+			.lock()
+			.unwrap()
+			.iter()
+			.map(|(id, client)| (*id, client.clone()))
+			.collect();
+		for (installation_id, client) in clients {
+			client.set_token(&self.app.token_for(installation_id)?);
+		}
+		Ok(())
+	}
+}
+
+/// The installation a delivery came from, when the app is installed in
+/// more than one place.
+pub fn installation_id(notification: &serde_json::Value) -> Option<u64> {
+	notification["installation"]["id"].as_u64()
+}
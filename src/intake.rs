@@ -0,0 +1,121 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::queue::panic_message;
+use slog::{error, Logger};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+/// Work handed off by the server to a few threads of its own, so that a
+/// handler never waits on anything slower than its own request. Unlike
+/// the `WorkQueue`, there is no ordering by repository or pausing:
+/// whatever is handed in is handled as soon as a thread is free.
+pub struct Intake<T> {
+	sender: Sender<T>,
+	receiver: Arc<Mutex<Receiver<T>>>,
+	waiting: Arc<AtomicUsize>,
+}
+
+/* Derived, this would need T to be Clone too. */
+impl<T> Clone for Intake<T> {
+	fn clone(&self) -> Self {
+		Self {
+			sender: self.sender.clone(),
+			receiver: self.receiver.clone(),
+			waiting: self.waiting.clone(),
+		}
+	}
+}
+
+impl<T: Send + 'static> Intake<T> {
+	pub fn new() -> Self {
+		let (sender, receiver) = channel();
+		Self {
+			sender,
+			receiver: Arc::new(Mutex::new(receiver)),
+			waiting: Arc::new(AtomicUsize::new(0)),
+		}
+	}
+
+	/// Start `workers` threads that each hand one item at a time to
+	/// `handle`. An item that panics is logged and its thread carries on
+	/// with the next one.
+	pub fn spawn_workers<F>(&self, workers: usize, logger: &Logger, handle: F)
+	where
+		F: Fn(T) + Clone + Send + 'static,
+	{
+		for _ in 0..workers {
+			let receiver = self.receiver.clone();
+			let waiting = self.waiting.clone();
+			let logger = logger.clone();
+			let handle = handle.clone();
+			std::thread::spawn(move || loop {
+				/* Hold the lock only while waiting, not while handling. */
+				let item = match receiver.lock().unwrap().recv() {
+					Ok(item) => item,
+					Err(_) => return,
+				};
+				waiting.fetch_sub(1, Ordering::SeqCst);
+				if let Err(payload) = catch_unwind(AssertUnwindSafe(|| handle(item))) {
+					error!(
+						logger,
+						"Taking in a notification panicked: {}",
+						panic_message(&*payload)
+					);
+				}
+			});
+		}
+	}
+
+	/// Hand `item` to the threads, unless `max_waiting` items are already
+	/// waiting for one, in which case it is handed back.
+	pub fn submit(&self, item: T, max_waiting: usize) -> Result<(), T> {
+		if self.waiting.fetch_add(1, Ordering::SeqCst) >= max_waiting {
+			self.waiting.fetch_sub(1, Ordering::SeqCst);
+			return Err(item);
+		}
+		/* The receiver lives as long as any clone, so this can't fail. */
+		self.sender.send(item).map_err(|e| {
+			self.waiting.fetch_sub(1, Ordering::SeqCst);
+			e.0
+		})
+	}
+
+	/// How many items are waiting for a thread.
+	pub fn waiting(&self) -> usize {
+		self.waiting.load(Ordering::SeqCst)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::Duration;
+
+	#[test]
+	fn hands_every_item_to_a_thread() {
+		let intake = Intake::new();
+		for i in 0..5 {
+			assert!(intake.submit(i, 5).is_ok());
+		}
+		assert_eq!(intake.submit(5, 5), Err(5));
+		assert_eq!(intake.waiting(), 5);
+
+		let (handled, received) = channel();
+		let logger = Logger::root(slog::Discard, slog::o!());
+		intake.spawn_workers(2, &logger, move |i| {
+			handled.send(i).unwrap();
+		});
+		let mut seen: Vec<i32> = (0..5)
+			.map(|_| received.recv_timeout(Duration::from_secs(10)).unwrap())
+			.collect();
+		seen.sort_unstable();
+		assert_eq!(seen, vec![0, 1, 2, 3, 4]);
+		assert_eq!(intake.waiting(), 0);
+	}
+}
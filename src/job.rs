@@ -0,0 +1,430 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::baseline::Baseline;
+use crate::batch::Batch;
+use crate::config::Config;
+use crate::profile::ScenarioResult;
+use crate::results::RecentResult;
+use serde_json::{json, Value};
+use slog::Logger;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A full commit SHA, SHA-1 or SHA-256, in lowercase; `None` if `sha`
+/// isn't one.
+pub fn normalize_sha(sha: &str) -> Option<String> {
+	if (sha.len() == 40 || sha.len() == 64) && sha.chars().all(|c| c.is_ascii_hexdigit()) {
+		Some(sha.to_lowercase())
+	} else {
+		None
+	}
+}
+
+/// Lets a running job be stopped early. Clones share the same flag.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+	cancelled: Arc<AtomicBool>,
+	expired: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+	pub fn cancel(&self) {
+		self.cancelled.store(true, Ordering::SeqCst);
+	}
+
+	pub fn is_cancelled(&self) -> bool {
+		self.cancelled.load(Ordering::SeqCst)
+	}
+
+	/// Cancel the job because it ran out of time, rather than because
+	/// someone asked.
+	pub fn expire(&self) {
+		self.expired.store(true, Ordering::SeqCst);
+		self.cancel();
+	}
+
+	pub fn is_expired(&self) -> bool {
+		self.expired.load(Ordering::SeqCst)
+	}
+}
+
+/// Everything about one profile job that the pipeline and the result
+/// sinks need, regardless of which kind of notification triggered it.
+pub struct JobContext {
+	/// The correlation ID for the job's logs and artifacts.
+	pub id: String,
+	/// Where to post comments about the pull request, or about the commit
+	/// when a push triggered the job.
+	pub comments_url: String,
+	/// The pull request's page (or the push's comparison), for humans.
+	pub html_url: String,
+	/// The pull request's API URL, unless a push triggered the job.
+	pub pull_request_url: Option<String>,
+	/// Where to post commit statuses for the head.
+	pub statuses_url: String,
+	/// The repository (`owner/name`) the pull request belongs to.
+	pub repo_full_name: String,
+	pub clone_url: String,
+	/// The commit to build: the pull request's head, or its merge commit
+	/// when `profiled_ref` says so.
+	pub head_sha: String,
+	/// Which version of the pull request is built, when
+	/// `profile_merge_ref` is set.
+	pub profiled_ref: Option<ProfiledRef>,
+	/// Which of the configured build variants to profile.
+	pub variant: String,
+	/// Which scenarios to report on. Empty means all of them.
+	pub scenarios: Vec<String>,
+	/// Re-profile only the scenarios that failed in the last result for
+	/// this commit, and report them merged into it.
+	pub retry_failed: bool,
+	/// Keep a successful result as the baseline for the commit, as for
+	/// pushes to a branch.
+	pub record_baseline: bool,
+	/// Who asked for the profile, if anyone did.
+	pub commenter: Option<String>,
+	/// The users and teams to @-mention in the results, without the @.
+	pub mentions: Vec<String>,
+	/// The API URL of the comment that asked for the profile, if one did.
+	pub trigger_comment_url: Option<String>,
+	/// When set, DELETE this once the results are delivered (the trigger
+	/// label on the pull request).
+	pub label_url: Option<String>,
+	/// The configuration for the job's repository, as it was when the
+	/// job was accepted.
+	pub config: Config,
+	/// What the build reported about what it built. Empty until the
+	/// build has run.
+	pub build: BuildInfo,
+	/// The pull request's previous profile, of an earlier commit, to
+	/// compare with when `compare_previous_run` is set. Found when the
+	/// job runs.
+	pub previous_run: Option<RecentResult>,
+	/// The commit at the tip of the pull request's base branch, when a
+	/// pull request triggered the job.
+	pub base_sha: Option<String>,
+	/// The baseline profile of `base_sha`, to compare with when
+	/// `compare_base` is set and there is one. Found when the job runs.
+	pub baseline: Option<Baseline>,
+	/// The results being delivered came in after the job had already
+	/// reported a timeout.
+	pub late_results: bool,
+	/// Wait for the results of this NimbleDroid profile rather than
+	/// building and uploading, for `profile resume`.
+	pub resume_profile_url: Option<String>,
+	/// The `profile batch` the job is part of, and the number of the
+	/// pull request it profiles. Its results go to the batch's
+	/// comparison instead of the sinks.
+	pub batch: Option<(Batch, u64)>,
+	/// The GitHub App installation the job's notification came from, to
+	/// act for on GitHub.
+	pub installation_id: Option<u64>,
+	/// Set when someone cancels the job.
+	pub cancel: CancelToken,
+	pub logger: Logger,
+}
+
+#[cfg(test)]
+impl JobContext {
+	/// A job to profile `head_sha` of the pull request whose comments
+	/// are at `comments_url`, with the default configuration.
+	pub fn for_test(id: &str, comments_url: &str, head_sha: &str) -> Self {
+		let config = Config::default();
+		Self {
+			id: id.to_string(),
+			comments_url: comments_url.to_string(),
+			html_url: String::new(),
+			pull_request_url: None,
+			statuses_url: String::new(),
+			repo_full_name: "mozilla-mobile/fenix".to_string(),
+			clone_url: "https://github.com/mozilla-mobile/fenix.git".to_string(),
+			head_sha: head_sha.to_string(),
+			profiled_ref: None,
+			variant: config.default_variant.clone(),
+			scenarios: vec![],
+			retry_failed: false,
+			record_baseline: false,
+			commenter: None,
+			mentions: vec![],
+			trigger_comment_url: None,
+			label_url: None,
+			config,
+			build: BuildInfo::default(),
+			previous_run: None,
+			base_sha: None,
+			baseline: None,
+			late_results: false,
+			resume_profile_url: None,
+			batch: None,
+			installation_id: None,
+			cancel: CancelToken::default(),
+			logger: Logger::root(slog::Discard, slog::o!()),
+		}
+	}
+}
+
+/// What the build step reported about its output.
+#[derive(Clone, Debug, Default)]
+pub struct BuildInfo {
+	/// The commit the build actually checked out, which may not be the
+	/// one it was asked for if the pull request moved in the meantime.
+	pub built_sha: Option<String>,
+	/// The Docker image the build ran in, as configured.
+	pub image: Option<String>,
+	/// The ID that image resolved to when the build ran.
+	pub image_id: Option<String>,
+	/// The toolchain version the build script recorded, if any.
+	pub toolchain_version: Option<String>,
+	/// The APK, when the artifacts are kept after the run, so that it
+	/// can be profiled again without rebuilding.
+	pub kept_apk: Option<String>,
+	/// The size of the APK that was profiled.
+	pub apk_bytes: Option<u64>,
+	/// Where the build's full log was posted, when `build_log_gist` is
+	/// set.
+	pub log_url: Option<String>,
+	/// Where the artifact store put the run's artifacts, by what they
+	/// are.
+	pub artifact_urls: Vec<(String, String)>,
+	/// Which version of the pull request was built, when
+	/// `profile_merge_ref` is set.
+	pub profiled_ref: Option<ProfiledRef>,
+}
+
+/// The version of a pull request a profile was built from.
+#[derive(Clone, Debug)]
+pub enum ProfiledRef {
+	/// Its merge into the base branch, from GitHub's merge ref.
+	Merge { base_ref: String },
+	/// Its head, because GitHub had no clean merge of it to build.
+	Head,
+}
+
+/// How a profile run ended, once it got as far as building.
+pub enum ProfileOutcome {
+	/// NimbleDroid finished and returned results.
+	Success {
+		profile_url: String,
+		scenarios: Vec<ScenarioResult>,
+	},
+	/// NimbleDroid did not finish in time. Whatever scenarios it had
+	/// reported by then are kept, pending ones included.
+	Timeout {
+		profile_url: String,
+		scenarios: Vec<ScenarioResult>,
+	},
+	/// NimbleDroid finished but its results could not be fetched,
+	/// because it reports the profile failed or kept failing to answer.
+	ResultsUnavailable { profile_url: String, detail: String },
+	/// The job was cancelled while NimbleDroid was profiling.
+	Cancelled { profile_url: String },
+	/// The build failed, or produced no APK to profile. `infrastructure`
+	/// is set when what failed was the machinery around the build
+	/// (Docker, SSH, a killed container) rather than the code being
+	/// built. `log_tail` is the end of the build's log, when it exited
+	/// non-zero and the log was kept.
+	BuildFailed {
+		detail: String,
+		infrastructure: bool,
+		log_tail: Option<String>,
+	},
+	/// NimbleDroid refused the upload, or answered it with something
+	/// that isn't a profile. `quota` is set when it refused because the
+	/// account has no runs left.
+	UploadRejected { detail: String, quota: bool },
+}
+
+impl ProfileOutcome {
+	/// Where NimbleDroid shows the profile, if there is one.
+	pub fn profile_url(&self) -> Option<&str> {
+		match self {
+			ProfileOutcome::Success { profile_url, .. }
+			| ProfileOutcome::Timeout { profile_url, .. }
+			| ProfileOutcome::ResultsUnavailable { profile_url, .. }
+			| ProfileOutcome::Cancelled { profile_url } => Some(profile_url),
+			ProfileOutcome::BuildFailed { .. } | ProfileOutcome::UploadRejected { .. } => None,
+		}
+	}
+
+	/// The scenarios NimbleDroid reported, if it got far enough to.
+	pub fn scenarios(&self) -> &[ScenarioResult] {
+		match self {
+			ProfileOutcome::Success { scenarios, .. }
+			| ProfileOutcome::Timeout { scenarios, .. } => scenarios,
+			_ => &[],
+		}
+	}
+
+	/// Whether the run finished with every scenario passing and within
+	/// its budget.
+	pub fn is_passing(&self, budgets_ms: &BTreeMap<String, u64>) -> bool {
+		match self {
+			ProfileOutcome::Success { scenarios, .. } => scenarios
+				.iter()
+				.all(|s| s.passed() && s.over_budget(budgets_ms).is_none()),
+			_ => false,
+		}
+	}
+
+	/// Whether the run failed for reasons that running it again might
+	/// not meet: NimbleDroid or the build machinery, not the code. A
+	/// timeout isn't, as a second wait would most likely be as long.
+	pub fn is_retryable(&self) -> bool {
+		match self {
+			ProfileOutcome::ResultsUnavailable { .. } => true,
+			ProfileOutcome::UploadRejected { quota, .. } => !*quota,
+			ProfileOutcome::BuildFailed { infrastructure, .. } => *infrastructure,
+			ProfileOutcome::Success { .. }
+			| ProfileOutcome::Timeout { .. }
+			| ProfileOutcome::Cancelled { .. } => false,
+		}
+	}
+
+	/// A word or two saying how the run ended.
+	pub fn summary(&self, budgets_ms: &BTreeMap<String, u64>) -> &'static str {
+		match self {
+			ProfileOutcome::Success { .. } if self.is_passing(budgets_ms) => "passed",
+			ProfileOutcome::Success { .. } => "failed",
+			ProfileOutcome::Timeout { .. } => "timed out",
+			ProfileOutcome::ResultsUnavailable { .. } => "no results",
+			ProfileOutcome::Cancelled { .. } => "cancelled",
+			ProfileOutcome::BuildFailed { .. } => "build failed",
+			ProfileOutcome::UploadRejected { .. } => "upload rejected",
+		}
+	}
+
+	/// The outcome as JSON, for API clients rather than pull requests.
+	pub fn to_json(&self) -> Value {
+		let (outcome, scenarios) = match self {
+			ProfileOutcome::Success { scenarios, .. } => ("success", scenarios.as_slice()),
+			ProfileOutcome::Timeout { scenarios, .. } => ("timeout", scenarios.as_slice()),
+			ProfileOutcome::ResultsUnavailable { .. } => ("results_unavailable", &[][..]),
+			ProfileOutcome::Cancelled { .. } => ("cancelled", &[][..]),
+			ProfileOutcome::BuildFailed { .. } => ("build_failed", &[][..]),
+			ProfileOutcome::UploadRejected { .. } => ("upload_rejected", &[][..]),
+		};
+		let scenarios: Vec<Value> = scenarios
+			.iter()
+			.map(|s| {
+				json!({
+					"name": s.name,
+					"status": s.status,
+					"time_in_ms": s.time_in_ms,
+					"pending": s.is_pending(),
+				})
+			})
+			.collect();
+		let mut json = json!({
+			"outcome": outcome,
+			"profile_url": self.profile_url(),
+			"scenarios": scenarios,
+		});
+		match self {
+			ProfileOutcome::ResultsUnavailable { detail, .. }
+			| ProfileOutcome::BuildFailed { detail, .. }
+			| ProfileOutcome::UploadRejected { detail, .. } => json["detail"] = json!(detail),
+			_ => (),
+		}
+		json
+	}
+
+	/// Put the results of a retry into the result it retried: the
+	/// scenarios that passed before keep their earlier results, and the
+	/// rest take the new ones.
+	pub fn merge_retried(&mut self, prior: &[ScenarioResult]) {
+		match self {
+			ProfileOutcome::Success { scenarios, .. }
+			| ProfileOutcome::Timeout { scenarios, .. } => {
+				let retried = std::mem::take(scenarios);
+				*scenarios = prior
+					.iter()
+					.map(|p| match retried.iter().find(|r| r.name == p.name) {
+						Some(r) if !p.passed() => r.clone(),
+						_ => p.clone(),
+					})
+					.collect();
+			}
+			ProfileOutcome::ResultsUnavailable { .. }
+			| ProfileOutcome::Cancelled { .. }
+			| ProfileOutcome::BuildFailed { .. }
+			| ProfileOutcome::UploadRejected { .. } => (),
+		}
+	}
+
+	/// Drop every scenario named in `names`.
+	pub fn hide_scenarios(&mut self, names: &[String]) {
+		if let ProfileOutcome::Success { scenarios, .. }
+		| ProfileOutcome::Timeout { scenarios, .. } = self
+		{
+			scenarios.retain(|s| !names.iter().any(|n| n.eq_ignore_ascii_case(&s.name)))
+		}
+	}
+
+	/// Mark every scenario named in `names` as ignored, so that it can't
+	/// fail the run.
+	pub fn ignore_scenarios(&mut self, names: &[String]) {
+		if let ProfileOutcome::Success { scenarios, .. }
+		| ProfileOutcome::Timeout { scenarios, .. } = self
+		{
+			for s in scenarios.iter_mut() {
+				if names.iter().any(|n| n.eq_ignore_ascii_case(&s.name)) {
+					s.ignored = true;
+				}
+			}
+		}
+	}
+
+	/// Drop every scenario not named in `names`. Empty means keep all.
+	pub fn retain_scenarios(&mut self, names: &[String]) {
+		if names.is_empty() {
+			return;
+		}
+		match self {
+			ProfileOutcome::Success { scenarios, .. }
+			| ProfileOutcome::Timeout { scenarios, .. } => {
+				scenarios.retain(|s| names.iter().any(|n| n.eq_ignore_ascii_case(&s.name)))
+			}
+			ProfileOutcome::ResultsUnavailable { .. }
+			| ProfileOutcome::Cancelled { .. }
+			| ProfileOutcome::BuildFailed { .. }
+			| ProfileOutcome::UploadRejected { .. } => (),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn normalizes_full_shas() {
+		let sha1 = "0123456789abcdef0123456789abcdef01234567";
+		let sha256 = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+		assert_eq!(normalize_sha(sha1), Some(sha1.to_string()));
+		assert_eq!(normalize_sha(sha256), Some(sha256.to_string()));
+		assert_eq!(normalize_sha(&sha1.to_uppercase()), Some(sha1.to_string()));
+	}
+
+	#[test]
+	fn refuses_what_isnt_a_full_sha() {
+		assert_eq!(normalize_sha(""), None);
+		assert_eq!(normalize_sha("0123456"), None);
+		assert_eq!(
+			normalize_sha("0123456789abcdef0123456789abcdef012345678"),
+			None
+		);
+		assert_eq!(
+			normalize_sha("g123456789abcdef0123456789abcdef01234567"),
+			None
+		);
+		assert_eq!(
+			normalize_sha("0123456789abcdef0123456789abcdef0123456 "),
+			None
+		);
+	}
+}
@@ -0,0 +1,182 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/*
+ * The window an allowance is spread over. A commenter's bucket left
+ * alone this long is full again, and so is forgotten.
+ */
+const WINDOW: Duration = Duration::from_secs(3600);
+
+/// The allowance a profile request went over, with how long until it
+/// has a profile again.
+#[derive(Debug, PartialEq)]
+pub enum Over {
+	/// The commenter's own, `rate_per_user`.
+	User(Duration),
+	/// Everyone's together, `rate_global`.
+	Global(Duration),
+}
+
+/// How many profiles each commenter, and everyone together, has left,
+/// shared by every worker. Each allowance refills steadily over an hour.
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+	buckets: Arc<Mutex<Buckets>>,
+}
+
+#[derive(Default)]
+struct Buckets {
+	users: HashMap<String, Bucket>,
+	global: Option<Bucket>,
+}
+
+struct Bucket {
+	tokens: f64,
+	updated_at: Instant,
+}
+
+impl Bucket {
+	fn full(capacity: f64, now: Instant) -> Self {
+		Self {
+			tokens: capacity,
+			updated_at: now,
+		}
+	}
+
+	fn refill(&mut self, capacity: f64, now: Instant) {
+		let refilled =
+			now.duration_since(self.updated_at).as_secs_f64() / WINDOW.as_secs_f64() * capacity;
+		self.tokens = (self.tokens + refilled).min(capacity);
+		self.updated_at = now;
+	}
+
+	/*
+	 * How long until there is a whole profile in the bucket, when there
+	 * isn't one now.
+	 */
+	fn wait(&self, capacity: f64) -> Option<Duration> {
+		if self.tokens >= 1.0 {
+			return None;
+		}
+		Some(WINDOW.mul_f64((1.0 - self.tokens) / capacity))
+	}
+}
+
+impl RateLimiter {
+	/// Take one profile from `login`'s allowance of `per_user` an hour
+	/// and one from everyone's `global` an hour; `None` is unlimited.
+	/// Nothing is taken from either unless both have one left.
+	pub fn take(
+		&self,
+		login: &str,
+		per_user: Option<u32>,
+		global: Option<u32>,
+	) -> Result<(), Over> {
+		self.take_at(login, per_user, global, Instant::now())
+	}
+
+	fn take_at(
+		&self,
+		login: &str,
+		per_user: Option<u32>,
+		global: Option<u32>,
+		now: Instant,
+	) -> Result<(), Over> {
+		let mut buckets = self.buckets.lock().unwrap();
+		let Buckets {
+			users,
+			global: everyone,
+		} = &mut *buckets;
+		users.retain(|_, bucket| now.duration_since(bucket.updated_at) < WINDOW);
+		let mut user = per_user.map(|per_hour| {
+			let capacity = f64::from(per_hour);
+			let bucket = users
+				.entry(login.to_lowercase())
+				.or_insert_with(|| Bucket::full(capacity, now));
+			bucket.refill(capacity, now);
+			(bucket, capacity)
+		});
+		let mut everyone = global.map(|per_hour| {
+			let capacity = f64::from(per_hour);
+			let bucket = everyone.get_or_insert_with(|| Bucket::full(capacity, now));
+			bucket.refill(capacity, now);
+			(bucket, capacity)
+		});
+		if let Some(wait) = user
+			.as_ref()
+			.and_then(|(bucket, capacity)| bucket.wait(*capacity))
+		{
+			return Err(Over::User(wait));
+		}
+		if let Some(wait) = everyone
+			.as_ref()
+			.and_then(|(bucket, capacity)| bucket.wait(*capacity))
+		{
+			return Err(Over::Global(wait));
+		}
+		for (bucket, _) in user.iter_mut().chain(everyone.iter_mut()) {
+			bucket.tokens -= 1.0;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn refills_after_the_window() {
+		let limiter = RateLimiter::default();
+		let take = |login, at| limiter.take_at(login, Some(3), None, at);
+		let start = Instant::now();
+		for _ in 0..3 {
+			assert_eq!(take("alice", start), Ok(()));
+		}
+		assert_eq!(take("alice", start), Err(Over::User(WINDOW / 3)));
+		/* Logins are counted without regard to case. */
+		assert!(take("Alice", start).is_err());
+		/* Others have their own allowance. */
+		assert_eq!(take("bob", start), Ok(()));
+
+		/* A third of the way through, one profile is back. */
+		let later = start + WINDOW / 3;
+		assert_eq!(take("alice", later), Ok(()));
+		assert!(take("alice", later).is_err());
+
+		/* After the whole window, all of them are. */
+		let refilled = later + WINDOW;
+		for _ in 0..3 {
+			assert_eq!(take("alice", refilled), Ok(()));
+		}
+		assert!(take("alice", refilled).is_err());
+	}
+
+	#[test]
+	fn shares_the_global_allowance() {
+		let limiter = RateLimiter::default();
+		let take = |login, at| limiter.take_at(login, Some(1), Some(3), at);
+		let start = Instant::now();
+		assert_eq!(take("alice", start), Ok(()));
+		/* Going over their own allowance costs alice none of everyone's. */
+		assert_eq!(take("alice", start), Err(Over::User(WINDOW)));
+		assert_eq!(take("bob", start), Ok(()));
+		assert_eq!(take("carol", start), Ok(()));
+		assert_eq!(take("dave", start), Err(Over::Global(WINDOW / 3)));
+
+		/*
+		 * A third of the way through, one of everyone's profiles is
+		 * back, and dave still has their own: being refused took none.
+		 */
+		let later = start + WINDOW / 3;
+		assert_eq!(take("dave", later), Ok(()));
+		assert_eq!(take("erin", later), Err(Over::Global(WINDOW / 3)));
+	}
+}
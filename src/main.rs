@@ -4,39 +4,330 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+extern crate clap;
 extern crate nimbledroidrs;
 extern crate slog;
 extern crate slog_async;
 extern crate slog_term;
 extern crate tempdir;
 
+mod artifacts;
+mod baseline;
+mod batch;
+mod breaker;
+mod builder;
+mod checks;
+mod command;
+mod config;
+mod deliveries;
+mod format;
+mod github;
+mod github_app;
+mod intake;
+mod job;
+mod limiter;
+mod metrics;
+mod pending;
+mod profile;
+mod queue;
+mod results;
+mod retry;
+mod server;
+mod signature;
+mod sink;
+mod store;
+mod timings;
+#[cfg(feature = "tls")]
+mod tls;
+mod trace;
+
 use tempdir::TempDir;
 
+use artifacts::{clean_artifacts, free_space, list_files, DirFailures, InFlightDirs, LowDisk};
+use baseline::BaselineCache;
+use batch::Batch;
+use breaker::Breaker;
+use builder::{builder_from_config, warmup_command, BuildRequest};
+use checks::{readiness_checks, run_checks, CheckTargets, Readiness};
+use command::{parse_command, CommandError};
+use config::{
+	config_from_env, config_from_file, Config, DuplicateJobs, GitHubAuth, ProfilersSource,
+	StoreKind, Variant,
+};
+use deliveries::RecentDeliveries;
+use format::{
+	format_config_comment, format_history_comment, format_outcome_comment, CommentOptions,
+};
+use github::{is_api_url, GitHubClient};
+use github_app::{installation_id, GitHubApp, Installations, TOKEN_REFRESH};
+use intake::Intake;
+use job::{normalize_sha, BuildInfo, CancelToken, JobContext, ProfileOutcome, ProfiledRef};
+use limiter::{Over, RateLimiter};
+use metrics::{Metrics, Outcome};
+use pending::PendingJobs;
+use profile::{
+	check_profile_url, diagnose_missing_results, is_quota_error, ResultsError, ScenarioResult,
+};
+use queue::{job_id, panic_message, progress, QueuedJob, WorkQueue};
+use results::{HistoryRecord, PastRun, ResultCache};
+use server::serve;
+use sink::sinks_from_config;
+use store::store_from_config;
+use timings::{Phase, Timings};
+
+use clap::{Arg, SubCommand};
+use flate2::read::GzDecoder;
+use futures::channel::oneshot;
+use futures::compat::Future01CompatExt;
+use futures::future::{select, Either};
+use futures::pin_mut;
 use nimbledroidrs::Profiler;
-use percent_encoding::percent_decode;
-use serde_json::Value;
-use slog::{error, info, o, Drain, Logger};
+use percent_encoding::{percent_decode, utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use slog::{debug, error, info, o, Drain, Level, Logger};
 use std::convert::TryFrom;
 use std::fs::File;
 use std::fs::Permissions;
-use std::io::Result;
+use std::io::{Read, Result};
 use std::os::unix::fs::PermissionsExt;
 use std::os::unix::process::ExitStatusExt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::time::Duration;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tide::forms::ExtractForms;
+use tide::http::StatusCode;
 use tide::App;
 use tide::Context;
 use tide::EndpointResult;
+use tokio::timer::Delay;
 
 struct PullRequestComment {
 	url: String,
+	pull_request_url: String,
+	html_url: String,
+	statuses_url: String,
+	comment: String,
+	comment_url: Option<String>,
+	/* Missing when GitHub has no account for the comment's author. */
+	commenter: Option<String>,
+	commenter_association: String,
+	repo_full_name: String,
+	installation_id: Option<u64>,
+}
+
+/*
+ * What a comment's pull request has to be built from. A comment event
+ * doesn't carry it, so it is looked up once the comment turns out to be
+ * a command.
+ */
+struct PullRequestHead {
 	clone_url: String,
 	head_sha: String,
-	comment: String,
-	commenter: String,
+	base_ref: String,
+	base_sha: Option<String>,
+	draft: bool,
+	merge: Option<MergeRef>,
+}
+
+/*
+ * What `render-comment` reads: a profile outcome as it might come back
+ * from ND, with whatever else the comment shows. `result` is `success`,
+ * `timeout` or `error`; `detail` explains an error.
+ */
+#[derive(Deserialize)]
+#[serde(default)]
+struct CommentFixture {
+	result: String,
+	profile_url: String,
+	detail: String,
+	sha: String,
+	commenter: Option<String>,
+	scenarios: Vec<ScenarioResult>,
+}
+
+impl Default for CommentFixture {
+	fn default() -> Self {
+		Self {
+			result: "success".to_string(),
+			profile_url: "https://nimbledroid.com/api/v2/profiles/fixture".to_string(),
+			detail: String::new(),
+			sha: "0000000000000000000000000000000000000000".to_string(),
+			commenter: None,
+			scenarios: vec![],
+		}
+	}
+}
+
+/*
+ * Render the comment the bot would post for the fixture in `path`, with
+ * the configured templates and options.
+ */
+fn render_comment(config: &Config, path: &str) -> std::result::Result<String, String> {
+	let fixture = std::fs::read_to_string(path)
+		.map_err(|e| format!("Oops, could not read {}: {}", path, e))?;
+	let fixture: CommentFixture = serde_json::from_str(&fixture)
+		.map_err(|e| format!("Oops, could not parse {}: {}", path, e))?;
+	let outcome = match fixture.result.as_str() {
+		"success" => ProfileOutcome::Success {
+			profile_url: fixture.profile_url,
+			scenarios: fixture.scenarios,
+		},
+		"timeout" => ProfileOutcome::Timeout {
+			profile_url: fixture.profile_url,
+			scenarios: fixture.scenarios,
+		},
+		"error" => ProfileOutcome::ResultsUnavailable {
+			profile_url: fixture.profile_url,
+			detail: fixture.detail,
+		},
+		other => {
+			return Err(format!(
+				"Oops, {} isn't a result to render; use success, timeout or error.",
+				other
+			))
+		}
+	};
+	let comment = format_outcome_comment(
+		&outcome,
+		&BuildInfo::default(),
+		&fixture.sha,
+		fixture.commenter.as_deref(),
+		&CommentOptions::from_config(config),
+	);
+	Ok(comment)
+}
+
+/*
+ * GitHub nulls a pull request's head repository once the fork it came
+ * from is deleted, leaving nothing to clone the head from.
+ */
+static HEAD_REPO_GONE: &str = "The head repository is unavailable (fork deleted?).";
+
+/*
+ * GitHub's test merge of a pull request into its base branch, which is
+ * what refs/pull/N/merge points at. It lives in the base repository.
+ */
+struct MergeRef {
+	clone_url: String,
+	sha: String,
+	base_ref: String,
+}
+
+/*
+ * A pull_request event (opened, synchronize, labeled, ...). Unlike a
+ * comment, the payload carries the head directly, so there is nothing
+ * to fetch.
+ */
+struct PullRequestUpdate {
+	action: String,
+	url: String,
+	pull_request_url: String,
+	html_url: String,
+	statuses_url: String,
+	issue_url: String,
+	clone_url: String,
+	head_sha: String,
+	repo_full_name: String,
+	author_association: String,
+	base_ref: String,
+	base_sha: Option<String>,
+	draft: bool,
+	label: Option<String>,
+	merge: Option<MergeRef>,
+	installation_id: Option<u64>,
+}
+
+/*
+ * A push event. Pushes to the configured branches are profiled and
+ * recorded as baselines; comments go on the pushed commit.
+ */
+struct PushEvent {
+	git_ref: String,
+	after: String,
+	deleted: bool,
+	commits_url: String,
+	statuses_url: String,
+	compare_url: String,
+	clone_url: String,
+	repo_full_name: String,
+	installation_id: Option<u64>,
+}
+
+/* Which build this is, as recorded by build.rs. */
+static VERSION: &str = env!("CARGO_PKG_VERSION");
+static GIT_COMMIT: &str = env!("NDHOOK_GIT_COMMIT");
+static BUILT_AT: &str = env!("NDHOOK_BUILT_AT");
+
+/*
+ * How many more times to ask ND for the results of a finished profile
+ * when it can't be reached, a poll interval apart.
+ */
+const RESULTS_RETRIES: u32 = 3;
+
+/*
+ * The most a compressed notification may inflate to. GitHub caps
+ * deliveries at 25 MB, so anything bigger isn't from GitHub.
+ */
+const MAX_INFLATED_BODY: u64 = 25 * 1024 * 1024;
+
+/*
+ * Whether a build's exit status says the container or the connection
+ * to it failed, rather than the build: Docker exits 125 when it can't
+ * run the container, ssh 255 when it can't connect, and anything past
+ * 128 has been killed by a signal, the OOM killer's most often.
+ */
+fn infrastructure_exit(exit_code: i32) -> bool {
+	exit_code == 125 || exit_code > 128
+}
+
+/* How much of a failed post-build command's stderr to report. */
+const POST_BUILD_STDERR_LINES: usize = 5;
+
+/* Why anything that would build is refused when build_enabled is off. */
+static PROFILE_ONLY: &str =
+	"This deployment is profile-only; it doesn't build, but profiles APKs uploaded to /profile-apk.";
+
+/* Where, in the build output, the build script records what it built. */
+static BUILT_SHA_FILE: &str = "built_sha";
+static TOOLCHAIN_VERSION_FILE: &str = "toolchain_version";
+
+/*
+ * GitHub only has a merge commit for a pull request that merges cleanly;
+ * `mergeable` is false when it conflicts and null while GitHub is still
+ * working it out.
+ */
+fn merge_ref(pull_request: &Value) -> Option<MergeRef> {
+	if pull_request["mergeable"] != Value::Bool(true) {
+		return None;
+	}
+	match (
+		&pull_request["base"]["repo"]["clone_url"],
+		&pull_request["merge_commit_sha"],
+		&pull_request["base"]["ref"],
+	) {
+		(Value::String(clone_url), Value::String(sha), Value::String(base_ref)) => Some(MergeRef {
+			clone_url: clone_url.to_string(),
+			sha: sha.to_string(),
+			base_ref: base_ref.to_string(),
+		}),
+		_ => None,
+	}
 }
 
-static ONE_HOUR: u64 = 3600;
+/*
+ * The first of a notification's API URLs that isn't on the configured
+ * GitHub API, if any. The bot would otherwise send its token wherever a
+ * forged notification pointed it.
+ */
+fn foreign_url<'a>(state: &ServerState, urls: &[&'a str]) -> Option<&'a str> {
+	urls.iter()
+		.copied()
+		.find(|url| !is_api_url(url, &state.config.github_api_base))
+}
 
 impl TryFrom<Value> for PullRequestComment {
 	type Error = String;
@@ -55,58 +346,258 @@ impl TryFrom<Value> for PullRequestComment {
 			}
 		};
 
-		let comment = match &notification["comment"]["body"] {
+		let html_url = match &notification["issue"]["html_url"] {
 			Value::String(s) => s,
 			_ => {
-				return Err("Oops, couldn't find the comment body.".to_string());
+				return Err("Oops, couldn't find the PR's html url.".to_string());
 			}
 		};
 
-		let commenter = match &notification["comment"]["user"]["login"] {
+		let comment = match &notification["comment"]["body"] {
 			Value::String(s) => s,
 			_ => {
-				return Err("Oops, couldn't find the commenter.".to_string());
+				return Err("Oops, couldn't find the comment body.".to_string());
 			}
 		};
 
-		let pull_information_raw = match reqwest::get(pr_url) {
-			Ok(mut response) => match response.text() {
-				Ok(body) => body,
-				Err(e) => {
-					return Err(format!("Oops, couldn't download PR information: {}", e));
-				}
-			},
-			Err(e) => {
-				return Err(format!("Oops, couldn't download PR information: {}", e));
+		let repo_full_name = match &notification["repository"]["full_name"] {
+			Value::String(s) => s,
+			_ => {
+				return Err("Oops, couldn't find the repository name.".to_string());
 			}
 		};
 
-		let pull_information_structured: Value = match serde_json::from_str(&pull_information_raw) {
-			Ok(parsed) => parsed,
-			Err(e) => {
-				return Err(format!("Oops, couldn't parse PR information: {}", e));
+		let statuses_url = match &notification["repository"]["statuses_url"] {
+			Value::String(s) => s,
+			_ => {
+				return Err("Oops, couldn't find the statuses url.".to_string());
 			}
 		};
 
-		let head_sha = &pull_information_structured["head"]["sha"];
-		let clone_url = &pull_information_structured["head"]["repo"]["clone_url"];
+		Ok(Self {
+			url: comments_url.to_string(),
+			pull_request_url: pr_url.to_string(),
+			html_url: html_url.to_string(),
+			statuses_url: statuses_url.to_string(),
+			comment: comment.to_string(),
+			comment_url: notification["comment"]["url"]
+				.as_str()
+				.map(|s| s.to_string()),
+			commenter: notification["comment"]["user"]["login"]
+				.as_str()
+				.map(|s| s.to_string()),
+			commenter_association: notification["comment"]["author_association"]
+				.as_str()
+				.unwrap_or("NONE")
+				.to_string(),
+			repo_full_name: repo_full_name.to_string(),
+			installation_id: installation_id(&notification),
+		})
+	}
+}
+
+impl TryFrom<Value> for PullRequestHead {
+	type Error = String;
+	fn try_from(pull_request: Value) -> std::result::Result<Self, Self::Error> {
+		let head_sha = &pull_request["head"]["sha"];
+		let clone_url = &pull_request["head"]["repo"]["clone_url"];
 
 		match clone_url {
 			Value::String(clone_url) => match head_sha {
 				Value::String(head_sha) => Ok(Self {
-					url: comments_url.to_string(),
 					clone_url: clone_url.to_string(),
 					head_sha: head_sha.to_string(),
-					comment: comment.to_string(),
-					commenter: commenter.to_string(),
+					base_ref: pull_request["base"]["ref"]
+						.as_str()
+						.unwrap_or_default()
+						.to_string(),
+					base_sha: pull_request["base"]["sha"]
+						.as_str()
+						.map(|sha| sha.to_string()),
+					draft: pull_request["draft"].as_bool().unwrap_or(false),
+					merge: merge_ref(&pull_request),
 				}),
 				_ => Err("Oops, couldn't get the PR head's sha.".to_string()),
 			},
+			_ if pull_request["head"]["repo"].is_null() => Err(HEAD_REPO_GONE.to_string()),
 			_ => Err("Oops, couldn't get the PR head's clone url.".to_string()),
 		}
 	}
 }
 
+impl TryFrom<Value> for PullRequestUpdate {
+	type Error = String;
+	fn try_from(notification: Value) -> std::result::Result<Self, Self::Error> {
+		let action = match &notification["action"] {
+			Value::String(s) => s,
+			_ => {
+				return Err("Oops, couldn't find the pull request action.".to_string());
+			}
+		};
+
+		let pull_request = &notification["pull_request"];
+
+		let comments_url = match &pull_request["comments_url"] {
+			Value::String(s) => s,
+			_ => {
+				return Err("Oops, couldn't find a comments url.".to_string());
+			}
+		};
+
+		let html_url = match &pull_request["html_url"] {
+			Value::String(s) => s,
+			_ => {
+				return Err("Oops, couldn't find the PR's html url.".to_string());
+			}
+		};
+
+		let pull_request_url = match &pull_request["url"] {
+			Value::String(s) => s,
+			_ => {
+				return Err("Oops, couldn't find the PR's url.".to_string());
+			}
+		};
+
+		let issue_url = match &pull_request["issue_url"] {
+			Value::String(s) => s,
+			_ => {
+				return Err("Oops, couldn't find an issue url.".to_string());
+			}
+		};
+
+		let head_sha = match &pull_request["head"]["sha"] {
+			Value::String(s) => s,
+			_ => {
+				return Err("Oops, couldn't get the PR head's sha.".to_string());
+			}
+		};
+
+		let clone_url = match &pull_request["head"]["repo"]["clone_url"] {
+			Value::String(s) => s,
+			_ if pull_request["head"]["repo"].is_null() => {
+				return Err(HEAD_REPO_GONE.to_string());
+			}
+			_ => {
+				return Err("Oops, couldn't get the PR head's clone url.".to_string());
+			}
+		};
+
+		let repo_full_name = match &notification["repository"]["full_name"] {
+			Value::String(s) => s,
+			_ => {
+				return Err("Oops, couldn't find the repository name.".to_string());
+			}
+		};
+
+		let statuses_url = match &notification["repository"]["statuses_url"] {
+			Value::String(s) => s,
+			_ => {
+				return Err("Oops, couldn't find the statuses url.".to_string());
+			}
+		};
+
+		let label = match &notification["label"]["name"] {
+			Value::String(s) => Some(s.to_string()),
+			_ => None,
+		};
+
+		Ok(Self {
+			action: action.to_string(),
+			url: comments_url.to_string(),
+			pull_request_url: pull_request_url.to_string(),
+			html_url: html_url.to_string(),
+			statuses_url: statuses_url.to_string(),
+			issue_url: issue_url.to_string(),
+			clone_url: clone_url.to_string(),
+			head_sha: head_sha.to_string(),
+			repo_full_name: repo_full_name.to_string(),
+			author_association: pull_request["author_association"]
+				.as_str()
+				.unwrap_or("NONE")
+				.to_string(),
+			base_ref: pull_request["base"]["ref"]
+				.as_str()
+				.unwrap_or_default()
+				.to_string(),
+			base_sha: pull_request["base"]["sha"]
+				.as_str()
+				.map(|sha| sha.to_string()),
+			draft: pull_request["draft"].as_bool().unwrap_or(false),
+			label,
+			merge: merge_ref(pull_request),
+			installation_id: installation_id(&notification),
+		})
+	}
+}
+
+impl TryFrom<Value> for PushEvent {
+	type Error = String;
+	fn try_from(notification: Value) -> std::result::Result<Self, Self::Error> {
+		let git_ref = match &notification["ref"] {
+			Value::String(s) => s,
+			_ => {
+				return Err("Oops, couldn't find the pushed ref.".to_string());
+			}
+		};
+
+		let after = match &notification["after"] {
+			Value::String(s) => s,
+			_ => {
+				return Err("Oops, couldn't find the pushed sha.".to_string());
+			}
+		};
+
+		let compare_url = match &notification["compare"] {
+			Value::String(s) => s,
+			_ => {
+				return Err("Oops, couldn't find the push's compare url.".to_string());
+			}
+		};
+
+		let repository = &notification["repository"];
+
+		let commits_url = match &repository["commits_url"] {
+			Value::String(s) => s,
+			_ => {
+				return Err("Oops, couldn't find the commits url.".to_string());
+			}
+		};
+
+		let statuses_url = match &repository["statuses_url"] {
+			Value::String(s) => s,
+			_ => {
+				return Err("Oops, couldn't find the statuses url.".to_string());
+			}
+		};
+
+		let clone_url = match &repository["clone_url"] {
+			Value::String(s) => s,
+			_ => {
+				return Err("Oops, couldn't get the repository's clone url.".to_string());
+			}
+		};
+
+		let repo_full_name = match &repository["full_name"] {
+			Value::String(s) => s,
+			_ => {
+				return Err("Oops, couldn't find the repository name.".to_string());
+			}
+		};
+
+		Ok(Self {
+			git_ref: git_ref.to_string(),
+			after: after.to_string(),
+			deleted: notification["deleted"].as_bool().unwrap_or(false),
+			commits_url: commits_url.to_string(),
+			statuses_url: statuses_url.to_string(),
+			compare_url: compare_url.to_string(),
+			clone_url: clone_url.to_string(),
+			repo_full_name: repo_full_name.to_string(),
+			installation_id: installation_id(&notification),
+		})
+	}
+}
+
 trait ToExitCode {
 	fn to_exit_code(&self) -> i32;
 }
@@ -124,238 +615,4264 @@ impl ToExitCode for Result<std::process::ExitStatus> {
 					o.signal().unwrap()
 				}
 			}
-			Err(e) => e.raw_os_error().unwrap(),
+			Err(e) => e.raw_os_error().unwrap_or(-1),
 		}
 	}
 }
 
-fn parse_body_bytes(bytes: &[u8]) -> serde_json::Result<Value> {
-	let decoded = percent_decode(bytes).decode_utf8().unwrap();
-	let body: String = decoded.to_string().replace("payload=", "");
-	serde_json::from_str(&body)
+/*
+ * Undo the body's Content-Encoding, if it has one we understand.
+ */
+fn inflate_body(bytes: Vec<u8>, encoding: Option<&str>) -> std::result::Result<Vec<u8>, String> {
+	match encoding.map(|e| e.trim().to_ascii_lowercase()).as_deref() {
+		None | Some("") | Some("identity") => Ok(bytes),
+		Some("gzip") | Some("x-gzip") => {
+			let mut inflated = Vec::new();
+			GzDecoder::new(&bytes[..])
+				.take(MAX_INFLATED_BODY + 1)
+				.read_to_end(&mut inflated)
+				.map_err(|e| format!("Oops, could not decompress the notification: {}", e))?;
+			if inflated.len() as u64 > MAX_INFLATED_BODY {
+				return Err(format!(
+					"Oops, the notification decompresses to more than {} bytes.",
+					MAX_INFLATED_BODY
+				));
+			}
+			Ok(inflated)
+		}
+		Some(other) => Err(format!(
+			"Oops, the notification has an unsupported Content-Encoding: {}",
+			other
+		)),
+	}
 }
 
-#[allow(clippy::cognitive_complexity)]
-fn take_action(state: ServerState, notification: Value) {
-	let logger = state.logger;
+fn parse_body_bytes(bytes: &[u8]) -> std::result::Result<Value, String> {
+	/*
+	 * Form-encoded deliveries arrive as payload=<json>, percent-encoded
+	 * and with spaces as +. Raw JSON deliveries are parsed as they are:
+	 * decoding them too would turn a %22 in a comment into a quote.
+	 */
+	let parsed = match bytes.strip_prefix(b"payload=") {
+		Some(form) => {
+			let spaced: Vec<u8> = form
+				.iter()
+				.map(|&b| if b == b'+' { b' ' } else { b })
+				.collect();
+			let decoded = percent_decode(&spaced)
+				.decode_utf8()
+				.map_err(|e| format!("Oops, the notification isn't UTF-8: {}", e))?;
+			serde_json::from_str(&decoded)
+		}
+		None => serde_json::from_slice(bytes),
+	};
+	parsed.map_err(|e| format!("Oops, could not parse the body of the notification: {}", e))
+}
 
-	info!(logger, "Begin take_action");
+/*
+ * The events job_for acts on. GitHub can be set to send others, such as
+ * its ping when a webhook is set up, but they are nothing to do.
+ */
+static HANDLED_EVENTS: [&str; 3] = ["issue_comment", "pull_request", "push"];
 
-	info!(logger, "Begin extract_url_and_sha.");
-	let extract_url_and_sha_result = PullRequestComment::try_from(notification);
-	if let Err(e) = extract_url_and_sha_result {
-		error!(
-			logger,
-			"Could not extract the URL/SHA from the notification: {}", e
-		);
-		return;
+/*
+ * The checks on a notification that need nothing but the notification
+ * itself, so that handle_post can drop one that the bot could never act
+ * on before it costs any more. job_from_comment makes them all again;
+ * this only has to agree with it, never go further.
+ */
+fn preflight(
+	state: &ServerState,
+	event: &str,
+	notification: &Value,
+) -> std::result::Result<(), &'static str> {
+	if event == "pull_request" || event == "push" {
+		return Ok(());
 	}
-
-	let pull_request = extract_url_and_sha_result.unwrap();
-	let clone_url = pull_request.clone_url;
-	let head_sha = pull_request.head_sha;
-	let pr_url = pull_request.url;
-	let comment = pull_request.comment;
-	let commenter = pull_request.commenter;
-	info!(logger, "clone_url: {}", clone_url);
-	info!(logger, "head_sha: {}", head_sha);
-	info!(logger, "pr_url: {}", pr_url);
-	info!(logger, "comment: {}", comment);
-	info!(logger, "commenter: {}", commenter);
-
-	if comment != "profile" {
-		info!(logger, "Bad command: {}", comment);
-		return;
+	match notification["action"].as_str() {
+		Some("created") | None => (),
+		Some(_) => return Err("the comment wasn't just created"),
 	}
-
-	if !state.profilers.contains(&commenter.to_lowercase()) {
-		info!(logger, "Bad commenter: {} not found in {:?}", commenter, state.profilers);
-		return;
+	let comment = match notification["comment"]["body"].as_str() {
+		Some(comment) => comment,
+		None => return Ok(()),
+	};
+	match parse_command(comment) {
+		Err(CommandError::NotACommand) => return Err("the comment isn't a command"),
+		Err(CommandError::Unknown(_)) if !state.config.suggest_commands => {
+			return Err("the comment isn't a command")
+		}
+		_ => (),
 	}
-
-	// Create a directory to build in.
-	let temp_dir = TempDir::new("prefix");
-	if let Err(e) = temp_dir {
-		error!(logger, "(Err) Failed to make an artifact directory: {}", e);
-		return;
+	let commenter = match notification["comment"]["user"]["login"].as_str() {
+		Some(commenter) => commenter,
+		None => return Err("the comment has no commenter"),
+	};
+	if state.config.is_ignored_commenter(commenter) {
+		return Err("the commenter is ignored");
 	}
-	let temp_dir = temp_dir.unwrap();
-	let artifact_area = temp_dir.path();
-	let artifact_area_permissions = Permissions::from_mode(0o733);
-	if std::fs::set_permissions(&artifact_area, artifact_area_permissions).is_err() {
-		error!(
-			logger,
-			"(Err) Could not set the permissions on the artifact directory."
-		);
-		return;
+	if let Some(repo_full_name) = notification["repository"]["full_name"].as_str() {
+		let state = state.for_repo(repo_full_name);
+		let login = state.config.canonical_login(commenter);
+		let known = state.profilers.contains(&login)
+			|| state
+				.config
+				.admins
+				.iter()
+				.any(|admin| admin.eq_ignore_ascii_case(&login));
+		if !known {
+			return Err("the commenter isn't a profiler");
+		}
 	}
-	info!(
-		logger,
-		"Succeeded in making the artifact directory and setting the permissions."
-	);
+	Ok(())
+}
 
-	let build_result = Command::new("docker")
-		.arg("run")
-		.arg("--rm")
-		.arg("-ti")
-		.arg("--volume")
-		.arg(format!("{}:/build_output/", artifact_area.display()))
-		.arg("3683fdbe380c")
-		.arg("/buildtools/build_fenix.sh")
-		.arg(clone_url)
-		.arg(head_sha)
-		.arg("assembleGeckoNightlyFenixNightly")
-		.arg("app/build/outputs/apk/*")
-		.status();
-	if build_result.to_exit_code() != 0 {
-		error!(
-			logger,
-			"Failed to build: {}",
-			std::io::Error::from_raw_os_error(build_result.to_exit_code())
-		);
-	}
+/*
+ * Turn a notification into a job, if it asks for one.
+ */
+fn job_for(state: &ServerState, id: &str, event: &str, notification: Value) -> Option<JobContext> {
+	let ctx = match event {
+		"pull_request" => job_from_update(state, id, notification),
+		"push" => job_from_push(state, id, notification),
+		_ => job_from_comment(state, id, notification),
+	}?;
 
-	let profile = Profiler::new(
-		&state.nd_key,
-		&format!(
-			"{}/fenixNightly/app-geckoNightly-armeabi-v7a-fenixNightly-unsigned.apk",
-			&temp_dir.path().to_str().unwrap()
-		),
-	);
-	let profile_url: reqwest::Url;
-	match profile.upload() {
-		Ok(url) => profile_url = url,
-		Err(e) => {
-			error!(logger, "Failed to upload the artifact to ND: {}.", e);
-			return;
+	/*
+	 * The SHA ends up on the build script's command line, so insist that
+	 * it really is one.
+	 */
+	match normalize_sha(&ctx.head_sha) {
+		Some(head_sha) => Some(JobContext { head_sha, ..ctx }),
+		None => {
+			error!(
+				state.logger,
+				"Refusing to profile a malformed head SHA: {:?}", ctx.head_sha
+			);
+			let comment = format!(
+				"Not profiling: `{}` is not a valid commit SHA.",
+				ctx.head_sha
+			);
+			if let Err(e) = state.github.post_comment(&ctx.comments_url, &comment) {
+				error!(state.logger, "Failed to post the SHA error: {}", e);
+			}
+			None
 		}
 	}
+}
 
-	let mut comment_string = "".to_string();
+static SKIPPING_DRAFT: &str =
+	"Skipping draft pull request; mark it ready for review to profile it.";
+static NO_PROFILED_PATHS: &str = "No code changes detected; skipping profile.";
 
-	info!(logger, "Starting to wait for the profile.");
-	if profile
-		.wait_for_profile(&profile_url, Duration::from_secs(2*ONE_HOUR))
-		.is_err()
-	{
-		comment_string =
-			"Timeout while waiting for ND to complete profiling the application.".to_string();
-		error!(logger, "{}", comment_string);
+fn unprofiled_base(base_ref: &str) -> String {
+	format!(
+		"Skipping profile: this pull request targets `{}`, which isn't one of the base_branches.",
+		base_ref
+	)
+}
+
+/*
+ * Whether a pull request changes any of the profile_paths, when there
+ * are any. If the files can't be listed, it is profiled anyway.
+ */
+fn changes_profiled_paths(state: &ServerState, pull_request_url: &str) -> bool {
+	if state.config.profile_paths.is_empty() {
+		return true;
+	}
+	match state.github.changed_files(pull_request_url) {
+		Ok(files) => files.iter().any(|file| state.config.is_profiled_path(file)),
+		Err(e) => {
+			error!(
+				state.logger,
+				"Could not list the changed files; profiling anyway: {}", e
+			);
+			true
+		}
+	}
+}
+
+fn approx_minutes(duration: Duration) -> u64 {
+	((duration.as_secs() + 30) / 60).max(1)
+}
+
+static QUEUE_FULL: &str = "The profiling queue is full, please try again later.";
+
+/* Why a job is turned away while NimbleDroid keeps failing. */
+static ND_UNAVAILABLE: &str = "NimbleDroid appears to be unavailable; try again later.";
+
+/* Why a build is turned away while the host is short of disk space. */
+static LOW_DISK: &str = "Insufficient disk space on build host; try again later.";
+
+/* Why a request is turned away, or left waiting, while profiling is paused. */
+static PAUSED: &str = "Profiling is temporarily paused, please try again later.";
+static PAUSED_QUEUED: &str =
+	"Profiling is temporarily paused; this request will start once it resumes.";
+
+/*
+ * Queue a job, first telling the requester where it is in line if it
+ * will have to wait. When the queue is full the job is turned away
+ * instead, and this returns false. A duplicate that duplicate_jobs says
+ * to skip is dropped without that counting as turning it away.
+ */
+fn enqueue_job(state: &ServerState, mut queued: QueuedJob) -> bool {
+	let logger = &state.logger;
+	let queue = &state.queue;
+	/* A batch compares its own jobs, so it is left whole. */
+	let ctx = &queued.ctx;
+	if state.config.duplicate_jobs != DuplicateJobs::Allow && ctx.batch.is_none() {
+		if queue.is_profiling(&ctx.comments_url, &ctx.head_sha, &ctx.variant) {
+			info!(
+				logger,
+				"Skipping the job: {} of {} is already being profiled.", ctx.variant, ctx.head_sha
+			);
+			if let Err(e) = state.pending.remove(&ctx.id) {
+				error!(logger, "{}", e);
+			}
+			return true;
+		}
+		if state.config.duplicate_jobs == DuplicateJobs::Supersede {
+			let superseded = queue.cancel_other_commits(&ctx.comments_url, &ctx.head_sha);
+			if superseded > 0 {
+				info!(
+					logger,
+					"Cancelled {} jobs for older commits of the pull request.", superseded
+				);
+			}
+		}
+	}
+	let max_queued_jobs = state.config.max_queued_jobs;
+	let full = match max_queued_jobs {
+		Some(max) => queue.depth() >= max,
+		None => false,
+	};
+	/*
+	 * Only a persistent queue is sure to keep a request through the
+	 * maintenance a pause is usually for.
+	 */
+	let paused = queue.is_paused();
+	if paused && !state.config.persistent_queue {
+		info!(logger, "Turning the job away: profiling is paused.");
+		if let Err(e) = state.github.post_comment(&queued.ctx.comments_url, PAUSED) {
+			error!(logger, "Failed to say profiling is paused: {}", e);
+		}
+		return false;
+	}
+	if paused && !full {
+		match state
+			.github
+			.create_comment(&queued.ctx.comments_url, PAUSED_QUEUED)
+		{
+			Ok(url) => queued.queued_comment_url = url,
+			Err(e) => error!(logger, "Failed to say profiling is paused: {}", e),
+		}
+	} else if state.config.queue_position_comment && queue.busy() && !full {
+		let mut comment = format!("Queued at position {}", queue.depth() + 1);
+		if let Some(wait) = queue.estimated_wait() {
+			comment.push_str(&format!(
+				", estimated start in ~{} minutes",
+				approx_minutes(wait)
+			));
+		}
+		comment.push('.');
+		match state
+			.github
+			.create_comment(&queued.ctx.comments_url, &comment)
+		{
+			Ok(url) => queued.queued_comment_url = url,
+			Err(e) => error!(logger, "Failed to post the queue position: {}", e),
+		}
+	} else if state.config.start_comment && queued.ctx.commenter.is_some() && !full {
+		let sha = &queued.ctx.head_sha;
+		let comment = format!(
+			"Starting a profile run for `{}`, this usually takes several minutes…",
+			&sha[..sha.len().min(7)]
+		);
+		if let Err(e) = state
+			.github
+			.post_comment(&queued.ctx.comments_url, &comment)
+		{
+			error!(logger, "Failed to say the profile is starting: {}", e);
+		}
+	}
+	match queue.enqueue(queued, max_queued_jobs) {
+		Ok(()) => {
+			info!(logger, "Queued the job; {} waiting.", queue.depth());
+			true
+		}
+		Err(queued) => {
+			info!(logger, "Turning the job away: the queue is full.");
+			if let Err(e) = state
+				.github
+				.post_comment(&queued.ctx.comments_url, QUEUE_FULL)
+			{
+				error!(logger, "Failed to say the queue is full: {}", e);
+			}
+			false
+		}
+	}
+}
+
+fn take_action(state: ServerState, queued: QueuedJob) {
+	let ctx = queued.ctx;
+	/*
+	 * The comment that said where the job was in the queue goes on to
+	 * say how far its profile has got.
+	 */
+	let state = ServerState {
+		config: ctx.config.clone(),
+		logger: ctx.logger.clone(),
+		github: state.github_for(ctx.installation_id),
+		progress_comment_url: queued.queued_comment_url.clone(),
+		..state
+	};
+	let logger = &state.logger;
+
+	info!(logger, "Begin take_action");
+
+	if ctx.cancel.is_cancelled() {
+		info!(logger, "Skipping a request cancelled in the queue.");
+		info!(logger, "End   take_action.");
+		return;
+	}
+
+	let waited = queued.enqueued_at.elapsed();
+	if let Some(max_wait) = state.config.max_queue_wait_secs {
+		if waited > Duration::from_secs(max_wait) {
+			info!(
+				logger,
+				"Skipping a request that waited {}s in the queue.",
+				waited.as_secs()
+			);
+			if let Err(e) = state.github.post_comment(
+				&ctx.comments_url,
+				"This profile request expired in the queue.",
+			) {
+				error!(logger, "Failed to post the expiry comment: {}", e);
+			}
+			info!(logger, "End   take_action.");
+			return;
+		}
+	}
+
+	/*
+	 * Give whoever asked a chance to take it back by deleting their
+	 * comment, counting any time already spent in the queue.
+	 */
+	let delay = Duration::from_secs(state.config.trigger_delay_secs);
+	if let (Some(comment_url), true) = (&ctx.trigger_comment_url, delay > Duration::from_secs(0)) {
+		if let Some(rest) = delay.checked_sub(waited) {
+			std::thread::sleep(rest);
+		}
+		if ctx.cancel.is_cancelled() {
+			info!(logger, "The request was cancelled while waiting.");
+			info!(logger, "End   take_action.");
+			return;
+		}
+		match state.github.exists(comment_url) {
+			Ok(false) => {
+				info!(logger, "The triggering comment was deleted; not profiling.");
+				info!(logger, "End   take_action.");
+				return;
+			}
+			Ok(true) => (),
+			Err(e) => error!(logger, "Could not check the triggering comment: {}", e),
+		}
+	}
+
+	if let Some(queued_comment_url) = &queued.queued_comment_url {
+		let comment = format!(
+			"Started profiling after ~{} minutes in the queue.",
+			approx_minutes(waited)
+		);
+		if let Err(e) = state.github.edit_comment(queued_comment_url, &comment) {
+			error!(logger, "Failed to update the queue position: {}", e);
+		}
+	}
+
+	/*
+	 * Don't leave whoever asked waiting forever if something in the
+	 * pipeline panics.
+	 */
+	let comments_url = ctx.comments_url.clone();
+	let id = ctx.id.clone();
+	/*
+	 * A deadline for the whole job, as a safety net under the timeouts
+	 * of its steps. The job sees it as a cancellation, and its build
+	 * is killed so that it notices promptly.
+	 */
+	let (finished, running) = channel::<()>();
+	if let Some(job_timeout_secs) = state.config.job_timeout_secs {
+		let state = state.clone();
+		let cancel = ctx.cancel.clone();
+		let id = id.clone();
+		std::thread::spawn(move || {
+			let timeout = Duration::from_secs(job_timeout_secs);
+			if let Err(RecvTimeoutError::Timeout) = running.recv_timeout(timeout) {
+				error!(
+					state.logger,
+					"(Err) Abandoning job {}: it has run for {}s.", id, job_timeout_secs
+				);
+				cancel.expire();
+				kill_build(&state, &id);
+			}
+		});
+	}
+	let ran = catch_unwind(AssertUnwindSafe(|| run_job(&state, ctx)));
+	drop(finished);
+	if let Err(payload) = ran {
+		error!(logger, "Job {} panicked: {}", id, panic_message(&*payload));
+		let comment = format!(
+			"Something went wrong internally while profiling (job `{}`).",
+			id
+		);
+		if let Err(e) = state.github.post_comment(&comments_url, &comment) {
+			error!(logger, "Failed to post the panic comment: {}", e);
+		}
+	}
+
+	info!(logger, "End   take_action.");
+}
+
+fn run_job(state: &ServerState, ctx: JobContext) {
+	let state = state.for_repo(&ctx.repo_full_name);
+	let logger = &state.logger;
+	let root = trace::start(
+		&state.config,
+		"take_action",
+		&[
+			("repo", &ctx.repo_full_name),
+			("sha", &ctx.head_sha),
+			("variant", &ctx.variant),
+		],
+	);
+	/*
+	 * A reload may have removed the variant while the job waited. That
+	 * is nothing to do with the code, so it ends as a failure of
+	 * infrastructure.
+	 */
+	let variant = match state.config.variant(&ctx.variant) {
+		Some(variant) => variant,
+		None => {
+			error!(
+				logger,
+				"(Err) The variant {:?} is no longer configured.", ctx.variant
+			);
+			let outcome = ProfileOutcome::BuildFailed {
+				detail: format!(
+					"The variant `{}` is no longer configured, so it can't be built.",
+					ctx.variant
+				),
+				infrastructure: true,
+				log_tail: None,
+			};
+			state.metrics.ended(Outcome::from(&outcome));
+			deliver_outcome(&state, &ctx, outcome);
+			trace::finish(root, logger);
+			return;
+		}
+	};
+	let prior = if ctx.retry_failed {
+		state
+			.results
+			.get(&ctx.repo_full_name, &ctx.head_sha, &ctx.variant)
+	} else {
+		None
+	};
+	/*
+	 * NimbleDroid profiles every scenario of an upload, so a retry saves
+	 * its time only by reusing the APK it built last time, when that was
+	 * kept.
+	 */
+	let kept = prior.as_ref().and_then(|prior| {
+		prior
+			.build
+			.kept_apk
+			.as_ref()
+			.filter(|apk| Path::new(apk).is_file())
+			.map(|apk| (prior.build.clone(), apk.clone()))
+	});
+	/*
+	 * Don't spend a build on an APK that NimbleDroid won't take.
+	 */
+	if state.config.nd_breaker_failures.is_some()
+		&& !state
+			.nd_breaker
+			.allow(Duration::from_secs(state.config.nd_breaker_probe_secs))
+	{
+		info!(logger, "Not profiling: NimbleDroid keeps failing.");
+		if let Err(e) = state.github.post_comment(&ctx.comments_url, ND_UNAVAILABLE) {
+			error!(logger, "Failed to say ND is unavailable: {}", e);
+		}
+		return;
+	}
+	if ctx.resume_profile_url.is_none() && kept.is_none() && !has_disk_space(&state) {
+		if let Err(e) = state.github.post_comment(&ctx.comments_url, LOW_DISK) {
+			error!(logger, "Failed to say the disk is low: {}", e);
+		}
+		return;
+	}
+	/*
+	 * Run failures of infrastructure again, up to infra_retries times.
+	 * Nothing is reported until the last attempt, so a blip costs time
+	 * rather than another comment. A run that ends without an outcome
+	 * failed before its build, which is never the code's fault.
+	 */
+	let mut attempt = 0;
+	let ran = loop {
+		let ran = match (&ctx.resume_profile_url, &kept) {
+			(Some(profile_url), _) => {
+				info!(logger, "Resuming the profile at {}.", profile_url);
+				let state = state.for_variant(variant);
+				Some((
+					BuildInfo::default(),
+					resume_profile(&state, profile_url, &ctx.cancel),
+				))
+			}
+			(None, Some((build, apk))) => {
+				info!(logger, "Re-profiling the APK kept from the earlier build.");
+				let state = state.for_variant(variant);
+				Some((
+					build.clone(),
+					timed_profile(&state, &ctx.variant, apk, &ctx.cancel),
+				))
+			}
+			(None, None) => run_profile(
+				&state,
+				&ctx.id,
+				&ctx.clone_url,
+				&ctx.head_sha,
+				&ctx.variant,
+				&ctx.cancel,
+			),
+		};
+		let retryable = match &ran {
+			Some((_, outcome)) => outcome.is_retryable(),
+			None => true,
+		};
+		if !retryable || attempt >= state.config.infra_retries || ctx.cancel.is_cancelled() {
+			break ran;
+		}
+		attempt += 1;
+		info!(
+			logger,
+			"The run failed for reasons of infrastructure; running it again ({} of {}).",
+			attempt,
+			state.config.infra_retries
+		);
+		std::thread::sleep(Duration::from_secs(state.config.profile_poll_interval_secs));
+		progress();
+	};
+	if ctx.cancel.is_expired() {
+		let comment = format!(
+			"Profiling exceeded the maximum allowed time (~{} minutes), so it was abandoned.",
+			approx_minutes(Duration::from_secs(
+				state.config.job_timeout_secs.unwrap_or(0)
+			))
+		);
+		if let Err(e) = state.github.post_comment(&ctx.comments_url, &comment) {
+			error!(logger, "Failed to say the job ran out of time: {}", e);
+		}
+		trace::finish(root, logger);
+		return;
+	}
+	if let Some((build, mut outcome)) = ran {
+		let build = BuildInfo {
+			profiled_ref: ctx.profiled_ref.clone(),
+			..build
+		};
+		/*
+		 * Compare with the pull request's last profile before this one
+		 * replaces it, unless that was of the same commit.
+		 */
+		let previous_run = if state.config.compare_previous_run {
+			state
+				.results
+				.last_for_pull_request(&ctx.comments_url, &ctx.variant)
+				.filter(|previous| previous.sha != ctx.head_sha)
+		} else {
+			None
+		};
+		let baseline = match &ctx.base_sha {
+			Some(base_sha) if state.config.compare_base => state.baselines.for_sha(base_sha),
+			_ => None,
+		};
+		let ctx = JobContext {
+			build,
+			previous_run,
+			baseline,
+			..ctx
+		};
+		if let Some(prior) = &prior {
+			outcome.merge_retried(&prior.scenarios);
+		}
+		let timed_out = match &outcome {
+			ProfileOutcome::Timeout { profile_url, .. } => Some(profile_url.clone()),
+			_ => None,
+		};
+		state.metrics.ended(Outcome::from(&outcome));
+		deliver_outcome(&state, &ctx, outcome);
+
+		if let Some(label_url) = &ctx.label_url {
+			match state.github.delete(label_url) {
+				Ok(()) => info!(logger, "Removed the trigger label."),
+				Err(e) => error!(logger, "Failed to remove the trigger label: {}", e),
+			}
+		}
+
+		if let (Some(profile_url), Some(grace)) = (timed_out, state.config.late_results_grace_secs)
+		{
+			let state = state.for_variant(variant);
+			let prior = prior.map(|prior| prior.scenarios);
+			std::thread::spawn(move || {
+				watch_late_results(&state, ctx, &profile_url, prior, Duration::from_secs(grace))
+			});
+		}
+	}
+	trace::finish(root, logger);
+}
+
+/*
+ * Remember how a job's profile went, and deliver it to the sinks.
+ */
+fn deliver_outcome(state: &ServerState, ctx: &JobContext, mut outcome: ProfileOutcome) {
+	let logger = &state.logger;
+	if ctx.pull_request_url.is_some() {
+		state.results.record_run(
+			&ctx.comments_url,
+			PastRun {
+				repo_full_name: ctx.repo_full_name.clone(),
+				html_url: ctx.html_url.clone(),
+				sha: ctx.head_sha.clone(),
+				variant: ctx.variant.clone(),
+				result: outcome.summary(&state.config.scenario_budgets_ms),
+				profile_url: outcome.profile_url().map(|url| url.to_string()),
+				scenarios: outcome.scenarios().to_vec(),
+				recorded_at: SystemTime::now(),
+			},
+		);
+	}
+	if let ProfileOutcome::Success { scenarios, .. } = &outcome {
+		state.results.insert(
+			&ctx.repo_full_name,
+			&ctx.comments_url,
+			&ctx.head_sha,
+			&ctx.variant,
+			&ctx.build,
+			scenarios.clone(),
+		);
+		if ctx.record_baseline {
+			state.baselines.insert(&ctx.head_sha, scenarios.clone());
+			info!(logger, "Recorded {} as a baseline.", ctx.head_sha);
+		}
+		if let Some(history_file) = &state.config.history_file {
+			let record = HistoryRecord::new(
+				&ctx.clone_url,
+				&ctx.head_sha,
+				ctx.commenter.as_deref(),
+				scenarios,
+			);
+			if let Err(e) = results::append_history(history_file, &record) {
+				error!(logger, "{}", e);
+			}
+		}
+	}
+	outcome.retain_scenarios(&ctx.scenarios);
+	outcome.hide_scenarios(&state.config.hidden_scenarios);
+	outcome.ignore_scenarios(&state.config.ignored_scenarios);
+	if let Some((batch, number)) = &ctx.batch {
+		batch.record(
+			*number,
+			&ctx.head_sha,
+			&outcome,
+			&state.config.scenario_budgets_ms,
+		);
+		return;
+	}
+	for sink in sinks_from_config(&state.config, &state.github, &state.results) {
+		sink.deliver(ctx, &outcome);
+	}
+}
+
+/*
+ * Keep polling ND for a profile that was reported as timed out, and
+ * deliver its results again if it finishes within `grace`. The sinks
+ * know the job by its ID, so the comment is edited rather than posted
+ * anew.
+ */
+fn watch_late_results(
+	state: &ServerState,
+	ctx: JobContext,
+	profile_url: &str,
+	prior: Option<Vec<ScenarioResult>>,
+	grace: Duration,
+) {
+	let logger = &state.logger;
+	let url = match reqwest::Url::parse(profile_url) {
+		Ok(url) => url,
+		Err(e) => {
+			error!(
+				logger,
+				"Can't watch for late results of {}: {}", profile_url, e
+			);
+			return;
+		}
+	};
+	let profile = new_profiler(state, "");
+	let poll_interval = Duration::from_secs(state.config.profile_poll_interval_secs.max(1));
+	let deadline = Instant::now() + grace;
+	info!(
+		logger,
+		"Watching for late results for up to {}s.",
+		grace.as_secs()
+	);
+	while Instant::now() < deadline {
+		std::thread::sleep(poll_interval);
+		if ctx.cancel.is_cancelled() {
+			return;
+		}
+		let scenarios = match scenario_results(&profile, &state.nd_key, &url) {
+			Ok(scenarios) => scenarios,
+			Err(_) => continue,
+		};
+		if scenarios.is_empty() || scenarios.iter().any(|s| s.is_pending()) {
+			continue;
+		}
+		info!(
+			logger,
+			"ND finished after the timeout; delivering the results."
+		);
+		let mut outcome = ProfileOutcome::Success {
+			profile_url: profile_url.to_string(),
+			scenarios,
+		};
+		if let Some(prior) = &prior {
+			outcome.merge_retried(prior);
+		}
+		let ctx = JobContext {
+			late_results: true,
+			..ctx
+		};
+		deliver_outcome(state, &ctx, outcome);
+		return;
+	}
+	info!(logger, "ND didn't finish within the grace period either.");
+}
+
+/*
+ * What to build of a pull request: where to clone it from, the commit,
+ * and, when profile_merge_ref is set, which version of it that is.
+ */
+fn choose_ref(
+	config: &Config,
+	merge: Option<MergeRef>,
+	clone_url: String,
+	head_sha: &str,
+	logger: &Logger,
+) -> (String, String, Option<ProfiledRef>) {
+	if !config.profile_merge_ref {
+		return (clone_url, head_sha.to_string(), None);
+	}
+	match merge {
+		Some(merge) => {
+			info!(
+				logger,
+				"Profiling the merge into {}: {}", merge.base_ref, merge.sha
+			);
+			(
+				merge.clone_url,
+				merge.sha,
+				Some(ProfiledRef::Merge {
+					base_ref: merge.base_ref,
+				}),
+			)
+		}
+		None => {
+			info!(
+				logger,
+				"There is no clean merge to profile; profiling the head."
+			);
+			(clone_url, head_sha.to_string(), Some(ProfiledRef::Head))
+		}
+	}
+}
+
+fn job_from_comment(state: &ServerState, id: &str, notification: Value) -> Option<JobContext> {
+	let logger = &state.logger;
+
+	/*
+	 * Comments on plain issues arrive as issue_comment events too;
+	 * they're nothing to do with the bot, not malformed.
+	 */
+	if notification["issue"].is_object() && notification["issue"]["pull_request"].is_null() {
+		debug!(
+			logger,
+			"Ignoring a comment on an issue that isn't a pull request."
+		);
+		return None;
+	}
+
+	/* An edited or deleted command isn't a new request. */
+	match notification["action"].as_str() {
+		Some("created") | None => (),
+		Some(action) => {
+			info!(logger, "Ignoring a comment that was {}.", action);
+			return None;
+		}
+	}
+
+	/*
+	 * A bot or app can comment without a login, or be one whose chatter
+	 * is ignored. Neither is worth more than a debug line; a comment
+	 * without any user at all is malformed.
+	 */
+	let user = &notification["comment"]["user"];
+	if !user.is_object() {
+		error!(logger, "Oops, couldn't find the commenter.");
+		return None;
+	}
+	match user["login"].as_str() {
+		None => {
+			debug!(logger, "Ignoring a comment by an account without a login.");
+			return None;
+		}
+		Some(login) if state.config.is_ignored_commenter(login) => {
+			debug!(
+				logger,
+				"Ignoring a comment by {}, an ignored commenter.", login
+			);
+			return None;
+		}
+		Some(_) => (),
+	}
+
+	info!(logger, "Begin extract_url_and_sha.");
+	let extract_url_and_sha_result = PullRequestComment::try_from(notification);
+	if let Err(e) = extract_url_and_sha_result {
+		error!(
+			logger,
+			"Could not extract the URL/SHA from the notification: {}", e
+		);
+		return None;
+	}
+
+	let pull_request = extract_url_and_sha_result.unwrap();
+	let mut urls = vec![
+		pull_request.pull_request_url.as_str(),
+		&pull_request.url,
+		&pull_request.statuses_url,
+	];
+	urls.extend(pull_request.comment_url.as_deref());
+	if let Some(url) = foreign_url(state, &urls) {
+		error!(
+			logger,
+			"(Err) Refusing a notification that points at {}.", url
+		);
+		return None;
+	}
+	let pull_request_url = pull_request.pull_request_url;
+	let pr_url = pull_request.url;
+	let html_url = pull_request.html_url;
+	let statuses_url = pull_request.statuses_url;
+	let comment = pull_request.comment;
+	let comment_url = pull_request.comment_url;
+	/* Checked above. */
+	let commenter = pull_request.commenter.unwrap_or_default();
+	let commenter_association = pull_request.commenter_association;
+	let repo_full_name = pull_request.repo_full_name;
+	info!(logger, "pr_url: {}", pr_url);
+	info!(logger, "comment: {}", comment);
+	info!(logger, "commenter: {}", commenter);
+	info!(logger, "repo_full_name: {}", repo_full_name);
+
+	if !state.config.comment_trigger {
+		info!(logger, "Ignoring comment: comment_trigger is off.");
+		return None;
+	}
+
+	if !state.config.repo_allowed(&repo_full_name) {
+		info!(logger, "Bad repository: {} is not allowed", repo_full_name);
+		return None;
+	}
+	let state = &state.for_repo(&repo_full_name);
+
+	let command = parse_command(&comment);
+	if let Err(CommandError::NotACommand) = command {
+		info!(logger, "Bad command: {}", comment);
+		return None;
+	}
+
+	/*
+	 * Break glass: an admin can profile unrecognized, so that whoever is
+	 * handling an incident needn't be added to the lists first.
+	 */
+	let forced = match &command {
+		Ok(command) => command.force,
+		Err(_) => false,
+	};
+	let login = state.config.canonical_login(&commenter);
+	let admin = state
+		.config
+		.admins
+		.iter()
+		.any(|admin| admin.eq_ignore_ascii_case(&login));
+	if forced && admin {
+		error!(
+			logger,
+			"(Force) {} is forcing a profile of {}, bypassing the profilers list.",
+			commenter,
+			pr_url
+		);
+	} else if !state.profilers.contains(&login) {
+		info!(logger, "Bad commenter: {} not found in {:?}", commenter, state.profilers);
+		state.metrics.ended(Outcome::BadCommenter);
+		return None;
+	} else if !state.config.is_trusted(&commenter_association) {
+		info!(
+			logger,
+			"Untrusted commenter: {} is {}", commenter, commenter_association
+		);
+		state.metrics.ended(Outcome::BadCommenter);
+		return None;
+	}
+
+	let usage_error = |message: &str| {
+		info!(logger, "Usage error: {}", message);
+		state.metrics.ended(Outcome::BadCommand);
+		if let Err(e) = state.github.post_comment(&pr_url, message) {
+			error!(logger, "Failed to post the usage error: {}", e);
+		}
+		None
+	};
+
+	let command = match command {
+		Ok(command) => command,
+		Err(CommandError::Usage(message)) => return usage_error(&message),
+		Err(CommandError::Unknown(message)) if state.config.suggest_commands => {
+			return usage_error(&message)
+		}
+		Err(CommandError::Unknown(_)) => {
+			state.metrics.ended(Outcome::BadCommand);
+			return None;
+		}
+		Err(CommandError::NotACommand) => return None,
+	};
+
+	if command.force && !admin {
+		return usage_error("`force` is only for the configured admins.");
+	}
+
+	if let Some(name) = state
+		.config
+		.restricted(&command.names(), &commenter_association)
+	{
+		return usage_error(&format!(
+			"`{}` is restricted to {} here.",
+			name,
+			state.config.command_associations[name].join(", ")
+		));
+	}
+
+	if command.help {
+		if let Err(e) = state.github.post_comment(&pr_url, &command::help_text()) {
+			error!(logger, "Failed to post the help: {}", e);
+		}
+		return None;
+	}
+
+	if command.debug {
+		let comment = format_config_comment(&state.config, &repo_full_name);
+		if let Err(e) = state.github.post_comment(&pr_url, &comment) {
+			error!(logger, "Failed to post the configuration: {}", e);
+		}
+		return None;
+	}
+
+	if command.history {
+		let runs = state.results.runs(&pr_url, state.config.history_count);
+		if let Err(e) = state
+			.github
+			.post_comment(&pr_url, &format_history_comment(&runs))
+		{
+			error!(logger, "Failed to post the history: {}", e);
+		}
+		return None;
+	}
+
+	if command.cancel {
+		let comment = match state.queue.cancel_pull_request(&pr_url) {
+			0 => "There is no profile of this pull request to cancel.".to_string(),
+			1 => "Cancelled 1 profile.".to_string(),
+			n => format!("Cancelled {} profiles.", n),
+		};
+		info!(logger, "{}", comment);
+		if let Err(e) = state.github.post_comment(&pr_url, &comment) {
+			error!(logger, "Failed to confirm the cancellation: {}", e);
+		}
+		return None;
+	}
+
+	if !command.batch.is_empty() {
+		if !admin {
+			return usage_error("`batch` is only for the configured admins.");
+		}
+		if command.batch.len() > state.config.max_batch_size {
+			return usage_error(&format!(
+				"A batch can profile at most {} pull requests.",
+				state.config.max_batch_size
+			));
+		}
+		start_batch(
+			state,
+			id,
+			&pull_request_url,
+			&pr_url,
+			&command.batch,
+			(&commenter, pull_request.installation_id),
+		);
+		return None;
+	}
+
+	if let Some(url) = &command.resume {
+		let checked = reqwest::Url::parse(url)
+			.map_err(|e| e.to_string())
+			.and_then(|url| check_profile_url(&url, &state.config.nd_base_url));
+		if let Err(e) = checked {
+			return usage_error(&format!("`{}` can't be resumed: {}", url, e));
+		}
+	}
+
+	let variant = command
+		.variant
+		.unwrap_or_else(|| state.config.default_variant.clone());
+	if state.config.variant(&variant).is_none() {
+		let known: Vec<&str> = state.config.variants.keys().map(|k| k.as_str()).collect();
+		return usage_error(&format!(
+			"`{}` is not a known variant; try one of `{}`. {}",
+			variant,
+			known.join("`, `"),
+			command::usage_line()
+		));
+	}
+
+	/*
+	 * Once the command is known to ask for a profile, so that help, a
+	 * cancel or a typo doesn't use up the allowance, and before anything
+	 * asks GitHub about the pull request, so that a commenter over it
+	 * costs no more than the one reply.
+	 */
+	let (per_user, global) = (state.config.rate_per_user, state.config.rate_global);
+	if let Err(over) = state.rate_limiter.take(&login, per_user, global) {
+		info!(logger, "Rate limited: {} is {:?}", commenter, over);
+		let comment = match over {
+			Over::User(wait) => format!(
+				"@{}, you can ask for {} profiles an hour, and have had them all; try again in ~{} minutes.",
+				commenter,
+				per_user.unwrap_or_default(),
+				approx_minutes(wait)
+			),
+			Over::Global(wait) => format!(
+				"@{}, everyone together can ask for {} profiles an hour, and they have all been taken; try again in ~{} minutes.",
+				commenter,
+				global.unwrap_or_default(),
+				approx_minutes(wait)
+			),
+		};
+		if let Err(e) = state.github.post_comment(&pr_url, &comment) {
+			error!(logger, "Failed to say the commenter is rate limited: {}", e);
+		}
+		return None;
+	}
+
+	let head = state
+		.github
+		.pull_request(&pull_request_url, state.config.max_pull_request_bytes)
+		.and_then(PullRequestHead::try_from);
+	let head = match head {
+		Ok(head) => head,
+		Err(e) if e == HEAD_REPO_GONE => return usage_error(HEAD_REPO_GONE),
+		Err(e) => {
+			error!(logger, "Could not get the pull request's head: {}", e);
+			return None;
+		}
+	};
+	if head.draft && !state.config.profile_drafts {
+		return usage_error(SKIPPING_DRAFT);
+	}
+	if !state.config.is_profiled_base(&head.base_ref) {
+		return usage_error(&unprofiled_base(&head.base_ref));
+	}
+	let clone_url = head.clone_url;
+	let head_sha = head.head_sha;
+	let merge = head.merge;
+	let base_sha = head.base_sha;
+	info!(logger, "clone_url: {}", clone_url);
+	info!(logger, "head_sha: {}", head_sha);
+
+	/*
+	 * A retry may be able to profile the APK kept from last time; if not,
+	 * run_profile refuses to build it.
+	 */
+	let resuming = command.resume.is_some();
+	if !state.config.build_enabled && !command.retry_failed && !resuming {
+		return usage_error(PROFILE_ONLY);
+	}
+
+	if !resuming && !changes_profiled_paths(state, &pull_request_url) {
+		return usage_error(NO_PROFILED_PATHS);
+	}
+
+	/* A commit asked for by name is built as it is. */
+	let (clone_url, build_sha, profiled_ref) = match &command.sha {
+		Some(sha) => (clone_url, sha.clone(), None),
+		None => choose_ref(&state.config, merge, clone_url, &head_sha, logger),
+	};
+	let head_sha = command.sha.unwrap_or(head_sha);
+	if command.retry_failed {
+		let short_sha = &build_sha[..build_sha.len().min(7)];
+		match state.results.get(&repo_full_name, &build_sha, &variant) {
+			None => {
+				return usage_error(&format!(
+					"There is no recent `{}` profile of `{}` to retry.",
+					variant, short_sha
+				))
+			}
+			Some(ref prior) if prior.failed().is_empty() => {
+				return usage_error(&format!(
+					"Every scenario passed in the last `{}` profile of `{}`; there is nothing to retry.",
+					variant, short_sha
+				))
+			}
+			Some(_) => (),
+		}
+	} else if let (false, Some(cooldown_secs)) = (resuming, state.config.profile_cooldown_secs) {
+		/*
+		 * The last profile of the pull request is the one to cool down
+		 * from; a retry only reruns what failed in it, and a resumed
+		 * profile has already run, so neither has to wait.
+		 */
+		let last = state
+			.results
+			.last_for_pull_request(&pr_url, &variant)
+			.filter(|last| last.sha == build_sha);
+		let since = last.and_then(|last| last.recorded_at.elapsed().ok());
+		if let Some(since) = since {
+			let cooldown = Duration::from_secs(cooldown_secs);
+			if since < cooldown {
+				return usage_error(&format!(
+					"`{}` was profiled ~{} minutes ago; it can be profiled again in ~{} minutes, or as soon as the pull request changes.",
+					&build_sha[..build_sha.len().min(7)],
+					approx_minutes(since),
+					approx_minutes(cooldown - since)
+				));
+			}
+		}
+	}
+	Some(JobContext {
+		id: id.to_string(),
+		comments_url: pr_url,
+		html_url,
+		pull_request_url: Some(pull_request_url),
+		statuses_url: statuses_url.replace("{sha}", &head_sha),
+		repo_full_name,
+		clone_url,
+		head_sha: build_sha,
+		profiled_ref,
+		variant,
+		scenarios: command.scenarios,
+		retry_failed: command.retry_failed,
+		record_baseline: false,
+		mentions: mentions(&state.config, Some(&commenter), &command.notify),
+		commenter: Some(commenter),
+		trigger_comment_url: comment_url,
+		label_url: None,
+		config: state.config.clone(),
+		build: BuildInfo::default(),
+		previous_run: None,
+		base_sha,
+		baseline: None,
+		late_results: false,
+		resume_profile_url: command.resume,
+		batch: None,
+		installation_id: pull_request.installation_id,
+		cancel: CancelToken::default(),
+		logger: logger.clone(),
+	})
+}
+
+/*
+ * Who to mention in a job's results: whoever the configuration always
+ * mentions, the commenter when it says to, and whoever they asked for,
+ * each once.
+ */
+fn mentions(config: &Config, commenter: Option<&str>, notify: &[String]) -> Vec<String> {
+	let commenter = commenter.filter(|_| config.mention_commenter);
+	let mut mentions: Vec<String> = vec![];
+	for mention in config
+		.mention
+		.iter()
+		.map(|m| m.trim_start_matches('@'))
+		.chain(commenter)
+		.chain(notify.iter().map(|n| n.as_str()))
+	{
+		if !mentions.iter().any(|m| m.eq_ignore_ascii_case(mention)) {
+			mentions.push(mention.to_string());
+		}
+	}
+	mentions
+}
+
+/*
+ * Queue a profile of the head of each pull request of a `profile
+ * batch`, in the same repository as the pull request it was asked for
+ * on, to be compared there once they have all finished. These are
+ * release candidates rather than work in progress, so drafts and
+ * unchanged paths are profiled too.
+ */
+fn start_batch(
+	state: &ServerState,
+	id: &str,
+	pull_request_url: &str,
+	comments_url: &str,
+	numbers: &[u64],
+	(commenter, installation_id): (&str, Option<u64>),
+) {
+	let logger = &state.logger;
+	let pulls_url = match pull_request_url.rsplit_once('/') {
+		Some((pulls_url, _)) => pulls_url,
+		None => {
+			error!(logger, "Oops, {} isn't a pull request.", pull_request_url);
+			return;
+		}
+	};
+	let batch = Batch::new(numbers, comments_url, state.github.clone(), logger.clone());
+	let mut queued = vec![];
+	for &number in numbers {
+		let url = format!("{}/{}", pulls_url, number);
+		let ctx = state
+			.github
+			.pull_request(&url, state.config.max_pull_request_bytes)
+			.and_then(|pull_request| {
+				batch_job(
+					state,
+					id,
+					&url,
+					pull_request,
+					(&batch, number),
+					(commenter, installation_id),
+				)
+			});
+		let ctx = match ctx {
+			Ok(ctx) => ctx,
+			Err(e) => {
+				error!(logger, "Not profiling #{} in the batch: {}", number, e);
+				continue;
+			}
+		};
+		batch.queued(number);
+		if enqueue_job(state, QueuedJob::new(ctx)) {
+			queued.push(format!("#{}", number));
+		} else {
+			batch.turned_away(number);
+		}
+	}
+	info!(
+		logger,
+		"Queued {} of the batch's {} profiles.",
+		queued.len(),
+		numbers.len()
+	);
+	if !queued.is_empty() {
+		let comment = format!(
+			"Profiling {}; they will be compared here once they have all finished.",
+			queued.join(", ")
+		);
+		if let Err(e) = state.github.post_comment(comments_url, &comment) {
+			error!(logger, "Failed to confirm the batch: {}", e);
+		}
+	}
+	batch.all_queued();
+}
+
+/*
+ * The job profiling the head of one pull request of a batch.
+ */
+fn batch_job(
+	state: &ServerState,
+	id: &str,
+	pull_request_url: &str,
+	pull_request: Value,
+	(batch, number): (&Batch, u64),
+	(commenter, installation_id): (&str, Option<u64>),
+) -> std::result::Result<JobContext, String> {
+	let field = |name: &str| {
+		pull_request[name]
+			.as_str()
+			.map(|value| value.to_string())
+			.ok_or_else(|| format!("Oops, couldn't find the pull request's {}.", name))
+	};
+	let comments_url = field("comments_url")?;
+	let html_url = field("html_url")?;
+	let statuses_url = field("statuses_url")?;
+	let repo_full_name = pull_request["base"]["repo"]["full_name"]
+		.as_str()
+		.map(|name| name.to_string())
+		.ok_or_else(|| "Oops, couldn't find the pull request's repository.".to_string())?;
+	let head = PullRequestHead::try_from(pull_request)?;
+	let (clone_url, build_sha, profiled_ref) = choose_ref(
+		&state.config,
+		head.merge,
+		head.clone_url,
+		&head.head_sha,
+		&state.logger,
+	);
+	let logger = state.logger.new(o!("batch" => number));
+	Ok(JobContext {
+		id: format!("{}-{}", id, number),
+		comments_url,
+		html_url,
+		pull_request_url: Some(pull_request_url.to_string()),
+		statuses_url: statuses_url.replace("{sha}", &head.head_sha),
+		repo_full_name,
+		clone_url,
+		head_sha: build_sha,
+		profiled_ref,
+		variant: state.config.default_variant.clone(),
+		scenarios: vec![],
+		retry_failed: false,
+		record_baseline: false,
+		mentions: vec![],
+		commenter: Some(commenter.to_string()),
+		trigger_comment_url: None,
+		label_url: None,
+		config: state.config.clone(),
+		build: BuildInfo::default(),
+		previous_run: None,
+		base_sha: None,
+		baseline: None,
+		late_results: false,
+		resume_profile_url: None,
+		batch: Some((batch.clone(), number)),
+		installation_id,
+		cancel: CancelToken::default(),
+		logger,
+	})
+}
+
+fn job_from_update(state: &ServerState, id: &str, notification: Value) -> Option<JobContext> {
+	let logger = &state.logger;
+
+	let update = match PullRequestUpdate::try_from(notification) {
+		Ok(update) => update,
+		Err(e) if e == HEAD_REPO_GONE => {
+			info!(logger, "Ignoring pull_request event: {}", e);
+			return None;
+		}
+		Err(e) => {
+			error!(
+				logger,
+				"Could not extract the URL/SHA from the notification: {}", e
+			);
+			return None;
+		}
+	};
+	let urls = [
+		update.url.as_str(),
+		&update.pull_request_url,
+		&update.statuses_url,
+		&update.issue_url,
+	];
+	if let Some(url) = foreign_url(state, &urls) {
+		error!(
+			logger,
+			"(Err) Refusing a notification that points at {}.", url
+		);
+		return None;
+	}
+	info!(logger, "action: {}", update.action);
+	info!(logger, "clone_url: {}", update.clone_url);
+	info!(logger, "head_sha: {}", update.head_sha);
+	info!(logger, "pr_url: {}", update.url);
+	info!(logger, "repo_full_name: {}", update.repo_full_name);
+
+	let mut label_url = None;
+	match update.action.as_str() {
+		/* A draft that was skipped is profiled once it is ready. */
+		"opened" | "synchronize" | "ready_for_review" => {
+			if !state.config.auto_profile_on_push {
+				info!(
+					logger,
+					"Ignoring pull_request event: auto_profile_on_push is off."
+				);
+				return None;
+			}
+		}
+		"labeled" => {
+			let trigger_label = match &state.config.trigger_label {
+				Some(trigger_label) => trigger_label,
+				None => {
+					info!(logger, "Ignoring labeled event: no trigger_label is set.");
+					return None;
+				}
+			};
+			match &update.label {
+				Some(label) if label == trigger_label => (),
+				label => {
+					info!(logger, "Ignoring label: {:?}", label);
+					return None;
+				}
+			}
+			if state.config.remove_trigger_label {
+				label_url = Some(format!(
+					"{}/labels/{}",
+					update.issue_url,
+					utf8_percent_encode(trigger_label, NON_ALPHANUMERIC)
+				));
+			}
+		}
+		_ => {
+			info!(logger, "Ignoring pull_request action: {}", update.action);
+			return None;
+		}
+	}
+
+	if !state.config.repo_allowed(&update.repo_full_name) {
+		info!(
+			logger,
+			"Bad repository: {} is not allowed", update.repo_full_name
+		);
+		return None;
+	}
+	let state = &state.for_repo(&update.repo_full_name);
+	if !state.config.build_enabled {
+		info!(logger, "Ignoring pull_request event: build_enabled is off.");
+		return None;
+	}
+
+	/*
+	 * Only maintainers can label, so a label counts as one of them
+	 * asking for the profile.
+	 */
+	if update.action != "labeled" && !state.config.is_trusted(&update.author_association) {
+		info!(
+			logger,
+			"Not profiling automatically: the author is {}", update.author_association
+		);
+		return None;
+	}
+
+	if update.draft && !state.config.profile_drafts {
+		info!(logger, "{}", SKIPPING_DRAFT);
+		/* As below, only say so when someone asked. */
+		if update.action == "labeled" {
+			if let Err(e) = state.github.post_comment(&update.url, SKIPPING_DRAFT) {
+				error!(logger, "Failed to say why not: {}", e);
+			}
+		}
+		return None;
+	}
+
+	if !state.config.is_profiled_base(&update.base_ref) {
+		let why = unprofiled_base(&update.base_ref);
+		info!(logger, "{}", why);
+		if update.action == "labeled" {
+			if let Err(e) = state.github.post_comment(&update.url, &why) {
+				error!(logger, "Failed to say why not: {}", e);
+			}
+		}
+		return None;
+	}
+
+	if !changes_profiled_paths(state, &update.pull_request_url) {
+		info!(logger, "{}", NO_PROFILED_PATHS);
+		/* Say so when someone asked; pushes would only add noise. */
+		if update.action == "labeled" {
+			if let Err(e) = state.github.post_comment(&update.url, NO_PROFILED_PATHS) {
+				error!(logger, "Failed to say why not: {}", e);
+			}
+		}
+		return None;
+	}
+
+	let (clone_url, build_sha, profiled_ref) = choose_ref(
+		&state.config,
+		update.merge,
+		update.clone_url,
+		&update.head_sha,
+		logger,
+	);
+	Some(JobContext {
+		id: id.to_string(),
+		comments_url: update.url,
+		html_url: update.html_url,
+		pull_request_url: Some(update.pull_request_url),
+		statuses_url: update.statuses_url.replace("{sha}", &update.head_sha),
+		repo_full_name: update.repo_full_name,
+		clone_url,
+		head_sha: build_sha,
+		profiled_ref,
+		variant: state.config.default_variant.clone(),
+		scenarios: vec![],
+		retry_failed: false,
+		record_baseline: false,
+		commenter: None,
+		mentions: mentions(&state.config, None, &[]),
+		trigger_comment_url: None,
+		label_url,
+		config: state.config.clone(),
+		build: BuildInfo::default(),
+		previous_run: None,
+		base_sha: update.base_sha,
+		baseline: None,
+		late_results: false,
+		resume_profile_url: None,
+		batch: None,
+		installation_id: update.installation_id,
+		cancel: CancelToken::default(),
+		logger: logger.clone(),
+	})
+}
+
+fn job_from_push(state: &ServerState, id: &str, notification: Value) -> Option<JobContext> {
+	let logger = &state.logger;
+
+	let push = match PushEvent::try_from(notification) {
+		Ok(push) => push,
+		Err(e) => {
+			error!(logger, "Could not extract the SHA from the push: {}", e);
+			return None;
+		}
+	};
+	if let Some(url) = foreign_url(state, &[&push.commits_url, &push.statuses_url]) {
+		error!(logger, "(Err) Refusing a push that points at {}.", url);
+		return None;
+	}
+	info!(logger, "ref: {}", push.git_ref);
+	info!(logger, "after: {}", push.after);
+	info!(logger, "repo_full_name: {}", push.repo_full_name);
+
+	if push.deleted {
+		info!(logger, "Ignoring the deletion of {}.", push.git_ref);
+		return None;
+	}
+
+	if !state.config.repo_allowed(&push.repo_full_name) {
+		info!(
+			logger,
+			"Bad repository: {} is not allowed", push.repo_full_name
+		);
+		return None;
+	}
+	let state = &state.for_repo(&push.repo_full_name);
+	if !state.config.build_enabled {
+		info!(logger, "Ignoring push: build_enabled is off.");
+		return None;
+	}
+
+	let branch = push.git_ref.trim_start_matches("refs/heads/");
+	if !state
+		.config
+		.repo_push_branches(&push.repo_full_name)
+		.iter()
+		.any(|b| b == branch)
+	{
+		info!(
+			logger,
+			"Ignoring a push to {}: it's not a push branch.", branch
+		);
+		return None;
+	}
+
+	Some(JobContext {
+		id: id.to_string(),
+		comments_url: format!(
+			"{}/comments",
+			push.commits_url
+				.replace("{/sha}", &format!("/{}", push.after))
+		),
+		html_url: push.compare_url,
+		pull_request_url: None,
+		statuses_url: push.statuses_url.replace("{sha}", &push.after),
+		repo_full_name: push.repo_full_name,
+		clone_url: push.clone_url,
+		head_sha: push.after,
+		profiled_ref: None,
+		variant: state.config.default_variant.clone(),
+		scenarios: vec![],
+		retry_failed: false,
+		record_baseline: true,
+		commenter: None,
+		mentions: mentions(&state.config, None, &[]),
+		trigger_comment_url: None,
+		label_url: None,
+		config: state.config.clone(),
+		build: BuildInfo::default(),
+		previous_run: None,
+		base_sha: None,
+		baseline: None,
+		late_results: false,
+		resume_profile_url: None,
+		batch: None,
+		installation_id: push.installation_id,
+		cancel: CancelToken::default(),
+		logger: logger.clone(),
+	})
+}
+
+/*
+ * Whether there is min_free_disk_mb free where builds go, so a build
+ * isn't started only to run out partway through. The host reports
+ * unhealthy until a check finds there is.
+ */
+fn has_disk_space(state: &ServerState) -> bool {
+	let min_free_disk_mb = match state.config.min_free_disk_mb {
+		Some(min_free_disk_mb) => min_free_disk_mb,
+		None => return true,
+	};
+	let build_root = match &state.config.artifact_root {
+		Some(artifact_root) => PathBuf::from(artifact_root),
+		None => std::env::temp_dir(),
+	};
+	let free_mb = match free_space(&build_root) {
+		Ok(free) => free / (1024 * 1024),
+		Err(e) => {
+			/* Let the build find out for itself. */
+			error!(state.logger, "(Err) {}", e);
+			return true;
+		}
+	};
+	let enough = free_mb >= min_free_disk_mb;
+	if !enough {
+		error!(
+			state.logger,
+			"(Err) Not building: {}MB free on {}, below min_free_disk_mb ({}MB). Reporting unhealthy.",
+			free_mb,
+			build_root.display(),
+			min_free_disk_mb
+		);
+	}
+	state.low_disk.set(!enough);
+	enough
+}
+
+/*
+ * Name the build directory and container after the job so that each
+ * can be traced back to the request it is for.
+ */
+fn job_name(job_id: &str) -> String {
+	format!(
+		"ndhook-{}",
+		job_id
+			.chars()
+			.map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+			.collect::<String>()
+	)
+}
+
+/*
+ * Build, upload and profile clone_url at head_sha. None means the run
+ * failed before there was anything worth reporting.
+ */
+#[allow(clippy::cognitive_complexity)]
+fn run_profile(
+	state: &ServerState,
+	job_id: &str,
+	clone_url: &str,
+	head_sha: &str,
+	variant_name: &str,
+	cancel: &CancelToken,
+) -> Option<(BuildInfo, ProfileOutcome)> {
+	let variant = match state.config.variant(variant_name) {
+		Some(variant) => variant,
+		None => {
+			error!(
+				state.logger,
+				"(Err) There is no variant {:?} to build.", variant_name
+			);
+			return None;
+		}
+	};
+	let state = &state.for_variant(variant);
+	let logger = &state.logger;
+
+	if !state.config.build_enabled {
+		error!(logger, "(Err) Refusing to build: build_enabled is off.");
+		return Some((
+			BuildInfo::default(),
+			ProfileOutcome::BuildFailed {
+				detail: PROFILE_ONLY.to_string(),
+				infrastructure: false,
+				log_tail: None,
+			},
+		));
+	}
+
+	if normalize_sha(head_sha).is_none() {
+		error!(
+			logger,
+			"(Err) Refusing to build a malformed SHA: {:?}", head_sha
+		);
+		return None;
+	}
+
+	/*
+	 * The configuration was checked when it was loaded, but the task goes
+	 * straight onto the build script's command line, so check again.
+	 */
+	if !state.config.is_allowed_task(&variant.gradle_task) {
+		error!(
+			logger,
+			"(Err) Refusing to run a gradle task that isn't allowed: {:?}", variant.gradle_task
+		);
+		return Some((
+			BuildInfo::default(),
+			ProfileOutcome::BuildFailed {
+				detail: format!(
+					"The gradle task `{}` isn't one of the allowed gradle_tasks.",
+					variant.gradle_task
+				),
+				infrastructure: false,
+				log_tail: None,
+			},
+		));
+	}
+
+	let job_name = job_name(job_id);
+
+	// Create a directory to build in.
+	let temp_dir = match &state.config.artifact_root {
+		Some(artifact_root) => TempDir::new_in(artifact_root, &job_name),
+		None => TempDir::new(&job_name),
+	};
+	if let Err(e) = temp_dir {
+		error!(logger, "(Err) Failed to make an artifact directory: {}", e);
+		let failures = state.dir_failures.failed();
+		if failures >= state.config.dir_failures_unhealthy {
+			error!(
+				logger,
+				"(Err) {} artifact directories in a row couldn't be made; the disk may be full or read-only. Reporting unhealthy.",
+				failures
+			);
+		}
+		return None;
+	}
+	state.dir_failures.succeeded();
+	let temp_dir = temp_dir.unwrap();
+	let artifact_area = temp_dir.path();
+	let _in_flight = state.in_flight.claim(artifact_area);
+	/*
+	 * The build runs as a different user, so open the directory up for it
+	 * to write in. On SELinux hosts this isn't enough by itself; see
+	 * build_volume_relabel.
+	 */
+	let artifact_area_permissions = Permissions::from_mode(0o733);
+	if std::fs::set_permissions(artifact_area, artifact_area_permissions).is_err() {
+		error!(
+			logger,
+			"(Err) Could not set the permissions on the artifact directory."
+		);
+		return None;
+	}
+	info!(
+		logger,
+		"Succeeded in making the artifact directory and setting the permissions."
+	);
+
+	progress();
+	let log_path = if state.config.build_log_gist || state.config.build_log_tail_lines.is_some() {
+		Some(artifact_area.join(BUILD_LOG))
+	} else {
+		None
+	};
+	let build_span = trace::span("build");
+	let build_started = Instant::now();
+	let build_run = builder_from_config(&state.config).build(
+		&BuildRequest {
+			job_name: &job_name,
+			artifact_area,
+			clone_url,
+			sha: head_sha,
+			gradle_task: &variant.gradle_task,
+			config: &state.config,
+			log_path: log_path.as_deref(),
+		},
+		logger,
+	);
+	drop(build_span);
+	state.timings.record(
+		variant_name,
+		Phase::Build,
+		build_started.elapsed(),
+		state.config.timing_window,
+	);
+	progress();
+	let exit_code = build_run.exit_code;
+	if let Some(e) = &build_run.error {
+		error!(logger, "(Err) Could not run the build: {}", e);
+	} else if exit_code != 0 {
+		error!(logger, "The build exited with code {}.", exit_code);
+	}
+	if cancel.is_cancelled() {
+		info!(logger, "The job was cancelled during the build.");
+		return None;
+	}
+
+	let mut build = BuildInfo {
+		built_sha: built_sha(state, artifact_area, head_sha),
+		image: Some(state.config.build_image.clone()),
+		image_id: build_run.image_id,
+		toolchain_version: toolchain_version(artifact_area),
+		kept_apk: None,
+		apk_bytes: None,
+		log_url: None,
+		artifact_urls: vec![],
+		profiled_ref: None,
+	};
+	if let (Some(log_path), true) = (&log_path, state.config.build_log_gist) {
+		build.log_url = gist_build_log(state, log_path, clone_url, head_sha);
+	}
+	if let Some(detail) = build_run.error {
+		return Some((
+			build,
+			ProfileOutcome::BuildFailed {
+				detail,
+				infrastructure: true,
+				log_tail: None,
+			},
+		));
+	}
+
+	let apk_path = artifact_area.join(&variant.apk_path);
+	let apk_path = match apk_path.to_str() {
+		Some(apk_path) => apk_path,
+		None => {
+			error!(
+				logger,
+				"(Err) The APK path {} isn't valid UTF-8.",
+				apk_path.display()
+			);
+			return None;
+		}
+	};
+	/*
+	 * A build that exits non-zero has failed, whatever it left behind;
+	 * one that succeeds has still failed without an APK, and an empty
+	 * file isn't one.
+	 */
+	build.apk_bytes = std::fs::metadata(apk_path)
+		.ok()
+		.filter(|m| m.is_file())
+		.map(|m| m.len());
+	let built = exit_code == 0 && build.apk_bytes.unwrap_or(0) > 0;
+	let outcome = if exit_code != 0 {
+		ProfileOutcome::BuildFailed {
+			detail: format!("the build exited with status {}", exit_code),
+			infrastructure: infrastructure_exit(exit_code),
+			log_tail: state.config.build_log_tail_lines.and_then(|lines| {
+				log_path
+					.as_deref()
+					.and_then(|path| build_log_tail(path, lines))
+			}),
+		}
+	} else if built {
+		match post_build(state, artifact_area, apk_path) {
+			Ok(()) => timed_profile(state, variant_name, apk_path, cancel),
+			Err(detail) => {
+				error!(logger, "The post-build command failed: {}", detail);
+				ProfileOutcome::BuildFailed {
+					detail,
+					infrastructure: false,
+					log_tail: None,
+				}
+			}
+		}
+	} else {
+		/*
+		 * Nothing went wrong that the build noticed, so show what it
+		 * did make: most likely the APK is there under another name.
+		 */
+		error!(
+			logger,
+			"The build succeeded but left no APK at {}.", variant.apk_path
+		);
+		let files = list_files(artifact_area, MAX_LISTED_OUTPUTS);
+		let made = if files.is_empty() {
+			"nothing at all".to_string()
+		} else {
+			files.join(", ")
+		};
+		ProfileOutcome::BuildFailed {
+			detail: format!(
+				"it exited successfully but produced no APK at {}. It left: {}",
+				variant.apk_path, made
+			),
+			infrastructure: false,
+			log_tail: None,
+		}
+	};
+
+	if built && state.config.artifact_store != StoreKind::Local {
+		build.artifact_urls = store_artifacts(
+			state,
+			&format!("{}/{}", head_sha, job_name),
+			Path::new(apk_path),
+			log_path.as_deref(),
+			&outcome,
+		);
+	}
+
+	if state.config.artifact_root.is_some() {
+		let kept = temp_dir.into_path();
+		info!(logger, "Kept the artifacts in {}.", kept.display());
+		if built {
+			build.kept_apk = Some(apk_path.to_string());
+		}
+	}
+	Some((build, outcome))
+}
+
+/*
+ * How many of the files a build made to name when none of them is the
+ * APK.
+ */
+const MAX_LISTED_OUTPUTS: usize = 20;
+
+/*
+ * The build log posted to a gist is cut down to its end, where a failure
+ * would be, beyond this.
+ */
+const MAX_GIST_LOG_BYTES: usize = 1024 * 1024;
+/*
+ * GitHub refuses comments over 65536 characters, and Gradle can print
+ * more than that in a handful of lines.
+ */
+const MAX_LOG_TAIL_BYTES: usize = 16 * 1024;
+static BUILD_LOG: &str = "build.log";
+static RESULTS_FILE: &str = "results.json";
+
+/*
+ * Put a successful build's APK, log and results in the artifact store
+ * under `prefix`, returning the links to them. An artifact that can't be
+ * stored is left out; the run goes on without it.
+ */
+fn store_artifacts(
+	state: &ServerState,
+	prefix: &str,
+	apk_path: &Path,
+	log_path: Option<&Path>,
+	outcome: &ProfileOutcome,
+) -> Vec<(String, String)> {
+	let logger = &state.logger;
+	let store = store_from_config(&state.config, state.github.dry_run());
+	let results_path = apk_path.with_file_name(RESULTS_FILE);
+	let mut artifacts = vec![("APK", apk_path)];
+	if let Some(log_path) = log_path {
+		artifacts.push(("build log", log_path));
+	}
+	match std::fs::write(&results_path, outcome.to_json().to_string()) {
+		Ok(()) => artifacts.push(("results", &results_path)),
+		Err(e) => error!(logger, "(Err) Could not write the results to store: {}", e),
+	}
+	let mut urls = vec![];
+	for (name, path) in artifacts {
+		let file_name = path
+			.file_name()
+			.map(|f| f.to_string_lossy().into_owned())
+			.unwrap_or_default();
+		match store.store(&format!("{}/{}", prefix, file_name), path) {
+			Ok(Some(url)) => {
+				info!(logger, "Stored the {} at {}.", name, url);
+				urls.push((name.to_string(), url));
+			}
+			Ok(None) => (),
+			Err(e) => error!(logger, "(Err) Could not store the {}: {}", name, e),
+		}
+	}
+	urls
+}
+
+/*
+ * The last `lines` lines of the build's log, for the comment on a failed
+ * build, cut to their last MAX_LOG_TAIL_BYTES.
+ */
+fn build_log_tail(log_path: &Path, lines: usize) -> Option<String> {
+	let log = std::fs::read(log_path).ok()?;
+	let log = String::from_utf8_lossy(&log);
+	let all: Vec<&str> = log.trim_end().lines().collect();
+	let tail = all[all.len().saturating_sub(lines)..].join("\n");
+	let mut start = tail.len().saturating_sub(MAX_LOG_TAIL_BYTES);
+	while !tail.is_char_boundary(start) {
+		start += 1;
+	}
+	let tail = &tail[start..];
+	if tail.trim().is_empty() {
+		None
+	} else {
+		Some(tail.to_string())
+	}
+}
+
+/*
+ * Post the build's log to a gist, returning the gist's page. A log that
+ * can't be posted is only missing from the results.
+ */
+fn gist_build_log(
+	state: &ServerState,
+	log_path: &Path,
+	clone_url: &str,
+	sha: &str,
+) -> Option<String> {
+	let logger = &state.logger;
+	let log = match std::fs::read(log_path) {
+		Ok(log) => log,
+		Err(e) => {
+			error!(logger, "Could not read the build log: {}", e);
+			return None;
+		}
+	};
+	if log.is_empty() {
+		return None;
+	}
+	let mut content = String::new();
+	if log.len() > MAX_GIST_LOG_BYTES {
+		content.push_str(&format!(
+			"(The first {} bytes are left out.)\n",
+			log.len() - MAX_GIST_LOG_BYTES
+		));
+	}
+	content.push_str(&String::from_utf8_lossy(
+		&log[log.len().saturating_sub(MAX_GIST_LOG_BYTES)..],
+	));
+	match state.github.create_gist(
+		&format!("ndhook build of {} at {}", clone_url, sha),
+		BUILD_LOG,
+		&content,
+	) {
+		Ok(url) => url,
+		Err(e) => {
+			error!(logger, "Failed to post the build log: {}", e);
+			None
+		}
+	}
+}
+
+/*
+ * Run the configured post-build command, if there is one, on the APK.
+ * The error describes how it failed, for the pull request.
+ */
+fn post_build(
+	state: &ServerState,
+	artifact_area: &Path,
+	apk_path: &str,
+) -> std::result::Result<(), String> {
+	let (program, args) = match state.config.post_build_command.split_first() {
+		Some(command) => command,
+		None => return Ok(()),
+	};
+	let _span = trace::span("post-build");
+	let output = Command::new(program)
+		.args(args)
+		.arg(apk_path)
+		.current_dir(artifact_area)
+		.output()
+		.map_err(|e| format!("the post-build command couldn't run: {}", e))?;
+	progress();
+	if output.status.success() {
+		info!(state.logger, "The post-build command succeeded.");
+		return Ok(());
+	}
+	/* The end of what it said is the likeliest to explain why. */
+	let stderr = String::from_utf8_lossy(&output.stderr);
+	let lines: Vec<&str> = stderr.trim().lines().collect();
+	let tail = lines[lines.len().saturating_sub(POST_BUILD_STDERR_LINES)..].join(" ");
+	if tail.is_empty() {
+		Err(format!("the post-build command failed ({})", output.status))
+	} else {
+		Err(format!(
+			"the post-build command failed ({}): {}",
+			output.status, tail
+		))
+	}
+}
+
+/*
+ * The build script may record a toolchain version in the output
+ * directory. Keep the first line, and not too much of it.
+ */
+fn toolchain_version(artifact_area: &Path) -> Option<String> {
+	let contents = std::fs::read_to_string(artifact_area.join(TOOLCHAIN_VERSION_FILE)).ok()?;
+	let version: String = contents.lines().next()?.trim().chars().take(64).collect();
+	if version.is_empty() {
+		None
+	} else {
+		Some(version)
+	}
+}
+
+/*
+ * The build script records the commit it actually checked out in the
+ * output directory. Trust it only if it looks like a commit SHA.
+ */
+fn built_sha(state: &ServerState, artifact_area: &Path, head_sha: &str) -> Option<String> {
+	let logger = &state.logger;
+	let built_sha = match std::fs::read_to_string(artifact_area.join(BUILT_SHA_FILE)) {
+		Ok(contents) => contents.trim().to_lowercase(),
+		Err(e) => {
+			info!(
+				logger,
+				"The build did not record the commit it built: {}", e
+			);
+			return None;
+		}
+	};
+	if built_sha.is_empty() || !built_sha.chars().all(|c| c.is_ascii_hexdigit()) {
+		error!(
+			logger,
+			"The build recorded a malformed commit: {:?}", built_sha
+		);
+		return None;
+	}
+	if !built_sha.starts_with(&head_sha.to_lowercase()) {
+		info!(
+			logger,
+			"Asked to build {} but the build checked out {}.", head_sha, built_sha
+		);
+	}
+	Some(built_sha)
+}
+
+/*
+ * Profile an APK built for `variant`, keeping track of how long it took.
+ */
+fn timed_profile(
+	state: &ServerState,
+	variant: &str,
+	apk_path: &str,
+	cancel: &CancelToken,
+) -> ProfileOutcome {
+	let started = Instant::now();
+	let outcome = profile_apk(state, apk_path, cancel);
+	state.timings.record(
+		variant,
+		Phase::Profile,
+		started.elapsed(),
+		state.config.timing_window,
+	);
+	outcome
+}
+
+/*
+ * Upload an APK to ND and wait for it to be profiled, keeping track of
+ * whether NimbleDroid is working for the breaker.
+ */
+fn profile_apk(state: &ServerState, apk_path: &str, cancel: &CancelToken) -> ProfileOutcome {
+	let logger = &state.logger;
+	let outcome = upload_and_wait(state, apk_path, cancel);
+	let nd_failed = matches!(
+		outcome,
+		ProfileOutcome::UploadRejected { .. } | ProfileOutcome::ResultsUnavailable { .. }
+	);
+	match (nd_failed, state.config.nd_breaker_failures) {
+		(true, Some(threshold)) => {
+			if state.nd_breaker.failed(threshold) {
+				error!(
+					logger,
+					"(Err) NimbleDroid failed {} times in a row; turning jobs away until it works again.",
+					state.nd_breaker.consecutive()
+				);
+			}
+		}
+		(true, None) => (),
+		(false, _) => {
+			if let ProfileOutcome::Success { .. } = outcome {
+				if state.nd_breaker.succeeded() {
+					info!(logger, "NimbleDroid is working again.");
+				}
+			}
+		}
+	}
+	outcome
+}
+
+fn upload_and_wait(state: &ServerState, apk_path: &str, cancel: &CancelToken) -> ProfileOutcome {
+	let logger = &state.logger;
+
+	let profile = new_profiler(state, apk_path);
+	let upload_span = trace::span("upload");
+	let profile_url = match profile.upload().map_err(|e| e.to_string()).and_then(|url| {
+		check_profile_url(&url, &state.config.nd_base_url)?;
+		Ok(url)
+	}) {
+		Ok(url) => url,
+		Err(detail) => {
+			error!(logger, "Failed to upload the artifact to ND: {}.", detail);
+			let quota = is_quota_error(&detail);
+			if quota {
+				error!(logger, "(Err) NimbleDroid's run quota is exhausted.");
+			}
+			return ProfileOutcome::UploadRejected { detail, quota };
+		}
+	};
+	drop(upload_span);
+	progress();
+	wait_for_results(state, &profile, profile_url, cancel)
+}
+
+/*
+ * Every profiler is made here, so that nd_base_url can be handed to it
+ * once nimbledroidrs takes one. Until then the profiler talks to its own
+ * default endpoint, which is why the configuration refuses any other.
+ */
+fn new_profiler(state: &ServerState, apk_path: &str) -> Profiler {
+	Profiler::new(&state.nd_key, apk_path)
+}
+
+/*
+ * Wait on a profile that was uploaded some other time, as for `profile
+ * resume`. With nothing uploaded, the profiler needs no APK.
+ */
+fn resume_profile(state: &ServerState, profile_url: &str, cancel: &CancelToken) -> ProfileOutcome {
+	match reqwest::Url::parse(profile_url) {
+		Ok(url) => wait_for_results(state, &new_profiler(state, ""), url, cancel),
+		Err(e) => ProfileOutcome::ResultsUnavailable {
+			profile_url: profile_url.to_string(),
+			detail: format!("the profile URL isn't a URL: {}", e),
+		},
+	}
+}
+
+/*
+ * Wait for ND to finish the profile at `profile_url`, until the profile
+ * timeout.
+ */
+fn wait_for_results(
+	state: &ServerState,
+	profile: &Profiler,
+	profile_url: reqwest::Url,
+	cancel: &CancelToken,
+) -> ProfileOutcome {
+	let logger = &state.logger;
+	let _wait_span = trace::span("wait");
+	info!(logger, "Starting to wait for the profile.");
+	let deadline = Instant::now() + Duration::from_secs(state.config.profile_timeout_secs);
+	let poll_interval = Duration::from_secs(state.config.profile_poll_interval_secs.max(1));
+	let cancelled = || {
+		info!(
+			logger,
+			"Stopped waiting for the profile: the job was cancelled."
+		);
+		ProfileOutcome::Cancelled {
+			profile_url: profile_url.to_string(),
+		}
+	};
+	/*
+	 * Wait a poll interval at a time, rather than for the whole timeout
+	 * at once, so that a cancelled job stops waiting promptly.
+	 */
+	let mut reported = None;
+	loop {
+		if cancel.is_cancelled() {
+			return cancelled();
+		}
+		let now = Instant::now();
+		if now >= deadline {
+			error!(
+				logger,
+				"Timeout while waiting for ND to complete profiling the application."
+			);
+			return ProfileOutcome::Timeout {
+				profile_url: profile_url.to_string(),
+				scenarios: scenario_results(profile, &state.nd_key, &profile_url)
+					.unwrap_or_default(),
+			};
+		}
+		let slice = poll_interval.min(deadline - now);
+		if profile.wait_for_profile(&profile_url, slice).is_ok() {
+			break;
+		}
+		progress();
+		if state.progress_comment_url.is_some() {
+			if let Ok(scenarios) = scenario_results(profile, &state.nd_key, &profile_url) {
+				report_progress(state, &scenarios, &mut reported);
+			}
+		}
+		/* Don't spin if ND failed the wait without waiting. */
+		if let Some(rest) = slice.checked_sub(now.elapsed()) {
+			std::thread::sleep(rest);
+		}
+	}
+	info!(logger, "Done waiting for the profile.");
+
+	/*
+	 * ND can call a profile ready while some of its scenarios are still
+	 * running, so keep polling until every scenario has finished.
+	 */
+	let mut transient_failures = 0;
+	loop {
+		let scenarios = match scenario_results(profile, &state.nd_key, &profile_url) {
+			Ok(scenarios) => {
+				transient_failures = 0;
+				scenarios
+			}
+			Err(ResultsError::Transient(detail)) if transient_failures < RESULTS_RETRIES => {
+				transient_failures += 1;
+				info!(
+					logger,
+					"Could not get the results from ND ({}); trying again.", detail
+				);
+				std::thread::sleep(poll_interval);
+				if cancel.is_cancelled() {
+					return cancelled();
+				}
+				continue;
+			}
+			Err(e) => {
+				error!(
+					logger,
+					"Failed to get the results of the profile from ND: {}", e
+				);
+				let detail = match e {
+					ResultsError::Transient(detail) => {
+						format!("{}, still after {} retries", detail, RESULTS_RETRIES)
+					}
+					ResultsError::Failed(detail) => detail,
+				};
+				return ProfileOutcome::ResultsUnavailable {
+					profile_url: profile_url.to_string(),
+					detail,
+				};
+			}
+		};
+
+		let pending = scenarios.iter().filter(|s| s.is_pending()).count();
+		if pending == 0 {
+			return ProfileOutcome::Success {
+				profile_url: profile_url.to_string(),
+				scenarios,
+			};
+		}
+		if Instant::now() >= deadline {
+			error!(
+				logger,
+				"Timeout with {} scenarios still pending on ND.", pending
+			);
+			return ProfileOutcome::Timeout {
+				profile_url: profile_url.to_string(),
+				scenarios,
+			};
+		}
+
+		info!(logger, "{} scenarios still pending on ND.", pending);
+		report_progress(state, &scenarios, &mut reported);
+		progress();
+		std::thread::sleep(poll_interval);
+		if cancel.is_cancelled() {
+			return cancelled();
+		}
+	}
+}
+
+/*
+ * Say how many of the profile's scenarios ND has finished on the job's
+ * progress comment, when it has one and the count has changed since
+ * `reported`.
+ */
+fn report_progress(
+	state: &ServerState,
+	scenarios: &[ScenarioResult],
+	reported: &mut Option<(usize, usize)>,
+) {
+	let comment_url = match &state.progress_comment_url {
+		Some(comment_url) => comment_url,
+		None => return,
+	};
+	if scenarios.is_empty() {
+		return;
+	}
+	let total = scenarios.len();
+	let complete = total - scenarios.iter().filter(|s| s.is_pending()).count();
+	if *reported == Some((complete, total)) {
+		return;
+	}
+	*reported = Some((complete, total));
+	let comment = format!("Profiling: {}/{} scenarios complete.", complete, total);
+	if let Err(e) = state.github.edit_comment(comment_url, &comment) {
+		error!(
+			state.logger,
+			"Failed to update the profiling progress: {}", e
+		);
+	}
+}
+
+/*
+ * The profiler only says whether it got the results, so when it didn't,
+ * ask ND why.
+ */
+fn scenario_results(
+	profile: &Profiler,
+	nd_key: &str,
+	profile_url: &reqwest::Url,
+) -> std::result::Result<Vec<ScenarioResult>, ResultsError> {
+	match profile.get_profile_result(profile_url) {
+		Some(profile_result) => Ok(profile_result
+			.profiles
+			.iter()
+			.map(|p| ScenarioResult {
+				name: p.get_scenario_name().to_string(),
+				status: p.get_status().to_string(),
+				time_in_ms: p.get_time_in_ms() as u64,
+				ignored: false,
+			})
+			.collect()),
+		None => Err(diagnose_missing_results(nd_key, profile_url)),
+	}
+}
+
+/*
+ * The part of the state that can be reloaded while the server runs.
+ */
+struct Settings {
+	config: Config,
+	profilers: Vec<String>,
+}
+
+#[derive(Clone)]
+struct ServerState {
+	pub nd_key: String,
+	pub profilers: Vec<String>,
+	pub config: Config,
+	pub config_file: String,
+	settings: Arc<RwLock<Settings>>,
+	pub github: GitHubClient,
+	pub baselines: BaselineCache,
+	pub results: ResultCache,
+	pub in_flight: InFlightDirs,
+	pub dir_failures: DirFailures,
+	pub low_disk: LowDisk,
+	pub readiness: Readiness,
+	pub nd_breaker: Breaker,
+	pub deliveries: RecentDeliveries,
+	pub rate_limiter: RateLimiter,
+	/// The GitHub App's installations, when authenticating as one.
+	pub installations: Option<Installations>,
+	pub timings: Timings,
+	pub metrics: Metrics,
+	pub queue: WorkQueue,
+	/// Notifications the webhook accepted, waiting to become jobs.
+	pub intake: Intake<Accepted>,
+	pub pending: PendingJobs,
+	/// The comment to keep up to date with how far the job's profile
+	/// has got, when the job has one.
+	pub progress_comment_url: Option<String>,
+	pub logger: Logger,
+}
+
+impl ServerState {
+	fn new(
+		git_key: String,
+		nd_key: String,
+		profilers: &[String],
+		config: Config,
+		config_file: &str,
+		dry_run: bool,
+		logger: Logger,
+	) -> Self {
+		Self {
+			github: GitHubClient::new(&git_key, &config.github_api_base, dry_run, logger.clone()),
+			baselines: BaselineCache::default(),
+			results: ResultCache::default(),
+			in_flight: InFlightDirs::default(),
+			dir_failures: DirFailures::default(),
+			low_disk: LowDisk::default(),
+			readiness: Readiness::default(),
+			nd_breaker: Breaker::default(),
+			deliveries: RecentDeliveries::default(),
+			rate_limiter: RateLimiter::default(),
+			installations: None,
+			timings: Timings::default(),
+			metrics: Metrics::default(),
+			queue: WorkQueue::new(),
+			intake: Intake::new(),
+			pending: PendingJobs::default(),
+			progress_comment_url: None,
+			nd_key,
+			profilers: profilers.to_vec(),
+			settings: Arc::new(RwLock::new(Settings {
+				config: config.clone(),
+				profilers: profilers.to_vec(),
+			})),
+			config,
+			config_file: config_file.to_string(),
+			logger,
+		}
+	}
+
+	/*
+	 * A copy of the state with the most recently loaded settings. Each
+	 * job takes one when it starts so that a reload never changes the
+	 * configuration out from under it.
+	 */
+	fn current(&self) -> Self {
+		let settings = self.settings.read().unwrap();
+		Self {
+			config: settings.config.clone(),
+			profilers: settings.profilers.clone(),
+			..self.clone()
+		}
+	}
+
+	/*
+	 * A copy of the state for the app whose webhook is mounted at
+	 * `path`, if there still is one.
+	 */
+	fn for_app(&self, path: &str) -> Option<Self> {
+		let config = self.config.for_app(path)?;
+		let overrides = &self.config.apps[path];
+		let profilers =
+			self.merge_profilers(overrides.profilers.as_deref(), &overrides.extra_profilers);
+		Some(Self {
+			nd_key: config
+				.app_nd_key
+				.clone()
+				.unwrap_or_else(|| self.nd_key.clone()),
+			config,
+			profilers,
+			..self.clone()
+		})
+	}
+
+	/*
+	 * Who may ask for profiles given a list of its own, if there is
+	 * one, in place of the current list, and some extra profilers.
+	 */
+	fn merge_profilers(&self, own: Option<&[String]>, extra: &[String]) -> Vec<String> {
+		let mut profilers: Vec<String> = match own {
+			Some(profilers) => profilers.iter().map(|s| s.to_lowercase()).collect(),
+			None => self.profilers.clone(),
+		};
+		for extra in extra {
+			let extra = extra.to_lowercase();
+			if !profilers.contains(&extra) {
+				profilers.push(extra);
+			}
+		}
+		profilers
+	}
+
+	/*
+	 * The client to act on GitHub with for a notification from
+	 * `installation_id`. Without a GitHub App there is only the one; if
+	 * an installation's token can't be had, the configured one is
+	 * tried.
+	 */
+	fn github_for(&self, installation_id: Option<u64>) -> GitHubClient {
+		match (&self.installations, installation_id) {
+			(Some(installations), Some(installation_id)) => {
+				match installations.client(installation_id, &self.github) {
+					Ok(github) => github,
+					Err(e) => {
+						error!(self.logger, "{}", e);
+						self.github.clone()
+					}
+				}
+			}
+			_ => self.github.clone(),
+		}
+	}
+
+	/*
+	 * A copy of the state with a repository's overrides applied.
+	 */
+	fn for_repo(&self, repo_full_name: &str) -> Self {
+		let profilers = self.merge_profilers(
+			self.config.repo_profilers(repo_full_name),
+			self.config.repo_extra_profilers(repo_full_name),
+		);
+		let nd_key = match (
+			self.config.repo_nd_key(repo_full_name),
+			self.config.app_nd_key.as_deref(),
+		) {
+			(Some(nd_key), _) | (None, Some(nd_key)) => nd_key.to_string(),
+			(None, None) => self.nd_key.clone(),
+		};
+		Self {
+			config: self.config.for_repo(repo_full_name),
+			profilers,
+			nd_key,
+			..self.clone()
+		}
+	}
+
+	/*
+	 * A copy of the state that profiles with the variant's own
+	 * NimbleDroid project and timeout, if it has them.
+	 */
+	fn for_variant(&self, variant: &Variant) -> Self {
+		let mut state = self.clone();
+		if let Some(nd_key) = &variant.nd_key {
+			state.nd_key = nd_key.clone();
+		}
+		if let Some(profile_timeout_secs) = variant.profile_timeout_secs {
+			state.config.profile_timeout_secs = profile_timeout_secs;
+		}
+		state
+	}
+
+	/*
+	 * Re-read the configuration and the profilers list it points at.
+	 * Nothing changes unless both load cleanly.
+	 */
+	fn reload(&self) -> std::result::Result<(), String> {
+		let config = config_from_env(config_from_file(&self.config_file)?)?;
+		let profilers = lowercase(load_profilers(&config.profilers)?);
+		*self.settings.write().unwrap() = Settings { config, profilers };
+		Ok(())
+	}
+
+	fn set_profilers(&self, profilers: Vec<String>) {
+		self.settings.write().unwrap().profilers = lowercase(profilers);
+	}
+}
+
+/*
+ * Run `work` on a thread of its own, and wait for it without holding up
+ * any of the server's threads: a handler that waits on GitHub, Docker or
+ * NimbleDroid would stall every other request, /health and /metrics
+ * among them. None if `work` panicked.
+ */
+async fn off_the_server<T, F>(work: F) -> Option<T>
+where
+	T: Send + 'static,
+	F: FnOnce() -> T + Send + 'static,
+{
+	let (done, result) = oneshot::channel();
+	std::thread::spawn(move || {
+		let _ = done.send(work());
+	});
+	result.await.ok()
+}
+
+/*
+ * Every response, error or not, is a JSON envelope: whether the request
+ * worked, a message for people and whatever data the endpoint returns.
+ */
+fn reply(status: StatusCode, message: &str, data: Value) -> tide::Response {
+	let mut response = tide::response::json(json!({
+		"ok": status.is_success(),
+		"message": message,
+		"data": data,
+	}));
+	*response.status_mut() = status;
+	response
+}
+
+static NOT_ADMIN: &str = "This needs the admin token";
+static BAD_SIGNATURE: &str = "The delivery isn't validly signed";
+
+/*
+ * Whether the request carries the configured admin token. Compare every
+ * byte so the time taken doesn't reveal how much of a guess was right.
+ */
+fn is_admin(request: &Context<ServerState>) -> bool {
+	let expected = match request.state().current().config.admin_token {
+		Some(token) => format!("Bearer {}", token),
+		None => return false,
+	};
+	let given = match request
+		.headers()
+		.get("Authorization")
+		.and_then(|v| v.to_str().ok())
+	{
+		Some(given) => given.to_string(),
+		None => return false,
+	};
+	given.len() == expected.len()
+		&& given
+			.bytes()
+			.zip(expected.bytes())
+			.fold(0, |diff, (a, b)| diff | (a ^ b))
+			== 0
+}
+
+async fn handle_health(request: Context<ServerState>) -> EndpointResult {
+	let state = request.state();
+	let queue = &state.queue;
+	let dir_failures = state.dir_failures.consecutive();
+	let low_disk = state.low_disk.is_low();
+	let healthy = dir_failures < state.current().config.dir_failures_unhealthy && !low_disk;
+	let paused = queue.is_paused();
+	/* A pause is deliberate, so it doesn't make the bot unhealthy. */
+	let status = match (healthy, paused) {
+		(false, _) => "unhealthy",
+		(true, true) => "paused",
+		(true, false) => "ok",
+	};
+	let data = json!({
+		"status": status,
+		"paused": paused,
+		"active_jobs": queue.active(),
+		"queued_jobs": queue.depth(),
+		"stuck_workers": queue.stuck(),
+		"artifact_dir_failures": dir_failures,
+		"low_disk": low_disk,
+		"nd_available": !state.nd_breaker.is_open(),
+		"nd_failures": state.nd_breaker.consecutive(),
+		"durations": state.timings.to_json(),
+	});
+	let code = if healthy {
+		StatusCode::OK
+	} else {
+		StatusCode::SERVICE_UNAVAILABLE
+	};
+	Ok(reply(code, status, data))
+}
+
+/*
+ * Liveness: the process answers. Restarting it won't help with anything
+ * else.
+ */
+async fn handle_livez(_request: Context<ServerState>) -> EndpointResult {
+	Ok(reply(StatusCode::OK, "Alive", json!({})))
+}
+
+/*
+ * Readiness: the bot can take work now. Unlike /health, a pause makes
+ * it unready, as do a failing ND, a short disk and the latest checks of
+ * Docker and the GitHub token.
+ */
+async fn handle_readyz(request: Context<ServerState>) -> EndpointResult {
+	let state = request.state();
+	let config = &state.current().config;
+	let mut reasons = vec![];
+	if state.queue.is_paused() {
+		reasons.push("profiling is paused".to_string());
+	}
+	if state.dir_failures.consecutive() >= config.dir_failures_unhealthy {
+		reasons.push("artifact directories can't be made".to_string());
+	}
+	if state.low_disk.is_low() {
+		reasons.push("the build host is low on disk space".to_string());
+	}
+	if state.nd_breaker.is_open() {
+		reasons.push("NimbleDroid keeps failing".to_string());
+	}
+	if config.warmup_gates_ready && state.readiness.is_warming_up() {
+		reasons.push("the warmup hasn't finished".to_string());
+	}
+	match state.readiness.failures() {
+		Some(failures) => reasons.extend(failures),
+		None => reasons.push("Docker and the GitHub token haven't been checked yet".to_string()),
+	}
+	let ready = reasons.is_empty();
+	let (code, message) = if ready {
+		(StatusCode::OK, "Ready")
+	} else {
+		(StatusCode::SERVICE_UNAVAILABLE, "Not ready")
+	};
+	Ok(reply(
+		code,
+		message,
+		json!({ "ready": ready, "reasons": reasons }),
+	))
+}
+
+/*
+ * Warm up wherever builds run, for warmup_on_start.
+ */
+fn run_warmup(state: ServerState) {
+	let command = warmup_command(&state.config);
+	info!(state.logger, "Warming up: {}", command.join(" "));
+	let started = Instant::now();
+	match builder_from_config(&state.config).warm_up(&command) {
+		Ok(()) => info!(
+			state.logger,
+			"Warmed up in {}s.",
+			started.elapsed().as_secs()
+		),
+		Err(e) => error!(state.logger, "(Err) {}", e),
+	}
+	state.readiness.set_warming_up(false);
+}
+
+/*
+ * Check Docker and the GitHub token for /readyz, forever. The token is
+ * whichever one is current, so a rotation is checked too.
+ */
+fn run_readiness_checks(state: ServerState) {
+	loop {
+		let current = state.current();
+		let config = &current.config;
+		let build_image = if config.build_enabled {
+			Some(config.build_image.as_str())
+		} else {
+			None
+		};
+		let checks = readiness_checks(
+			build_image,
+			&config.build_hosts,
+			state.github.api_base(),
+			&state.github.token(),
+		);
+		for check in &checks {
+			if let Err(detail) = &check.result {
+				error!(
+					state.logger,
+					"Readiness check {} failed: {}", check.name, detail
+				);
+			}
+		}
+		state.readiness.update(&checks);
+		std::thread::sleep(Duration::from_secs(config.readiness_interval_secs));
+	}
+}
+
+/*
+ * The metrics as JSON, or in Prometheus's text format for a client that
+ * asks for it, as a Prometheus scraper does.
+ */
+async fn handle_metrics(request: Context<ServerState>) -> EndpointResult {
+	let state = request.state();
+	let accept = request
+		.headers()
+		.get("Accept")
+		.and_then(|v| v.to_str().ok())
+		.unwrap_or("");
+	if accept.contains("text/plain") || accept.contains("application/openmetrics-text") {
+		let text = state.metrics.to_prometheus(
+			state.queue.depth(),
+			state.queue.active(),
+			state.intake.waiting(),
+		);
+		let response = tide::http::Response::builder()
+			.status(StatusCode::OK)
+			.header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+			.body(text.into())
+			.unwrap();
+		return Ok(response);
+	}
+	Ok(reply(
+		StatusCode::OK,
+		"Metrics",
+		json!({
+			"queue_depth": state.queue.depth(),
+			"active_jobs": state.queue.active(),
+			"intake_waiting": state.intake.waiting(),
+			"durations": state.timings.to_json(),
+		}),
+	))
+}
+
+async fn handle_version(_request: Context<ServerState>) -> EndpointResult {
+	Ok(reply(
+		StatusCode::OK,
+		"Version",
+		json!({
+			"version": VERSION,
+			"git_commit": GIT_COMMIT,
+			"built_at": BUILT_AT.parse::<u64>().unwrap_or(0),
+		}),
+	))
+}
+
+async fn handle_commands(_request: Context<ServerState>) -> EndpointResult {
+	Ok(reply(StatusCode::OK, "Commands", command::commands_json()))
+}
+
+async fn handle_admin_reload(request: Context<ServerState>) -> EndpointResult {
+	if !is_admin(&request) {
+		return Ok(reply(StatusCode::UNAUTHORIZED, NOT_ADMIN, Value::Null));
+	}
+	/* A profilers list at a URL is fetched again. */
+	let reloading = request.state().clone();
+	let reloaded = off_the_server(move || reloading.reload())
+		.await
+		.unwrap_or_else(|| Err("reloading panicked".to_string()));
+	match reloaded {
+		Ok(()) => {
+			info!(request.state().logger, "Reloaded the configuration.");
+			Ok(reply(StatusCode::OK, "Reloaded", Value::Null))
+		}
+		Err(e) => {
+			error!(request.state().logger, "Reload failed: {}", e);
+			Ok(reply(
+				StatusCode::INTERNAL_SERVER_ERROR,
+				&format!("Reload failed: {}", e),
+				Value::Null,
+			))
+		}
+	}
+}
+
+async fn handle_admin_flush_cache(request: Context<ServerState>) -> EndpointResult {
+	if !is_admin(&request) {
+		return Ok(reply(StatusCode::UNAUTHORIZED, NOT_ADMIN, Value::Null));
+	}
+	let cleared = request.state().baselines.clear();
+	let cleared_results = request.state().results.clear();
+	info!(
+		request.state().logger,
+		"Flushed {} cached baselines and {} cached results.", cleared, cleared_results
+	);
+	Ok(reply(
+		StatusCode::OK,
+		&format!(
+			"Flushed {} cached baselines and {} cached results",
+			cleared, cleared_results
+		),
+		json!({ "baselines": cleared, "results": cleared_results }),
+	))
+}
+
+async fn handle_admin_pause(request: Context<ServerState>) -> EndpointResult {
+	if !is_admin(&request) {
+		return Ok(reply(StatusCode::UNAUTHORIZED, NOT_ADMIN, Value::Null));
+	}
+	request.state().queue.pause();
+	info!(request.state().logger, "Paused profiling.");
+	Ok(reply(StatusCode::OK, "Paused", Value::Null))
+}
+
+async fn handle_admin_resume(request: Context<ServerState>) -> EndpointResult {
+	if !is_admin(&request) {
+		return Ok(reply(StatusCode::UNAUTHORIZED, NOT_ADMIN, Value::Null));
+	}
+	request.state().queue.resume();
+	info!(request.state().logger, "Resumed profiling.");
+	Ok(reply(StatusCode::OK, "Resumed", Value::Null))
+}
+
+/*
+ * The first value of `name` in the request's query string, decoded.
+ */
+fn query_param(request: &Context<ServerState>, name: &str) -> Option<String> {
+	let prefix = format!("{}=", name);
+	request.uri().query().and_then(|query| {
+		query
+			.split('&')
+			.filter_map(|pair| pair.strip_prefix(prefix.as_str()))
+			.find_map(|value| percent_decode(value.as_bytes()).decode_utf8().ok())
+			.map(|value| value.into_owned())
+	})
+}
+
+/*
+ * Quote a CSV field when it needs it.
+ */
+fn csv_field(field: &str) -> String {
+	if field.contains(&[',', '"', '\n', '\r'][..]) {
+		format!("\"{}\"", field.replace('"', "\"\""))
 	} else {
-		info!(logger, "Done waiting for the profile.");
-
-		if let Some(profile_result) = profile.get_profile_result(&profile_url) {
-			comment_string.push_str(&"Scenario | Status | Time (ms)\\n".to_string());
-			comment_string.push_str(&"---------|--------|----------\\n".to_string());
-			for p in profile_result.profiles {
-				comment_string.push_str(&format!(
-					"{} | {} | {}\\n",
-					p.get_scenario_name(),
-					p.get_status(),
-					p.get_time_in_ms()
+		field.to_string()
+	}
+}
+
+/*
+ * The rows of /history.csv for one run: one per scenario, or one saying
+ * how the run ended when it has none.
+ */
+fn history_rows(run: &PastRun) -> String {
+	let timestamp = run
+		.recorded_at
+		.duration_since(UNIX_EPOCH)
+		.map_or(0, |d| d.as_secs())
+		.to_string();
+	let row = |scenario: &str, status: &str, time: &str| {
+		let fields = [
+			timestamp.as_str(),
+			&run.html_url,
+			&run.sha,
+			&run.variant,
+			scenario,
+			status,
+			time,
+		];
+		let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+		format!("{}\r\n", fields.join(","))
+	};
+	if run.scenarios.is_empty() {
+		return row("", run.result, "");
+	}
+	run.scenarios
+		.iter()
+		.map(|s| row(&s.name, &s.status, &s.time_in_ms.to_string()))
+		.collect()
+}
+
+/*
+ * The profile history as CSV, for spreadsheets: every run the bot knows
+ * of since it last started, of `?repo=owner/name` if given, and since
+ * `?since=` (Unix seconds) if given. Rows are written out as the body is
+ * sent rather than all at once. It needs the admin token, since the
+ * repositories may be private.
+ */
+async fn handle_history_csv(request: Context<ServerState>) -> EndpointResult {
+	if !is_admin(&request) {
+		return Ok(reply(StatusCode::UNAUTHORIZED, NOT_ADMIN, Value::Null));
+	}
+	let repo = query_param(&request, "repo");
+	let since = match query_param(&request, "since").map(|since| since.parse::<u64>()) {
+		None => None,
+		Some(Ok(since)) => Some(UNIX_EPOCH + Duration::from_secs(since)),
+		Some(Err(_)) => {
+			return Ok(reply(
+				StatusCode::BAD_REQUEST,
+				"Say since when as ?since=<Unix seconds>",
+				Value::Null,
+			))
+		}
+	};
+	let runs: Vec<PastRun> = request
+		.state()
+		.results
+		.all_runs()
+		.into_iter()
+		.filter(|run| match &repo {
+			Some(repo) => run.repo_full_name.eq_ignore_ascii_case(repo),
+			None => true,
+		})
+		.filter(|run| match since {
+			Some(since) => run.recorded_at >= since,
+			None => true,
+		})
+		.collect();
+	let header = "timestamp,pull_request,sha,variant,scenario,status,time_ms\r\n".to_string();
+	let rows = std::iter::once(header).chain(runs.into_iter().map(|run| history_rows(&run)));
+	let body =
+		http_service::Body::from_stream(futures::stream::iter(rows.map(|row| Ok(row.into()))));
+	let response = tide::http::Response::builder()
+		.status(StatusCode::OK)
+		.header("Content-Type", "text/csv; charset=utf-8")
+		.body(body)
+		.unwrap();
+	Ok(response)
+}
+
+static CANCELLED_BY_OPERATOR: &str = "This profile was cancelled by an operator.";
+
+async fn handle_admin_cancel(request: Context<ServerState>) -> EndpointResult {
+	if !is_admin(&request) {
+		return Ok(reply(StatusCode::UNAUTHORIZED, NOT_ADMIN, Value::Null));
+	}
+	let repo = match query_param(&request, "repo") {
+		Some(repo) if repo.contains('/') => repo,
+		_ => {
+			return Ok(reply(
+				StatusCode::BAD_REQUEST,
+				"Say which repository as ?repo=owner/name",
+				Value::Null,
+			))
+		}
+	};
+	let state = request.state().current();
+	let cancelled = state.queue.cancel_repo(&repo);
+	error!(
+		state.logger,
+		"(Admin) Cancelled {} jobs for {}.",
+		cancelled.len(),
+		repo
+	);
+	let ids: Vec<String> = cancelled.iter().map(|(id, _)| id.clone()).collect();
+	/* Nobody waits for the builds to be killed or the comments posted. */
+	std::thread::spawn(move || {
+		for (id, comments_url) in &cancelled {
+			kill_build(&state, id);
+			if let Err(e) = state
+				.github
+				.post_comment(comments_url, CANCELLED_BY_OPERATOR)
+			{
+				error!(
+					state.logger,
+					"Failed to say job {} was cancelled: {}", id, e
+				);
+			}
+		}
+	});
+	Ok(reply(
+		StatusCode::OK,
+		&format!("Cancelled {} jobs for {}", ids.len(), repo),
+		json!({ "repo": repo, "jobs": ids }),
+	))
+}
+
+async fn handle_post(request: Context<ServerState>) -> EndpointResult {
+	handle_delivery(request, None).await
+}
+
+/*
+ * Handle a webhook delivery for the app mounted at `app`, or with the
+ * global settings when that is `None`.
+ */
+async fn handle_delivery(mut request: Context<ServerState>, app: Option<String>) -> EndpointResult {
+	info!(request.state().logger, "Start handle_post");
+	request.state().metrics.delivered();
+	let current = request.state().current();
+	let current = match &app {
+		Some(path) => match current.for_app(path) {
+			Some(current) => current,
+			None => {
+				info!(
+					request.state().logger,
+					"There is no longer an app at {}.", path
+				);
+				return Ok(reply(
+					StatusCode::NOT_FOUND,
+					"There is no such app",
+					Value::Null,
+				));
+			}
+		},
+		None => current,
+	};
+	let event = request
+		.headers()
+		.get("X-GitHub-Event")
+		.and_then(|v| v.to_str().ok())
+		.unwrap_or("issue_comment")
+		.to_string();
+	let delivery = request
+		.headers()
+		.get("X-GitHub-Delivery")
+		.and_then(|v| v.to_str().ok())
+		.map(|v| v.to_string());
+	let signature = request
+		.headers()
+		.get("X-Hub-Signature-256")
+		.and_then(|v| v.to_str().ok())
+		.map(|v| v.to_string());
+	let encoding = request
+		.headers()
+		.get("Content-Encoding")
+		.and_then(|v| v.to_str().ok())
+		.map(|v| v.to_string());
+	info!(request.state().logger, "event: {}", event);
+	if !HANDLED_EVENTS.contains(&event.as_str()) {
+		info!(request.state().logger, "Ignoring a {} event.", event);
+		let response = tide::http::Response::builder()
+			.status(StatusCode::NO_CONTENT)
+			.body(http_service::Body::empty())
+			.unwrap();
+		return Ok(response);
+	}
+	let timeout = Duration::from_secs(current.config.request_body_timeout_secs);
+	let body_bytes = match body_within(&mut request, timeout).await {
+		Ok(body_bytes) => body_bytes,
+		Err(status) => {
+			error!(
+				request.state().logger,
+				"Giving up on reading the notification: {}", status
+			);
+			return Ok(reply(
+				status,
+				"Could not read the notification in time",
+				Value::Null,
+			));
+		}
+	};
+	let raw_bytes = &body_bytes;
+	/*
+	 * Check the bytes exactly as they arrived: that's what GitHub
+	 * signed, not what they decode to. When repositories have secrets
+	 * of their own, that has to wait until the delivery says which
+	 * repository it is for.
+	 */
+	let config = current.config.clone();
+	let per_repo = config.has_repo_webhook_secrets();
+	let signed = |secrets: &[String]| {
+		secrets.is_empty() || signature::signed_by_any(secrets, raw_bytes, signature.as_deref())
+	};
+	if !per_repo && !signed(&config.webhook_secrets) {
+		error!(
+			request.state().logger,
+			"Rejecting a delivery without a valid signature."
+		);
+		return Ok(reply(StatusCode::UNAUTHORIZED, BAD_SIGNATURE, Value::Null));
+	}
+	let body_bytes = match inflate_body(raw_bytes.to_vec(), encoding.as_deref()) {
+		Ok(body_bytes) => body_bytes,
+		Err(e) => {
+			error!(request.state().logger, "{}", e);
+			return Ok(reply(StatusCode::BAD_REQUEST, &e, Value::Null));
+		}
+	};
+	let parsed = parse_body_bytes(&body_bytes);
+	if per_repo {
+		let repo_full_name = match &parsed {
+			Ok(parsed) => parsed["repository"]["full_name"].as_str(),
+			Err(_) => None,
+		};
+		let secrets = match repo_full_name {
+			Some(repo_full_name) => config.for_repo(repo_full_name).webhook_secrets,
+			None => config.webhook_secrets.clone(),
+		};
+		if !signed(&secrets) {
+			error!(
+				request.state().logger,
+				"Rejecting a delivery for {:?} without a valid signature.", repo_full_name
+			);
+			return Ok(reply(StatusCode::UNAUTHORIZED, BAD_SIGNATURE, Value::Null));
+		}
+	}
+	/*
+	 * Only once the signature is checked, so that a delivery nobody
+	 * signed can't stand in for the real one.
+	 */
+	if let (Some(delivery), Some(window)) = (&delivery, config.dedup_window_secs) {
+		if request
+			.state()
+			.deliveries
+			.is_duplicate(delivery, Duration::from_secs(window))
+		{
+			info!(
+				request.state().logger,
+				"Ignoring delivery {}, which already came.", delivery
+			);
+			return Ok(reply(
+				StatusCode::OK,
+				"Ignored",
+				json!({ "queued": false, "reason": "a duplicate delivery" }),
+			));
+		}
+	}
+	let job = match parsed {
+		Ok(parsed) => {
+			let id = job_id(delivery.as_deref());
+			let state = ServerState {
+				logger: current.logger.new(o!("job" => id.clone())),
+				..current
+			};
+			if let Err(reason) = preflight(&state, &event, &parsed) {
+				debug!(state.logger, "Ignoring the notification: {}.", reason);
+				return Ok(reply(
+					StatusCode::OK,
+					"Ignored",
+					json!({ "job": id, "queued": false, "reason": reason }),
+				));
+			}
+			/*
+			 * The intake threads can't answer GitHub any more, so a full
+			 * queue is turned away here, with a 503 it will redeliver
+			 * on. One that fills up in the meantime still gets its
+			 * comment from enqueue_job.
+			 */
+			let full = match state.config.max_queued_jobs {
+				Some(max) => state.queue.depth() >= max,
+				None => false,
+			};
+			if full {
+				info!(
+					state.logger,
+					"Turning the notification away: the queue is full."
+				);
+				return Ok(reply(
+					StatusCode::SERVICE_UNAVAILABLE,
+					QUEUE_FULL,
+					json!({ "job": id, "queued": false }),
+				));
+			}
+			if state.config.persistent_queue {
+				if let Err(e) = state.pending.record(&id, &event, &parsed) {
+					error!(state.logger, "{}", e);
+				}
+			}
+			/*
+			 * Whether it makes a job can take requests to GitHub, so
+			 * that is left to the intake threads, which also say where
+			 * in the queue the job is.
+			 */
+			let accepted = Accepted {
+				state: state.clone(),
+				id: id.clone(),
+				event,
+				notification: parsed,
+			};
+			if state.intake.submit(accepted, MAX_INTAKE_WAITING).is_err() {
+				error!(
+					state.logger,
+					"Turning the notification away: {} are waiting to be taken in.",
+					MAX_INTAKE_WAITING
+				);
+				if let Err(e) = state.pending.remove(&id) {
+					error!(state.logger, "{}", e);
+				}
+				return Ok(reply(
+					StatusCode::SERVICE_UNAVAILABLE,
+					"Too many notifications are waiting to be taken in",
+					json!({ "job": id }),
 				));
 			}
-		} else {
-			comment_string = "Failed to get the results of the profile from ND.".to_string();
-			error!(logger, "{}", comment_string);
+			json!({ "job": id })
+		}
+		Err(e) => {
+			error!(request.state().logger, "{}", e);
+			return Ok(reply(StatusCode::BAD_REQUEST, &e, Value::Null));
+		}
+	};
+	info!(request.state().logger, "End handle_post");
+	Ok(reply(StatusCode::ACCEPTED, "Accepted", job))
+}
+
+/*
+ * A notification the webhook accepted, waiting for an intake thread to
+ * turn it into a job.
+ */
+struct Accepted {
+	state: ServerState,
+	id: String,
+	event: String,
+	notification: Value,
+}
+
+/*
+ * How many intake threads turn notifications into jobs. That is mostly
+ * waiting on GitHub, so a few keep up with a busy webhook.
+ */
+const INTAKE_WORKERS: usize = 4;
+
+/*
+ * How many accepted notifications can wait for an intake thread before
+ * the webhook turns more away.
+ */
+const MAX_INTAKE_WAITING: usize = 256;
+
+/*
+ * Turn a notification into a job and queue it, posting whatever the job
+ * has to say about its place in the queue. This runs on an intake
+ * thread: it may well ask GitHub about the pull request, and wait out
+ * retries while it does.
+ */
+fn take_in(accepted: Accepted) {
+	let Accepted {
+		state,
+		id,
+		event,
+		notification,
+	} = accepted;
+	let state = ServerState {
+		github: state.github_for(installation_id(&notification)),
+		..state
+	};
+	let queued = match job_for(&state, &id, &event, notification) {
+		Some(ctx) => enqueue_job(&state, QueuedJob::new(ctx)),
+		None => false,
+	};
+	if !queued {
+		if let Err(e) = state.pending.remove(&id) {
+			error!(state.logger, "{}", e);
 		}
 	}
+}
 
-	let comment_post_client = reqwest::Client::new();
-	match comment_post_client
-		.post(&pr_url)
-		.header(
-			reqwest::header::AUTHORIZATION,
-			format!("token {}", state.git_key),
-		)
-		.body(format!("{{ \"body\": \"{}\" }}", comment_string))
-		.send()
-	{
-		Ok(o) => {
-			info!(logger, "Posted a comment: {:?}", o);
+/*
+ * Read a request's body, unless it takes longer than `timeout` to
+ * arrive: a client trickling it in would otherwise hold the connection
+ * for as long as it liked. The timer is the runtime's, and is dropped
+ * along with the wait as soon as the body is in.
+ */
+async fn body_within(
+	request: &mut Context<ServerState>,
+	timeout: Duration,
+) -> std::result::Result<Vec<u8>, StatusCode> {
+	let expired = Delay::new(Instant::now() + timeout).compat();
+	let body = request.body_bytes();
+	pin_mut!(body);
+	match select(body, expired).await {
+		Either::Left((Ok(body), _)) => Ok(body),
+		Either::Left((Err(_), _)) => Err(StatusCode::BAD_REQUEST),
+		Either::Right((Ok(()), _)) => Err(StatusCode::REQUEST_TIMEOUT),
+		/* Without a timer to go by, wait as long as the body takes. */
+		Either::Right((Err(_), body)) => body.await.map_err(|_| StatusCode::BAD_REQUEST),
+	}
+}
+
+/*
+ * Profile an uploaded APK without building anything or talking to
+ * GitHub. The upload is a multipart form with the APK in its `apk`
+ * field. The response waits for ND, so it can take as long as a
+ * profile does.
+ */
+async fn handle_profile_apk(mut request: Context<ServerState>) -> EndpointResult {
+	if !is_admin(&request) {
+		return Ok(reply(StatusCode::UNAUTHORIZED, NOT_ADMIN, Value::Null));
+	}
+	let state = request.state().current();
+	let logger = &state.logger;
+
+	/*
+	 * Insist on a length up front so an oversized upload is refused
+	 * before any of it is read.
+	 */
+	let length: Option<u64> = request
+		.headers()
+		.get("Content-Length")
+		.and_then(|v| v.to_str().ok())
+		.and_then(|v| v.parse().ok());
+	match length {
+		None => {
+			return Ok(reply(
+				StatusCode::LENGTH_REQUIRED,
+				"The upload needs a Content-Length",
+				Value::Null,
+			))
 		}
+		Some(length) if length > state.config.max_apk_upload_bytes => {
+			info!(logger, "Refused an APK upload of {} bytes.", length);
+			return Ok(reply(
+				StatusCode::PAYLOAD_TOO_LARGE,
+				&format!(
+					"The APK is bigger than {} bytes",
+					state.config.max_apk_upload_bytes
+				),
+				Value::Null,
+			));
+		}
+		Some(_) => (),
+	}
+
+	let mut form = request.body_multipart().await?;
+	let temp_dir = match TempDir::new("upload") {
+		Ok(temp_dir) => temp_dir,
 		Err(e) => {
-			error!(logger, "Failed to post a comment: {}", e);
+			error!(logger, "(Err) Failed to make an upload directory: {}", e);
+			return Ok(reply(
+				StatusCode::INTERNAL_SERVER_ERROR,
+				"Could not store the upload",
+				Value::Null,
+			));
 		}
 	};
+	let apk_path = temp_dir.path().join("upload.apk");
+	let mut received = false;
+	loop {
+		match form.read_entry() {
+			Ok(Some(mut field)) => {
+				if &*field.headers.name != "apk" {
+					continue;
+				}
+				let saved = File::create(&apk_path)
+					.and_then(|mut apk| std::io::copy(&mut field.data, &mut apk));
+				if let Err(e) = saved {
+					error!(logger, "(Err) Failed to save the uploaded APK: {}", e);
+					return Ok(reply(
+						StatusCode::INTERNAL_SERVER_ERROR,
+						"Could not store the upload",
+						Value::Null,
+					));
+				}
+				received = true;
+				break;
+			}
+			Ok(None) => break,
+			Err(e) => {
+				info!(logger, "Could not read the upload form: {}", e);
+				return Ok(reply(
+					StatusCode::BAD_REQUEST,
+					"Could not read the upload form",
+					Value::Null,
+				));
+			}
+		}
+	}
+	if !received {
+		info!(logger, "The upload form had no apk field.");
+		return Ok(reply(
+			StatusCode::BAD_REQUEST,
+			"The upload form has no apk field",
+			Value::Null,
+		));
+	}
 
-	info!(logger, "End   take_action.");
+	let apk_path = match apk_path.to_str() {
+		Some(apk_path) => apk_path.to_string(),
+		None => {
+			error!(
+				logger,
+				"(Err) The upload path {} isn't valid UTF-8.",
+				apk_path.display()
+			);
+			return Ok(reply(
+				StatusCode::INTERNAL_SERVER_ERROR,
+				"Could not store the upload",
+				Value::Null,
+			));
+		}
+	};
+	/*
+	 * Profiling takes minutes, which none of the server's threads can
+	 * spare. The upload is kept until it is done with.
+	 */
+	let profiling = state.clone();
+	let outcome = off_the_server(move || {
+		let outcome = profile_apk(&profiling, &apk_path, &CancelToken::default());
+		drop(temp_dir);
+		outcome
+	})
+	.await;
+	let outcome = match outcome {
+		Some(outcome) => outcome,
+		None => {
+			error!(logger, "(Err) Profiling the uploaded APK panicked.");
+			return Ok(reply(
+				StatusCode::INTERNAL_SERVER_ERROR,
+				"Could not profile the upload",
+				Value::Null,
+			));
+		}
+	};
+	Ok(reply(
+		StatusCode::OK,
+		outcome.summary(&state.config.scenario_budgets_ms),
+		outcome.to_json(),
+	))
 }
 
-#[derive(Clone)]
-struct ServerState {
-	pub git_key: String,
-	pub nd_key: String,
-	pub profilers: Vec<String>,
-	pub logger: Logger,
+/*
+ * Profile the default branch of every allowed repository, forever, on
+ * the configured schedule, caching each result as a baseline.
+ */
+fn run_baseline_schedule(shared: ServerState, interval: Duration) {
+	let logger = &shared.logger;
+	loop {
+		let state = shared.current();
+		for repo_full_name in &state.config.allowed_repos {
+			info!(logger, "Begin baseline profile of {}.", repo_full_name);
+			let (clone_url, sha) = match state.github.default_branch_head(repo_full_name) {
+				Ok(head) => head,
+				Err(e) => {
+					error!(logger, "Could not find a baseline to profile: {}", e);
+					continue;
+				}
+			};
+			if state.baselines.for_sha(&sha).is_some() {
+				info!(logger, "Baseline for {} is already cached.", sha);
+				continue;
+			}
+			let repo_state = state.for_repo(repo_full_name);
+			let config = &repo_state.config;
+			match run_profile(
+				&repo_state,
+				&job_id(None),
+				&clone_url,
+				&sha,
+				&config.default_variant,
+				&CancelToken::default(),
+			) {
+				Some((_, ProfileOutcome::Success { scenarios, .. })) => {
+					state.baselines.insert(&sha, scenarios);
+					info!(
+						logger,
+						"Cached the baseline for {} at {} ({} cached).",
+						repo_full_name,
+						sha,
+						state.baselines.len()
+					);
+				}
+				_ => error!(
+					logger,
+					"Baseline profile of {} at {} did not succeed.", repo_full_name, sha
+				),
+			}
+		}
+		std::thread::sleep(interval);
+	}
 }
 
-impl ServerState {
-	fn new(git_key: String, nd_key: String, profilers: &[String], logger: Logger) -> Self {
-		Self {
-			git_key,
-			nd_key,
-			profilers: profilers.to_vec(),
-			logger,
+/*
+ * Clean up the kept artifact directories, forever, on the configured
+ * schedule.
+ */
+fn run_artifact_cleanup(shared: ServerState) {
+	loop {
+		let state = shared.current();
+		let (artifact_root, retention) = match (
+			&state.config.artifact_root,
+			&state.config.artifact_retention,
+		) {
+			(Some(artifact_root), Some(retention)) => (artifact_root, retention),
+			_ => return,
+		};
+		clean_artifacts(
+			Path::new(artifact_root),
+			retention,
+			&state.in_flight,
+			&state.logger,
+		);
+		std::thread::sleep(Duration::from_secs(retention.interval_secs));
+	}
+}
+
+/*
+ * Stop a job's build container, if it is still running.
+ */
+fn kill_build(state: &ServerState, job_id: &str) {
+	builder_from_config(&state.config).kill(&job_name(job_id), &state.logger);
+}
+
+fn read_token(filename: &str) -> std::result::Result<String, String> {
+	let token = std::fs::read_to_string(filename)
+		.map_err(|e| format!("Oops, couldn't read the token in {}: {}", filename, e))?;
+	let token = token.trim();
+	if token.is_empty() {
+		return Err(format!("Oops, the token file {} is empty.", filename));
+	}
+	Ok(token.to_string())
+}
+
+/*
+ * Re-read the GitHub token file, forever, so that a rotated token is
+ * picked up without a restart. A file that can't be read keeps the
+ * current token.
+ */
+fn run_token_refresh(state: ServerState, filename: String, interval: Duration) {
+	let mut current = read_token(&filename).unwrap_or_default();
+	loop {
+		std::thread::sleep(interval);
+		match read_token(&filename) {
+			Ok(token) if token != current => {
+				state.github.set_token(&token);
+				current = token;
+				info!(state.logger, "Picked up a new GitHub token.");
+			}
+			Ok(_) => (),
+			Err(e) => error!(state.logger, "{}", e),
 		}
 	}
 }
 
-async fn handle_post(mut request: Context<ServerState>) -> EndpointResult<String> {
-	info!(request.state().logger, "Start handle_post");
-	if let Ok(body_bytes) = &request.body_bytes().await {
-		match parse_body_bytes(body_bytes) {
-			Ok(parsed) => {
-				let state = (*request.state()).clone();
-				info!(request.state().logger, "Begin spawn(take_action).");
-				std::thread::spawn(|| {
-					take_action(state, parsed);
-				});
-				info!(request.state().logger, "End spawn(take_action).");
+/*
+ * Mint installation tokens for the GitHub App, forever, each shortly
+ * before the last expires. A failure is retried every minute, which
+ * leaves time before the current token runs out.
+ */
+fn run_app_token_refresh(state: ServerState, installations: Installations) {
+	let mut wait = TOKEN_REFRESH;
+	loop {
+		std::thread::sleep(wait);
+		match installations.refresh(&state.github) {
+			Ok(()) => {
+				info!(state.logger, "Minted new GitHub App installation tokens.");
+				wait = TOKEN_REFRESH;
 			}
 			Err(e) => {
-				error!(
-					request.state().logger,
-					"Oops, could not parse the body of the notification: {}", e
-				);
+				error!(state.logger, "{}", e);
+				wait = Duration::from_secs(60);
 			}
 		}
 	}
-	info!(request.state().logger, "End handle_post");
-	Ok("Success".to_string())
 }
 
-fn profilers_from_file(filename: &str) -> Vec<String> {
-	if let Ok(f) = File::open(filename) {
-		if let Ok(profilers) = serde_json::from_reader(f) {
-			profilers
-		} else {
-			vec![]
+fn load_profilers(source: &ProfilersSource) -> std::result::Result<Vec<String>, String> {
+	match source {
+		ProfilersSource::Inline(profilers) => Ok(profilers.clone()),
+		ProfilersSource::File(filename) => {
+			let f = File::open(filename)
+				.map_err(|e| format!("Oops, couldn't open {}: {}", filename, e))?;
+			serde_json::from_reader(f)
+				.map_err(|e| format!("Oops, couldn't parse {}: {}", filename, e))
+		}
+		ProfilersSource::Url(url) => {
+			let mut response = reqwest::get(url.as_str())
+				.and_then(|response| response.error_for_status())
+				.map_err(|e| format!("Oops, couldn't fetch {}: {}", url, e))?;
+			response
+				.json()
+				.map_err(|e| format!("Oops, couldn't parse {}: {}", url, e))
+		}
+	}
+}
+
+fn lowercase(profilers: Vec<String>) -> Vec<String> {
+	profilers.into_iter().map(|s| s.to_lowercase()).collect()
+}
+
+/*
+ * Fetch the profilers list again, forever, for as long as it comes from
+ * a URL. A fetch that fails keeps the last good list.
+ */
+fn run_profilers_refresh(state: ServerState) {
+	loop {
+		let current = state.current();
+		std::thread::sleep(Duration::from_secs(current.config.profilers_refresh_secs));
+		let current = state.current();
+		if let ProfilersSource::Url(_) = current.config.profilers {
+			match load_profilers(&current.config.profilers) {
+				Ok(profilers) => state.set_profilers(profilers),
+				Err(e) => error!(state.logger, "{}; keeping the last profilers list.", e),
+			}
+		}
+	}
+}
+
+/*
+ * Print each check as a green or red line and report whether they all
+ * passed.
+ */
+fn selftest(targets: &CheckTargets) -> bool {
+	let mut healthy = true;
+	for check in run_checks(targets) {
+		match check.result {
+			Ok(detail) => println!("\x1b[32m✔ {}\x1b[0m: {}", check.name, detail),
+			Err(detail) => {
+				healthy = false;
+				println!("\x1b[31m✘ {}\x1b[0m: {}", check.name, detail);
+			}
 		}
-	} else {
-		vec![]
 	}
+	healthy
+}
+
+fn cli() -> clap::App<'static, 'static> {
+	clap::App::new("ndhook")
+		.version(env!("CARGO_PKG_VERSION"))
+		.about("Builds pull requests and profiles them with NimbleDroid.")
+		.arg(
+			Arg::with_name("config")
+				.long("config")
+				.value_name("FILE")
+				.default_value("./ndhook.toml")
+				.global(true)
+				.help("The configuration file to read"),
+		)
+		.arg(
+			Arg::with_name("log-level")
+				.long("log-level")
+				.value_name("LEVEL")
+				.possible_values(&["critical", "error", "warn", "info", "debug", "trace"])
+				.default_value("info")
+				.global(true)
+				.help("The least severe log messages to show"),
+		)
+		.arg(
+			Arg::with_name("dry-run")
+				.long("dry-run")
+				.global(true)
+				.help("Log what would be posted to GitHub or Slack instead of posting it"),
+		)
+		.subcommand(
+			SubCommand::with_name("serve").about("Listen for webhook notifications (the default)"),
+		)
+		.subcommand(SubCommand::with_name("selftest").about("Check the environment and exit"))
+		.subcommand(
+			SubCommand::with_name("profile-once")
+				.about("Build and profile a single commit, printing the results")
+				.arg(Arg::with_name("clone_url").required(true))
+				.arg(Arg::with_name("sha").required(true)),
+		)
+		.subcommand(
+			SubCommand::with_name("render-comment")
+				.about("Print the comment the bot would post for a fixture of results")
+				.arg(Arg::with_name("results").required(true).help(
+					"A JSON file with result, profile_url, sha, commenter and the scenarios' name, status and time_in_ms",
+				)),
+		)
+}
+
+/*
+ * Exit with `code` once everything logged so far is written out. The
+ * log is written by a thread of its own, which exiting doesn't wait for
+ * while any other thread still holds a logger.
+ */
+fn exit_logged(log_guard: slog_async::AsyncGuard, code: i32) -> ! {
+	drop(log_guard);
+	std::process::exit(code)
 }
 
 fn main() {
+	let matches = cli().get_matches();
+	let (subcommand, subcommand_matches) = matches.subcommand();
+	let args = subcommand_matches.unwrap_or(&matches);
+
+	let level = args
+		.value_of("log-level")
+		.and_then(|l| l.parse().ok())
+		.unwrap_or(Level::Info);
 	let decorator = slog_term::TermDecorator::new().build();
 	let drain = slog_term::FullFormat::new(decorator).build().fuse();
-	let drain = slog_async::Async::new(drain).build().fuse();
-	let log = slog::Logger::root(drain, o!());
+	let (drain, log_guard) =
+		slog_async::Async::new(drain.filter_level(level).fuse()).build_with_guard();
+	let log = slog::Logger::root(drain.fuse(), o!());
+
+	info!(
+		log,
+		"Starting ndhook {} (commit {}, built at Unix time {}).", VERSION, GIT_COMMIT, BUILT_AT
+	);
+
+	let config_file = args.value_of("config").unwrap_or("./ndhook.toml");
+	let config = match config_from_file(config_file) {
+		Ok(config) => config,
+		Err(e) => {
+			error!(log, "{}", e);
+			exit_logged(log_guard, 1);
+		}
+	};
+
+	if subcommand == "render-comment" {
+		let rendered = render_comment(&config, args.value_of("results").unwrap());
+		let code = match rendered {
+			Ok(comment) => {
+				println!("{}", comment);
+				0
+			}
+			Err(e) => {
+				error!(log, "{}", e);
+				1
+			}
+		};
+		exit_logged(log_guard, code);
+	}
+
+	/* Say which keys were found, never what they are. */
+	for name in &config::ENV_KEYS {
+		let found = if config::env_key(name).is_some() {
+			"found"
+		} else {
+			"not found"
+		};
+		info!(log, "{}: {}", name, found);
+	}
+	let config = match config_from_env(config) {
+		Ok(config) => config,
+		Err(e) => {
+			error!(log, "{}", e);
+			exit_logged(log_guard, 1);
+		}
+	};
+	if config.build_enabled {
+		info!(
+			log,
+			"Building in image {} with {}.", config.build_image, config.build_script
+		);
+	}
+
+	let app = match config.github_auth {
+		GitHubAuth::App => match GitHubApp::from_config(&config) {
+			Ok(app) => Some(app),
+			Err(e) => {
+				error!(log, "{}", e);
+				exit_logged(log_guard, 1);
+			}
+		},
+		GitHubAuth::Pat => None,
+	};
+	let git_key = match (&app, &config.github_token_file) {
+		(Some(app), _) => match app.installation_token() {
+			Ok(token) => token,
+			Err(e) => {
+				error!(log, "{}", e);
+				exit_logged(log_guard, 1);
+			}
+		},
+		(None, Some(filename)) => match read_token(filename) {
+			Ok(token) => token,
+			Err(e) => {
+				error!(log, "{}", e);
+				exit_logged(log_guard, 1);
+			}
+		},
+		/* config_from_env made sure there is one. */
+		(None, None) => config.github_token.clone().unwrap_or_default(),
+	};
+	let nd_key = config.nd_key.clone().unwrap_or_default();
+	let targets = CheckTargets {
+		build_image: if config.build_enabled {
+			Some(&config.build_image)
+		} else {
+			None
+		},
+		build_hosts: &config.build_hosts,
+		api_base: &config.github_api_base,
+		git_key: &git_key,
+		nd_key: &nd_key,
+		profilers: &config.profilers,
+	};
 
-	info!(log, "Starting.");
+	if subcommand == "selftest" {
+		let healthy = selftest(&targets);
+		exit_logged(log_guard, if healthy { 0 } else { 1 });
+	}
+
+	for check in run_checks(&targets) {
+		match check.result {
+			Ok(detail) => info!(log, "Check {}: {}", check.name, detail),
+			Err(detail) => error!(log, "Check {} failed: {}", check.name, detail),
+		}
+	}
 
-	let profilers = profilers_from_file("./profilers.json");
-	let lc_profilers: Vec<String> = profilers.into_iter().map(|s| s.to_lowercase()).collect();
+	/* The startup checks have already logged why this might fail. */
+	let lc_profilers = load_profilers(&config.profilers)
+		.map(lowercase)
+		.unwrap_or_default();
 
-	let mut server = App::with_state(ServerState::new(
-		"git_key".to_string(),
-		"nd_key".to_string(),
+	let state = ServerState::new(
+		git_key,
+		nd_key,
 		&lc_profilers,
+		config,
+		config_file,
+		args.is_present("dry-run"),
 		log,
-	));
+	);
+	let state = ServerState {
+		installations: app.map(Installations::new),
+		..state
+	};
+
+	if subcommand == "profile-once" {
+		let clone_url = args.value_of("clone_url").unwrap();
+		let sha = args.value_of("sha").unwrap();
+		let healthy = match run_profile(
+			&state,
+			&job_id(None),
+			clone_url,
+			sha,
+			&state.config.default_variant,
+			&CancelToken::default(),
+		) {
+			Some((build, outcome)) => {
+				let comment = format_outcome_comment(
+					&outcome,
+					&build,
+					sha,
+					None,
+					&CommentOptions::from_config(&state.config),
+				);
+				println!("{}", comment);
+				outcome.profile_url().is_some()
+			}
+			None => false,
+		};
+		exit_logged(log_guard, if healthy { 0 } else { 1 });
+	}
+
+	if state.config.otel_endpoint.is_some() && !cfg!(feature = "otel") {
+		error!(
+			state.logger,
+			"otel_endpoint is set but ndhook was built without the otel feature."
+		);
+	}
+
+	if let Some(schedule) = &state.config.auto_baseline_schedule {
+		if state.config.allowed_repos.is_empty() {
+			error!(
+				state.logger,
+				"auto_baseline_schedule needs allowed_repos to know what to profile."
+			);
+		} else {
+			let state = state.clone();
+			let interval = Duration::from_secs(schedule.interval_secs);
+			std::thread::spawn(move || run_baseline_schedule(state, interval));
+		}
+	}
+
+	if let Some(installations) = state.installations.clone() {
+		let state = state.clone();
+		std::thread::spawn(move || run_app_token_refresh(state, installations));
+	} else if let Some(filename) = &state.config.github_token_file {
+		let filename = filename.clone();
+		let interval = Duration::from_secs(state.config.github_token_refresh_secs);
+		let state = state.clone();
+		std::thread::spawn(move || run_token_refresh(state, filename, interval));
+	}
+
+	{
+		let state = state.clone();
+		std::thread::spawn(move || run_readiness_checks(state));
+	}
+
+	if let ProfilersSource::Url(_) = state.config.profilers {
+		let state = state.clone();
+		std::thread::spawn(move || run_profilers_refresh(state));
+	}
+
+	if state.config.artifact_retention.is_some() {
+		if state.config.artifact_root.is_none() {
+			error!(
+				state.logger,
+				"artifact_retention needs artifact_root to know what to clean up."
+			);
+		} else {
+			let state = state.clone();
+			std::thread::spawn(move || run_artifact_cleanup(state));
+		}
+	}
+
+	if let Ok(cpus) = std::thread::available_parallelism() {
+		if state.config.workers > cpus.get() {
+			error!(
+				state.logger,
+				"{} workers is more than the {} CPUs; builds will compete for them.",
+				state.config.workers,
+				cpus
+			);
+		}
+	}
+
+	let (state, resumed) = if state.config.persistent_queue {
+		match PendingJobs::load(&state.config.pending_jobs_file) {
+			Ok((pending, resumed)) => (ServerState { pending, ..state }, resumed),
+			Err(e) => {
+				error!(state.logger, "{}", e);
+				(state, vec![])
+			}
+		}
+	} else {
+		(state, vec![])
+	};
+
+	let worker_state = state.clone();
+	let handle = move |queued: QueuedJob| {
+		let id = queued.ctx.id.clone();
+		let started = Instant::now();
+		let batch = queued.ctx.batch.as_ref().map(|(batch, _)| batch.clone());
+		take_action(worker_state.current(), queued);
+		/* The last of a batch's jobs to finish compares them all. */
+		if let Some(batch) = batch {
+			batch.finished();
+		}
+		worker_state.metrics.ran(started.elapsed());
+		if let Err(e) = worker_state.pending.remove(&id) {
+			error!(worker_state.logger, "{}", e);
+		}
+	};
+	state
+		.queue
+		.spawn_workers(state.config.workers, &state.logger, handle.clone());
+	if let Some(stuck_secs) = state.config.worker_stuck_secs {
+		let kill_state = state.clone();
+		state.queue.spawn_watchdog(
+			Duration::from_secs(stuck_secs),
+			&state.logger,
+			handle,
+			move |id| kill_build(&kill_state.current(), id),
+		);
+	}
+
+	/*
+	 * Run again whatever was still pending when the server last stopped.
+	 * Anything that no longer makes a job is forgotten.
+	 */
+	state
+		.intake
+		.spawn_workers(INTAKE_WORKERS, &state.logger, take_in);
+	for job in resumed {
+		let state = state.current();
+		let state = ServerState {
+			logger: state.logger.new(o!("job" => job.id.clone())),
+			..state
+		};
+		info!(state.logger, "Resuming a job from before the restart.");
+		let accepted = Accepted {
+			state: state.clone(),
+			id: job.id,
+			event: job.event,
+			notification: job.notification,
+		};
+		/* These were all accepted once already; none is turned away. */
+		if let Err(accepted) = state.intake.submit(accepted, usize::MAX) {
+			take_in(accepted);
+		}
+	}
+
+	/* The server is about to listen; the warmup needn't hold it up. */
+	if state.config.warmup_on_start {
+		state.readiness.set_warming_up(true);
+		let state = state.clone();
+		std::thread::spawn(move || run_warmup(state));
+	}
+
+	let state_apps: Vec<String> = state.config.apps.keys().cloned().collect();
+	let config = state.config.clone();
+	let logger = state.logger.clone();
+	let mut server = App::with_state(state);
 	server.at("/").post(handle_post);
-	match server.run("localhost:8000") {
-		_ => (),
+	for path in state_apps {
+		let app = path.clone();
+		server
+			.at(&path)
+			.post(move |request| handle_delivery(request, Some(app.clone())));
+	}
+	server.at("/health").get(handle_health);
+	server.at("/livez").get(handle_livez);
+	server.at("/readyz").get(handle_readyz);
+	server.at("/metrics").get(handle_metrics);
+	server.at("/history.csv").get(handle_history_csv);
+	server.at("/commands").get(handle_commands);
+	server.at("/version").get(handle_version);
+	server.at("/admin/reload").post(handle_admin_reload);
+	server.at("/admin/pause").post(handle_admin_pause);
+	server.at("/admin/resume").post(handle_admin_resume);
+	server.at("/admin/cancel").post(handle_admin_cancel);
+	server
+		.at("/admin/flush-cache")
+		.post(handle_admin_flush_cache);
+	server.at("/profile-apk").post(handle_profile_apk);
+	if let Err(e) = serve(server, &config, &logger) {
+		error!(logger, "{}", e);
+		exit_logged(log_guard, 1);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+
+	/*
+	 * A server with `config` whose GitHub client only logs what it
+	 * would have sent.
+	 */
+	fn test_state(config: Config) -> ServerState {
+		ServerState::new(
+			"token".to_string(),
+			"key".to_string(),
+			&[],
+			config,
+			"ndhook.toml",
+			true,
+			Logger::root(slog::Discard, o!()),
+		)
+	}
+
+	fn pull_request_opened(pull_request_url: &str) -> Value {
+		let api = "https://api.github.com/repos/mozilla-mobile/fenix";
+		json!({
+			"action": "opened",
+			"pull_request": {
+				"url": pull_request_url,
+				"comments_url": format!("{}/issues/1/comments", api),
+				"issue_url": format!("{}/issues/1", api),
+				"html_url": "https://github.com/mozilla-mobile/fenix/pull/1",
+				"head": {
+					"sha": "0123456789abcdef0123456789abcdef01234567",
+					"repo": { "clone_url": "https://github.com/mozilla-mobile/fenix.git" },
+				},
+			},
+			"repository": {
+				"full_name": "mozilla-mobile/fenix",
+				"statuses_url": format!("{}/statuses/{{sha}}", api),
+			},
+		})
+	}
+
+	fn dedup_state(duplicate_jobs: DuplicateJobs) -> ServerState {
+		test_state(Config {
+			duplicate_jobs,
+			..Config::default()
+		})
+	}
+
+	fn job(id: &str, head_sha: &str) -> QueuedJob {
+		QueuedJob::new(JobContext::for_test(id, "pull/1/comments", head_sha))
+	}
+
+	#[test]
+	fn allows_duplicate_jobs_by_default() {
+		let state = dedup_state(DuplicateJobs::Allow);
+		assert!(enqueue_job(&state, job("first", "aaaa")));
+		assert!(enqueue_job(&state, job("second", "aaaa")));
+		assert_eq!(state.queue.depth(), 2);
+	}
+
+	#[test]
+	fn skips_a_job_already_queued() {
+		let state = dedup_state(DuplicateJobs::Skip);
+		assert!(enqueue_job(&state, job("first", "aaaa")));
+		assert!(enqueue_job(&state, job("second", "aaaa")));
+		assert_eq!(state.queue.depth(), 1);
+		assert!(state
+			.queue
+			.is_profiling("pull/1/comments", "aaaa", &state.config.default_variant));
+	}
+
+	#[test]
+	fn supersedes_a_job_for_an_older_commit() {
+		let state = dedup_state(DuplicateJobs::Supersede);
+		let older = job("older", "aaaa");
+		let older_cancel = older.ctx.cancel.clone();
+		assert!(enqueue_job(&state, older));
+		let newer = job("newer", "bbbb");
+		let newer_cancel = newer.ctx.cancel.clone();
+		assert!(enqueue_job(&state, newer));
+		assert!(older_cancel.is_cancelled());
+		assert!(!newer_cancel.is_cancelled());
+
+		/* The same commit again is still skipped. */
+		assert!(enqueue_job(&state, job("again", "bbbb")));
+		assert_eq!(state.queue.depth(), 2);
+		assert!(!newer_cancel.is_cancelled());
+	}
+
+	#[test]
+	fn refuses_a_pull_request_on_a_foreign_host() {
+		let state = test_state(Config::default());
+		let forged = pull_request_opened("https://evil.example/repos/mozilla-mobile/fenix/pulls/1");
+		let update = PullRequestUpdate::try_from(forged.clone()).unwrap();
+		let urls = [
+			update.url.as_str(),
+			&update.pull_request_url,
+			&update.statuses_url,
+			&update.issue_url,
+		];
+		assert_eq!(
+			foreign_url(&state, &urls),
+			Some("https://evil.example/repos/mozilla-mobile/fenix/pulls/1")
+		);
+		assert!(job_for(&state, "forged", "pull_request", forged).is_none());
+
+		let genuine =
+			pull_request_opened("https://api.github.com/repos/mozilla-mobile/fenix/pulls/1");
+		let update = PullRequestUpdate::try_from(genuine).unwrap();
+		let urls = [
+			update.url.as_str(),
+			&update.pull_request_url,
+			&update.statuses_url,
+			&update.issue_url,
+		];
+		assert_eq!(foreign_url(&state, &urls), None);
+	}
+
+	#[test]
+	fn parses_a_form_encoded_body() {
+		let body = b"payload=%7B%22action%22%3A%22created%22%7D";
+		assert_eq!(
+			parse_body_bytes(body).unwrap(),
+			json!({ "action": "created" })
+		);
+	}
+
+	#[test]
+	fn parses_a_raw_json_body() {
+		let body = br#"{"action": "created", "number": 7}"#;
+		assert_eq!(
+			parse_body_bytes(body).unwrap(),
+			json!({ "action": "created", "number": 7 })
+		);
+	}
+
+	#[test]
+	fn parses_a_form_encoded_body_with_spaces() {
+		let body = b"payload=%7B%22body%22%3A%22a+b%22%7D";
+		assert_eq!(parse_body_bytes(body).unwrap(), json!({ "body": "a b" }));
+	}
+
+	#[test]
+	fn leaves_percent_signs_in_raw_json_alone() {
+		let body = br#"{"comment": {"body": "50%22 faster, 2+2"}}"#;
+		assert_eq!(
+			parse_body_bytes(body).unwrap(),
+			json!({ "comment": { "body": "50%22 faster, 2+2" } })
+		);
+	}
+
+	#[test]
+	fn parses_raw_json_that_mentions_payload() {
+		let body = br#"{"comment": {"body": "try payload=1"}}"#;
+		assert_eq!(
+			parse_body_bytes(body).unwrap(),
+			json!({ "comment": { "body": "try payload=1" } })
+		);
+	}
+
+	#[test]
+	fn refuses_a_body_that_isnt_json() {
+		assert!(parse_body_bytes(b"payload=nope").is_err());
 	}
 }
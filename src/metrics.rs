@@ -0,0 +1,166 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::job::ProfileOutcome;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How a request for a profile ended, as counted for `/metrics`.
+#[derive(Clone, Copy)]
+pub enum Outcome {
+	BadCommand,
+	BadCommenter,
+	BuildFailed,
+	UploadFailed,
+	ProfileTimeout,
+	ResultsUnavailable,
+	Cancelled,
+	Success,
+}
+
+static OUTCOMES: [(Outcome, &str); 8] = [
+	(Outcome::BadCommand, "bad_command"),
+	(Outcome::BadCommenter, "bad_commenter"),
+	(Outcome::BuildFailed, "build_failed"),
+	(Outcome::UploadFailed, "upload_failed"),
+	(Outcome::ProfileTimeout, "profile_timeout"),
+	(Outcome::ResultsUnavailable, "results_unavailable"),
+	(Outcome::Cancelled, "cancelled"),
+	(Outcome::Success, "success"),
+];
+
+/*
+ * The upper bounds, in seconds, of the buckets runs are timed into.
+ * Profiling takes minutes, so they needn't be any finer.
+ */
+static DURATION_BUCKETS_SECS: [u64; 4] = [60, 300, 600, 1200];
+
+impl From<&ProfileOutcome> for Outcome {
+	fn from(outcome: &ProfileOutcome) -> Self {
+		match outcome {
+			ProfileOutcome::Success { .. } => Outcome::Success,
+			ProfileOutcome::Timeout { .. } => Outcome::ProfileTimeout,
+			ProfileOutcome::ResultsUnavailable { .. } => Outcome::ResultsUnavailable,
+			ProfileOutcome::Cancelled { .. } => Outcome::Cancelled,
+			ProfileOutcome::BuildFailed { .. } => Outcome::BuildFailed,
+			ProfileOutcome::UploadRejected { .. } => Outcome::UploadFailed,
+		}
+	}
+}
+
+/// Counts of deliveries and of how runs ended, and how long the runs
+/// took, shared by the server and every worker.
+#[derive(Clone, Default)]
+pub struct Metrics {
+	inner: Arc<MetricsInner>,
+}
+
+#[derive(Default)]
+struct MetricsInner {
+	deliveries: AtomicU64,
+	outcomes: [AtomicU64; 8],
+	/* One more than there are bounds, for the runs longer than all. */
+	durations: [AtomicU64; 5],
+	duration_ms_sum: AtomicU64,
+}
+
+impl Metrics {
+	/// Count a webhook delivery.
+	pub fn delivered(&self) {
+		self.inner.deliveries.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Count a request that ended in `outcome`.
+	pub fn ended(&self, outcome: Outcome) {
+		self.inner.outcomes[outcome as usize].fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Record how long a run took from start to finish.
+	pub fn ran(&self, took: Duration) {
+		let bucket = DURATION_BUCKETS_SECS
+			.iter()
+			.position(|&bound| took.as_secs_f64() <= bound as f64)
+			.unwrap_or(DURATION_BUCKETS_SECS.len());
+		self.inner.durations[bucket].fetch_add(1, Ordering::Relaxed);
+		self.inner
+			.duration_ms_sum
+			.fetch_add(took.as_millis() as u64, Ordering::Relaxed);
+	}
+
+	/// The metrics in Prometheus's text exposition format, along with
+	/// the depth of the queue, how many jobs are running and how many
+	/// notifications are waiting to be taken in.
+	pub fn to_prometheus(
+		&self,
+		queue_depth: usize,
+		active_jobs: usize,
+		intake_waiting: usize,
+	) -> String {
+		let inner = &self.inner;
+		let mut text = String::new();
+		/* Writing to a String can't fail. */
+		let _ = writeln!(
+			text,
+			"# HELP ndhook_deliveries_total Webhook deliveries received.\n\
+			 # TYPE ndhook_deliveries_total counter\n\
+			 ndhook_deliveries_total {}",
+			inner.deliveries.load(Ordering::Relaxed)
+		);
+		let _ = writeln!(
+			text,
+			"# HELP ndhook_outcomes_total Requests for profiles, by how they ended.\n\
+			 # TYPE ndhook_outcomes_total counter"
+		);
+		for (outcome, label) in OUTCOMES.iter() {
+			let _ = writeln!(
+				text,
+				"ndhook_outcomes_total{{outcome=\"{}\"}} {}",
+				label,
+				inner.outcomes[*outcome as usize].load(Ordering::Relaxed)
+			);
+		}
+		let _ = writeln!(
+			text,
+			"# HELP ndhook_run_duration_seconds How long runs took from start to finish.\n\
+			 # TYPE ndhook_run_duration_seconds histogram"
+		);
+		let mut count = 0;
+		for (i, bound) in DURATION_BUCKETS_SECS.iter().enumerate() {
+			count += inner.durations[i].load(Ordering::Relaxed);
+			let _ = writeln!(
+				text,
+				"ndhook_run_duration_seconds_bucket{{le=\"{}\"}} {}",
+				bound, count
+			);
+		}
+		count += inner.durations[DURATION_BUCKETS_SECS.len()].load(Ordering::Relaxed);
+		let _ = writeln!(
+			text,
+			"ndhook_run_duration_seconds_bucket{{le=\"+Inf\"}} {}\n\
+			 ndhook_run_duration_seconds_sum {}\n\
+			 ndhook_run_duration_seconds_count {}",
+			count,
+			inner.duration_ms_sum.load(Ordering::Relaxed) as f64 / 1000.0,
+			count
+		);
+		let _ = writeln!(
+			text,
+			"# HELP ndhook_queue_depth Jobs waiting for a worker.\n\
+			 # TYPE ndhook_queue_depth gauge\n\
+			 ndhook_queue_depth {}\n\
+			 # HELP ndhook_active_jobs Jobs running.\n\
+			 # TYPE ndhook_active_jobs gauge\n\
+			 ndhook_active_jobs {}\n\
+			 # HELP ndhook_intake_waiting Notifications waiting to be turned into jobs.\n\
+			 # TYPE ndhook_intake_waiting gauge\n\
+			 ndhook_intake_waiting {}",
+			queue_depth, active_jobs, intake_waiting
+		);
+		text
+	}
+}
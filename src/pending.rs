@@ -0,0 +1,114 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+/*
+ * The notifications behind jobs that have been accepted but haven't
+ * finished, kept in a file so that a restart can run them again.
+ *
+ * Jobs hold loggers, tokens and configuration that don't belong on disk,
+ * so it is the notification that is kept, and a restart turns it back
+ * into a job the way the webhook did in the first place. Result comments
+ * are marked with the job ID, so a job that had already commented before
+ * the restart edits that comment rather than posting another.
+ */
+
+use serde_json::{json, Map, Value};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// A notification whose job was still pending when the file was saved.
+pub struct PendingJob {
+	pub id: String,
+	pub event: String,
+	pub notification: Value,
+}
+
+/// The pending jobs, by ID, and the file they are saved in. Without a
+/// file nothing is kept.
+#[derive(Clone, Default)]
+pub struct PendingJobs {
+	path: Option<PathBuf>,
+	jobs: Arc<Mutex<Map<String, Value>>>,
+}
+
+impl PendingJobs {
+	/// Keep pending jobs in `path`, returning whatever was left there
+	/// from before. Those stay pending until they are removed.
+	pub fn load(path: &str) -> Result<(Self, Vec<PendingJob>), String> {
+		let jobs: Map<String, Value> = match std::fs::read_to_string(path) {
+			Ok(contents) => serde_json::from_str(&contents)
+				.map_err(|e| format!("Oops, couldn't parse the pending jobs in {}: {}", path, e))?,
+			Err(ref e) if e.kind() == ErrorKind::NotFound => Map::new(),
+			Err(e) => {
+				return Err(format!(
+					"Oops, couldn't read the pending jobs in {}: {}",
+					path, e
+				))
+			}
+		};
+		let loaded = jobs
+			.iter()
+			.filter_map(|(id, job)| {
+				Some(PendingJob {
+					id: id.clone(),
+					event: job["event"].as_str()?.to_string(),
+					notification: job["notification"].clone(),
+				})
+			})
+			.collect();
+		Ok((
+			Self {
+				path: Some(PathBuf::from(path)),
+				jobs: Arc::new(Mutex::new(jobs)),
+			},
+			loaded,
+		))
+	}
+
+	/// Keep a job's notification until the job is removed.
+	pub fn record(&self, id: &str, event: &str, notification: &Value) -> Result<(), String> {
+		if self.path.is_none() {
+			return Ok(());
+		}
+		let mut jobs = self.jobs.lock().unwrap();
+		jobs.insert(
+			id.to_string(),
+			json!({ "event": event, "notification": notification }),
+		);
+		self.save(&jobs)
+	}
+
+	/// Forget a job, once it has finished one way or another.
+	pub fn remove(&self, id: &str) -> Result<(), String> {
+		if self.path.is_none() {
+			return Ok(());
+		}
+		let mut jobs = self.jobs.lock().unwrap();
+		if jobs.remove(id).is_none() {
+			return Ok(());
+		}
+		self.save(&jobs)
+	}
+
+	/*
+	 * Write the whole file afresh and move it into place, so that a crash
+	 * part way through leaves the previous version intact.
+	 */
+	fn save(&self, jobs: &Map<String, Value>) -> Result<(), String> {
+		let path = self.path.as_ref().unwrap();
+		let temp = path.with_extension("tmp");
+		std::fs::write(&temp, Value::Object(jobs.clone()).to_string())
+			.and_then(|()| std::fs::rename(&temp, path))
+			.map_err(|e| {
+				format!(
+					"Oops, couldn't save the pending jobs in {}: {}",
+					path.display(),
+					e
+				)
+			})
+	}
+}
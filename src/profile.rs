@@ -0,0 +1,153 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Check that a URL handed back by an upload really points at a profile
+/// on the NimbleDroid deployment at `base_url`, or one of its
+/// subdomains, describing the problem if it doesn't.
+pub fn check_profile_url(url: &reqwest::Url, base_url: &str) -> Result<(), String> {
+	let base = reqwest::Url::parse(base_url)
+		.map_err(|e| format!("nd_base_url {:?} isn't a URL: {}", base_url, e))?;
+	let host = url.host_str().unwrap_or("");
+	let base_host = base.host_str().unwrap_or("");
+	let same_host = host == base_host || host.ends_with(&format!(".{}", base_host));
+	if url.scheme() != base.scheme() || url.port() != base.port() || !same_host {
+		return Err(format!("it answered with an unexpected URL ({})", url));
+	}
+	if !url.path().starts_with("/api/") {
+		return Err(format!(
+			"it answered with a URL that isn't a profile ({})",
+			url
+		));
+	}
+	Ok(())
+}
+
+/*
+ * What NimbleDroid's upload errors say when the account has run out of
+ * runs, rather than anything being wrong with the upload.
+ */
+static QUOTA_SIGNS: [&str; 4] = ["quota", "billing", "payment required", "402"];
+
+/// Whether an upload failed because the NimbleDroid account's run quota
+/// is used up.
+pub fn is_quota_error(detail: &str) -> bool {
+	let detail = detail.to_lowercase();
+	QUOTA_SIGNS.iter().any(|sign| detail.contains(sign))
+}
+
+/// Why the results of a finished profile couldn't be had.
+#[derive(Debug)]
+pub enum ResultsError {
+	/// NimbleDroid couldn't be reached or answered with a server error;
+	/// asking again later may well work.
+	Transient(String),
+	/// NimbleDroid answered that there are no results to be had.
+	Failed(String),
+}
+
+impl std::fmt::Display for ResultsError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			ResultsError::Transient(detail) | ResultsError::Failed(detail) => {
+				write!(f, "{}", detail)
+			}
+		}
+	}
+}
+
+/*
+ * NimbleDroid's status strings for a profile that won't have results.
+ */
+static FAILED_STATUSES: [&str; 2] = ["failed", "error"];
+
+/// Ask NimbleDroid directly about a profile whose results the profiler
+/// couldn't get, to tell a blip from a profile that really failed.
+pub fn diagnose_missing_results(nd_key: &str, profile_url: &reqwest::Url) -> ResultsError {
+	let response = reqwest::Client::new()
+		.get(profile_url.clone())
+		.basic_auth(nd_key, Some(""))
+		.send();
+	let mut response = match response {
+		Ok(response) => response,
+		Err(e) => return ResultsError::Transient(format!("couldn't reach ND: {}", e)),
+	};
+	let status = response.status();
+	if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+		return ResultsError::Transient(format!("ND responded {}", status));
+	}
+	if !status.is_success() {
+		return ResultsError::Failed(format!("ND responded {}", status));
+	}
+	match response.json::<Value>() {
+		Ok(profile) => match profile["status"].as_str() {
+			Some(status)
+				if FAILED_STATUSES
+					.iter()
+					.any(|s| s.eq_ignore_ascii_case(status)) =>
+			{
+				ResultsError::Failed(format!("ND reports the profile {}", status.to_lowercase()))
+			}
+			_ => ResultsError::Transient("ND answered without the results".to_string()),
+		},
+		Err(e) => ResultsError::Transient(format!("ND's answer couldn't be read: {}", e)),
+	}
+}
+
+/// One scenario's outcome, copied out of NimbleDroid's profile result.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ScenarioResult {
+	pub name: String,
+	pub status: String,
+	pub time_in_ms: u64,
+	/// The scenario is in `ignored_scenarios`: it is shown, but can't
+	/// fail the run.
+	#[serde(default)]
+	pub ignored: bool,
+}
+
+/*
+ * NimbleDroid's status strings for a scenario that ran to completion.
+ */
+static PASSING_STATUSES: [&str; 3] = ["profiled", "completed", "success"];
+
+/*
+ * NimbleDroid's status strings for a scenario that hasn't finished yet.
+ */
+static PENDING_STATUSES: [&str; 5] = ["pending", "queued", "running", "profiling", "in progress"];
+
+impl ScenarioResult {
+	pub fn is_pending(&self) -> bool {
+		PENDING_STATUSES
+			.iter()
+			.any(|s| s.eq_ignore_ascii_case(&self.status))
+	}
+
+	/// The budget this scenario went over, if it finished and took
+	/// longer than the budget configured for it.
+	pub fn over_budget(&self, budgets_ms: &BTreeMap<String, u64>) -> Option<u64> {
+		if self.is_pending() || self.ignored {
+			return None;
+		}
+		budgets_ms
+			.iter()
+			.find(|(name, _)| name.eq_ignore_ascii_case(&self.name))
+			.map(|(_, budget)| *budget)
+			.filter(|budget| self.time_in_ms > *budget)
+	}
+
+	/// Whether the scenario ran to completion. An ignored one always
+	/// counts as passing.
+	pub fn passed(&self) -> bool {
+		self.ignored
+			|| PASSING_STATUSES
+				.iter()
+				.any(|s| s.eq_ignore_ascii_case(&self.status))
+	}
+}
@@ -0,0 +1,513 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::job::{CancelToken, JobContext};
+use crate::trace::random_id;
+use slog::{error, info, Logger};
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/*
+ * How much each finished job moves the rolling average duration.
+ */
+const AVERAGE_WEIGHT: f64 = 0.3;
+
+/*
+ * How often a job held back by the load average looks again.
+ */
+const LOAD_RECHECK: Duration = Duration::from_secs(15);
+
+/// Identifies a job in logs and artifacts: GitHub's delivery ID when
+/// there is one, otherwise a random one.
+pub fn job_id(delivery: Option<&str>) -> String {
+	delivery.map_or_else(|| random_id(8), |d| d.to_string())
+}
+
+/// A profile job waiting for a worker.
+pub struct QueuedJob {
+	pub ctx: JobContext,
+	pub enqueued_at: Instant,
+	/// The comment telling the requester where the job is in the queue,
+	/// if one was posted.
+	pub queued_comment_url: Option<String>,
+}
+
+impl QueuedJob {
+	pub fn new(ctx: JobContext) -> Self {
+		Self {
+			ctx,
+			enqueued_at: Instant::now(),
+			queued_comment_url: None,
+		}
+	}
+}
+
+/// Jobs waiting to be run by a fixed pool of workers.
+#[derive(Clone)]
+pub struct WorkQueue {
+	waiting: Arc<(Mutex<Waiting>, Condvar)>,
+	depth: Arc<AtomicUsize>,
+	active: Arc<AtomicUsize>,
+	workers: Arc<AtomicUsize>,
+	average_secs: Arc<Mutex<Option<f64>>>,
+	/* Every job queued or running, by ID. */
+	jobs: Arc<Mutex<HashMap<String, TrackedJob>>>,
+	slots: Arc<Mutex<Vec<Arc<Mutex<WorkerSlot>>>>>,
+	stuck: Arc<AtomicUsize>,
+}
+
+/*
+ * The jobs waiting for a worker, oldest first, and how many jobs each
+ * repository has running, to hold a repository to its
+ * max_concurrent_jobs. Nothing is taken while the queue is paused.
+ */
+#[derive(Default)]
+struct Waiting {
+	jobs: VecDeque<QueuedJob>,
+	running: HashMap<String, usize>,
+	paused: bool,
+	/* The load average the oldest job is waiting for to come down. */
+	deferred_load: Option<f64>,
+}
+
+/*
+ * The host's one-minute load average, where /proc says what it is.
+ */
+fn load_average() -> Option<f64> {
+	let loadavg = std::fs::read_to_string("/proc/loadavg").ok()?;
+	loadavg.split_whitespace().next()?.parse().ok()
+}
+
+impl Waiting {
+	/*
+	 * Take the oldest job whose repository has room for another, if
+	 * there is one and the host isn't too loaded to start it.
+	 */
+	fn take(&mut self) -> Option<QueuedJob> {
+		self.deferred_load = None;
+		if self.paused {
+			return None;
+		}
+		let running = &self.running;
+		let index = self
+			.jobs
+			.iter()
+			.position(|job| match job.ctx.config.max_concurrent_jobs {
+				Some(max) => running.get(&repo_key(job)).copied().unwrap_or(0) < max,
+				None => true,
+			})?;
+		if let Some(max) = self.jobs[index].ctx.config.max_load_average {
+			match load_average() {
+				Some(load) if load > max => {
+					self.deferred_load = Some(load);
+					return None;
+				}
+				_ => (),
+			}
+		}
+		let job = self.jobs.remove(index)?;
+		*self.running.entry(repo_key(&job)).or_insert(0) += 1;
+		Some(job)
+	}
+
+	fn finished(&mut self, repo: &str) {
+		if let Some(running) = self.running.get_mut(repo) {
+			*running -= 1;
+			if *running == 0 {
+				self.running.remove(repo);
+			}
+		}
+	}
+}
+
+fn repo_key(job: &QueuedJob) -> String {
+	job.ctx.repo_full_name.to_lowercase()
+}
+
+/*
+ * What the watchdog knows about one worker.
+ */
+struct WorkerSlot {
+	job: Option<String>,
+	last_progress: Instant,
+	/* Given up on by the watchdog, and already replaced. */
+	abandoned: bool,
+}
+
+thread_local! {
+	static SLOT: RefCell<Option<Arc<Mutex<WorkerSlot>>>> = const { RefCell::new(None) };
+}
+
+/// Tell the watchdog that the job on this thread is still getting
+/// somewhere. Off a worker thread this does nothing.
+pub fn progress() {
+	SLOT.with(|current| {
+		if let Some(slot) = current.borrow().as_ref() {
+			slot.lock().unwrap().last_progress = Instant::now();
+		}
+	});
+}
+
+struct TrackedJob {
+	comments_url: String,
+	head_sha: String,
+	variant: String,
+	repo: String,
+	cancel: CancelToken,
+}
+
+/// The message a panic was raised with, when it has one.
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+	if let Some(message) = payload.downcast_ref::<&str>() {
+		message.to_string()
+	} else if let Some(message) = payload.downcast_ref::<String>() {
+		message.clone()
+	} else {
+		"(no message)".to_string()
+	}
+}
+
+impl WorkQueue {
+	pub fn new() -> Self {
+		Self {
+			waiting: Arc::new((Mutex::new(Waiting::default()), Condvar::new())),
+			depth: Arc::new(AtomicUsize::new(0)),
+			active: Arc::new(AtomicUsize::new(0)),
+			workers: Arc::new(AtomicUsize::new(0)),
+			average_secs: Arc::new(Mutex::new(None)),
+			jobs: Arc::new(Mutex::new(HashMap::new())),
+			slots: Arc::new(Mutex::new(vec![])),
+			stuck: Arc::new(AtomicUsize::new(0)),
+		}
+	}
+
+	/// Start `workers` threads that each take one job at a time off the
+	/// queue and hand it to `handle`. A job that panics is logged and
+	/// its worker carries on with the next one. Jobs are taken oldest
+	/// first, skipping any for a repository already running as many as
+	/// its configuration allows.
+	pub fn spawn_workers<F>(&self, workers: usize, logger: &Logger, handle: F)
+	where
+		F: Fn(QueuedJob) + Clone + Send + 'static,
+	{
+		for _ in 0..workers {
+			self.spawn_worker(logger, handle.clone());
+		}
+	}
+
+	fn spawn_worker<F>(&self, logger: &Logger, handle: F)
+	where
+		F: Fn(QueuedJob) + Send + 'static,
+	{
+		self.workers.fetch_add(1, Ordering::SeqCst);
+		let slot = Arc::new(Mutex::new(WorkerSlot {
+			job: None,
+			last_progress: Instant::now(),
+			abandoned: false,
+		}));
+		self.slots.lock().unwrap().push(slot.clone());
+		let waiting = self.waiting.clone();
+		let depth = self.depth.clone();
+		let active = self.active.clone();
+		let stuck = self.stuck.clone();
+		let average_secs = self.average_secs.clone();
+		let jobs = self.jobs.clone();
+		let logger = logger.clone();
+		std::thread::spawn(move || {
+			SLOT.with(|current| *current.borrow_mut() = Some(slot.clone()));
+			loop {
+				/*
+				 * Hold the lock only while waiting for a job, not while
+				 * running it, so idle workers can pick up the next one.
+				 */
+				let (lock, ready) = &*waiting;
+				let mut queued = lock.lock().unwrap();
+				let mut deferred = false;
+				let job = loop {
+					match (queued.take(), queued.deferred_load) {
+						(Some(job), _) => break job,
+						/* Nothing will say when the load comes down. */
+						(None, Some(load)) => {
+							if !deferred {
+								info!(
+									logger,
+									"Holding the next job back: the load average is {:.2}.", load
+								);
+								deferred = true;
+							}
+							queued = ready.wait_timeout(queued, LOAD_RECHECK).unwrap().0;
+						}
+						(None, None) => queued = ready.wait(queued).unwrap(),
+					}
+				};
+				drop(queued);
+				let repo = repo_key(&job);
+				depth.fetch_sub(1, Ordering::SeqCst);
+				active.fetch_add(1, Ordering::SeqCst);
+				let started = Instant::now();
+				let id = job.ctx.id.clone();
+				{
+					let mut slot = slot.lock().unwrap();
+					slot.job = Some(id.clone());
+					slot.last_progress = started;
+				}
+				if let Err(payload) = catch_unwind(AssertUnwindSafe(|| handle(job))) {
+					error!(logger, "A job panicked: {}", panic_message(&*payload));
+				}
+				jobs.lock().unwrap().remove(&id);
+				/* Its repository may have had jobs waiting on this one. */
+				lock.lock().unwrap().finished(&repo);
+				ready.notify_all();
+				let took = started.elapsed().as_secs_f64();
+				let mut average_secs = average_secs.lock().unwrap();
+				*average_secs = Some(match *average_secs {
+					Some(average) => average + AVERAGE_WEIGHT * (took - average),
+					None => took,
+				});
+				drop(average_secs);
+				active.fetch_sub(1, Ordering::SeqCst);
+
+				let mut slot = slot.lock().unwrap();
+				slot.job = None;
+				if slot.abandoned {
+					/* A replacement has already taken this worker's place. */
+					stuck.fetch_sub(1, Ordering::SeqCst);
+					info!(logger, "A stuck worker finished its job {} after all.", id);
+					return;
+				}
+			}
+		});
+	}
+
+	/// Check the workers every so often for one whose job hasn't made
+	/// progress in `stuck_after`. Its job is cancelled and handed to
+	/// `on_stuck` (to kill whatever it is waiting on), and a new worker
+	/// running `handle` takes its place so the queue keeps draining.
+	pub fn spawn_watchdog<F, G>(
+		&self,
+		stuck_after: Duration,
+		logger: &Logger,
+		handle: F,
+		on_stuck: G,
+	) where
+		F: Fn(QueuedJob) + Clone + Send + 'static,
+		G: Fn(&str) + Send + 'static,
+	{
+		let queue = self.clone();
+		let logger = logger.clone();
+		let check_every = (stuck_after / 4).max(Duration::from_secs(1));
+		std::thread::spawn(move || loop {
+			std::thread::sleep(check_every);
+			let slots: Vec<Arc<Mutex<WorkerSlot>>> = queue.slots.lock().unwrap().clone();
+			for slot in slots {
+				let mut slot = slot.lock().unwrap();
+				let id = match &slot.job {
+					Some(id) if !slot.abandoned && slot.last_progress.elapsed() > stuck_after => {
+						id.clone()
+					}
+					_ => continue,
+				};
+				slot.abandoned = true;
+				drop(slot);
+				error!(
+					logger,
+					"Job {} has made no progress in {}s; replacing its worker.",
+					id,
+					stuck_after.as_secs()
+				);
+				queue.stuck.fetch_add(1, Ordering::SeqCst);
+				queue.workers.fetch_sub(1, Ordering::SeqCst);
+				if let Some(job) = queue.jobs.lock().unwrap().get(&id) {
+					job.cancel.cancel();
+				}
+				on_stuck(&id);
+				queue.spawn_worker(&logger, handle.clone());
+			}
+			queue
+				.slots
+				.lock()
+				.unwrap()
+				.retain(|slot| !slot.lock().unwrap().abandoned);
+		});
+	}
+
+	/// Queue a job, unless `max_waiting` jobs are already waiting, in
+	/// which case it is handed back.
+	pub fn enqueue(
+		&self,
+		job: QueuedJob,
+		max_waiting: Option<usize>,
+	) -> Result<(), Box<QueuedJob>> {
+		let (lock, ready) = &*self.waiting;
+		let mut waiting = lock.lock().unwrap();
+		if let Some(max_waiting) = max_waiting {
+			if waiting.jobs.len() >= max_waiting {
+				return Err(Box::new(job));
+			}
+		}
+		self.jobs.lock().unwrap().insert(
+			job.ctx.id.clone(),
+			TrackedJob {
+				comments_url: job.ctx.comments_url.clone(),
+				head_sha: job.ctx.head_sha.clone(),
+				variant: job.ctx.variant.clone(),
+				repo: repo_key(&job),
+				cancel: job.ctx.cancel.clone(),
+			},
+		);
+		self.depth.fetch_add(1, Ordering::SeqCst);
+		waiting.jobs.push_back(job);
+		ready.notify_one();
+		Ok(())
+	}
+
+	/// Stop handing jobs to workers. Jobs already running carry on, and
+	/// jobs can still be queued; they wait until the queue is resumed.
+	pub fn pause(&self) {
+		self.waiting.0.lock().unwrap().paused = true;
+	}
+
+	pub fn resume(&self) {
+		let (lock, ready) = &*self.waiting;
+		lock.lock().unwrap().paused = false;
+		ready.notify_all();
+	}
+
+	pub fn is_paused(&self) -> bool {
+		self.waiting.0.lock().unwrap().paused
+	}
+
+	/// Cancel every job, queued or running, for the pull request whose
+	/// comments are at `comments_url`. Returns how many there were.
+	pub fn cancel_pull_request(&self, comments_url: &str) -> usize {
+		let jobs = self.jobs.lock().unwrap();
+		let mut cancelled = 0;
+		for job in jobs.values() {
+			if job.comments_url == comments_url && !job.cancel.is_cancelled() {
+				job.cancel.cancel();
+				cancelled += 1;
+			}
+		}
+		cancelled
+	}
+
+	/// Whether a job, queued or running, for the pull request whose
+	/// comments are at `comments_url` is already profiling `variant` of
+	/// `head_sha`.
+	pub fn is_profiling(&self, comments_url: &str, head_sha: &str, variant: &str) -> bool {
+		self.jobs.lock().unwrap().values().any(|job| {
+			job.comments_url == comments_url
+				&& job.head_sha == head_sha
+				&& job.variant == variant
+				&& !job.cancel.is_cancelled()
+		})
+	}
+
+	/// Cancel every job, queued or running, for the pull request whose
+	/// comments are at `comments_url` that is profiling a commit other
+	/// than `head_sha`. Returns how many there were.
+	pub fn cancel_other_commits(&self, comments_url: &str, head_sha: &str) -> usize {
+		let jobs = self.jobs.lock().unwrap();
+		let mut cancelled = 0;
+		for job in jobs.values() {
+			if job.comments_url == comments_url
+				&& job.head_sha != head_sha
+				&& !job.cancel.is_cancelled()
+			{
+				job.cancel.cancel();
+				cancelled += 1;
+			}
+		}
+		cancelled
+	}
+
+	/// Cancel every job, queued or running, for a repository (`owner/name`).
+	/// Returns the ID and comments URL of each.
+	pub fn cancel_repo(&self, repo_full_name: &str) -> Vec<(String, String)> {
+		let repo = repo_full_name.to_lowercase();
+		let jobs = self.jobs.lock().unwrap();
+		let mut cancelled = vec![];
+		for (id, job) in jobs.iter() {
+			if job.repo == repo && !job.cancel.is_cancelled() {
+				job.cancel.cancel();
+				cancelled.push((id.clone(), job.comments_url.clone()));
+			}
+		}
+		cancelled
+	}
+
+	/// How many jobs are waiting for a worker.
+	pub fn depth(&self) -> usize {
+		self.depth.load(Ordering::SeqCst)
+	}
+
+	/// How many jobs workers are running now.
+	pub fn active(&self) -> usize {
+		self.active.load(Ordering::SeqCst)
+	}
+
+	/// How many workers the watchdog has given up on whose jobs are
+	/// still hung.
+	pub fn stuck(&self) -> usize {
+		self.stuck.load(Ordering::SeqCst)
+	}
+
+	/// Whether a job queued now would have to wait for a worker.
+	pub fn busy(&self) -> bool {
+		self.depth() + self.active() >= self.workers.load(Ordering::SeqCst)
+	}
+
+	/// Roughly how long a job queued now would wait for a worker, once
+	/// enough jobs have finished to know how long they take.
+	pub fn estimated_wait(&self) -> Option<Duration> {
+		let average_secs = (*self.average_secs.lock().unwrap())?;
+		let workers = self.workers.load(Ordering::SeqCst).max(1);
+		let rounds = (self.depth() + self.active()) / workers;
+		Some(Duration::from_secs_f64(average_secs * rounds as f64))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::collections::HashSet;
+	use std::sync::mpsc::channel;
+
+	#[test]
+	fn runs_every_job_on_its_workers() {
+		let queue = WorkQueue::new();
+		let jobs = 8;
+		let workers = 3;
+		for i in 0..jobs {
+			let ctx = JobContext::for_test(&format!("job-{}", i), "comments", "sha");
+			assert!(queue.enqueue(QueuedJob::new(ctx), None).is_ok());
+		}
+		assert_eq!(queue.depth(), jobs);
+
+		let (ran, finished) = channel();
+		let logger = Logger::root(slog::Discard, slog::o!());
+		queue.spawn_workers(workers, &logger, move |job: QueuedJob| {
+			std::thread::sleep(Duration::from_millis(20));
+			ran.send((job.ctx.id, std::thread::current().id())).unwrap();
+		});
+
+		let mut ids = HashSet::new();
+		let mut threads = HashSet::new();
+		for _ in 0..jobs {
+			let (id, thread) = finished.recv_timeout(Duration::from_secs(10)).unwrap();
+			ids.insert(id);
+			threads.insert(thread);
+		}
+		assert_eq!(ids.len(), jobs);
+		assert_eq!(threads.len(), workers);
+		assert_eq!(queue.depth(), 0);
+	}
+}
@@ -0,0 +1,304 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::job::BuildInfo;
+use crate::profile::ScenarioResult;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/*
+ * Enough for every open pull request of a busy repo to be retried; beyond
+ * this the oldest results are dropped.
+ */
+const MAX_RESULTS: usize = 256;
+
+/*
+ * More runs of one pull request than anyone would list.
+ */
+const MAX_RUNS_PER_PULL_REQUEST: usize = 20;
+
+/// The scenarios of a finished profile, kept so that a later request can
+/// retry only the ones that failed, or be compared with it.
+#[derive(Clone, Debug)]
+pub struct RecentResult {
+	/// The commit that was profiled.
+	pub sha: String,
+	pub build: BuildInfo,
+	pub scenarios: Vec<ScenarioResult>,
+	pub recorded_at: SystemTime,
+}
+
+/// How one profile of a pull request ended, whether or not it has
+/// results to keep, for `profile history` and `/history.csv`.
+#[derive(Clone, Debug)]
+pub struct PastRun {
+	pub repo_full_name: String,
+	/// The pull request's page.
+	pub html_url: String,
+	pub sha: String,
+	pub variant: String,
+	/// A word or two, such as "passed" or "build failed".
+	pub result: &'static str,
+	pub profile_url: Option<String>,
+	/// Whatever scenarios NimbleDroid reported, all of them.
+	pub scenarios: Vec<ScenarioResult>,
+	pub recorded_at: SystemTime,
+}
+
+/// A successful profile, as a line of the `history_file`.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct HistoryRecord {
+	/// When the results came in, in Unix seconds.
+	pub timestamp: u64,
+	pub clone_url: String,
+	pub head_sha: String,
+	pub commenter: Option<String>,
+	pub scenarios: Vec<HistoryScenario>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct HistoryScenario {
+	pub name: String,
+	pub status: String,
+	pub time_in_ms: u64,
+}
+
+impl HistoryRecord {
+	pub fn new(
+		clone_url: &str,
+		head_sha: &str,
+		commenter: Option<&str>,
+		scenarios: &[ScenarioResult],
+	) -> Self {
+		Self {
+			timestamp: SystemTime::now()
+				.duration_since(UNIX_EPOCH)
+				.map_or(0, |d| d.as_secs()),
+			clone_url: clone_url.to_string(),
+			head_sha: head_sha.to_string(),
+			commenter: commenter.map(|c| c.to_string()),
+			scenarios: scenarios
+				.iter()
+				.map(|s| HistoryScenario {
+					name: s.name.clone(),
+					status: s.status.clone(),
+					time_in_ms: s.time_in_ms,
+				})
+				.collect(),
+		}
+	}
+}
+
+/*
+ * Held while a line is appended, so the workers' lines can't interleave.
+ */
+static HISTORY_LOCK: Mutex<()> = Mutex::new(());
+
+/// Append `record` to the JSON-lines file at `path`, creating it if
+/// need be.
+pub fn append_history(path: &str, record: &HistoryRecord) -> Result<(), String> {
+	let mut line = serde_json::to_string(record)
+		.map_err(|e| format!("Oops, couldn't serialize the history record: {}", e))?;
+	line.push('\n');
+	let _held = HISTORY_LOCK.lock().unwrap();
+	OpenOptions::new()
+		.create(true)
+		.append(true)
+		.open(path)
+		.and_then(|mut file| file.write_all(line.as_bytes()))
+		.map_err(|e| format!("Oops, couldn't append to {}: {}", path, e))
+}
+
+impl RecentResult {
+	pub fn failed(&self) -> Vec<&ScenarioResult> {
+		self.scenarios.iter().filter(|s| !s.passed()).collect()
+	}
+}
+
+/// Recent results keyed by repository, commit and variant, and the
+/// latest result of each pull request, shared by every worker.
+#[derive(Clone, Default)]
+pub struct ResultCache {
+	results: Arc<Mutex<HashMap<String, RecentResult>>>,
+	by_pull_request: Arc<Mutex<HashMap<String, RecentResult>>>,
+	/* Each pull request's runs, oldest first. */
+	runs: Arc<Mutex<HashMap<String, Vec<PastRun>>>>,
+}
+
+fn key(repo_full_name: &str, sha: &str, variant: &str) -> String {
+	format!(
+		"{}@{}/{}",
+		repo_full_name.to_lowercase(),
+		sha.to_lowercase(),
+		variant
+	)
+}
+
+fn pull_request_key(pull_request_url: &str, variant: &str) -> String {
+	format!("{}/{}", pull_request_url.to_lowercase(), variant)
+}
+
+fn insert_capped(results: &mut HashMap<String, RecentResult>, key: String, result: RecentResult) {
+	if results.len() >= MAX_RESULTS && !results.contains_key(&key) {
+		let oldest = results
+			.iter()
+			.min_by_key(|(_, r)| r.recorded_at)
+			.map(|(k, _)| k.clone());
+		if let Some(oldest) = oldest {
+			results.remove(&oldest);
+		}
+	}
+	results.insert(key, result);
+}
+
+impl ResultCache {
+	/// Keep a result both by commit and as the latest of its pull
+	/// request, which `pull_request_url` names.
+	pub fn insert(
+		&self,
+		repo_full_name: &str,
+		pull_request_url: &str,
+		sha: &str,
+		variant: &str,
+		build: &BuildInfo,
+		scenarios: Vec<ScenarioResult>,
+	) {
+		let result = RecentResult {
+			sha: sha.to_string(),
+			build: build.clone(),
+			scenarios,
+			recorded_at: SystemTime::now(),
+		};
+		insert_capped(
+			&mut self.by_pull_request.lock().unwrap(),
+			pull_request_key(pull_request_url, variant),
+			result.clone(),
+		);
+		insert_capped(
+			&mut self.results.lock().unwrap(),
+			key(repo_full_name, sha, variant),
+			result,
+		);
+	}
+
+	pub fn get(&self, repo_full_name: &str, sha: &str, variant: &str) -> Option<RecentResult> {
+		self.results
+			.lock()
+			.unwrap()
+			.get(&key(repo_full_name, sha, variant))
+			.cloned()
+	}
+
+	/// The latest result of a pull request for a variant.
+	pub fn last_for_pull_request(
+		&self,
+		pull_request_url: &str,
+		variant: &str,
+	) -> Option<RecentResult> {
+		self.by_pull_request
+			.lock()
+			.unwrap()
+			.get(&pull_request_key(pull_request_url, variant))
+			.cloned()
+	}
+
+	/// Remember how a profile of a pull request ended.
+	pub fn record_run(&self, pull_request_url: &str, run: PastRun) {
+		let key = pull_request_url.to_lowercase();
+		let mut runs = self.runs.lock().unwrap();
+		if runs.len() >= MAX_RESULTS && !runs.contains_key(&key) {
+			let oldest = runs
+				.iter()
+				.min_by_key(|(_, r)| r.last().map(|run| run.recorded_at))
+				.map(|(k, _)| k.clone());
+			if let Some(oldest) = oldest {
+				runs.remove(&oldest);
+			}
+		}
+		let pull_request_runs = runs.entry(key).or_default();
+		if pull_request_runs.len() >= MAX_RUNS_PER_PULL_REQUEST {
+			pull_request_runs.remove(0);
+		}
+		pull_request_runs.push(run);
+	}
+
+	/// Up to `count` of a pull request's latest runs, newest first.
+	pub fn runs(&self, pull_request_url: &str, count: usize) -> Vec<PastRun> {
+		self.runs
+			.lock()
+			.unwrap()
+			.get(&pull_request_url.to_lowercase())
+			.map(|runs| runs.iter().rev().take(count).cloned().collect())
+			.unwrap_or_default()
+	}
+
+	/// Every pull request's runs, oldest first.
+	pub fn all_runs(&self) -> Vec<PastRun> {
+		let mut runs: Vec<PastRun> = self
+			.runs
+			.lock()
+			.unwrap()
+			.values()
+			.flat_map(|runs| runs.iter().cloned())
+			.collect();
+		runs.sort_by_key(|run| run.recorded_at);
+		runs
+	}
+
+	/// Forget every cached result, returning how many there were.
+	pub fn clear(&self) -> usize {
+		self.runs.lock().unwrap().clear();
+		self.by_pull_request.lock().unwrap().clear();
+		let mut results = self.results.lock().unwrap();
+		let cleared = results.len();
+		results.clear();
+		cleared
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tempdir::TempDir;
+
+	#[test]
+	fn appends_history_records_a_line_each() {
+		let dir = TempDir::new("history").unwrap();
+		let path = dir.path().join("history.jsonl");
+		let path = path.to_str().unwrap();
+		let scenarios = vec![ScenarioResult {
+			name: "startup".to_string(),
+			status: "profiled".to_string(),
+			time_in_ms: 1200,
+			ignored: false,
+		}];
+		let first = HistoryRecord::new(
+			"https://github.com/mozilla-mobile/fenix.git",
+			"aaaa",
+			Some("alice"),
+			&scenarios,
+		);
+		let second = HistoryRecord::new(
+			"https://github.com/mozilla-mobile/fenix.git",
+			"bbbb",
+			None,
+			&scenarios,
+		);
+		append_history(path, &first).unwrap();
+		append_history(path, &second).unwrap();
+
+		let contents = std::fs::read_to_string(path).unwrap();
+		let records: Vec<HistoryRecord> = contents
+			.lines()
+			.map(|line| serde_json::from_str(line).unwrap())
+			.collect();
+		assert_eq!(records, vec![first, second]);
+	}
+}
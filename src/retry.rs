@@ -0,0 +1,54 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use slog::{info, Logger};
+use std::fmt::Display;
+use std::time::Duration;
+
+/// How an attempt at something failed.
+pub enum Failure<E> {
+	/// Trying again may well work, as when the other end couldn't be
+	/// reached or answered with a server error.
+	Transient(E),
+	/// Trying again won't help, as for a bad token.
+	Permanent(E),
+}
+
+/// Whether a response with `status` is worth asking again for: a server
+/// error, or a 429. Any other 4xx is for something wrong with the
+/// request.
+pub fn is_transient(status: reqwest::StatusCode) -> bool {
+	status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Do `op` until it succeeds or fails permanently, at most `attempts`
+/// times, waiting 1s, 2s, 4s and so on between attempts.
+pub fn with_retries<T, E: Display>(
+	logger: &Logger,
+	attempts: u32,
+	mut op: impl FnMut() -> Result<T, Failure<E>>,
+) -> Result<T, E> {
+	let mut attempt = 1;
+	loop {
+		match op() {
+			Ok(value) => return Ok(value),
+			Err(Failure::Transient(e)) if attempt < attempts => {
+				let wait = Duration::from_secs(1 << (attempt - 1).min(6));
+				info!(
+					logger,
+					"Attempt {} of {} failed: {}; retrying in {}s.",
+					attempt,
+					attempts,
+					e,
+					wait.as_secs()
+				);
+				std::thread::sleep(wait);
+				attempt += 1;
+			}
+			Err(Failure::Transient(e)) | Err(Failure::Permanent(e)) => return Err(e),
+		}
+	}
+}
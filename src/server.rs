@@ -0,0 +1,94 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::config::Config;
+#[cfg(feature = "tls")]
+use crate::tls::Certificates;
+use futures::compat::{AsyncRead01CompatExt, Stream01CompatExt};
+use futures::future::{FutureExt, FutureObj, TryFutureExt};
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::stream::{TryStream, TryStreamExt};
+use futures::task::{Spawn, SpawnError};
+use hyper::server::conn::AddrIncoming;
+use slog::{info, Logger};
+use std::net::ToSocketAddrs;
+use tide::App;
+use tokio::runtime::{Builder, Runtime, TaskExecutor};
+
+/*
+ * Hands each connection hyper accepts to the runtime the server runs
+ * on, which is what hyper's own default would have done.
+ */
+#[derive(Clone)]
+struct OnTheRuntime(TaskExecutor);
+
+impl Spawn for &OnTheRuntime {
+	fn spawn_obj(&mut self, future: FutureObj<'static, ()>) -> Result<(), SpawnError> {
+		self.0.spawn(future.unit_error().compat());
+		Ok(())
+	}
+}
+
+/// Serve `app` on `config.bind` until the process exits, with
+/// `config.server_threads` threads to answer requests on, or one per
+/// CPU. With `config.tls_cert_path`, that's HTTPS.
+pub fn serve<State: Send + Sync + 'static>(
+	app: App<State>,
+	config: &Config,
+	logger: &Logger,
+) -> Result<(), String> {
+	let bind = &config.bind;
+	let addr = bind
+		.to_socket_addrs()
+		.ok()
+		.and_then(|mut addrs| addrs.next())
+		.ok_or_else(|| format!("Oops, {} isn't an address to listen on.", bind))?;
+	let mut builder = Builder::new();
+	builder.name_prefix("ndhook-server-");
+	if let Some(threads) = config.server_threads {
+		builder.core_threads(threads);
+	}
+	let runtime = builder
+		.build()
+		.map_err(|e| format!("Oops, couldn't start the server's threads: {}", e))?;
+	/*
+	 * hyper's own listener, so that running out of file descriptors
+	 * pauses accepting instead of stopping the server.
+	 */
+	let incoming = AddrIncoming::bind(&addr)
+		.map_err(|e| format!("Oops, couldn't listen on {}: {}", bind, e))?
+		.compat();
+	/* validate refuses TLS settings when there's no tls feature. */
+	match (&config.tls_cert_path, &config.tls_key_path) {
+		#[cfg(feature = "tls")]
+		(Some(cert_path), Some(key_path)) => {
+			let certificates = Certificates::load(cert_path, key_path)?;
+			certificates.reload_on_sighup(logger.clone())?;
+			let incoming = incoming.map_ok(move |stream| certificates.accept(stream).compat());
+			info!(logger, "Listening on {} with TLS.", bind);
+			run(runtime, incoming, app)
+		}
+		_ => {
+			info!(logger, "Listening on {}.", bind);
+			run(runtime, incoming.map_ok(|stream| stream.compat()), app)
+		}
+	}
+}
+
+fn run<State, I>(mut runtime: Runtime, incoming: I, app: App<State>) -> Result<(), String>
+where
+	State: Send + Sync + 'static,
+	I: TryStream + Unpin + Send + 'static,
+	I::Ok: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+	I::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+	let server = http_service_hyper::Server::builder(incoming)
+		.with_spawner(OnTheRuntime(runtime.executor()))
+		.serve(app.into_http_service());
+	runtime
+		.block_on(server.boxed().compat())
+		.map_err(|e| format!("Oops, the server stopped: {}", e))
+}
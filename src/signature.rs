@@ -0,0 +1,57 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+
+fn hmac_sha256(secret: &str, body: &[u8]) -> Option<Vec<u8>> {
+	let key = PKey::hmac(secret.as_bytes()).ok()?;
+	let mut signer = Signer::new(MessageDigest::sha256(), &key).ok()?;
+	signer.update(body).ok()?;
+	signer.sign_to_vec().ok()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+	hex.as_bytes()
+		.chunks(2)
+		.map(|pair| match std::str::from_utf8(pair) {
+			Ok(pair) if pair.len() == 2 => u8::from_str_radix(pair, 16).ok(),
+			_ => None,
+		})
+		.collect()
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Sign `body` the way GitHub signs its deliveries, as the value of a
+/// `sha256=<hex>` header, for whoever receives what the bot sends.
+pub fn sign(secret: &str, body: &[u8]) -> Option<String> {
+	hmac_sha256(secret, body).map(|hmac| format!("sha256={}", to_hex(&hmac)))
+}
+
+/// Whether `signature`, an `X-Hub-Signature-256` header, is GitHub's
+/// HMAC of `body` under any of `secrets`. Each comparison takes the same
+/// time however much of the signature matches.
+pub fn signed_by_any(secrets: &[String], body: &[u8], signature: Option<&str>) -> bool {
+	let given = match signature
+		.and_then(|s| s.strip_prefix("sha256="))
+		.and_then(from_hex)
+	{
+		Some(given) => given,
+		None => return false,
+	};
+	secrets
+		.iter()
+		.filter_map(|secret| hmac_sha256(secret, body))
+		.fold(false, |matched, expected| {
+			let equal = expected.len() == given.len() && memcmp::eq(&expected, &given);
+			matched | equal
+		})
+}
@@ -0,0 +1,483 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::config::{CommentOn, Config, SinkKind};
+use crate::format::{
+	format_history_comment, format_outcome_comment, format_status_description, CommentOptions,
+};
+use crate::github::GitHubClient;
+use crate::job::{JobContext, ProfileOutcome, ProfiledRef};
+use crate::results::ResultCache;
+use crate::signature;
+use crate::trace;
+use serde_json::json;
+use slog::{error, info};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Somewhere the outcome of a profile run is delivered.
+///
+/// Sinks are independent of one another: each one logs its own failures
+/// and none can stop the others from running.
+pub trait ResultSink {
+	fn deliver(&self, ctx: &JobContext, result: &ProfileOutcome);
+}
+
+/// Comments on the pull request with the full results table: a comment
+/// per run, or one sticky comment kept up to date with the latest
+/// results and the pull request's recent runs.
+pub struct GitHubCommentSink {
+	github: GitHubClient,
+	options: CommentOptions,
+	comment_on: CommentOn,
+	/// The pull request's runs, and how many of them to list, when the
+	/// comment is sticky.
+	sticky: Option<(ResultCache, usize)>,
+	/// The file and line whose review thread the comments go to, if
+	/// they go to one.
+	thread: Option<(String, u64)>,
+	/// The secret to sign comments with, if they are signed.
+	signing_secret: Option<String>,
+}
+
+/*
+ * Marks the review comment that starts the thread every run replies to.
+ */
+static THREAD_MARKER: &str = "<!-- ndhook:thread -->";
+
+/*
+ * Room for the signature line: its marker and a SHA-256 in hex.
+ */
+const SIGNATURE_LENGTH: usize = 100;
+
+impl GitHubCommentSink {
+	/*
+	 * End a comment with its signature, when comments are signed.
+	 */
+	fn signed(&self, comment: String) -> String {
+		let secret = match &self.signing_secret {
+			Some(secret) => secret,
+			None => return comment,
+		};
+		match signature::sign(secret, comment.as_bytes()) {
+			Some(signature) => format!("{}\n\n<!-- ndhook:signature={} -->", comment, signature),
+			None => comment,
+		}
+	}
+}
+
+impl ResultSink for GitHubCommentSink {
+	fn deliver(&self, ctx: &JobContext, result: &ProfileOutcome) {
+		let wanted = match self.comment_on {
+			CommentOn::Always => true,
+			CommentOn::Failure => !result.is_passing(&self.options.budgets_ms),
+			CommentOn::Never => false,
+		};
+		if !wanted {
+			info!(
+				ctx.logger,
+				"Not commenting: comment_on is {:?}.", self.comment_on
+			);
+			return;
+		}
+		let _span = trace::span("comment-post");
+		/*
+		 * The job ID names this delivery, so that delivering it again
+		 * edits the comment rather than posting another. A sticky
+		 * comment is the same one for every job.
+		 */
+		let (marker, history) = match &self.sticky {
+			Some((results, count)) => (
+				"<!-- ndhook:sticky -->".to_string(),
+				format!(
+					"\n\n<details><summary>Recent runs</summary>\n\n{}</details>",
+					format_history_comment(&results.runs(&ctx.comments_url, *count))
+				),
+			),
+			None => (format!("<!-- ndhook:job={} -->", ctx.id), String::new()),
+		};
+		let options = CommentOptions {
+			max_length: self
+				.options
+				.max_length
+				.saturating_sub(marker.len() + history.len() + 2 + SIGNATURE_LENGTH),
+			previous_run: ctx.previous_run.clone(),
+			baseline: ctx.baseline.clone(),
+			mentions: ctx.mentions.clone(),
+			late: ctx.late_results,
+			..self.options.clone()
+		};
+		let comment = format_outcome_comment(
+			result,
+			&ctx.build,
+			&ctx.head_sha,
+			ctx.commenter.as_deref(),
+			&options,
+		);
+		let comment = format!("{}{}\n\n{}", comment, history, marker);
+		if let (Some((path, line)), Some(pull_request_url)) = (&self.thread, &ctx.pull_request_url)
+		{
+			let posted = self.github.post_to_review_thread(
+				pull_request_url,
+				THREAD_MARKER,
+				&ctx.head_sha,
+				(path, *line),
+				&self.signed(comment.clone()),
+				&self.signed(format!("{}\n\n{}", comment, THREAD_MARKER)),
+			);
+			match posted {
+				Ok(()) => {
+					info!(ctx.logger, "Posted to the review thread.");
+					return;
+				}
+				Err(e) => error!(
+					ctx.logger,
+					"Failed to post to the review thread; commenting instead: {}", e
+				),
+			}
+		}
+		let comment = self.signed(comment);
+		match self
+			.github
+			.upsert_comment(&ctx.comments_url, &marker, &comment)
+		{
+			Ok(()) => info!(ctx.logger, "Posted a comment."),
+			Err(e) => error!(ctx.logger, "Failed to post a comment: {}", e),
+		}
+	}
+}
+
+/// Reviews the pull request with the full results table, requesting
+/// changes when the profile doesn't pass. Reviews can't be edited into
+/// another verdict, so each run leaves a review of its own.
+pub struct PullRequestReviewSink {
+	github: GitHubClient,
+	options: CommentOptions,
+	/// Approve passing profiles, rather than only commenting.
+	approve: bool,
+}
+
+impl ResultSink for PullRequestReviewSink {
+	fn deliver(&self, ctx: &JobContext, result: &ProfileOutcome) {
+		let pull_request_url = match &ctx.pull_request_url {
+			Some(pull_request_url) => pull_request_url,
+			None => {
+				info!(ctx.logger, "Not reviewing: there's no pull request.");
+				return;
+			}
+		};
+		/*
+		 * Only a profile that ran says anything about the change; one
+		 * that couldn't is reported without a verdict.
+		 */
+		let passing = result.is_passing(&self.options.budgets_ms);
+		let event = match result {
+			ProfileOutcome::Success { .. } if passing && self.approve => "APPROVE",
+			ProfileOutcome::Success { .. } if !passing => "REQUEST_CHANGES",
+			_ => "COMMENT",
+		};
+
+		/*
+		 * A merge commit isn't one of the pull request's own, so a review
+		 * of it goes on the head as it is now.
+		 */
+		let commit_id = match ctx.profiled_ref {
+			Some(ProfiledRef::Merge { .. }) => None,
+			_ => Some(ctx.head_sha.as_str()),
+		};
+		let options = CommentOptions {
+			previous_run: ctx.previous_run.clone(),
+			baseline: ctx.baseline.clone(),
+			mentions: ctx.mentions.clone(),
+			late: ctx.late_results,
+			..self.options.clone()
+		};
+		let body = format_outcome_comment(
+			result,
+			&ctx.build,
+			&ctx.head_sha,
+			ctx.commenter.as_deref(),
+			&options,
+		);
+		match self
+			.github
+			.create_review(pull_request_url, commit_id, event, &body)
+		{
+			Ok(()) => info!(ctx.logger, "Reviewed the pull request: {}.", event),
+			Err(e) => error!(ctx.logger, "Failed to review the pull request: {}", e),
+		}
+	}
+}
+
+/// Sets an `ndhook/profile` commit status on the profiled head.
+pub struct CommitStatusSink {
+	github: GitHubClient,
+	/// When set, fail the status if any scenario goes over its budget.
+	budgets_ms: Option<BTreeMap<String, u64>>,
+	/// Describe successful profiles with the compact one-line summary,
+	/// counting regressions of at least this percent.
+	compact: Option<f64>,
+}
+
+impl ResultSink for CommitStatusSink {
+	fn deliver(&self, ctx: &JobContext, result: &ProfileOutcome) {
+		let (state, description) = match result {
+			ProfileOutcome::Success { scenarios, .. } => {
+				let failed = scenarios.iter().filter(|s| !s.passed()).count();
+				let over_budget = match &self.budgets_ms {
+					Some(budgets_ms) => scenarios
+						.iter()
+						.filter(|s| s.over_budget(budgets_ms).is_some())
+						.count(),
+					None => 0,
+				};
+				let state = if failed == 0 && over_budget == 0 {
+					"success"
+				} else {
+					"failure"
+				};
+				let description = match self.compact {
+					Some(regression_percent) => format_status_description(
+						scenarios,
+						&ctx.config.scenario_budgets_ms,
+						ctx.baseline.as_ref(),
+						regression_percent,
+						result.profile_url().is_some(),
+					),
+					None => {
+						let mut description =
+							format!("{} passed, {} failed", scenarios.len() - failed, failed);
+						if over_budget > 0 {
+							description.push_str(&format!(", {} over budget", over_budget));
+						}
+						description
+					}
+				};
+				(state, description)
+			}
+			ProfileOutcome::Timeout { .. } => {
+				("error", "Timed out waiting for NimbleDroid".to_string())
+			}
+			ProfileOutcome::ResultsUnavailable { .. } => (
+				"error",
+				"Could not get the results from NimbleDroid".to_string(),
+			),
+			ProfileOutcome::Cancelled { .. } => ("error", "Cancelled".to_string()),
+			/* The code failing to build is its own fault; the machinery isn't. */
+			ProfileOutcome::BuildFailed {
+				infrastructure: false,
+				..
+			} => ("failure", "The build failed".to_string()),
+			ProfileOutcome::BuildFailed { .. } => (
+				"error",
+				"The build failed for reasons of infrastructure".to_string(),
+			),
+			ProfileOutcome::UploadRejected { .. } => {
+				("error", "NimbleDroid rejected the upload".to_string())
+			}
+		};
+		match self.github.create_status(
+			&ctx.statuses_url,
+			state,
+			&description,
+			result.profile_url(),
+		) {
+			Ok(()) => info!(ctx.logger, "Set the commit status."),
+			Err(e) => error!(ctx.logger, "Failed to set the commit status: {}", e),
+		}
+	}
+}
+
+/// Posts a one-line summary to a Slack incoming webhook.
+pub struct SlackSink {
+	webhook_url: String,
+	dry_run: bool,
+}
+
+impl ResultSink for SlackSink {
+	fn deliver(&self, ctx: &JobContext, result: &ProfileOutcome) {
+		let mut text = format!("Profiled <{}|{}>", ctx.html_url, ctx.html_url);
+		if let Some(commenter) = &ctx.commenter {
+			text.push_str(&format!(" for {}", commenter));
+		}
+		match result {
+			ProfileOutcome::Success { scenarios, .. } => {
+				let failed = scenarios.iter().filter(|s| !s.passed()).count();
+				text.push_str(&format!(
+					": {} passed, {} failed.",
+					scenarios.len() - failed,
+					failed
+				));
+			}
+			ProfileOutcome::Timeout { .. } => text.push_str(": timed out waiting for NimbleDroid."),
+			ProfileOutcome::ResultsUnavailable { .. } => {
+				text.push_str(": could not get the results from NimbleDroid.")
+			}
+			ProfileOutcome::Cancelled { .. } => text.push_str(": cancelled."),
+			ProfileOutcome::BuildFailed { .. } => text.push_str(": the build failed."),
+			ProfileOutcome::UploadRejected { .. } => {
+				text.push_str(": NimbleDroid rejected the upload.")
+			}
+		}
+		if let Some(profile_url) = result.profile_url() {
+			text.push_str(&format!(" <{}|NimbleDroid results>", profile_url));
+		}
+
+		if self.dry_run {
+			info!(ctx.logger, "Dry run: not posting to Slack: {}", text);
+			return;
+		}
+
+		let client = reqwest::Client::new();
+		match client
+			.post(&self.webhook_url)
+			.json(&json!({ "text": text }))
+			.send()
+		{
+			Ok(ref response) if response.status().is_success() => {
+				info!(ctx.logger, "Posted to Slack.")
+			}
+			Ok(response) => error!(
+				ctx.logger,
+				"Failed to post to Slack: Slack responded {}",
+				response.status()
+			),
+			Err(e) => error!(ctx.logger, "Failed to post to Slack: {}", e),
+		}
+	}
+}
+
+/// POSTs the outcome as JSON to a URL of the deployment's choosing, for
+/// dashboards and automation of its own.
+pub struct WebhookSink {
+	url: String,
+	token: Option<String>,
+	secret: Option<String>,
+	retries: u32,
+	dry_run: bool,
+}
+
+impl ResultSink for WebhookSink {
+	fn deliver(&self, ctx: &JobContext, result: &ProfileOutcome) {
+		let body = json!({
+			"job": ctx.id,
+			"repository": ctx.repo_full_name,
+			"pull_request": ctx.html_url,
+			"sha": ctx.head_sha,
+			"variant": ctx.variant,
+			"commenter": ctx.commenter,
+			"result": result.to_json(),
+		})
+		.to_string();
+
+		if self.dry_run {
+			info!(ctx.logger, "Dry run: not posting to the webhook: {}", body);
+			return;
+		}
+
+		let client = reqwest::Client::new();
+		let signature = self
+			.secret
+			.as_ref()
+			.and_then(|secret| signature::sign(secret, body.as_bytes()));
+		for attempt in 0..=self.retries {
+			if attempt > 0 {
+				std::thread::sleep(Duration::from_secs(1 << attempt.min(6)));
+			}
+			let mut request = client
+				.post(&self.url)
+				.header(reqwest::header::CONTENT_TYPE, "application/json")
+				.body(body.clone());
+			if let Some(token) = &self.token {
+				request = request.bearer_auth(token);
+			}
+			if let Some(signature) = &signature {
+				request = request
+					.header("X-Ndhook-Signature", signature.as_str())
+					.header("X-Ndhook-Signature-256", signature.as_str());
+			}
+			match request.send() {
+				Ok(ref response) if response.status().is_success() => {
+					info!(ctx.logger, "Posted to the webhook.");
+					return;
+				}
+				Ok(response) => error!(
+					ctx.logger,
+					"Failed to post to the webhook (attempt {}): it responded {}",
+					attempt + 1,
+					response.status()
+				),
+				Err(e) => error!(
+					ctx.logger,
+					"Failed to post to the webhook (attempt {}): {}",
+					attempt + 1,
+					e
+				),
+			}
+		}
+	}
+}
+
+/// Build the sinks this deployment delivers results to, in the order
+/// they are listed in the configuration.
+pub fn sinks_from_config(
+	config: &Config,
+	github: &GitHubClient,
+	results: &ResultCache,
+) -> Vec<Box<dyn ResultSink>> {
+	config
+		.sinks
+		.iter()
+		.map(|kind| -> Box<dyn ResultSink> {
+			match kind {
+				SinkKind::Comment => Box::new(GitHubCommentSink {
+					github: github.clone(),
+					options: CommentOptions::from_config(config),
+					comment_on: config.comment_on,
+					sticky: if config.sticky_comment {
+						Some((results.clone(), config.history_count))
+					} else {
+						None
+					},
+					thread: config
+						.review_thread_path
+						.clone()
+						.map(|path| (path, config.review_thread_line)),
+					signing_secret: config.comment_signing_secret.clone(),
+				}),
+				SinkKind::Status => Box::new(CommitStatusSink {
+					github: github.clone(),
+					budgets_ms: if config.fail_over_budget {
+						Some(config.scenario_budgets_ms.clone())
+					} else {
+						None
+					},
+					compact: if config.compact_status {
+						Some(config.regression_percent)
+					} else {
+						None
+					},
+				}),
+				SinkKind::Slack => Box::new(SlackSink {
+					webhook_url: config.slack_webhook_url.clone().unwrap_or_default(),
+					dry_run: github.dry_run(),
+				}),
+				SinkKind::Review => Box::new(PullRequestReviewSink {
+					github: github.clone(),
+					options: CommentOptions::from_config(config),
+					approve: config.review_approve,
+				}),
+				SinkKind::Webhook => Box::new(WebhookSink {
+					url: config.webhook_sink_url.clone().unwrap_or_default(),
+					token: config.webhook_sink_token.clone(),
+					secret: config.webhook_sink_secret.clone(),
+					retries: config.webhook_sink_retries,
+					dry_run: github.dry_run(),
+				}),
+			}
+		})
+		.collect()
+}
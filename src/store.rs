@@ -0,0 +1,199 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::config::{Config, StoreKind};
+use crate::signature::to_hex;
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::fs::File;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Somewhere a run's artifacts are kept once the run is over.
+pub trait ArtifactStore {
+	/// Keep the file at `path` under `key`, returning the URL it can be
+	/// downloaded from, if it can be.
+	fn store(&self, key: &str, path: &Path) -> Result<Option<String>, String>;
+}
+
+/// Leaves the artifacts on the host, under `artifact_root` when that is
+/// set. There is nothing to link to.
+pub struct LocalStore;
+
+impl ArtifactStore for LocalStore {
+	fn store(&self, _key: &str, _path: &Path) -> Result<Option<String>, String> {
+		Ok(None)
+	}
+}
+
+/// Puts the artifacts in a bucket on an S3-compatible service, which
+/// includes Google Cloud Storage with HMAC keys.
+pub struct S3Store {
+	endpoint: reqwest::Url,
+	bucket: String,
+	region: String,
+	access_key_id: String,
+	secret_access_key: String,
+	dry_run: bool,
+}
+
+/*
+ * What S3 leaves unescaped in a key: everything else is percent-encoded
+ * before it is signed.
+ */
+const KEY_SAFE: &AsciiSet = &NON_ALPHANUMERIC
+	.remove(b'-')
+	.remove(b'_')
+	.remove(b'.')
+	.remove(b'~')
+	.remove(b'/');
+
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+impl ArtifactStore for S3Store {
+	fn store(&self, key: &str, path: &Path) -> Result<Option<String>, String> {
+		let object_path = format!(
+			"/{}/{}",
+			utf8_percent_encode(&self.bucket, KEY_SAFE),
+			utf8_percent_encode(key, KEY_SAFE)
+		);
+		let url = self
+			.endpoint
+			.join(&object_path)
+			.map_err(|e| format!("Oops, couldn't make the object URL: {}", e))?;
+		if self.dry_run {
+			return Ok(Some(url.to_string()));
+		}
+		let file = File::open(path)
+			.map_err(|e| format!("Oops, couldn't open {}: {}", path.display(), e))?;
+		let length = file
+			.metadata()
+			.map_err(|e| format!("Oops, couldn't read {}: {}", path.display(), e))?
+			.len();
+		let host = match (url.host_str(), url.port()) {
+			(Some(host), Some(port)) => format!("{}:{}", host, port),
+			(Some(host), None) => host.to_string(),
+			(None, _) => return Err(format!("Oops, {} has no host", url)),
+		};
+		let (date, timestamp) = amz_dates(SystemTime::now());
+		let authorization = self.authorize(&object_path, &host, &date, &timestamp)?;
+		let response = reqwest::Client::new()
+			.put(url.clone())
+			.header("Host", host)
+			.header("X-Amz-Date", timestamp)
+			.header("X-Amz-Content-Sha256", UNSIGNED_PAYLOAD)
+			.header("Authorization", authorization)
+			.body(reqwest::Body::sized(file, length))
+			.send()
+			.map_err(|e| format!("Oops, couldn't upload {}: {}", key, e))?;
+		if !response.status().is_success() {
+			return Err(format!(
+				"Oops, the bucket refused {}: {}",
+				key,
+				response.status()
+			));
+		}
+		Ok(Some(url.to_string()))
+	}
+}
+
+impl S3Store {
+	/*
+	 * Sign a PUT of `object_path` with AWS Signature Version 4, leaving
+	 * the body unsigned so an APK needn't be read twice.
+	 */
+	fn authorize(
+		&self,
+		object_path: &str,
+		host: &str,
+		date: &str,
+		timestamp: &str,
+	) -> Result<String, String> {
+		let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+		let canonical_request = format!(
+			"PUT\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+			object_path, host, UNSIGNED_PAYLOAD, timestamp, signed_headers, UNSIGNED_PAYLOAD
+		);
+		let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+		let hashed = hash(MessageDigest::sha256(), canonical_request.as_bytes())
+			.map_err(|e| format!("Oops, couldn't hash the request: {}", e))?;
+		let string_to_sign = format!(
+			"AWS4-HMAC-SHA256\n{}\n{}\n{}",
+			timestamp,
+			scope,
+			to_hex(&hashed)
+		);
+		let mut key = format!("AWS4{}", self.secret_access_key).into_bytes();
+		for part in &[date, &self.region, "s3", "aws4_request"] {
+			key = hmac(&key, part.as_bytes())?;
+		}
+		let signature = hmac(&key, string_to_sign.as_bytes())?;
+		Ok(format!(
+			"AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+			self.access_key_id,
+			scope,
+			signed_headers,
+			to_hex(&signature)
+		))
+	}
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Result<Vec<u8>, String> {
+	let sign = || -> Result<Vec<u8>, openssl::error::ErrorStack> {
+		let key = PKey::hmac(key)?;
+		let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+		signer.update(data)?;
+		signer.sign_to_vec()
+	};
+	sign().map_err(|e| format!("Oops, couldn't sign the request: {}", e))
+}
+
+/*
+ * The UTC date (`YYYYMMDD`) and timestamp (`YYYYMMDDTHHMMSSZ`) a signed
+ * request is stamped with.
+ */
+fn amz_dates(now: SystemTime) -> (String, String) {
+	let secs = now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+	let (days, rest) = ((secs / 86400) as i64, secs % 86400);
+	/* Days since the epoch to a civil date, after Howard Hinnant. */
+	let z = days + 719_468;
+	let era = z.div_euclid(146_097);
+	let doe = z.rem_euclid(146_097);
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let day = doy - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+	let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+	let date = format!("{:04}{:02}{:02}", year, month, day);
+	let timestamp = format!(
+		"{}T{:02}{:02}{:02}Z",
+		date,
+		rest / 3600,
+		rest % 3600 / 60,
+		rest % 60
+	);
+	(date, timestamp)
+}
+
+/// The store the configuration asks for.
+pub fn store_from_config(config: &Config, dry_run: bool) -> Box<dyn ArtifactStore> {
+	match config.artifact_store {
+		StoreKind::Local => Box::new(LocalStore),
+		/* The configuration was checked when it was loaded. */
+		StoreKind::S3 => Box::new(S3Store {
+			endpoint: reqwest::Url::parse(config.s3_endpoint.as_deref().unwrap_or_default())
+				.unwrap(),
+			bucket: config.s3_bucket.clone().unwrap_or_default(),
+			region: config.s3_region.clone(),
+			access_key_id: config.s3_access_key_id.clone().unwrap_or_default(),
+			secret_access_key: config.s3_secret_access_key.clone().unwrap_or_default(),
+			dry_run,
+		}),
+	}
+}
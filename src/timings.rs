@@ -0,0 +1,87 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A part of a job worth timing.
+#[derive(Clone, Copy)]
+pub enum Phase {
+	Build,
+	Profile,
+}
+
+/// How long the latest builds and profiles of each variant took, for
+/// sizing the host.
+#[derive(Clone, Default)]
+pub struct Timings {
+	variants: Arc<Mutex<BTreeMap<String, VariantTimings>>>,
+}
+
+#[derive(Default)]
+struct VariantTimings {
+	build: VecDeque<Duration>,
+	profile: VecDeque<Duration>,
+}
+
+impl Timings {
+	/// Record how long a phase of a job on `variant` took, keeping only
+	/// the latest `window` of them.
+	pub fn record(&self, variant: &str, phase: Phase, took: Duration, window: usize) {
+		let mut variants = self.variants.lock().unwrap();
+		let timings = variants.entry(variant.to_string()).or_default();
+		let samples = match phase {
+			Phase::Build => &mut timings.build,
+			Phase::Profile => &mut timings.profile,
+		};
+		samples.push_back(took);
+		while samples.len() > window {
+			samples.pop_front();
+		}
+	}
+
+	/// The mean, median and 95th percentile of each phase, in seconds,
+	/// by variant.
+	pub fn to_json(&self) -> Value {
+		let variants = self.variants.lock().unwrap();
+		let mut summary = Map::new();
+		for (name, timings) in variants.iter() {
+			summary.insert(
+				name.clone(),
+				json!({
+					"build": summarize(&timings.build),
+					"profile": summarize(&timings.profile),
+				}),
+			);
+		}
+		Value::Object(summary)
+	}
+}
+
+fn summarize(samples: &VecDeque<Duration>) -> Value {
+	if samples.is_empty() {
+		return Value::Null;
+	}
+	let mut secs: Vec<f64> = samples.iter().map(Duration::as_secs_f64).collect();
+	secs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+	let mean = secs.iter().sum::<f64>() / secs.len() as f64;
+	json!({
+		"count": secs.len(),
+		"mean_secs": mean,
+		"p50_secs": percentile(&secs, 50),
+		"p95_secs": percentile(&secs, 95),
+	})
+}
+
+/*
+ * The nearest-rank percentile of sorted, non-empty samples.
+ */
+fn percentile(sorted: &[f64], p: usize) -> f64 {
+	let rank = (p * sorted.len()).div_ceil(100);
+	sorted[rank.max(1) - 1]
+}
@@ -0,0 +1,255 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use openssl::error::ErrorStack;
+use openssl::ssl::{
+	ErrorCode, HandshakeError, MidHandshakeSslStream, SslAcceptor, SslFiletype, SslMethod,
+	SslStream,
+};
+use signal_hook::iterator::Signals;
+use signal_hook::SIGHUP;
+use slog::{error, info, Logger};
+use std::io::{self, Read, Write};
+use std::sync::{Arc, RwLock};
+use tokio::prelude::{Async, AsyncRead, AsyncWrite, Poll};
+
+/// The certificate and key the server terminates TLS with. They are
+/// read again whenever the process gets a SIGHUP, so that a renewed
+/// certificate can be put in place without a restart.
+#[derive(Clone)]
+pub struct Certificates {
+	cert_path: String,
+	key_path: String,
+	acceptor: Arc<RwLock<Arc<SslAcceptor>>>,
+}
+
+impl Certificates {
+	/// Read the PEM certificate chain at `cert_path` and the PEM private
+	/// key for it at `key_path`.
+	pub fn load(cert_path: &str, key_path: &str) -> Result<Self, String> {
+		Ok(Self {
+			cert_path: cert_path.to_string(),
+			key_path: key_path.to_string(),
+			acceptor: Arc::new(RwLock::new(Arc::new(acceptor(cert_path, key_path)?))),
+		})
+	}
+
+	/// Read the certificate and key again. Connections already made keep
+	/// the ones they were made with, and nothing changes unless both
+	/// load cleanly.
+	pub fn reload(&self) -> Result<(), String> {
+		let acceptor = acceptor(&self.cert_path, &self.key_path)?;
+		*self.acceptor.write().unwrap() = Arc::new(acceptor);
+		Ok(())
+	}
+
+	/// `stream`, with TLS on the wire. The handshake happens on the
+	/// connection's first read or write, so a client slow to shake hands
+	/// holds up only its own connection.
+	pub fn accept<S>(&self, stream: S) -> TlsStream<S> {
+		let acceptor = self.acceptor.read().unwrap().clone();
+		TlsStream(State::Accepting(acceptor, stream))
+	}
+
+	/// Read the certificate and key again on every SIGHUP, from a thread
+	/// of their own.
+	pub fn reload_on_sighup(&self, logger: Logger) -> Result<(), String> {
+		let signals = Signals::new([SIGHUP])
+			.map_err(|e| format!("Oops, couldn't watch for SIGHUP: {}", e))?;
+		let certificates = self.clone();
+		std::thread::spawn(move || {
+			for _ in signals.forever() {
+				match certificates.reload() {
+					Ok(()) => info!(logger, "Re-read the TLS certificate and key."),
+					Err(e) => error!(logger, "{}", e),
+				}
+			}
+		});
+		Ok(())
+	}
+}
+
+fn acceptor(cert_path: &str, key_path: &str) -> Result<SslAcceptor, String> {
+	/* Mozilla's intermediate settings, which every browser GitHub uses. */
+	let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())
+		.map_err(|e| format!("Oops, couldn't set up TLS: {}", e))?;
+	let cert_error = |e: ErrorStack| {
+		format!(
+			"Oops, couldn't use the TLS certificate {}: {}",
+			cert_path, e
+		)
+	};
+	let key_error = |e: ErrorStack| format!("Oops, couldn't use the TLS key {}: {}", key_path, e);
+	builder
+		.set_certificate_chain_file(cert_path)
+		.map_err(cert_error)?;
+	builder
+		.set_private_key_file(key_path, SslFiletype::PEM)
+		.map_err(key_error)?;
+	builder.check_private_key().map_err(key_error)?;
+	Ok(builder.build())
+}
+
+/// A connection with TLS on the wire, handshaking on first use.
+pub struct TlsStream<S>(State<S>);
+
+enum State<S> {
+	Accepting(Arc<SslAcceptor>, S),
+	Handshaking(MidHandshakeSslStream<S>),
+	Established(SslStream<S>),
+	Failed,
+}
+
+impl<S: Read + Write> TlsStream<S> {
+	/*
+	 * The stream, once the handshake is done. Until it is, this is as
+	 * far as the handshake got without blocking: a WouldBlock tells the
+	 * runtime to come back once the client has said more.
+	 */
+	fn established(&mut self) -> io::Result<&mut SslStream<S>> {
+		let handshake = match std::mem::replace(&mut self.0, State::Failed) {
+			State::Accepting(acceptor, stream) => acceptor.accept(stream),
+			State::Handshaking(mid) => mid.handshake(),
+			State::Established(stream) => Ok(stream),
+			State::Failed => return Err(io::ErrorKind::NotConnected.into()),
+		};
+		match handshake {
+			Ok(stream) => self.0 = State::Established(stream),
+			Err(HandshakeError::WouldBlock(mid)) => {
+				self.0 = State::Handshaking(mid);
+				return Err(io::ErrorKind::WouldBlock.into());
+			}
+			Err(HandshakeError::Failure(mid)) => return Err(io::Error::other(mid.into_error())),
+			Err(HandshakeError::SetupFailure(e)) => return Err(io::Error::other(e)),
+		}
+		match &mut self.0 {
+			State::Established(stream) => Ok(stream),
+			_ => Err(io::ErrorKind::NotConnected.into()),
+		}
+	}
+}
+
+impl<S: Read + Write> Read for TlsStream<S> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		self.established()?.read(buf)
+	}
+}
+
+impl<S: Read + Write> Write for TlsStream<S> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.established()?.write(buf)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.established()?.flush()
+	}
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncRead for TlsStream<S> {}
+
+impl<S: AsyncRead + AsyncWrite> AsyncWrite for TlsStream<S> {
+	fn shutdown(&mut self) -> Poll<(), io::Error> {
+		let stream = match self.established() {
+			Ok(stream) => stream,
+			Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+			Err(e) => return Err(e),
+		};
+		match stream.shutdown() {
+			Ok(_) => (),
+			/* The client closed its side first. */
+			Err(ref e) if e.code() == ErrorCode::ZERO_RETURN => (),
+			Err(e) => {
+				return match e.into_io_error() {
+					Ok(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+					Ok(e) => Err(e),
+					Err(e) => Err(io::Error::other(e)),
+				}
+			}
+		}
+		stream.get_mut().shutdown()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use openssl::asn1::Asn1Time;
+	use openssl::hash::MessageDigest;
+	use openssl::pkey::PKey;
+	use openssl::rsa::Rsa;
+	use openssl::ssl::{SslConnector, SslVerifyMode};
+	use openssl::x509::{X509NameBuilder, X509};
+	use std::net::{TcpListener, TcpStream};
+	use tempdir::TempDir;
+
+	/* A self-signed certificate for localhost and its key, as PEM. */
+	fn self_signed() -> (Vec<u8>, Vec<u8>) {
+		let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+		let mut name = X509NameBuilder::new().unwrap();
+		name.append_entry_by_text("CN", "localhost").unwrap();
+		let name = name.build();
+		let mut cert = X509::builder().unwrap();
+		cert.set_version(2).unwrap();
+		cert.set_subject_name(&name).unwrap();
+		cert.set_issuer_name(&name).unwrap();
+		cert.set_pubkey(&key).unwrap();
+		cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+			.unwrap();
+		cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+			.unwrap();
+		cert.sign(&key, MessageDigest::sha256()).unwrap();
+		(
+			cert.build().to_pem().unwrap(),
+			key.private_key_to_pem_pkcs8().unwrap(),
+		)
+	}
+
+	#[test]
+	fn shakes_hands_on_first_read() {
+		let dir = TempDir::new("ndhook-tls").unwrap();
+		let cert_path = dir.path().join("cert.pem");
+		let key_path = dir.path().join("key.pem");
+		let (cert, key) = self_signed();
+		std::fs::write(&cert_path, cert).unwrap();
+		std::fs::write(&key_path, key).unwrap();
+		let certificates =
+			Certificates::load(cert_path.to_str().unwrap(), key_path.to_str().unwrap()).unwrap();
+		assert!(certificates.reload().is_ok());
+
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		let client = std::thread::spawn(move || {
+			let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+			connector.set_verify(SslVerifyMode::NONE);
+			let stream = TcpStream::connect(addr).unwrap();
+			let mut stream = connector.build().connect("localhost", stream).unwrap();
+			stream.write_all(b"ping").unwrap();
+			let mut pong = [0u8; 4];
+			stream.read_exact(&mut pong).unwrap();
+			pong
+		});
+		let (stream, _) = listener.accept().unwrap();
+		let mut stream = certificates.accept(stream);
+		let mut ping = [0u8; 4];
+		stream.read_exact(&mut ping).unwrap();
+		assert_eq!(&ping, b"ping");
+		stream.write_all(b"pong").unwrap();
+		assert_eq!(&client.join().unwrap(), b"pong");
+	}
+
+	#[test]
+	fn refuses_a_key_for_another_certificate() {
+		let dir = TempDir::new("ndhook-tls").unwrap();
+		let cert_path = dir.path().join("cert.pem");
+		let key_path = dir.path().join("key.pem");
+		std::fs::write(&cert_path, self_signed().0).unwrap();
+		std::fs::write(&key_path, self_signed().1).unwrap();
+		let e = Certificates::load(cert_path.to_str().unwrap(), key_path.to_str().unwrap())
+			.err()
+			.unwrap();
+		assert!(e.contains("TLS key"), "{}", e);
+	}
+}
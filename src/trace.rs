@@ -0,0 +1,191 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+/*
+ * Optional OpenTelemetry traces of each profile job, exported as OTLP
+ * over HTTP.
+ *
+ * A job runs start to finish on one worker thread, so the trace being
+ * recorded lives in a thread local and any step of the pipeline can
+ * open a span without the trace being passed to it.
+ */
+
+use crate::config::Config;
+use serde_json::{json, Value};
+use slog::{error, info, Logger};
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+struct SpanRecord {
+	span_id: String,
+	parent_span_id: Option<String>,
+	name: String,
+	start: u128,
+	end: u128,
+	attributes: Vec<(String, String)>,
+}
+
+struct Recording {
+	endpoint: String,
+	trace_id: String,
+	/* The spans still open, innermost last; the root is first. */
+	open: Vec<String>,
+	finished: Vec<SpanRecord>,
+}
+
+thread_local! {
+	static CURRENT: RefCell<Option<Recording>> = const { RefCell::new(None) };
+}
+
+fn now() -> u128 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_nanos())
+		.unwrap_or(0)
+}
+
+/// A random hex ID `bytes` long. RandomState is seeded randomly, which
+/// is all the randomness trace and span IDs need.
+pub fn random_id(bytes: usize) -> String {
+	let mut id = String::new();
+	while id.len() < bytes * 2 {
+		let mut hasher = RandomState::new().build_hasher();
+		hasher.write_u128(now());
+		id.push_str(&format!("{:016x}", hasher.finish()));
+	}
+	id.truncate(bytes * 2);
+	id
+}
+
+/// A span of the current trace. It ends when dropped.
+pub struct Span {
+	id: Option<String>,
+	name: String,
+	start: u128,
+	attributes: Vec<(String, String)>,
+}
+
+impl Span {
+	pub fn attribute(&mut self, key: &str, value: &str) {
+		self.attributes.push((key.to_string(), value.to_string()));
+	}
+}
+
+impl Drop for Span {
+	fn drop(&mut self) {
+		let id = match self.id.take() {
+			Some(id) => id,
+			None => return,
+		};
+		CURRENT.with(|current| {
+			if let Some(recording) = current.borrow_mut().as_mut() {
+				recording.open.retain(|open| *open != id);
+				recording.finished.push(SpanRecord {
+					span_id: id,
+					parent_span_id: recording.open.last().cloned(),
+					name: self.name.clone(),
+					start: self.start,
+					end: now(),
+					attributes: self.attributes.drain(..).collect(),
+				});
+			}
+		});
+	}
+}
+
+/// Open a span in the current trace. Without one this does nothing.
+pub fn span(name: &str) -> Span {
+	let id = CURRENT.with(|current| {
+		current.borrow_mut().as_mut().map(|recording| {
+			let id = random_id(8);
+			recording.open.push(id.clone());
+			id
+		})
+	});
+	Span {
+		id,
+		name: name.to_string(),
+		start: now(),
+		attributes: vec![],
+	}
+}
+
+/// Begin recording a trace on this thread, if tracing is configured,
+/// and return its root span. Hand the root span to `finish` to export.
+pub fn start(config: &Config, name: &str, attributes: &[(&str, &str)]) -> Span {
+	if let (true, Some(endpoint)) = (cfg!(feature = "otel"), &config.otel_endpoint) {
+		CURRENT.with(|current| {
+			*current.borrow_mut() = Some(Recording {
+				endpoint: endpoint.clone(),
+				trace_id: random_id(16),
+				open: vec![],
+				finished: vec![],
+			})
+		});
+	}
+	let mut root = span(name);
+	for (key, value) in attributes {
+		root.attribute(key, value);
+	}
+	root
+}
+
+/// End the root span and send the trace to the collector.
+pub fn finish(root: Span, logger: &Logger) {
+	drop(root);
+	let recording = match CURRENT.with(|current| current.borrow_mut().take()) {
+		Some(recording) => recording,
+		None => return,
+	};
+
+	let spans: Vec<Value> = recording
+		.finished
+		.iter()
+		.map(|span| {
+			let attributes: Vec<Value> = span
+				.attributes
+				.iter()
+				.map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+				.collect();
+			let mut json = json!({
+				"traceId": recording.trace_id,
+				"spanId": span.span_id,
+				"name": span.name,
+				"kind": 1,
+				"startTimeUnixNano": span.start.to_string(),
+				"endTimeUnixNano": span.end.to_string(),
+				"attributes": attributes,
+			});
+			if let Some(parent_span_id) = &span.parent_span_id {
+				json["parentSpanId"] = json!(parent_span_id);
+			}
+			json
+		})
+		.collect();
+	let export = json!({
+		"resourceSpans": [{
+			"resource": {
+				"attributes": [{ "key": "service.name", "value": { "stringValue": "ndhook" } }],
+			},
+			"scopeSpans": [{ "scope": { "name": "ndhook" }, "spans": spans }],
+		}],
+	});
+
+	let url = format!("{}/v1/traces", recording.endpoint.trim_end_matches('/'));
+	match reqwest::Client::new().post(&url).json(&export).send() {
+		Ok(ref response) if response.status().is_success() => {
+			info!(logger, "Exported a trace of {} spans.", spans.len())
+		}
+		Ok(response) => error!(
+			logger,
+			"The trace collector responded {} to an export.",
+			response.status()
+		),
+		Err(e) => error!(logger, "Failed to export a trace: {}", e),
+	}
+}